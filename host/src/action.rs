@@ -0,0 +1,266 @@
+//! Actions that can be assigned to a button.
+//!
+//! Every action is stored as two bytes, the HID usage page followed by the usage ID, this way codes
+//! from different pages that share the same value can't be mistaken for each other.
+use crate::{
+    consumer::ConsumerCode,
+    key_code::KeyCode,
+    mouse::{MouseButton, MouseDirection},
+};
+use core::convert::TryFrom;
+use num_enum::TryFromPrimitive;
+
+/// HID usage pages supported by the buttons.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, TryFromPrimitive)]
+#[repr(u8)]
+pub enum Page {
+    Keyboard = 0x07,
+    Button = 0x09,
+    Consumer = 0x0C,
+    /// Not a HID usage page, the usage is the index of a sequence stored in the `Matrix`.
+    Sequence = 0x80,
+    /// Not a HID usage page, the usage is the modifier byte of the keyboard report.
+    Modifiers = 0x81,
+    /// Not a HID usage page, the usage is the tap key of a dual-role button.
+    TapHold = 0x82,
+    /// Not a HID usage page, the usage is the key latched by a toggle button.
+    Toggle = 0x83,
+    /// Not a HID usage page, the usage is the modifier byte applied by a one-shot button.
+    OneShot = 0x84,
+    /// Not a HID usage page, the usage is the direction the cursor moves.
+    MouseMove = 0x85,
+    /// Not a HID usage page, the usage is the key sent by a single tap of a double-tap button.
+    DoubleTap = 0x86,
+    /// Not a HID usage page, the usage is always 0.
+    NextProfile = 0x87,
+    /// Not a HID usage page, the usage is always 0.
+    CapsWord = 0x88,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    /// A key from the keyboard/keypad page.
+    Key(KeyCode),
+    /// A usage from the consumer page, only usages that fit in one byte are supported.
+    Consumer(u8),
+    /// A mouse button from the button page.
+    Mouse(MouseButton),
+    /// Types the sequence stored in the given slot of the `Matrix`.
+    Sequence(u8),
+    /// Only sets the modifiers, as given by `KeyCode::as_modifier_bit`, like real modifier keys
+    /// held together.
+    Modifiers(u8),
+    /// Sends the key when tapped, or the hold modifiers of the button stored in the `Matrix` when
+    /// held past the tap-hold threshold.
+    TapHold(KeyCode),
+    /// The first press latches the key, it stays pressed until the button is pressed again.
+    Toggle(KeyCode),
+    /// Sets the modifiers while held like `Modifiers`, a press also applies them to the next key
+    /// sent by another button, even after the one-shot button is released.
+    OneShot(u8),
+    /// Moves the cursor while held, by the mouse speed stored in the `Matrix` on every scan.
+    MouseMove(MouseDirection),
+    /// Sends the key when tapped once, or the double-tap key of the button stored in the `Matrix`
+    /// when tapped twice within the tap-hold threshold.
+    DoubleTap(KeyCode),
+    /// Switches to the next profile stored in flash, see `storage::NUM_PROFILES`.
+    NextProfile,
+    /// Toggles caps word, which shifts the letters typed until a key that isn't part of a word.
+    CapsWord,
+}
+
+impl Action {
+    /// Number of bytes used to store an action.
+    pub const SIZE: usize = 2;
+    /// Does nothing, for a button that's disabled without being removed.
+    pub const NONE: Action = Action::Key(KeyCode::No);
+
+    pub fn is_none(self) -> bool {
+        self == Self::NONE
+    }
+
+    pub fn page(self) -> Page {
+        match self {
+            Action::Key(_) => Page::Keyboard,
+            Action::Consumer(_) => Page::Consumer,
+            Action::Mouse(_) => Page::Button,
+            Action::Sequence(_) => Page::Sequence,
+            Action::Modifiers(_) => Page::Modifiers,
+            Action::TapHold(_) => Page::TapHold,
+            Action::Toggle(_) => Page::Toggle,
+            Action::OneShot(_) => Page::OneShot,
+            Action::MouseMove(_) => Page::MouseMove,
+            Action::DoubleTap(_) => Page::DoubleTap,
+            Action::NextProfile => Page::NextProfile,
+            Action::CapsWord => Page::CapsWord,
+        }
+    }
+
+    pub fn to_bytes(self) -> [u8; Self::SIZE] {
+        let usage = match self {
+            Action::Key(code)
+            | Action::TapHold(code)
+            | Action::Toggle(code)
+            | Action::DoubleTap(code) => code as u8,
+            Action::Mouse(button) => button as u8,
+            Action::MouseMove(direction) => direction as u8,
+            Action::Consumer(usage)
+            | Action::Sequence(usage)
+            | Action::Modifiers(usage)
+            | Action::OneShot(usage) => usage,
+            Action::NextProfile | Action::CapsWord => 0,
+        };
+        [self.page() as u8, usage]
+    }
+
+    pub fn from_bytes(bytes: [u8; Self::SIZE]) -> Option<Self> {
+        let [page, usage] = bytes;
+        match Page::try_from(page).ok()? {
+            Page::Keyboard => KeyCode::try_from(usage).ok().map(Action::Key),
+            Page::Consumer => Some(Action::Consumer(usage)),
+            Page::Button => MouseButton::try_from(usage).ok().map(Action::Mouse),
+            Page::Sequence => Some(Action::Sequence(usage)),
+            Page::Modifiers => Some(Action::Modifiers(usage)),
+            Page::TapHold => KeyCode::try_from(usage).ok().map(Action::TapHold),
+            Page::Toggle => KeyCode::try_from(usage).ok().map(Action::Toggle),
+            Page::OneShot => Some(Action::OneShot(usage)),
+            Page::MouseMove => MouseDirection::try_from(usage).ok().map(Action::MouseMove),
+            Page::DoubleTap => KeyCode::try_from(usage).ok().map(Action::DoubleTap),
+            Page::NextProfile if usage == 0 => Some(Action::NextProfile),
+            Page::CapsWord if usage == 0 => Some(Action::CapsWord),
+            Page::NextProfile | Page::CapsWord => None,
+        }
+    }
+}
+
+impl From<ConsumerCode> for Action {
+    fn from(code: ConsumerCode) -> Self {
+        Action::Consumer(code as u8)
+    }
+}
+
+impl From<KeyCode> for Action {
+    fn from(code: KeyCode) -> Self {
+        Action::Key(code)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keyboard_round_trip() {
+        for &code in &[
+            KeyCode::No,
+            KeyCode::A,
+            KeyCode::ExSel,
+            KeyCode::LCtrl,
+            KeyCode::MediaCalc,
+        ] {
+            let action = Action::Key(code);
+            assert_eq!(action.to_bytes(), [Page::Keyboard as u8, code as u8]);
+            assert_eq!(Action::from_bytes(action.to_bytes()), Some(action));
+        }
+    }
+
+    #[test]
+    fn consumer_round_trip() {
+        for &usage in &[0x00, 0xCD, 0xE9, 0xFF] {
+            let action = Action::Consumer(usage);
+            assert_eq!(action.to_bytes(), [Page::Consumer as u8, usage]);
+            assert_eq!(Action::from_bytes(action.to_bytes()), Some(action));
+        }
+    }
+
+    #[test]
+    fn sequence_round_trip() {
+        let action = Action::Sequence(2);
+        assert_eq!(action.to_bytes(), [Page::Sequence as u8, 2]);
+        assert_eq!(Action::from_bytes(action.to_bytes()), Some(action));
+    }
+
+    #[test]
+    fn modifiers_round_trip() {
+        let bits = KeyCode::LCtrl.as_modifier_bit() | KeyCode::LShift.as_modifier_bit();
+        let action = Action::Modifiers(bits);
+        assert_eq!(action.to_bytes(), [Page::Modifiers as u8, bits]);
+        assert_eq!(Action::from_bytes(action.to_bytes()), Some(action));
+    }
+
+    #[test]
+    fn tap_hold_round_trip() {
+        let action = Action::TapHold(KeyCode::Escape);
+        assert_eq!(
+            action.to_bytes(),
+            [Page::TapHold as u8, KeyCode::Escape as u8]
+        );
+        assert_eq!(Action::from_bytes(action.to_bytes()), Some(action));
+        assert_eq!(Action::from_bytes([Page::TapHold as u8, 0xA5]), None);
+
+        let action = Action::DoubleTap(KeyCode::Escape);
+        assert_eq!(Action::from_bytes(action.to_bytes()), Some(action));
+    }
+
+    #[test]
+    fn toggle_round_trip() {
+        let action = Action::Toggle(KeyCode::LShift);
+        assert_eq!(
+            action.to_bytes(),
+            [Page::Toggle as u8, KeyCode::LShift as u8]
+        );
+        assert_eq!(Action::from_bytes(action.to_bytes()), Some(action));
+    }
+
+    #[test]
+    fn one_shot_round_trip() {
+        let action = Action::OneShot(KeyCode::LAlt.as_modifier_bit());
+        assert_eq!(
+            action.to_bytes(),
+            [Page::OneShot as u8, KeyCode::LAlt.as_modifier_bit()]
+        );
+        assert_eq!(Action::from_bytes(action.to_bytes()), Some(action));
+    }
+
+    #[test]
+    fn mouse_round_trip() {
+        let action = Action::Mouse(MouseButton::Right);
+        assert_eq!(action.to_bytes(), [Page::Button as u8, 2]);
+        assert_eq!(Action::from_bytes(action.to_bytes()), Some(action));
+        assert_eq!(Action::from_bytes([Page::Button as u8, 0]), None);
+
+        let action = Action::MouseMove(MouseDirection::Down);
+        assert_eq!(Action::from_bytes(action.to_bytes()), Some(action));
+        assert_eq!(Action::from_bytes([Page::MouseMove as u8, 4]), None);
+    }
+
+    #[test]
+    fn next_profile_round_trip() {
+        let action = Action::NextProfile;
+        assert_eq!(action.to_bytes(), [Page::NextProfile as u8, 0]);
+        assert_eq!(Action::from_bytes(action.to_bytes()), Some(action));
+        assert_eq!(Action::from_bytes([Page::NextProfile as u8, 1]), None);
+
+        let action = Action::CapsWord;
+        assert_eq!(Action::from_bytes(action.to_bytes()), Some(action));
+        assert_eq!(Action::from_bytes([Page::CapsWord as u8, 1]), None);
+    }
+
+    #[test]
+    fn same_usage_different_page() {
+        let key = Action::Key(KeyCode::VolUp);
+        let consumer = Action::Consumer(KeyCode::VolUp as u8);
+        assert_ne!(key.to_bytes(), consumer.to_bytes());
+        assert_eq!(Action::from_bytes(consumer.to_bytes()), Some(consumer));
+    }
+
+    #[test]
+    fn invalid_bytes() {
+        // Unknown page
+        assert_eq!(Action::from_bytes([0x01, 0x04]), None);
+        // Erased flash
+        assert_eq!(Action::from_bytes([0xFF, 0xFF]), None);
+        // Key code in the unusable range
+        assert_eq!(Action::from_bytes([Page::Keyboard as u8, 0xA5]), None);
+    }
+}
@@ -1,93 +1,298 @@
-use anyhow::{anyhow, Context, Result};
+use crate::transport::{NoDevice, Transport};
+use anyhow::{anyhow, Context, Error, Result};
 use crossterm::{
     cursor, execute, queue,
-    style::{self, Colorize},
+    style::{self, Colorize, Styler},
     terminal::{self, disable_raw_mode, enable_raw_mode, ClearType},
 };
-use hidapi::{HidApi, HidDevice};
-use keylib::packets::VendorCommand;
-use keylib::{key_code::KeyCode, CTRL_INTERFACE, PID, VID};
+use hidapi::{DeviceInfo, HidApi, HidError};
+use keylib::packets::{self, InfoField, VendorCommand, Version, PROTOCOL_VERSION};
+use keylib::{
+    action::Action,
+    boot::{Status, UsbAttributes},
+    combo::{Combo, MAX_COMBOS},
+    consumer::ConsumerCode,
+    debounce::{DebounceInfo, MAX_WINDOW},
+    key_code::{KeyCategory, KeyCode},
+    matrix::{LayerState, Matrix},
+    mouse::{MouseButton, MouseDirection, MAX_MOUSE_SPEED},
+    name::{self, DeviceName},
+    repeat::RepeatConfig,
+    sequence::{Sequence, MAX_KEYS},
+    storage::{self, CONFIG_AREA_SIZE, CONFIG_SIZE, NUM_PAGES, NUM_PROFILES},
+    CTRL_INTERFACE, NUM_BTS, PID, VID,
+};
 use std::{
-    convert::AsRef,
-    fmt,
+    convert::{AsRef, TryFrom},
+    ffi::CString,
     io::{self, stdout, Stdout, Write},
+    thread,
+    time::{Duration, Instant},
 };
 use strum::IntoEnumIterator;
 
-const KEY_INPUT_LABEL: &'static str = "Search: ";
+const KEY_INPUT_LABEL: &str = "Search: ";
 const SELECT_MENU: &str = r#"Keykey configuration tool
 
 Controls:
  - 'ctrl + q' - quit
  - 'esc' - return to this menu
  - 'enter' - select key
+ - '+' - add the selected modifier to a modifier only button, e.g. lctrl+lshift
 
 Options:
 1. Config button 1
 2. Config button 2
 3. Config button 3
-s. Save current configuration to device flash
+s. Save current configuration to device flash, after listing the changed buttons
+c. Compact the device flash now, instead of on a later save (unsaved changes aren't stored)
+k. Press a button to configure it
+u. Undo the last button change made from this menu, without saving
+p. Apply a profile from disk
+e. Export the current configuration to keykey-config.json
+r. Reset the device to the defaults, after confirming
+b. Reboot device (unsaved changes are lost and the USB connection will drop)
 "#;
 
+/// Time the device gets to take a save from its queue before its status is trusted, a failure
+/// from an earlier save is still reported until then.
+const SAVE_DELAY: Duration = Duration::from_millis(50);
+/// How long a save, which may need to erase a flash page, can take to show up.
+const SAVE_TIMEOUT: Duration = Duration::from_secs(1);
+
+const PRESS_TO_SELECT: &str = "Press and release the button you want to configure, 'esc' to return";
+
+const SAVE_CONFIRM: &str =
+    "Save these changes to flash? 'y' or 'enter' to confirm, any other key to \
+                            cancel";
+
+const RESET_CONFIRM: &str = "Reset the device to the defaults? Every slot, the name and the other \
+                             settings are replaced in flash. 'y' to confirm, any other key to cancel";
+
+const DISCONNECTED: &str = "Device disconnected, waiting for it to be plugged back in, \
+                            'ctrl + q' to quit";
+
+const SAFE_MODE_WARNING: &str = "Device in safe mode: the stored configuration wasn't loaded, \
+                                 saving replaces it with the current one";
+
+const LOAD_FAILED_WARNING: &str = "The stored configuration was corrupted and the device reset to \
+                                   the defaults, saving stores the current one";
+
 pub struct App {
     current_line: usize,
+    /// First row of the hit list on the screen, headers included.
+    scroll: usize,
     user_input: String,
-    hits: Vec<KeyCode>,
-    usb_handle: HidDevice,
+    hits: Vec<Hit>,
+    /// Modifiers picked with '+', the selected key is added to them.
+    modifiers: u8,
+    /// What the buttons changed from the menu were before, the last change on top.
+    undo: Vec<Undo>,
+    usb_handle: Box<dyn Transport>,
+    /// Where the device was found, to tell if it's still plugged in. `None` without a device, see
+    /// `new_offline`.
+    path: Option<CString>,
+    /// To open the same device again once it's plugged back in, if it has one.
+    serial: Option<String>,
+}
+
+/// An entry of the key search, a button can be set to any of them.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Hit {
+    Key(KeyCode),
+    Mouse(MouseButton),
+}
+
+impl Hit {
+    /// Every entry, keys first.
+    fn all() -> impl Iterator<Item = Self> {
+        KeyCode::iter()
+            .map(Hit::Key)
+            .chain(MouseButton::iter().map(Hit::Mouse))
+    }
+
+    /// Name the search matches, as accepted by the commands, e.g. "mouseleft".
+    fn name(self) -> String {
+        match self {
+            Hit::Key(key) => String::from(key.as_ref()),
+            Hit::Mouse(mouse) => format!("mouse{}", mouse.as_ref()),
+        }
+    }
+
+    /// Name shown in the key search.
+    fn label(self) -> String {
+        match self {
+            Hit::Key(key) => key_label(key),
+            Hit::Mouse(mouse) => format!("Mouse {:?}", mouse),
+        }
+    }
+
+    /// Header of the entries of the same kind in the key search.
+    fn category(self) -> &'static str {
+        match self {
+            Hit::Key(key) => category_label(key.category()),
+            Hit::Mouse(_) => "Mouse buttons",
+        }
+    }
+
+    fn action(self) -> Action {
+        match self {
+            Hit::Key(key) => Action::Key(key),
+            Hit::Mouse(mouse) => Action::Mouse(mouse),
+        }
+    }
+}
+
+/// A line of the hit list, either a category header or the hit at an index of `App::hits`.
+#[derive(Clone, Copy)]
+enum Row {
+    Header(&'static str),
+    Hit(usize, Hit),
+}
+
+/// A button as it was before a change from the menu.
+struct Undo {
+    button: usize,
+    action: Action,
+    key_modifiers: u8,
 }
 
 impl App {
+    /// Opens the first device found, it fails if the firmware speaks another protocol version.
     pub fn new() -> Result<Self> {
+        let app = Self::new_unchecked()?;
+        app.check_protocol()?;
+        Ok(app)
+    }
+
+    /// Opens the first device found without checking its protocol version, only for reading what
+    /// describes the device, e.g. for the diagnostics.
+    pub fn new_unchecked() -> Result<Self> {
         let context = HidApi::new().context("Failed to create hidapi context")?;
-        let mut usb_handle = None;
-
-        for device in context.device_list() {
-            if device.vendor_id() == VID
-                && device.product_id() == PID
-                && device.interface_number() == CTRL_INTERFACE as i32
-            {
-                usb_handle = Some(
-                    device
-                        .open_device(&context)
-                        .context("Failed to open device")?,
-                );
-                break;
-            }
-        }
+        let device = context
+            .device_list()
+            .find(|device| is_keykey(device))
+            .ok_or_else(|| anyhow!("Couldn't find suitable device."))?;
+        Self::open(&context, device)
+    }
+
+    /// Doesn't look for a device, so the search and the menu work without one. Everything that
+    /// talks to the device fails with a "no device" error instead.
+    pub fn new_offline() -> Self {
+        Self::with_transport(Box::new(NoDevice), None, None)
+    }
+
+    /// Opens every device found, to tell them apart by name. Their protocol version isn't checked.
+    pub fn all() -> Result<Vec<Self>> {
+        let context = HidApi::new().context("Failed to create hidapi context")?;
+        context
+            .device_list()
+            .filter(|device| is_keykey(device))
+            .map(|device| Self::open(&context, device))
+            .collect()
+    }
 
+    fn open(context: &HidApi, device: &DeviceInfo) -> Result<Self> {
+        let usb_handle = device.open_device(context).map_err(open_error)?;
+        Ok(Self::with_transport(
+            Box::new(usb_handle),
+            Some(device.path().to_owned()),
+            device.serial_number().map(String::from),
+        ))
+    }
+
+    /// Talks to the device through `usb_handle`, found at `path`.
+    fn with_transport(
+        usb_handle: Box<dyn Transport>,
+        path: Option<CString>,
+        serial: Option<String>,
+    ) -> Self {
         let mut app = Self {
             current_line: 0,
+            scroll: 0,
             user_input: String::with_capacity(16),
             hits: Vec::with_capacity(16),
-            usb_handle: usb_handle.ok_or_else(|| anyhow!("Couldn't find suitable device."))?,
+            modifiers: 0,
+            undo: Vec::new(),
+            usb_handle,
+            path,
+            serial,
         };
         app.search_all();
-        Ok(app)
+        app
+    }
+
+    /// Checks if the device is still plugged in, to tell an unplugged device from any other
+    /// failure. Assumes it is if the devices can't be listed, or if there was none to begin with,
+    /// so errors are shown instead of waiting for it.
+    pub fn is_connected(&self) -> bool {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return true,
+        };
+        match HidApi::new() {
+            Ok(context) => context
+                .device_list()
+                .any(|device| device.path() == path.as_c_str()),
+            Err(_) => true,
+        }
+    }
+
+    /// Opens the device again after it was unplugged, returns `false` if it isn't back yet. The
+    /// search and selection are kept, while the undo history is dropped, since the device lost
+    /// the unsaved changes it refers to.
+    pub fn reconnect(&mut self) -> Result<bool> {
+        let context = HidApi::new().context("Failed to create hidapi context")?;
+        let device = context.device_list().find(|device| {
+            is_keykey(device)
+                && (self.serial.is_none() || device.serial_number() == self.serial.as_deref())
+        });
+        // It may take a moment to be usable after showing up
+        let (device, usb_handle) = match device {
+            Some(device) => match device.open_device(&context) {
+                Ok(usb_handle) => (device, usb_handle),
+                Err(_) => return Ok(false),
+            },
+            None => return Ok(false),
+        };
+        self.usb_handle = Box::new(usb_handle);
+        self.path = Some(device.path().to_owned());
+        self.undo.clear();
+        // Another firmware may have been flashed in the meantime
+        self.check_protocol()?;
+        Ok(true)
     }
 
     pub fn push_char_hit(&mut self, mut new: char) {
+        if new == '+' {
+            self.push_modifier();
+            return;
+        }
         if !new.is_ascii_alphanumeric() {
             return;
         }
         new.make_ascii_lowercase();
         self.user_input.push(new);
-
-        let input = self.user_input.as_str();
-        let new_hits = self
-            .hits
-            .iter()
-            .filter(|&k| k.as_ref().starts_with(input))
-            .map(|k| *k)
-            .collect();
-        self.hits = new_hits;
-        if self.current_line + 1 > self.hits.len() {
-            self.current_line = self.hits.len().saturating_sub(1);
-        }
+        // The ranks change with every letter, so search again instead of filtering the hits
+        self.search_all();
     }
 
     pub fn backspace(&mut self) {
-        if let Some(_) = self.user_input.pop() {
+        if self.user_input.pop().is_some() {
             self.search_all();
+        } else {
+            self.modifiers = 0;
+        }
+    }
+
+    /// Adds the selected key to the modifiers if it's a modifier, to search for the next one.
+    fn push_modifier(&mut self) {
+        if let Some(&Hit::Key(key)) = self.hits.get(self.current_line) {
+            if key.is_modifier() {
+                self.modifiers |= key.as_modifier_bit();
+                self.user_input.clear();
+                self.search_all();
+            }
         }
     }
 
@@ -103,23 +308,61 @@ impl App {
 
     pub fn clear(&mut self) {
         self.user_input.clear();
+        self.modifiers = 0;
         self.search_all();
     }
 
-    pub fn render(&self, w: &mut impl Write) -> Result<()> {
+    /// Draws the hits that fit below the search, scrolling to keep the selected one visible.
+    pub fn render(&mut self, w: &mut impl Write) -> Result<()> {
         queue!(
             w,
             style::ResetColor,
             terminal::Clear(ClearType::All),
             cursor::MoveTo(0, 1),
         )?;
-        for (index, &key) in self.hits.iter().enumerate() {
-            let mut text = String::new();
-            fmt::write(&mut text, format_args!("{:?}", key))?;
-            if index == self.current_line {
-                queue!(w, style::Print(text.black().on_yellow()))?;
-            } else {
-                queue!(w, style::Print(text))?;
+        let mut rows = Vec::with_capacity(self.hits.len());
+        let mut category = None;
+        for (index, &hit) in self.hits.iter().enumerate() {
+            // Only a header for every run of keys, hits are ordered by rank first
+            if category != Some(hit.category()) {
+                category = Some(hit.category());
+                rows.push(Row::Header(hit.category()));
+            }
+            rows.push(Row::Hit(index, hit));
+        }
+
+        // The first row is taken by the search
+        let height = terminal::size().map_or(24, |(_, rows)| usize::from(rows));
+        let visible = height.saturating_sub(1).max(1);
+        let selected = rows
+            .iter()
+            .position(|row| matches!(row, Row::Hit(index, _) if *index == self.current_line))
+            .unwrap_or(0);
+        if selected < self.scroll {
+            self.scroll = selected;
+            // Show the header of the selected key too
+            if let Some(Row::Header(_)) = selected.checked_sub(1).map(|row| rows[row]) {
+                self.scroll -= 1;
+            }
+        } else if selected >= self.scroll + visible {
+            self.scroll = selected + 1 - visible;
+        }
+        // A shorter list after another search shouldn't leave the screen half empty
+        self.scroll = self.scroll.min(rows.len().saturating_sub(visible));
+
+        for &row in rows.iter().skip(self.scroll).take(visible) {
+            match row {
+                Row::Header(category) => {
+                    queue!(w, style::Print(category.dim()))?;
+                }
+                Row::Hit(index, hit) => {
+                    let text = hit.label();
+                    if index == self.current_line {
+                        queue!(w, style::Print(text.black().on_yellow()))?;
+                    } else {
+                        queue!(w, style::Print(text))?;
+                    }
+                }
             }
             queue!(w, cursor::MoveToNextLine(1))?;
         }
@@ -127,63 +370,1187 @@ impl App {
             w,
             cursor::MoveTo(0, 0),
             style::Print(KEY_INPUT_LABEL),
+            style::Print(modifier_names(self.modifiers)),
             style::Print(&self.user_input),
         )?;
         w.flush()?;
         Ok(())
     }
 
-    pub fn send_selected(&mut self, command: VendorCommand) -> Result<()> {
-        let key = self
+    /// Sets `button` to the selected hit, button indexes start at 0.
+    pub fn send_selected(&mut self, button: usize) -> Result<()> {
+        let hit = *self
             .hits
             .get(self.current_line)
             .ok_or_else(|| anyhow!("Internal Error: Could not find selected key"))?;
 
+        let action = match hit {
+            _ if self.modifiers == 0 => hit.action(),
+            Hit::Key(key) if key.is_modifier() => {
+                Action::Modifiers(self.modifiers | key.as_modifier_bit())
+            }
+            _ => {
+                return Err(anyhow!(
+                    "Only modifiers can be combined, {} isn't one",
+                    hit.label()
+                ))
+            }
+        };
+        let report = packets::set_action_report(button, action)
+            .ok_or_else(|| anyhow!("Internal Error: Invalid button {}.", button))?;
+        // Older firmware can't read the layout back, changes just can't be undone then
+        let previous = self.read_config().ok().map(|matrix| Undo {
+            button,
+            action: matrix.layout()[button],
+            key_modifiers: matrix.key_modifiers()[button],
+        });
+        self.send_checked(&report)
+            .context("Failed to send feature report.")?;
+        self.undo.extend(previous);
+        Ok(())
+    }
+
+    /// Sends a command with a checksum, so the device rejects it if it gets corrupted, see
+    /// `packets::CHECKSUM_FLAG`. Firmware that passed `check_protocol` always accepts the
+    /// checksum, so a rejected report is an error instead of being sent again without it.
+    fn send_checked(&self, report: &[u8]) -> Result<()> {
+        let checked = packets::with_checksum(report)
+            .ok_or_else(|| anyhow!("Internal Error: Invalid report length {}.", report.len()))?;
         // First byte is the report ID
-        let data = [0, command as u8, *key as u8];
+        let mut data = [0; packets::CTRL_REPORT_SIZE + 1];
+        data[1..].copy_from_slice(&checked);
+        self.usb_handle.send_feature_report(&data[..])?;
+        Ok(())
+    }
+
+    /// Sets the button changed last from the menu back to what it was, without saving. Returns
+    /// `false` if there's nothing to undo.
+    pub fn undo(&mut self) -> Result<bool> {
+        let undo = match self.undo.pop() {
+            Some(undo) => undo,
+            None => return Ok(false),
+        };
+        let report = packets::set_action_report(undo.button, undo.action)
+            .ok_or_else(|| anyhow!("Internal Error: Invalid button"))?;
+        self.send_checked(&report)
+            .context("Failed to undo the last change.")?;
+        // Setting the key cleared them
+        if matches!(undo.action, Action::Key(_)) && undo.key_modifiers != 0 {
+            let command = VendorCommand::SetKeyModifiers as u8;
+            self.send_checked(&[command, undo.button as u8, undo.key_modifiers])
+                .context("Failed to undo the last change.")?;
+        }
+        Ok(true)
+    }
+
+    /// Sets `button` to hold all of `modifiers` without any other key and saves the
+    /// configuration, button indexes start at 0.
+    pub fn set_modifier_button(&mut self, button: usize, modifiers: &[KeyCode]) -> Result<()> {
+        let mut bits = 0;
+        for &key in modifiers {
+            if !key.is_modifier() {
+                return Err(anyhow!("{} isn't a modifier", key.as_ref()));
+            }
+            bits |= key.as_modifier_bit();
+        }
+        if button >= NUM_BTS {
+            return Err(anyhow!("Invalid button: {}", button + 1));
+        }
+        if bits == 0 {
+            return Err(anyhow!("At least one modifier is needed"));
+        }
+
+        self.send_checked(&[VendorCommand::SetModifiers as u8, button as u8, bits])
+            .context("Failed to send modifiers.")?;
+        self.save_config()
+    }
+
+    /// Sets `button` to send `key` together with `modifiers`, e.g. Ctrl+C, and saves the
+    /// configuration, button indexes start at 0.
+    pub fn set_shortcut(
+        &mut self,
+        button: usize,
+        key: KeyCode,
+        modifiers: &[KeyCode],
+    ) -> Result<()> {
+        let mut bits = 0;
+        for &modifier in modifiers {
+            if !modifier.is_modifier() {
+                return Err(anyhow!("{} isn't a modifier", modifier.as_ref()));
+            }
+            bits |= modifier.as_modifier_bit();
+        }
+        if button >= NUM_BTS {
+            return Err(anyhow!("Invalid button: {}", button + 1));
+        }
+
+        // The key clears the modifiers, so it goes first
+        let button = button as u8;
+        for &data in [
+            [VendorCommand::SetKey as u8, button, key as u8],
+            [VendorCommand::SetKeyModifiers as u8, button, bits],
+        ]
+        .iter()
+        {
+            self.send_checked(&data[..])
+                .context("Failed to send shortcut.")?;
+        }
+        self.save_config()
+    }
+
+    /// Sets combo `slot` to send `key` when `buttons` are pressed together and saves the
+    /// configuration, an empty `buttons` disables it. Slots and button indexes start at 0.
+    pub fn set_combo(&mut self, slot: usize, buttons: &[usize], key: KeyCode) -> Result<()> {
+        if slot >= MAX_COMBOS {
+            return Err(anyhow!("Invalid combo: {}", slot + 1));
+        }
+        let mut bits = 0u8;
+        for &button in buttons {
+            if button >= NUM_BTS {
+                return Err(anyhow!("Invalid button: {}", button + 1));
+            }
+            bits |= 1 << button;
+        }
+        if !Combo::valid_buttons(bits) {
+            return Err(anyhow!("A combo needs at least two different buttons"));
+        }
+
+        let slot = slot as u8;
+        for &data in [
+            [VendorCommand::SetComboButtons as u8, slot, bits],
+            [VendorCommand::SetCombo as u8, slot, key as u8],
+        ]
+        .iter()
+        {
+            self.send_checked(&data[..])
+                .context("Failed to send combo.")?;
+        }
+        self.save_config()
+    }
+
+    /// Sets `button` to send a media key and saves the configuration, button indexes start at 0.
+    pub fn set_consumer_button(&mut self, button: usize, code: ConsumerCode) -> Result<()> {
+        if button >= NUM_BTS {
+            return Err(anyhow!("Invalid button: {}", button + 1));
+        }
+
+        self.send_checked(&[VendorCommand::SetConsumer as u8, button as u8, code as u8])
+            .context("Failed to send media key.")?;
+        self.save_config()
+    }
+
+    /// Sets `button` to click a mouse button and saves the configuration, button indexes start at
+    /// 0.
+    pub fn set_mouse_button(&mut self, button: usize, mouse: MouseButton) -> Result<()> {
+        if button >= NUM_BTS {
+            return Err(anyhow!("Invalid button: {}", button + 1));
+        }
+
+        self.send_checked(&[VendorCommand::SetMouse as u8, button as u8, mouse as u8])
+            .context("Failed to send mouse button.")?;
+        self.save_config()
+    }
+
+    /// Sets `button` to move the cursor while held and saves the configuration, button indexes
+    /// start at 0.
+    pub fn set_mouse_move(&mut self, button: usize, direction: MouseDirection) -> Result<()> {
+        if button >= NUM_BTS {
+            return Err(anyhow!("Invalid button: {}", button + 1));
+        }
+
+        self.send_checked(&[
+            VendorCommand::SetMouseMove as u8,
+            button as u8,
+            direction as u8,
+        ])
+        .context("Failed to send mouse movement.")?;
+        self.save_config()
+    }
+
+    /// Sets the distance moved on every scan while a move button is held and saves the
+    /// configuration.
+    pub fn set_mouse_speed(&mut self, speed: u8) -> Result<()> {
+        if speed == 0 || speed > MAX_MOUSE_SPEED {
+            return Err(anyhow!(
+                "Invalid mouse speed: {}, it goes from 1 to {}",
+                speed,
+                MAX_MOUSE_SPEED
+            ));
+        }
+
+        self.send_checked(&[VendorCommand::SetMouseSpeed as u8, speed])
+            .context("Failed to send mouse speed.")?;
+        self.save_config()
+    }
+
+    /// Saves the configuration in use to flash and waits for the device to confirm it, then reads
+    /// the new record back to check it stores the layout in use. Older firmware can't confirm, the
+    /// save is only requested then.
+    pub fn save_config(&mut self) -> Result<()> {
+        let index = self.config_index().ok();
+        self.send_checked(&[VendorCommand::Save as u8, 0])
+            .context("Failed to send control transfer.")?;
+        let index = match index {
+            Some(index) => self.wait_for_write(index)?,
+            None => return Ok(()),
+        };
+
+        let record = self.read_record(index)?;
+        let saved = storage::active_matrix(&record)
+            .ok_or_else(|| anyhow!("The configuration read back from flash is corrupted"))?;
+        let expected = self
+            .read_config()
+            .context("Failed to read the layout in use.")?;
+        if saved != expected.to_bytes() {
+            return Err(anyhow!(
+                "The configuration read back doesn't match the one saved"
+            ));
+        }
+        Ok(())
+    }
+
+    /// Waits for the device to write a new record to flash, after the one at `index` was in use,
+    /// and returns the index of the new one.
+    fn wait_for_write(&self, index: u16) -> Result<u16> {
+        // Every write is a new record, so the index of the one in use always moves
+        thread::sleep(SAVE_DELAY);
+        let start = Instant::now();
+        loop {
+            if self.status()?.save_failed {
+                return Err(anyhow!(
+                    "The device couldn't write the configuration to flash, \
+                     the one stored before is kept"
+                ));
+            }
+            let current = self.config_index()?;
+            if current != index {
+                return Ok(current);
+            }
+            if start.elapsed() > SAVE_TIMEOUT {
+                return Err(anyhow!("The device didn't confirm the save"));
+            }
+            thread::sleep(SAVE_DELAY);
+        }
+    }
+
+    /// Replaces the stored configuration, every slot and the settings with the defaults and waits
+    /// for the device to confirm it, see `VendorCommand::FactoryReset`. Changes that weren't saved
+    /// are dropped, and so is the undo history.
+    pub fn factory_reset(&mut self) -> Result<()> {
+        let index = self.config_index()?;
+        self.send_checked(&[VendorCommand::FactoryReset as u8, 0])
+            .context("Failed to request a reset to the defaults.")?;
+        self.wait_for_write(index)?;
+        self.undo.clear();
+        Ok(())
+    }
+
+    /// Asks the device to reboot, a pending save is finished first. The device disconnects, so
+    /// this `App` can't be used afterwards.
+    pub fn reboot(&mut self) -> Result<()> {
+        self.send_checked(&[VendorCommand::Reboot as u8, 0])
+            .context("Failed to send reboot request.")
+    }
+
+    /// Asks the device to reboot into the system bootloader of the chip, like `reboot`. It only
+    /// talks over USART1, there's no USB DFU on the STM32F103, so the device doesn't come back on
+    /// USB until it's reset.
+    pub fn enter_usart_bootloader(&mut self) -> Result<()> {
+        self.send_checked(&[VendorCommand::EnterBootloader as u8, 0])
+            .context("Failed to send USART bootloader request.")
+    }
+
+    /// Sets `button` to switch to the next profile stored in the device and saves the
+    /// configuration, button indexes start at 0.
+    pub fn set_profile_button(&mut self, button: usize) -> Result<()> {
+        if button >= NUM_BTS {
+            return Err(anyhow!("Invalid button: {}", button + 1));
+        }
+        self.send_checked(&[VendorCommand::SetProfileButton as u8, button as u8])
+            .context("Failed to send profile button.")?;
+        self.save_config()
+    }
+
+    /// Sets `button` to toggle caps word and saves the configuration, button indexes start at 0.
+    pub fn set_caps_word_button(&mut self, button: usize) -> Result<()> {
+        if button >= NUM_BTS {
+            return Err(anyhow!("Invalid button: {}", button + 1));
+        }
+        self.send_checked(&[VendorCommand::SetCapsWordButton as u8, button as u8])
+            .context("Failed to send caps word button.")?;
+        self.save_config()
+    }
+
+    /// Switches the device to the stored `profile`, starting at 0. Changes that weren't saved are
+    /// dropped, even if it's already the active one.
+    pub fn select_profile(&mut self, profile: usize) -> Result<()> {
+        if profile >= NUM_PROFILES {
+            return Err(anyhow!("Invalid slot: {}", profile + 1));
+        }
+        self.send_checked(&[VendorCommand::SelectProfile as u8, profile as u8])
+            .context("Failed to switch profiles.")
+    }
+
+    /// Asks the device to move its stored config to the start of a freshly erased page, see
+    /// `VendorCommand::Compact`.
+    pub fn compact(&mut self) -> Result<()> {
+        self.send_checked(&[VendorCommand::Compact as u8, 0])
+            .context("Failed to request a compaction.")
+    }
+
+    /// Switches the device to its next stored profile, changes that weren't saved are dropped.
+    pub fn next_profile(&mut self) -> Result<()> {
+        self.send_checked(&[VendorCommand::NextProfile as u8, 0])
+            .context("Failed to switch profiles.")
+    }
+
+    /// Replaces the startup sequence typed when the host connects and saves the configuration,
+    /// an empty `keys` disables it.
+    pub fn set_startup_sequence(&mut self, keys: &[KeyCode]) -> Result<()> {
+        if keys.len() > MAX_KEYS {
+            return Err(anyhow!(
+                "The startup sequence can have at most {} keys",
+                MAX_KEYS
+            ));
+        }
+
+        let clear = [VendorCommand::ClearStartupSequence as u8, 0];
+        let pushes = keys
+            .iter()
+            .map(|&key| [VendorCommand::PushStartupKey as u8, key as u8]);
+        for data in std::iter::once(clear).chain(pushes) {
+            self.send_checked(&data[..])
+                .context("Failed to send startup sequence.")?;
+        }
+        self.save_config()
+    }
+
+    /// Enables pausing key output while configuring and saves the configuration.
+    pub fn set_quiet_config(&mut self, enabled: bool) -> Result<()> {
+        self.send_checked(&[VendorCommand::SetQuietConfig as u8, enabled as u8])
+            .context("Failed to send quiet config.")?;
+        self.save_config()
+    }
+
+    /// Enables or disables the sticky shift and, if given, sets how long a latched shift waits for
+    /// a key in 100 ms units, then saves the configuration.
+    pub fn set_sticky_shift(&mut self, enabled: bool, timeout: Option<u8>) -> Result<()> {
+        self.send_checked(&[VendorCommand::SetStickyShift as u8, enabled as u8])
+            .context("Failed to send sticky shift.")?;
+        if let Some(timeout) = timeout {
+            self.send_checked(&[VendorCommand::SetStickyTimeout as u8, timeout])
+                .context("Failed to send sticky timeout.")?;
+        }
+        self.save_config()
+    }
+
+    /// Wires an encoder to `first` and the next button, sending `actions` for a clockwise and a
+    /// counter-clockwise detent, and saves the configuration. `None` disables it, button indexes
+    /// start at 0. Only keys and media keys are supported.
+    pub fn set_encoder(&mut self, first: Option<usize>, actions: [Action; 2]) -> Result<()> {
+        let first = match first {
+            Some(first) if first + 1 >= NUM_BTS => {
+                return Err(anyhow!(
+                    "Invalid button: {}, the encoder uses it and the next one",
+                    first + 1
+                ))
+            }
+            Some(first) => first as u8,
+            None => 0xFF,
+        };
+        let mut commands = vec![[VendorCommand::SetEncoder as u8, first]];
+        if first != 0xFF {
+            let directions = [
+                (
+                    VendorCommand::SetClockwise,
+                    VendorCommand::SetClockwiseConsumer,
+                ),
+                (
+                    VendorCommand::SetCounterClockwise,
+                    VendorCommand::SetCounterClockwiseConsumer,
+                ),
+            ];
+            for (&(key, media), &action) in directions.iter().zip(actions.iter()) {
+                commands.push(match action {
+                    Action::Key(code) => [key as u8, code as u8],
+                    Action::Consumer(usage) => [media as u8, usage],
+                    _ => {
+                        return Err(anyhow!(
+                            "Only keys and media keys can be sent by an encoder"
+                        ))
+                    }
+                });
+            }
+        }
+        for data in &commands {
+            self.send_checked(&data[..])
+                .context("Failed to send encoder.")?;
+        }
+        self.save_config()
+    }
+
+    /// Makes `button` select the Fn layer while held, with `keys` for the other buttons in order,
+    /// and saves the configuration. `None` disables the layer, button indexes start at 0.
+    pub fn set_layer(&mut self, button: Option<usize>, keys: &[KeyCode]) -> Result<()> {
+        let button = match button {
+            Some(button) if button >= NUM_BTS => {
+                return Err(anyhow!("Invalid button: {}", button + 1))
+            }
+            Some(button) => button,
+            None => {
+                self.send_checked(&[VendorCommand::SetLayerButton as u8, 0xFF])
+                    .context("Failed to send layer button.")?;
+                return self.save_config();
+            }
+        };
+        if keys.len() != NUM_BTS - 1 {
+            return Err(anyhow!(
+                "The layer needs {} keys, one for each other button",
+                NUM_BTS - 1
+            ));
+        }
+        let others = (0..NUM_BTS).filter(|&other| other != button);
+        for (other, &key) in others.zip(keys.iter()) {
+            self.send_checked(&[VendorCommand::SetLayer as u8, other as u8, key as u8])
+                .context("Failed to send layer keys.")?;
+        }
+        self.send_checked(&[VendorCommand::SetLayerButton as u8, button as u8])
+            .context("Failed to send layer button.")?;
+        self.save_config()
+    }
+
+    /// Sets `button` to send `key` when tapped and `modifiers` when held, then saves the
+    /// configuration, button indexes start at 0.
+    pub fn set_tap_hold(
+        &mut self,
+        button: usize,
+        key: KeyCode,
+        modifiers: &[KeyCode],
+    ) -> Result<()> {
+        let mut bits = 0;
+        for &modifier in modifiers {
+            if !modifier.is_modifier() {
+                return Err(anyhow!("{} isn't a modifier", modifier.as_ref()));
+            }
+            bits |= modifier.as_modifier_bit();
+        }
+        if button >= NUM_BTS {
+            return Err(anyhow!("Invalid button: {}", button + 1));
+        }
+        if bits == 0 {
+            return Err(anyhow!("At least one modifier is needed"));
+        }
+
+        let button = button as u8;
+        for &data in [
+            [VendorCommand::SetTapHold as u8, button, key as u8],
+            [VendorCommand::SetHoldModifiers as u8, button, bits],
+        ]
+        .iter()
+        {
+            self.send_checked(&data[..])
+                .context("Failed to send tap-hold button.")?;
+        }
+        self.save_config()
+    }
+
+    /// Sets `button` to send `single` when tapped once and `double` when tapped twice, then saves
+    /// the configuration, button indexes start at 0.
+    pub fn set_double_tap(
+        &mut self,
+        button: usize,
+        single: KeyCode,
+        double: KeyCode,
+    ) -> Result<()> {
+        if button >= NUM_BTS {
+            return Err(anyhow!("Invalid button: {}", button + 1));
+        }
+
+        let button = button as u8;
+        for &data in [
+            [VendorCommand::SetDoubleTap as u8, button, single as u8],
+            [VendorCommand::SetDoubleTapKey as u8, button, double as u8],
+        ]
+        .iter()
+        {
+            self.send_checked(&data[..])
+                .context("Failed to send double-tap button.")?;
+        }
+        self.save_config()
+    }
+
+    /// Sets how long a tap-hold button has to be held to send its modifiers, in 10 ms units, and
+    /// saves the configuration.
+    pub fn set_tap_hold_threshold(&mut self, threshold: u8) -> Result<()> {
+        if threshold == 0 {
+            return Err(anyhow!("The tap-hold threshold can't be 0"));
+        }
+
+        self.send_checked(&[VendorCommand::SetTapHoldThreshold as u8, threshold])
+            .context("Failed to send tap-hold threshold.")?;
+        self.save_config()
+    }
+
+    /// Sets how many samples the debouncer needs to confirm a press or a release, see
+    /// `debounce_info` for the scan rate, and saves the configuration.
+    pub fn set_debounce(&mut self, window: u8) -> Result<()> {
+        if window == 0 || window > MAX_WINDOW {
+            return Err(anyhow!(
+                "The debounce window has to be from 1 to {} samples",
+                MAX_WINDOW
+            ));
+        }
+
+        self.send_checked(&[VendorCommand::SetDebounce as u8, window])
+            .context("Failed to send debounce window.")?;
+        self.save_config()
+    }
+
+    /// Replaces the auto-repeat config and saves the configuration.
+    pub fn set_repeat(&mut self, config: &RepeatConfig) -> Result<()> {
+        let commands = [
+            [VendorCommand::SetRepeatInitial as u8, config.initial],
+            [VendorCommand::SetRepeatFloor as u8, config.floor],
+            [VendorCommand::SetRepeatStep as u8, config.step],
+            [VendorCommand::SetRepeat as u8, config.enabled as u8],
+        ];
+        for data in &commands {
+            self.send_checked(&data[..])
+                .context("Failed to send auto-repeat config.")?;
+        }
+        self.save_config()
+    }
+
+    /// Stores a new name on the device, an empty one clears it.
+    pub fn set_name(&mut self, new_name: &str) -> Result<()> {
+        // Checked here since the device silently drops what doesn't fit
+        let mut checked = DeviceName::new();
+        for ch in new_name.bytes() {
+            if !checked.push(ch) {
+                return Err(anyhow!(
+                    "Invalid name, use up to {} printable ASCII characters",
+                    name::MAX_LEN
+                ));
+            }
+        }
+
+        self.send_checked(&[VendorCommand::ClearName as u8, 0])
+            .context("Failed to clear the name.")?;
+        for ch in new_name.bytes() {
+            self.send_checked(&[VendorCommand::PushNameChar as u8, ch])
+                .context("Failed to send the name.")?;
+        }
+        self.save_config()
+    }
+
+    /// Reads the name of the device, empty if it was never set. The query fails on firmware
+    /// without names.
+    pub fn name(&self) -> Result<String> {
+        // First byte is the report ID
+        let data = [0, VendorCommand::GetName as u8, 0];
         self.usb_handle
             .send_feature_report(&data[..])
-            .map(|_| ())
-            .context("Failed to send feature report.")
+            .context("Failed to query the name.")?;
+        let mut bytes = [0; DeviceName::SIZE];
+        for chunk in bytes.chunks_mut(2) {
+            let mut buf = [0; 3];
+            self.usb_handle
+                .get_feature_report(&mut buf)
+                .context("Failed to read the name.")?;
+            chunk.copy_from_slice(&buf[1..]);
+        }
+        DeviceName::from_bytes(bytes)
+            .map(|name| String::from(name.as_str()))
+            .ok_or_else(|| anyhow!("The device answered an invalid name."))
     }
 
-    pub fn save_config(&mut self) -> Result<()> {
+    /// Version of the firmware running on the device, older firmware can't tell.
+    pub fn firmware_version(&self) -> Result<Version> {
         // First byte is the report ID
-        let data = [0, VendorCommand::Save as u8, 0];
+        let data = [0, VendorCommand::GetVersion as u8, 0];
+        self.usb_handle
+            .send_feature_report(&data[..])
+            .context("Failed to query the firmware version.")?;
+        // The last report is padded
+        let mut bytes = [0; (Version::SIZE + 1) & !1];
+        for chunk in bytes.chunks_mut(2) {
+            let mut buf = [0; 3];
+            self.usb_handle
+                .get_feature_report(&mut buf)
+                .context("Failed to read the firmware version.")?;
+            chunk.copy_from_slice(&buf[1..]);
+        }
+        let mut version = [0; Version::SIZE];
+        version.copy_from_slice(&bytes[..Version::SIZE]);
+        Ok(Version::from_bytes(version))
+    }
+
+    /// Reads the version of the control interface protocol of the firmware, see
+    /// `packets::PROTOCOL_VERSION`. The query fails on firmware from before it existed.
+    pub fn protocol_version(&self) -> Result<u8> {
+        // First byte is the report ID
+        let data = [0, VendorCommand::GetProtocolVersion as u8, 0];
+        self.usb_handle
+            .send_feature_report(&data[..])
+            .context("Failed to query the protocol version.")?;
+        let mut buf = [0; 3];
+        self.usb_handle
+            .get_feature_report(&mut buf)
+            .context("Failed to read the protocol version.")?;
+        Ok(buf[1])
+    }
 
+    /// Fails if the firmware speaks another protocol version than this tool, the commands of one
+    /// could be misread by the other, the error tells which side to update.
+    fn check_protocol(&self) -> Result<()> {
+        let version = self.protocol_version().map_err(|_| {
+            anyhow!("The firmware is too old for this version of keyconfig, update it first")
+        })?;
+        if version < PROTOCOL_VERSION {
+            Err(anyhow!(
+                "The firmware speaks protocol version {} and keyconfig version {}, update the firmware",
+                version,
+                PROTOCOL_VERSION
+            ))
+        } else if version > PROTOCOL_VERSION {
+            Err(anyhow!(
+                "The firmware speaks protocol version {} and keyconfig version {}, update keyconfig",
+                version,
+                PROTOCOL_VERSION
+            ))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Reads back the layout the device is using, including changes that weren't saved yet.
+    pub fn read_config(&self) -> Result<Matrix> {
+        // First byte is the report ID
+        let data = [0, VendorCommand::ReadConfig as u8, 0];
         self.usb_handle
             .send_feature_report(&data[..])
-            .map(|_| ())
-            .context("Failed to send control transfer.")
+            .context("Failed to query the layout.")?;
+        // The last report is padded when the layout has an odd size
+        let mut bytes = [0; (Matrix::SIZE + 1) & !1];
+        for chunk in bytes.chunks_mut(2) {
+            let mut buf = [0; 3];
+            self.usb_handle
+                .get_feature_report(&mut buf)
+                .context("Failed to read the layout.")?;
+            chunk.copy_from_slice(&buf[1..]);
+        }
+        let mut config = [0; Matrix::SIZE];
+        config.copy_from_slice(&bytes[..Matrix::SIZE]);
+        Matrix::from_bytes(config).ok_or_else(|| anyhow!("The device answered an invalid layout."))
+    }
+
+    /// Buttons whose action in use differs from the one stored in flash, which saving would
+    /// change, as the button index with the stored and the current action. The stored one is
+    /// `None` when flash holds no valid configuration.
+    pub fn pending_changes(&self) -> Result<Vec<(usize, Option<Action>, Action)>> {
+        let stored = storage::active_matrix(&self.read_record(self.config_index()?)?)
+            .and_then(Matrix::from_bytes)
+            .map(|matrix| matrix.layout());
+        let current = self.read_config()?.layout();
+        Ok((0..NUM_BTS)
+            .map(|button| (button, stored.map(|layout| layout[button]), current[button]))
+            .filter(|&(_, stored, current)| stored != Some(current))
+            .collect())
+    }
+
+    /// Which device is open, for the bottom line of the menu: VID and PID, serial number, product
+    /// and whether it's still plugged in.
+    pub fn device_line(&self) -> String {
+        if self.path.is_none() {
+            return String::from("No device, running offline");
+        }
+        let product = self.usb_handle.get_product_string().ok().flatten();
+        format!(
+            "{:04x}:{:04x} | Serial: {} | {} | {}",
+            VID,
+            PID,
+            self.serial_number().as_deref().unwrap_or("none"),
+            product.as_deref().unwrap_or("Unknown product"),
+            if self.is_connected() {
+                "Connected"
+            } else {
+                "Disconnected"
+            }
+        )
+    }
+
+    pub fn serial_number(&self) -> Option<String> {
+        self.usb_handle.get_serial_number_string().ok().flatten()
+    }
+
+    /// Sets every button to the matching key of `layout` and saves the configuration.
+    pub fn apply_layout(&mut self, layout: &[KeyCode; NUM_BTS]) -> Result<()> {
+        self.set_layout(layout)?;
+        self.save_config()
+    }
+
+    /// Sets `button` to `key` without saving, button indexes start at 0.
+    pub fn set_key(&mut self, button: usize, key: KeyCode) -> Result<()> {
+        if button >= NUM_BTS {
+            return Err(anyhow!("Invalid button: {}", button + 1));
+        }
+        self.send_checked(&[VendorCommand::SetKey as u8, button as u8, key as u8])
+            .context("Failed to send key.")
+    }
+
+    /// Sets every button to the matching key of `layout` without saving, a reboot reverts it.
+    pub fn set_layout(&mut self, layout: &[KeyCode; NUM_BTS]) -> Result<()> {
+        // A single report
+        let mut report = [0; NUM_BTS + 1];
+        report[0] = VendorCommand::SetAll as u8;
+        for (byte, &key) in report[1..].iter_mut().zip(layout.iter()) {
+            *byte = key as u8;
+        }
+        self.send_checked(&report).context("Failed to send layout.")
+    }
+
+    /// Sets `button` to type `sequence` and saves the configuration, button indexes start at 0.
+    pub fn set_sequence_button(&mut self, button: usize, sequence: &Sequence) -> Result<()> {
+        if button >= NUM_BTS {
+            return Err(anyhow!("Invalid button: {}", button + 1));
+        }
+
+        let clear = [VendorCommand::ClearSequence as u8, button as u8];
+        let pushes = sequence
+            .keys()
+            .iter()
+            .map(|&key| [VendorCommand::PushSequenceKey as u8, key as u8]);
+        let set = [VendorCommand::SetSequenceButton as u8, button as u8];
+        for data in std::iter::once(clear)
+            .chain(pushes)
+            .chain(std::iter::once(set))
+        {
+            self.send_checked(&data[..])
+                .context("Failed to send sequence.")?;
+        }
+        self.save_config()
+    }
+
+    /// Reads the device status, firmware without a status report answers with the defaults.
+    pub fn status(&self) -> Result<Status> {
+        // First byte is the report ID
+        let mut buf = [0; Status::SIZE + 1];
+        self.usb_handle
+            .get_feature_report(&mut buf)
+            .context("Failed to read device status.")?;
+        let mut bytes = [0; Status::SIZE];
+        bytes.copy_from_slice(&buf[1..]);
+        Ok(Status::from_bytes(bytes))
+    }
+
+    /// Reads the state of the shift layer, the query fails on firmware without layer state.
+    pub fn layer_state(&self) -> Result<LayerState> {
+        // First byte is the report ID
+        let data = [0, VendorCommand::GetLayerState as u8, 0];
+        self.usb_handle
+            .send_feature_report(&data[..])
+            .context("Failed to query the layer state.")?;
+        let mut buf = [0; LayerState::SIZE + 1];
+        self.usb_handle
+            .get_feature_report(&mut buf)
+            .context("Failed to read the layer state.")?;
+        let mut bytes = [0; LayerState::SIZE];
+        bytes.copy_from_slice(&buf[1..]);
+        Ok(LayerState::from_bytes(bytes))
+    }
+
+    /// Reads the debouncer parameters of the firmware, the query fails on firmware without them.
+    pub fn debounce_info(&self) -> Result<DebounceInfo> {
+        Ok(DebounceInfo {
+            window: self.info(InfoField::DebounceWindow)?,
+            hold_threshold: self.info(InfoField::HoldThreshold)?,
+            scan_hz: self.info(InfoField::ScanRate)?,
+        })
+    }
+
+    /// Stores new configuration descriptor attributes, the device uses them after a reboot.
+    pub fn set_usb_attributes(&mut self, attributes: &UsbAttributes) -> Result<()> {
+        let commands = [
+            [
+                VendorCommand::SetSelfPowered as u8,
+                attributes.self_powered as u8,
+            ],
+            [
+                VendorCommand::SetRemoteWakeup as u8,
+                attributes.remote_wakeup as u8,
+            ],
+            [VendorCommand::SetMaxPower as u8, attributes.max_power],
+        ];
+        for data in &commands {
+            self.send_checked(&data[..])
+                .context("Failed to send USB attributes.")?;
+        }
+        self.save_config()
+    }
+
+    /// bmAttributes and bMaxPower the device enumerated with.
+    pub fn active_usb_attributes(&self) -> Result<(u8, u8)> {
+        Ok((
+            self.info(InfoField::ConfigAttributes)? as u8,
+            self.info(InfoField::MaxPower)? as u8,
+        ))
+    }
+
+    /// Reads which buttons are pressed, as a mask with bit `n` set for button `n`.
+    pub fn pressed(&self) -> Result<u8> {
+        // First byte is the report ID
+        let data = [0, VendorCommand::GetPressed as u8, 0];
+        self.usb_handle
+            .send_feature_report(&data[..])
+            .context("Failed to query the buttons.")?;
+        let mut buf = [0; 3];
+        self.usb_handle
+            .get_feature_report(&mut buf)
+            .context("Failed to read the buttons.")?;
+        Ok(buf[1])
+    }
+
+    /// Profile stored in the device that's in use, see `storage::NUM_PROFILES`.
+    pub fn profile(&self) -> Result<u16> {
+        self.info(InfoField::Profile)
+    }
+
+    /// Index of the record the device is using, counting the records of all its config pages.
+    pub fn config_index(&self) -> Result<u16> {
+        self.info(InfoField::ConfigIndex)
+    }
+
+    /// Total number of erases of the config pages of the device, see `storage::erase_count`.
+    pub fn erase_count(&self) -> Result<u16> {
+        self.info(InfoField::EraseCount)
+    }
+
+    /// Lock LEDs the host set on the device, see `InfoField::Leds`.
+    pub fn leds(&self) -> Result<u8> {
+        self.info(InfoField::Leds).map(|leds| leds as u8)
+    }
+
+    /// Reads every config page of the device, one after the other, see `storage`.
+    pub fn dump_page(&self) -> Result<Vec<u8>> {
+        // First byte is the report ID
+        let data = [0, VendorCommand::DumpPage as u8, 0];
+        self.usb_handle
+            .send_feature_report(&data[..])
+            .context("Failed to request the config pages.")?;
+        let mut page = Vec::with_capacity(CONFIG_AREA_SIZE);
+        while page.len() < CONFIG_AREA_SIZE {
+            let mut buf = [0; 3];
+            self.usb_handle
+                .get_feature_report(&mut buf)
+                .context("Failed to read the config pages.")?;
+            page.extend_from_slice(&buf[1..]);
+        }
+        Ok(page)
+    }
+
+    /// Reads the record `index` of the config area, as answered by `config_index`.
+    fn read_record(&self, index: u16) -> Result<Vec<u8>> {
+        let index = u8::try_from(index)
+            .ok()
+            .filter(|&index| usize::from(index) < storage::NUM_RECORDS)
+            .ok_or_else(|| anyhow!("The device answered an invalid config index."))?;
+        // First byte is the report ID
+        let data = [0, VendorCommand::DumpPage as u8, index];
+        self.usb_handle
+            .send_feature_report(&data[..])
+            .context("Failed to request the saved config.")?;
+        let mut record = Vec::with_capacity(CONFIG_SIZE);
+        while record.len() < CONFIG_SIZE {
+            let mut buf = [0; 3];
+            self.usb_handle
+                .get_feature_report(&mut buf)
+                .context("Failed to read the saved config.")?;
+            record.extend_from_slice(&buf[1..]);
+        }
+        Ok(record)
+    }
+
+    fn info(&self, field: InfoField) -> Result<u16> {
+        // First byte is the report ID
+        let data = [0, VendorCommand::GetInfo as u8, field as u8];
+        self.usb_handle
+            .send_feature_report(&data[..])
+            .context("Failed to query device info.")?;
+        let mut buf = [0; 3];
+        self.usb_handle
+            .get_feature_report(&mut buf)
+            .context("Failed to read device info.")?;
+        Ok(u16::from_le_bytes([buf[1], buf[2]]))
+    }
+
+    /// Collects every diagnostic the device can answer as `(name, value)` pairs, reports that the
+    /// firmware doesn't support are `None`.
+    pub fn diagnostics(&self) -> Vec<(&'static str, Option<String>)> {
+        let handle = &self.usb_handle;
+        vec![
+            (
+                "Manufacturer",
+                handle.get_manufacturer_string().ok().flatten(),
+            ),
+            ("Product", handle.get_product_string().ok().flatten()),
+            ("Serial number", self.serial_number()),
+            ("Name", self.name().ok()),
+            (
+                "Firmware version",
+                self.firmware_version()
+                    .ok()
+                    .map(|version| version.to_string()),
+            ),
+            (
+                "Protocol version",
+                self.protocol_version()
+                    .ok()
+                    .map(|version| version.to_string()),
+            ),
+            (
+                "Safe mode",
+                self.status()
+                    .ok()
+                    .map(|status| status.safe_mode.to_string()),
+            ),
+            (
+                "Config load failed",
+                self.status()
+                    .ok()
+                    .map(|status| status.load_failed.to_string()),
+            ),
+            (
+                "Last save failed",
+                self.status()
+                    .ok()
+                    .map(|status| status.save_failed.to_string()),
+            ),
+            (
+                "Layer state",
+                self.layer_state().ok().map(|state| format!("{:?}", state)),
+            ),
+            ("Lock LEDs", self.leds().ok().map(leds_label)),
+            (
+                "Debounce",
+                self.debounce_info().ok().map(|info| format!("{:?}", info)),
+            ),
+            (
+                "USB attributes",
+                self.active_usb_attributes()
+                    .ok()
+                    .map(|(attributes, max_power)| {
+                        format!(
+                            "bmAttributes {:#04x}, bMaxPower {} ({} mA)",
+                            attributes,
+                            max_power,
+                            u32::from(max_power) * 2
+                        )
+                    }),
+            ),
+            ("USB statistics", None),
+            ("Button chatter", None),
+            ("Reset reason", None),
+            (
+                "Flash wear",
+                self.erase_count()
+                    .ok()
+                    .map(|count| format!("{} erases over {} config pages", count, NUM_PAGES)),
+            ),
+            (
+                "Current layout",
+                self.read_config()
+                    .ok()
+                    .map(|matrix| format!("{:?}", matrix.layout())),
+            ),
+        ]
     }
 
     fn search_all(&mut self) {
         self.hits.clear();
         let input = self.user_input.as_str();
-        for code in KeyCode::iter().filter(|k| k.as_ref().starts_with(input)) {
-            self.hits.push(code);
+        if is_code(input) {
+            let code = parse_code(input);
+            self.hits.extend(
+                KeyCode::iter()
+                    .filter(|&key| Some(key as u8) == code)
+                    .map(Hit::Key),
+            );
+            self.current_line = 0;
+            return;
         }
+        let mut ranked: Vec<_> = Hit::all()
+            .filter_map(|hit| hit_rank(hit, input).map(|rank| (rank, hit)))
+            .collect();
+        // Stable, so keys with the same rank keep their usual order
+        ranked.sort_by_key(|&(rank, _)| rank);
+        self.hits.extend(ranked.into_iter().map(|(_, hit)| hit));
         if self.current_line + 1 > self.hits.len() {
             self.current_line = self.hits.len().saturating_sub(1);
         }
     }
 }
 
+/// Name shown in the key search, `KeyCode::No` disables the button.
+fn key_label(key: KeyCode) -> String {
+    match key {
+        KeyCode::No => String::from("None"),
+        key => format!("{:?}", key),
+    }
+}
+
+/// Header shown above the keys of `category` in the key search.
+fn category_label(category: KeyCategory) -> &'static str {
+    match category {
+        KeyCategory::Letter => "Letters",
+        KeyCategory::Digit => "Numbers",
+        KeyCategory::Basic => "Basic",
+        KeyCategory::Symbol => "Symbols",
+        KeyCategory::Function => "Function keys",
+        KeyCategory::Navigation => "Navigation",
+        KeyCategory::Keypad => "Keypad",
+        KeyCategory::Modifier => "Modifiers",
+        KeyCategory::Media => "Media",
+        KeyCategory::Other => "Other",
+    }
+}
+
+/// Name of a button action shown in the menu, plain keys by their label.
+fn action_label(action: Action) -> String {
+    match action {
+        Action::Key(key) => key_label(key),
+        action => format!("{:?}", action),
+    }
+}
+
+/// Names of the lock LEDs that are on in `leds`, see `InfoField::Leds`.
+fn leds_label(leds: u8) -> String {
+    let on: Vec<&str> = ["Num Lock", "Caps Lock", "Scroll Lock"]
+        .iter()
+        .enumerate()
+        .filter(|&(bit, _)| leds & 1 << bit != 0)
+        .map(|(_, &name)| name)
+        .collect();
+    if on.is_empty() {
+        String::from("none")
+    } else {
+        on.join(", ")
+    }
+}
+
+/// How well `hit` matches the search `input` by its name or by its label, lower is better, `None`
+/// if it doesn't. Prefixes come first, then the letters of `input` in order anywhere in the name,
+/// e.g. "pgup" for "PageUp", the fewer letters skipped in between the better.
+fn hit_rank(hit: Hit, input: &str) -> Option<(u8, usize)> {
+    let name = hit.name();
+    let label = hit.label().to_lowercase();
+    if name.starts_with(input) || label.starts_with(input) {
+        return Some((0, 0));
+    }
+    let skipped = match (
+        skipped_letters(input, &name),
+        skipped_letters(input, &label),
+    ) {
+        (Some(name), Some(label)) => name.min(label),
+        (name, label) => name.or(label)?,
+    };
+    Some((1, skipped))
+}
+
+/// Whether the search `input` is a key code instead of a name: only digits, or hex digits after
+/// `0x`.
+fn is_code(input: &str) -> bool {
+    input.starts_with("0x") || (!input.is_empty() && input.bytes().all(|b| b.is_ascii_digit()))
+}
+
+/// Value of the key code typed in the search, see `is_code`. `None` while the hex digits are
+/// missing or if it doesn't fit a key code.
+fn parse_code(input: &str) -> Option<u8> {
+    match input.strip_prefix("0x") {
+        Some(hex) => u8::from_str_radix(hex, 16).ok(),
+        None => input.parse().ok(),
+    }
+}
+
+/// Number of letters of `text` skipped between the first and the last letter of `input`, when the
+/// letters of `input` appear in `text` in order.
+fn skipped_letters(input: &str, text: &str) -> Option<usize> {
+    let mut first = None;
+    let mut last = 0;
+    let mut letters = text.char_indices();
+    for ch in input.chars() {
+        let (index, _) = letters.find(|&(_, letter)| letter == ch)?;
+        first.get_or_insert(index);
+        last = index;
+    }
+    Some(first.map_or(0, |first| last - first + 1 - input.chars().count()))
+}
+
+/// Names of the modifiers in `bits`, each followed by a '+', as typed in the search.
+fn modifier_names(bits: u8) -> String {
+    KeyCode::iter()
+        .filter(|key| key.is_modifier() && bits & key.as_modifier_bit() != 0)
+        .map(|key| format!("{}+", key.as_ref()))
+        .collect()
+}
+
+fn is_keykey(device: &DeviceInfo) -> bool {
+    device.vendor_id() == VID
+        && device.product_id() == PID
+        && device.interface_number() == CTRL_INTERFACE as i32
+}
+
+/// Adds a friendlier context to a failure to open the device. The most common cause is another
+/// application (or another instance of this tool) holding the device, which Windows doesn't allow.
+fn open_error(err: HidError) -> Error {
+    let in_use = match &err {
+        HidError::HidApiError { message } => {
+            let message = message.to_lowercase();
+            message.contains("access is denied")
+                || message.contains("busy")
+                || message.contains("in use")
+        }
+        // Windows' hidapi doesn't always say why it failed, but we know the device is there
+        HidError::HidApiErrorEmpty => cfg!(windows),
+        _ => false,
+    };
+
+    if in_use {
+        Error::new(err).context("The device is in use by another application")
+    } else {
+        Error::new(err).context("Failed to open device")
+    }
+}
+
 #[derive(Debug, PartialEq, Copy, Clone)]
 pub enum State {
     SelectScreen,
     Set1,
     Set2,
     Set3,
+    Profiles,
+    PressToSelect,
+    /// Asks before resetting the device to the defaults.
+    ConfirmReset,
 }
 
 impl State {
-    pub fn to_vendor_command(self) -> Result<VendorCommand> {
+    /// Key selection screen of `button`, indexes start at 0.
+    pub fn for_button(button: usize) -> Result<Self> {
+        match button {
+            0 => Ok(State::Set1),
+            1 => Ok(State::Set2),
+            2 => Ok(State::Set3),
+            _ => Err(anyhow!("Internal Error: Invalid button {}.", button)),
+        }
+    }
+
+    /// Button of a key selection screen, the opposite of `for_button`.
+    pub fn button(self) -> Result<usize> {
         match self {
-            State::Set1 => Ok(VendorCommand::Set1),
-            State::Set2 => Ok(VendorCommand::Set2),
-            State::Set3 => Ok(VendorCommand::Set3),
-            _ => Err(anyhow!("Internal Error: Invalid Vendor command.")),
+            State::Set1 => Ok(0),
+            State::Set2 => Ok(1),
+            State::Set3 => Ok(2),
+            _ => Err(anyhow!("Internal Error: Not a key selection screen.")),
         }
     }
 }
@@ -203,7 +1570,88 @@ impl Term {
         enable_raw_mode()?;
         Ok(term)
     }
-    pub fn render_menu_screen(&mut self, config_saved: bool) -> Result<()> {
+    /// Lists the changes of `App::pending_changes` before saving them.
+    pub fn render_save_confirm(
+        &mut self,
+        changes: &[(usize, Option<Action>, Action)],
+    ) -> Result<()> {
+        queue!(
+            self,
+            style::ResetColor,
+            terminal::Clear(ClearType::All),
+            cursor::Hide,
+            cursor::MoveTo(0, 0),
+            style::Print(SAVE_CONFIRM),
+            cursor::MoveToNextLine(2),
+        )?;
+        if changes.is_empty() {
+            queue!(self, style::Print("The buttons are the same as stored"))?;
+        }
+        for &(button, stored, current) in changes {
+            let stored = stored.map_or_else(|| String::from("nothing stored"), action_label);
+            queue!(
+                self,
+                style::Print(format!(
+                    "Button {}: {} → {}",
+                    button + 1,
+                    stored,
+                    action_label(current)
+                )),
+                cursor::MoveToNextLine(1)
+            )?;
+        }
+        self.flush()?;
+        Ok(())
+    }
+
+    pub fn render_reset_confirm(&mut self) -> Result<()> {
+        queue!(
+            self,
+            style::ResetColor,
+            terminal::Clear(ClearType::All),
+            cursor::Hide,
+            cursor::MoveTo(0, 0),
+            style::Print(RESET_CONFIRM),
+        )?;
+        self.flush()?;
+        Ok(())
+    }
+
+    pub fn render_disconnected(&mut self) -> Result<()> {
+        queue!(
+            self,
+            style::ResetColor,
+            terminal::Clear(ClearType::All),
+            cursor::Hide,
+            cursor::MoveTo(0, 0),
+            style::Print(DISCONNECTED),
+        )?;
+        self.flush()?;
+        Ok(())
+    }
+
+    pub fn render_press_screen(&mut self) -> Result<()> {
+        queue!(
+            self,
+            style::ResetColor,
+            terminal::Clear(ClearType::All),
+            cursor::Hide,
+            cursor::MoveTo(0, 0),
+            style::Print(PRESS_TO_SELECT),
+        )?;
+        self.flush()?;
+        Ok(())
+    }
+
+    pub fn render_menu_screen(
+        &mut self,
+        config_saved: bool,
+        status: Status,
+        name: &str,
+        version: &str,
+        layout: Option<[Action; NUM_BTS]>,
+        notice: Option<&str>,
+    ) -> Result<()> {
         queue!(
             self,
             style::ResetColor,
@@ -212,8 +1660,48 @@ impl Term {
             cursor::MoveTo(0, 0)
         )?;
 
+        if !name.is_empty() {
+            queue!(
+                self,
+                style::Print(format!("Device: {}", name)),
+                cursor::MoveToNextLine(1)
+            )?;
+        }
+        queue!(
+            self,
+            style::Print(format!("Firmware: {}", version)),
+            cursor::MoveToNextLine(2)
+        )?;
+
         for line in SELECT_MENU.split('\n') {
-            queue!(self, style::Print(line), cursor::MoveToNextLine(1))?;
+            let current = layout.and_then(|layout| {
+                (1..=NUM_BTS)
+                    .find(|button| line == format!("{}. Config button {}", button, button))
+                    .map(|button| layout[button - 1])
+            });
+            match current {
+                Some(action) => queue!(
+                    self,
+                    style::Print(format!("{} (currently: {})", line, action_label(action))),
+                    cursor::MoveToNextLine(1)
+                )?,
+                None => queue!(self, style::Print(line), cursor::MoveToNextLine(1))?,
+            }
+        }
+        if status.safe_mode {
+            queue!(
+                self,
+                cursor::MoveToNextLine(1),
+                style::Print(SAFE_MODE_WARNING.black().on_yellow()),
+            )?;
+        }
+        // Saving clears it on the device, the status isn't read again
+        if status.load_failed && !config_saved {
+            queue!(
+                self,
+                cursor::MoveToNextLine(1),
+                style::Print(LOAD_FAILED_WARNING.black().on_yellow()),
+            )?;
         }
         if config_saved {
             queue!(
@@ -222,6 +1710,34 @@ impl Term {
                 style::Print("Configuration saved"),
             )?;
         }
+        if let Some(notice) = notice {
+            queue!(self, cursor::MoveToNextLine(1), style::Print(notice))?;
+        }
+        self.flush()?;
+        Ok(())
+    }
+
+    /// Draws the state of the buttons on the row above `render_device_line`.
+    pub fn render_preview(&mut self, preview: &str) -> Result<()> {
+        let (_, rows) = terminal::size()?;
+        queue!(
+            self,
+            cursor::MoveTo(0, rows.saturating_sub(2)),
+            terminal::Clear(ClearType::CurrentLine),
+            style::Print(preview)
+        )?;
+        self.flush()?;
+        Ok(())
+    }
+
+    /// Draws `App::device_line` on the last row of the terminal, below the menu.
+    pub fn render_device_line(&mut self, device: &str) -> Result<()> {
+        let (_, rows) = terminal::size()?;
+        queue!(
+            self,
+            cursor::MoveTo(0, rows.saturating_sub(1)),
+            style::Print(device.dim())
+        )?;
         self.flush()?;
         Ok(())
     }
@@ -252,32 +1768,257 @@ impl Drop for Term {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use hidapi::HidResult;
+    use keylib::{crc, packets::AppCommand};
+    use std::{cell::RefCell, collections::VecDeque, rc::Rc};
+
+    /// Records the reports sent and answers the ones read from a queue, failing like older
+    /// firmware once it's empty.
+    #[derive(Default)]
+    struct Mock {
+        sent: Rc<RefCell<Vec<Vec<u8>>>>,
+        replies: RefCell<VecDeque<[u8; 2]>>,
+    }
+
+    impl Transport for Mock {
+        fn send_feature_report(&self, data: &[u8]) -> HidResult<()> {
+            self.sent.borrow_mut().push(data.to_vec());
+            Ok(())
+        }
+
+        fn get_feature_report(&self, buf: &mut [u8]) -> HidResult<usize> {
+            let reply = self
+                .replies
+                .borrow_mut()
+                .pop_front()
+                .ok_or(HidError::HidApiErrorEmpty)?;
+            buf[0] = 0;
+            buf[1..3].copy_from_slice(&reply);
+            Ok(3)
+        }
+
+        fn get_manufacturer_string(&self) -> HidResult<Option<String>> {
+            Ok(None)
+        }
+
+        fn get_product_string(&self) -> HidResult<Option<String>> {
+            Ok(None)
+        }
+
+        fn get_serial_number_string(&self) -> HidResult<Option<String>> {
+            Ok(None)
+        }
+    }
+
+    /// An `App` answering `replies` in order, and the reports it sends.
+    fn mock_app(replies: &[[u8; 2]]) -> (App, Rc<RefCell<Vec<Vec<u8>>>>) {
+        let mock = Mock {
+            replies: RefCell::new(replies.iter().copied().collect()),
+            ..Mock::default()
+        };
+        let sent = Rc::clone(&mock.sent);
+        let app = App::with_transport(Box::new(mock), None, None);
+        (app, sent)
+    }
+
+    /// Replies answering `bytes` two at a time, padded to a whole report.
+    fn chunks(bytes: &[u8]) -> Vec<[u8; 2]> {
+        bytes
+            .chunks(2)
+            .map(|chunk| [chunk[0], chunk.get(1).copied().unwrap_or(0)])
+            .collect()
+    }
+
+    /// Report sent for a command with its checksum, report ID first.
+    fn checked(report: &[u8]) -> Vec<u8> {
+        let mut data = vec![0];
+        data.extend_from_slice(&packets::with_checksum(report).unwrap());
+        data
+    }
+
+    fn search(app: &mut App, input: &str) {
+        app.clear();
+        input.chars().for_each(|ch| app.push_char_hit(ch));
+    }
 
     #[test]
     fn init() {
-        let mut app = App::new();
+        let mut app = App::new_offline();
         app.push_char_hit('a');
+        let hits = app.hits.clone();
+        assert_eq!(hits[0], Hit::Key(KeyCode::A));
+        app.push_char_hit('P');
+        assert_eq!(app.hits[0], Hit::Key(KeyCode::Application));
+
+        app.backspace();
+        assert_eq!(app.hits, hits);
+    }
+
+    #[test]
+    fn send_selected() {
+        let (mut app, sent) = mock_app(&[]);
+        search(&mut app, "escape");
+        app.send_selected(1).unwrap();
         assert_eq!(
-            app.hits,
-            &[
-                KeyCode::A,
-                KeyCode::Application,
-                KeyCode::Again,
-                KeyCode::AltErase
+            sent.borrow().last().unwrap(),
+            &checked(&[VendorCommand::SetKey as u8, 1, KeyCode::Escape as u8])
+        );
+
+        search(&mut app, "lctrl+lshift");
+        app.send_selected(0).unwrap();
+        let modifiers = KeyCode::LCtrl.as_modifier_bit() | KeyCode::LShift.as_modifier_bit();
+        assert_eq!(
+            sent.borrow().last().unwrap(),
+            &checked(&[VendorCommand::SetModifiers as u8, 0, modifiers])
+        );
+    }
+
+    #[test]
+    fn protocol_check() {
+        let (app, sent) = mock_app(&[[PROTOCOL_VERSION, 0]]);
+        app.check_protocol().unwrap();
+        assert_eq!(
+            sent.borrow()[0],
+            [0, VendorCommand::GetProtocolVersion as u8, 0]
+        );
+
+        let (app, _) = mock_app(&[[PROTOCOL_VERSION + 1, 0]]);
+        let err = app.check_protocol().unwrap_err();
+        assert!(err.to_string().contains("update keyconfig"));
+        // No answer, the firmware doesn't know the query
+        let (app, _) = mock_app(&[]);
+        let err = app.check_protocol().unwrap_err();
+        assert!(err.to_string().contains("update it"));
+    }
+
+    #[test]
+    fn layout_at_once() {
+        let (mut app, sent) = mock_app(&[]);
+        let layout = [KeyCode::A, KeyCode::B, KeyCode::C];
+        app.set_layout(&layout).unwrap();
+        assert_eq!(
+            *sent.borrow(),
+            [checked(&[
+                VendorCommand::SetAll as u8,
+                KeyCode::A as u8,
+                KeyCode::B as u8,
+                KeyCode::C as u8
+            ])]
+        );
+    }
+
+    #[test]
+    fn offline() {
+        let mut app = App::new_offline();
+        search(&mut app, "f1");
+        assert_eq!(
+            app.hits[..2],
+            [Hit::Key(KeyCode::F1), Hit::Key(KeyCode::F10)]
+        );
+        app.down();
+        assert_eq!(app.current_line, 1);
+
+        let err = app.send_selected(0).unwrap_err();
+        assert!(format!("{:#}", err).contains("No device"));
+        let err = app.save_config().unwrap_err();
+        assert!(format!("{:#}", err).contains("No device"));
+    }
+
+    #[test]
+    fn mouse_buttons() {
+        let (mut app, sent) = mock_app(&[]);
+        search(&mut app, "mouseright");
+        assert_eq!(app.hits, [Hit::Mouse(MouseButton::Right)]);
+        app.send_selected(2).unwrap();
+        assert_eq!(
+            sent.borrow().last().unwrap(),
+            &checked(&[
+                VendorCommand::SetMouse as u8,
+                2,
+                MouseButton::Right as u8,
+                0
+            ])
+        );
+
+        // Found by their label too, after the keys
+        search(&mut app, "left");
+        assert_eq!(app.hits.last(), Some(&Hit::Mouse(MouseButton::Left)));
+    }
+
+    #[test]
+    fn config_commands_checked() {
+        let (mut app, sent) = mock_app(&[]);
+        app.set_combo(1, &[0, 2], KeyCode::Escape).unwrap();
+        assert_eq!(
+            sent.borrow()[..2],
+            [
+                checked(&[VendorCommand::SetComboButtons as u8, 1, 0b101]),
+                checked(&[VendorCommand::SetCombo as u8, 1, KeyCode::Escape as u8]),
             ]
         );
-        app.push_char_hit('P');
-        assert_eq!(app.hits, &[KeyCode::Application]);
 
-        app.backspace();
+        let shift = KeyCode::LShift.as_modifier_bit();
+        app.undo.push(Undo {
+            button: 2,
+            action: Action::Key(KeyCode::A),
+            key_modifiers: shift,
+        });
+        sent.borrow_mut().clear();
+        assert!(app.undo().unwrap());
         assert_eq!(
-            app.hits,
-            &[
-                KeyCode::A,
-                KeyCode::Application,
-                KeyCode::Again,
-                KeyCode::AltErase
+            *sent.borrow(),
+            [
+                checked(&[VendorCommand::SetKey as u8, 2, KeyCode::A as u8]),
+                checked(&[VendorCommand::SetKeyModifiers as u8, 2, shift]),
             ]
         );
     }
+
+    #[test]
+    fn save_without_confirmation() {
+        // Older firmware can't tell the config index
+        let (mut app, sent) = mock_app(&[]);
+        app.save_config().unwrap();
+        assert_eq!(
+            sent.borrow().last().unwrap(),
+            &checked(&[VendorCommand::Save as u8, 0])
+        );
+    }
+
+    #[test]
+    fn save_verified() {
+        let matrix = Matrix::new();
+        let mut record = [0; CONFIG_SIZE];
+        record[0] = storage::MAGIC;
+        let offset = storage::matrix_offset(0);
+        record[offset..offset + Matrix::SIZE].copy_from_slice(&matrix.to_bytes());
+        crc::seal(&mut record);
+
+        let mut replies = vec![[4, 0], Status::default().to_bytes(), [5, 0]];
+        replies.extend(chunks(&record));
+        replies.extend(chunks(&matrix.to_bytes()));
+        let (mut app, sent) = mock_app(&replies);
+        app.save_config().unwrap();
+        let sent = sent.borrow();
+        assert!(sent.contains(&checked(&[VendorCommand::Save as u8, 0])));
+        assert!(sent.contains(&vec![0, VendorCommand::DumpPage as u8, 5]));
+
+        // The layout in use changed in the meantime
+        let mut changed = Matrix::new();
+        changed.update_layout(AppCommand::SetKey(0, KeyCode::Escape, 0));
+        let mut replies = vec![[4, 0], Status::default().to_bytes(), [5, 0]];
+        replies.extend(chunks(&record));
+        replies.extend(chunks(&changed.to_bytes()));
+        assert!(mock_app(&replies).0.save_config().is_err());
+    }
+
+    #[test]
+    fn save_failed() {
+        let status = Status {
+            save_failed: true,
+            ..Status::default()
+        };
+        let (mut app, _) = mock_app(&[[4, 0], status.to_bytes()]);
+        assert!(app.save_config().is_err());
+    }
 }
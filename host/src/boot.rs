@@ -0,0 +1,183 @@
+//! Boot time decisions and the device status reported to the host.
+use crate::{matrix::Matrix, settings::Settings};
+
+/// Button that, held while the device boots, enters safe mode.
+pub const SAFE_MODE_BUTTON: usize = 0;
+
+/// Answered to a GetReport request on the control interface.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub struct Status {
+    /// The stored configuration wasn't loaded, the defaults are in use until the next reboot.
+    pub safe_mode: bool,
+    /// The stored configuration was corrupted and the defaults were loaded instead, cleared once a
+    /// new one is saved.
+    pub load_failed: bool,
+    /// The last save couldn't be written to flash, the config stored before it is still the one
+    /// in use after a reset. Cleared by the next successful save.
+    pub save_failed: bool,
+}
+
+impl Status {
+    /// Size of the feature report of the control interface.
+    pub const SIZE: usize = 2;
+
+    pub fn to_bytes(self) -> [u8; Self::SIZE] {
+        [
+            self.safe_mode as u8 | (self.load_failed as u8) << 1 | (self.save_failed as u8) << 2,
+            0,
+        ]
+    }
+
+    pub fn from_bytes(bytes: [u8; Self::SIZE]) -> Self {
+        Self {
+            safe_mode: bytes[0] & 1 != 0,
+            load_failed: bytes[0] & 2 != 0,
+            save_failed: bytes[0] & 4 != 0,
+        }
+    }
+}
+
+/// Configuration descriptor attributes, they're only read when the device is built at boot, so a
+/// change needs a reboot.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct UsbAttributes {
+    pub self_powered: bool,
+    pub remote_wakeup: bool,
+    /// Maximum current drawn from the bus, in 2 mA units as in bMaxPower.
+    pub max_power: u8,
+}
+
+impl UsbAttributes {
+    pub const SIZE: usize = 3;
+    /// Highest bMaxPower allowed by the spec, 500 mA.
+    pub const MAX_POWER_LIMIT: u8 = 250;
+
+    pub const fn new() -> Self {
+        Self {
+            self_powered: false,
+            remote_wakeup: false,
+            max_power: 50,
+        }
+    }
+
+    pub fn max_power_ma(self) -> usize {
+        usize::from(self.max_power) * 2
+    }
+
+    /// bmAttributes of the configuration descriptor, bit 7 is reserved and always set.
+    pub fn bm_attributes(self) -> u8 {
+        0x80 | (self.self_powered as u8) << 6 | (self.remote_wakeup as u8) << 5
+    }
+
+    pub fn to_bytes(self) -> [u8; Self::SIZE] {
+        [
+            self.self_powered as u8,
+            self.remote_wakeup as u8,
+            self.max_power,
+        ]
+    }
+
+    pub fn from_bytes(bytes: [u8; Self::SIZE]) -> Option<Self> {
+        let flag = |byte| match byte {
+            0 => Some(false),
+            1 => Some(true),
+            _ => None,
+        };
+        if bytes[2] > Self::MAX_POWER_LIMIT {
+            return None;
+        }
+        Some(Self {
+            self_powered: flag(bytes[0])?,
+            remote_wakeup: flag(bytes[1])?,
+            max_power: bytes[2],
+        })
+    }
+}
+
+/// Picks the configuration used after boot, `stored` is only read outside of safe mode. In safe
+/// mode nothing is written either, so the stored configuration survives until the user saves a
+/// new one.
+pub fn load_config<F>(safe_mode: bool, stored: F) -> (Matrix, Settings)
+where
+    F: FnOnce() -> Option<(Matrix, Settings)>,
+{
+    let stored = if safe_mode { None } else { stored() };
+    stored.unwrap_or_else(|| (Matrix::new(), Settings::new()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{key_code::KeyCode, packets::AppCommand};
+
+    fn custom() -> (Matrix, Settings) {
+        let mut matrix = Matrix::new();
+        matrix.update_layout(AppCommand::SetKey(0, KeyCode::Escape, 0));
+        (matrix, Settings::new())
+    }
+
+    #[test]
+    fn safe_mode_uses_defaults() {
+        let mut read = false;
+        let (matrix, _) = load_config(true, || {
+            read = true;
+            Some(custom())
+        });
+        assert!(!read);
+        assert_eq!(matrix.to_bytes(), Matrix::new().to_bytes());
+    }
+
+    #[test]
+    fn normal_boot_uses_stored() {
+        let (matrix, _) = load_config(false, || Some(custom()));
+        assert_eq!(matrix.to_bytes(), custom().0.to_bytes());
+
+        // Nothing valid stored
+        let (matrix, _) = load_config(false, || None);
+        assert_eq!(matrix.to_bytes(), Matrix::new().to_bytes());
+    }
+
+    #[test]
+    fn usb_attributes() {
+        let mut attributes = UsbAttributes::new();
+        assert_eq!(attributes.bm_attributes(), 0x80);
+        assert_eq!(attributes.max_power_ma(), 100);
+
+        let mut settings = Settings::new();
+        settings.update(AppCommand::SetRemoteWakeup(true));
+        settings.update(AppCommand::SetMaxPower(UsbAttributes::MAX_POWER_LIMIT));
+        attributes = settings.usb;
+        assert_eq!(attributes.bm_attributes(), 0xA0);
+        assert_eq!(attributes.max_power_ma(), 500);
+        settings.update(AppCommand::SetSelfPowered(true));
+        assert_eq!(settings.usb.bm_attributes(), 0xE0);
+
+        let bytes = settings.to_bytes();
+        assert_eq!(Settings::from_bytes(bytes).unwrap().usb, settings.usb);
+        // Over the spec limit
+        assert_eq!(UsbAttributes::from_bytes([0, 0, 251]), None);
+    }
+
+    #[test]
+    fn status_round_trip() {
+        let status = Status {
+            safe_mode: true,
+            ..Status::default()
+        };
+        assert_eq!(Status::from_bytes(status.to_bytes()), status);
+        let status = Status {
+            load_failed: true,
+            ..Status::default()
+        };
+        assert_eq!(status.to_bytes(), [2, 0]);
+        assert_eq!(Status::from_bytes(status.to_bytes()), status);
+        let status = Status {
+            save_failed: true,
+            ..Status::default()
+        };
+        assert_eq!(status.to_bytes(), [4, 0]);
+        assert_eq!(Status::from_bytes(status.to_bytes()), status);
+        // Older firmware answers with zeros
+        assert_eq!(Status::from_bytes([0; Status::SIZE]), Status::default());
+    }
+}
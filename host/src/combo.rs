@@ -0,0 +1,234 @@
+//! Combos: buttons pressed together send an action of their own instead of their individual
+//! ones.
+//!
+//! A press of a button that belongs to a combo is held back for `WINDOW_SCANS`, waiting for the
+//! other members. If they all go down in time the combo fires and its members are ignored until
+//! they are released, otherwise the held back presses are reported late. A member released while
+//! waiting is still reported as a tap.
+use crate::{action::Action, key_code::KeyCode, matrix::TAP_SCANS, NUM_BTS, SCAN_HZ};
+
+/// Number of combos stored in a matrix.
+pub const MAX_COMBOS: usize = 2;
+/// How long the members of a combo have to go down together, ~50 ms.
+const WINDOW_SCANS: u8 = (SCAN_HZ / 20) as u8;
+
+/// Buttons that send `action` when pressed together.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Combo {
+    /// One bit per button, starting with the first one in the LSB. A combo with less than two
+    /// buttons is disabled.
+    pub buttons: u8,
+    pub action: Action,
+}
+
+impl Combo {
+    /// Number of bytes used to store a combo.
+    pub const SIZE: usize = 1 + Action::SIZE;
+
+    pub const fn new() -> Self {
+        Self {
+            buttons: 0,
+            action: Action::Key(KeyCode::No),
+        }
+    }
+
+    /// Whether `buttons` is a valid set of members, 0 disables the combo.
+    pub fn valid_buttons(buttons: u8) -> bool {
+        buttons == 0 || (buttons.count_ones() >= 2 && buttons >> NUM_BTS == 0)
+    }
+
+    fn enabled(&self) -> bool {
+        self.buttons.count_ones() >= 2
+    }
+}
+
+fn bits(pressed: &[bool; NUM_BTS]) -> u8 {
+    pressed
+        .iter()
+        .enumerate()
+        .fold(0, |bits, (idx, &pressed)| bits | ((pressed as u8) << idx))
+}
+
+/// Tracks the presses held back while waiting for a combo.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ComboFilter {
+    /// Scans left for the other members to go down, 0 if the button isn't waiting.
+    waiting: [u8; NUM_BTS],
+    /// Scans left to report a button released while waiting.
+    tapping: [u8; NUM_BTS],
+    /// Members of a combo that fired, ignored until released.
+    suppressed: u8,
+    /// Combo reported while all its members are held.
+    active: Option<usize>,
+    previous: u8,
+}
+
+impl ComboFilter {
+    pub const fn new() -> Self {
+        Self {
+            waiting: [0; NUM_BTS],
+            tapping: [0; NUM_BTS],
+            suppressed: 0,
+            active: None,
+            previous: 0,
+        }
+    }
+
+    /// Should be called once per scan with the state of the buttons. Returns the state the
+    /// buttons should be handled with and the action of the combo being held, if any.
+    pub fn update(
+        &mut self,
+        combos: &[Combo; MAX_COMBOS],
+        pressed: &[bool; NUM_BTS],
+    ) -> ([bool; NUM_BTS], Option<Action>) {
+        let down = bits(pressed);
+        let rising = down & !self.previous;
+        self.previous = down;
+
+        if let Some(idx) = self.active {
+            if down & combos[idx].buttons != combos[idx].buttons {
+                self.active = None;
+            }
+        }
+        self.suppressed &= down;
+
+        let members = combos
+            .iter()
+            .filter(|combo| combo.enabled())
+            .fold(0, |members, combo| members | combo.buttons);
+        for (idx, waiting) in self.waiting.iter_mut().enumerate() {
+            let bit = 1 << idx;
+            if rising & members & !self.suppressed & bit != 0 {
+                *waiting = WINDOW_SCANS;
+            }
+        }
+
+        if self.active.is_none() {
+            let waiting = self
+                .waiting
+                .iter()
+                .enumerate()
+                .fold(0, |bits, (idx, &left)| bits | (((left > 0) as u8) << idx));
+            // Only presses still held back can start a combo, a key already sent stays a key
+            self.active = combos.iter().position(|combo| {
+                combo.enabled() && combo.buttons & down & waiting == combo.buttons
+            });
+            if let Some(idx) = self.active {
+                let buttons = combos[idx].buttons;
+                self.suppressed |= buttons;
+                for (idx, waiting) in self.waiting.iter_mut().enumerate() {
+                    if buttons & (1 << idx) != 0 {
+                        *waiting = 0;
+                    }
+                }
+            }
+        }
+
+        let mut filtered = [false; NUM_BTS];
+        for (idx, state) in filtered.iter_mut().enumerate() {
+            *state = if self.suppressed & (1 << idx) != 0 {
+                false
+            } else if self.waiting[idx] > 0 {
+                self.waiting[idx] -= 1;
+                if pressed[idx] {
+                    false
+                } else {
+                    // Released before the others, it was a tap of its own
+                    self.waiting[idx] = 0;
+                    self.tapping[idx] = TAP_SCANS - 1;
+                    true
+                }
+            } else if self.tapping[idx] > 0 {
+                self.tapping[idx] -= 1;
+                true
+            } else {
+                pressed[idx]
+            };
+        }
+        (filtered, self.active.map(|idx| combos[idx].action))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn combos() -> [Combo; MAX_COMBOS] {
+        [
+            Combo {
+                buttons: 0b011,
+                action: Action::Key(KeyCode::Escape),
+            },
+            Combo::new(),
+        ]
+    }
+
+    #[test]
+    fn fires_within_window() {
+        let combos = combos();
+        let mut filter = ComboFilter::new();
+        assert_eq!(
+            filter.update(&combos, &[true, false, false]),
+            ([false; NUM_BTS], None)
+        );
+        assert_eq!(
+            filter.update(&combos, &[true, true, true]),
+            ([false, false, true], Some(Action::Key(KeyCode::Escape)))
+        );
+        // Releasing a member ends the combo, the other one stays ignored until released
+        assert_eq!(
+            filter.update(&combos, &[true, false, false]),
+            ([false; NUM_BTS], None)
+        );
+        filter.update(&combos, &[false, false, false]);
+        assert_eq!(
+            filter.update(&combos, &[false, true, false]),
+            ([false; NUM_BTS], None)
+        );
+    }
+
+    #[test]
+    fn window_expires() {
+        let combos = combos();
+        let mut filter = ComboFilter::new();
+        for _ in 0..WINDOW_SCANS {
+            assert_eq!(
+                filter.update(&combos, &[true, false, false]),
+                ([false; NUM_BTS], None)
+            );
+        }
+        assert_eq!(
+            filter.update(&combos, &[true, false, false]),
+            ([true, false, false], None)
+        );
+        // Too late, the first button was already sent
+        let (filtered, action) = filter.update(&combos, &[true, true, false]);
+        assert_eq!(action, None);
+        assert!(filtered[0]);
+    }
+
+    #[test]
+    fn tap_while_waiting() {
+        let combos = combos();
+        let mut filter = ComboFilter::new();
+        filter.update(&combos, &[false, true, false]);
+        for _ in 0..TAP_SCANS {
+            assert_eq!(
+                filter.update(&combos, &[false, false, false]),
+                ([false, true, false], None)
+            );
+        }
+        assert_eq!(
+            filter.update(&combos, &[false, false, false]),
+            ([false; NUM_BTS], None)
+        );
+    }
+
+    #[test]
+    fn buttons() {
+        assert!(Combo::valid_buttons(0));
+        assert!(Combo::valid_buttons(0b101));
+        assert!(!Combo::valid_buttons(0b100));
+        assert!(!Combo::valid_buttons(1 << NUM_BTS | 1));
+    }
+}
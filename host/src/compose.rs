@@ -0,0 +1,138 @@
+//! Builds the key sequence that types an accented character through the operating system, to be
+//! stored in a sequence button.
+//!
+//! This relies on the host being set up for it, the device only types the keys:
+//! - `Compose`: a compose key (X11/Wayland) mapped to the Menu key, e.g. `setxkbmap -option
+//!   compose:menu`.
+//! - `DeadKeys`: the "US International" layout (Windows, macOS and Linux), where the accent keys
+//!   are dead keys combined with the next letter.
+use crate::{key_code::KeyCode, sequence::Sequence};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ComposeLayout {
+    Compose,
+    DeadKeys,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Accent {
+    Acute,
+    Grave,
+    Circumflex,
+    Tilde,
+    Diaeresis,
+    Cedilla,
+}
+
+impl Accent {
+    /// Keys typing the accent on a US layout.
+    fn keys(self, layout: ComposeLayout) -> &'static [KeyCode] {
+        match self {
+            Accent::Acute => &[KeyCode::Quote],
+            Accent::Grave => &[KeyCode::Grave],
+            Accent::Circumflex => &[KeyCode::LShift, KeyCode::Kb6],
+            Accent::Tilde => &[KeyCode::LShift, KeyCode::Grave],
+            Accent::Diaeresis => &[KeyCode::LShift, KeyCode::Quote],
+            Accent::Cedilla => match layout {
+                ComposeLayout::Compose => &[KeyCode::Comma],
+                // US International has no cedilla dead key, it uses the acute one
+                ComposeLayout::DeadKeys => &[KeyCode::Quote],
+            },
+        }
+    }
+}
+
+/// Splits an accented character into its accent and base letter, uppercase letters are returned
+/// as `true`.
+fn decompose(ch: char) -> Option<(Accent, KeyCode, bool)> {
+    let lower = ch.to_lowercase().next()?;
+    let (accent, base) = match lower {
+        'á' => (Accent::Acute, KeyCode::A),
+        'é' => (Accent::Acute, KeyCode::E),
+        'í' => (Accent::Acute, KeyCode::I),
+        'ó' => (Accent::Acute, KeyCode::O),
+        'ú' => (Accent::Acute, KeyCode::U),
+        'à' => (Accent::Grave, KeyCode::A),
+        'è' => (Accent::Grave, KeyCode::E),
+        'ì' => (Accent::Grave, KeyCode::I),
+        'ò' => (Accent::Grave, KeyCode::O),
+        'ù' => (Accent::Grave, KeyCode::U),
+        'â' => (Accent::Circumflex, KeyCode::A),
+        'ê' => (Accent::Circumflex, KeyCode::E),
+        'î' => (Accent::Circumflex, KeyCode::I),
+        'ô' => (Accent::Circumflex, KeyCode::O),
+        'û' => (Accent::Circumflex, KeyCode::U),
+        'ã' => (Accent::Tilde, KeyCode::A),
+        'õ' => (Accent::Tilde, KeyCode::O),
+        'ñ' => (Accent::Tilde, KeyCode::N),
+        'ä' => (Accent::Diaeresis, KeyCode::A),
+        'ë' => (Accent::Diaeresis, KeyCode::E),
+        'ï' => (Accent::Diaeresis, KeyCode::I),
+        'ö' => (Accent::Diaeresis, KeyCode::O),
+        'ü' => (Accent::Diaeresis, KeyCode::U),
+        'ç' => (Accent::Cedilla, KeyCode::C),
+        _ => return None,
+    };
+    Some((accent, base, lower != ch))
+}
+
+/// Returns the sequence typing `ch` with `layout`, `None` if the character isn't supported.
+pub fn sequence(ch: char, layout: ComposeLayout) -> Option<Sequence> {
+    let (accent, base, upper) = decompose(ch)?;
+    let mut sequence = Sequence::new();
+    if layout == ComposeLayout::Compose {
+        sequence.push(KeyCode::Application);
+    }
+    for &key in accent.keys(layout) {
+        sequence.push(key);
+    }
+    if upper {
+        sequence.push(KeyCode::LShift);
+    }
+    sequence.push(base);
+    Some(sequence)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compose() {
+        let seq = sequence('é', ComposeLayout::Compose).unwrap();
+        assert_eq!(
+            seq.keys(),
+            &[KeyCode::Application, KeyCode::Quote, KeyCode::E]
+        );
+        let seq = sequence('Ü', ComposeLayout::Compose).unwrap();
+        assert_eq!(
+            seq.keys(),
+            &[
+                KeyCode::Application,
+                KeyCode::LShift,
+                KeyCode::Quote,
+                KeyCode::LShift,
+                KeyCode::U
+            ]
+        );
+        let seq = sequence('ç', ComposeLayout::Compose).unwrap();
+        assert_eq!(
+            seq.keys(),
+            &[KeyCode::Application, KeyCode::Comma, KeyCode::C]
+        );
+    }
+
+    #[test]
+    fn dead_keys() {
+        let seq = sequence('ã', ComposeLayout::DeadKeys).unwrap();
+        assert_eq!(seq.keys(), &[KeyCode::LShift, KeyCode::Grave, KeyCode::A]);
+        let seq = sequence('ç', ComposeLayout::DeadKeys).unwrap();
+        assert_eq!(seq.keys(), &[KeyCode::Quote, KeyCode::C]);
+    }
+
+    #[test]
+    fn unsupported() {
+        assert_eq!(sequence('a', ComposeLayout::Compose), None);
+        assert_eq!(sequence('ß', ComposeLayout::DeadKeys), None);
+    }
+}
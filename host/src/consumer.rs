@@ -0,0 +1,65 @@
+//! Consumer control (media) keys, sent on their own interface since the keyboard report only
+//! covers the keyboard page.
+use crate::NUM_BTS;
+use num_enum::TryFromPrimitive;
+#[cfg(feature = "host")]
+use strum_macros::{AsRefStr, EnumIter};
+
+/// Usages of the consumer page that can be assigned by name, any other one byte usage can still be
+/// stored in an `Action::Consumer`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, TryFromPrimitive)]
+#[cfg_attr(feature = "host", derive(AsRefStr, EnumIter))]
+#[cfg_attr(feature = "host", strum(serialize_all = "lowercase"))]
+#[repr(u8)]
+pub enum ConsumerCode {
+    NextTrack = 0xB5,
+    PrevTrack = 0xB6,
+    Stop = 0xB7,
+    PlayPause = 0xCD,
+    Mute = 0xE2,
+    VolumeUp = 0xE9,
+    VolumeDown = 0xEA,
+}
+
+/// Number of usages reported at once, every button plus an encoder detent, which takes two
+/// buttons.
+pub const CONSUMER_SLOTS: usize = NUM_BTS;
+
+/// Report of the consumer interface, an array of one byte usages, 0 for an empty slot.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct ConsumerReport([u8; CONSUMER_SLOTS]);
+
+impl ConsumerReport {
+    pub const fn new() -> Self {
+        ConsumerReport([0; CONSUMER_SLOTS])
+    }
+
+    /// Adds `usage` to the first free slot, it's dropped if there's none.
+    pub fn pressed(&mut self, usage: u8) {
+        if usage == 0 || self.0.contains(&usage) {
+            return;
+        }
+        if let Some(slot) = self.0.iter_mut().find(|slot| **slot == 0) {
+            *slot = usage;
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn report() {
+        let mut report = ConsumerReport::new();
+        report.pressed(ConsumerCode::VolumeUp as u8);
+        report.pressed(ConsumerCode::VolumeUp as u8);
+        report.pressed(0);
+        report.pressed(ConsumerCode::Mute as u8);
+        assert_eq!(report.as_bytes(), &[0xE9, 0xE2, 0]);
+    }
+}
@@ -0,0 +1,107 @@
+//! CRC-16 used to check records stored in flash end to end.
+//!
+//! A record is sealed by storing the CRC of everything before it in its last two bytes, little
+//! endian.
+
+/// Number of bytes taken by the CRC at the end of a record.
+pub const SIZE: usize = 2;
+
+/// CRC-16/CCITT-FALSE: polynomial 0x1021, initial value 0xFFFF, no reflection.
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0xFFFFu16;
+    for &byte in data {
+        crc ^= u16::from(byte) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// CRC-8 with polynomial 0x07, initial value 0, no reflection. Control reports use it as their
+/// checksum, see `packets::CHECKSUM_FLAG`.
+pub fn crc8(data: &[u8]) -> u8 {
+    let mut crc = 0u8;
+    for &byte in data {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x07
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// Checks a record sealed with the CRC-8 in its last byte.
+pub fn check_crc8(record: &[u8]) -> bool {
+    match record.split_last() {
+        Some((&last, data)) => crc8(data) == last,
+        None => false,
+    }
+}
+
+/// Stores the CRC of `record` in its last two bytes.
+pub fn seal(record: &mut [u8]) {
+    if record.len() < SIZE {
+        return;
+    }
+    let (data, crc) = record.split_at_mut(record.len() - SIZE);
+    crc.copy_from_slice(&crc16(data).to_le_bytes());
+}
+
+/// Checks a record sealed with `seal`.
+pub fn check(record: &[u8]) -> bool {
+    if record.len() < SIZE {
+        return false;
+    }
+    let (data, crc) = record.split_at(record.len() - SIZE);
+    crc16(data).to_le_bytes() == crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_value() {
+        assert_eq!(crc16(b"123456789"), 0x29B1);
+        assert_eq!(crc16(&[]), 0xFFFF);
+        assert_eq!(crc8(b"123456789"), 0xF4);
+    }
+
+    #[test]
+    fn sealed_record() {
+        let mut record = [0x58, 0x07, 0x04, 0x0C, 0xE9, 0x00, 0x00, 0x00];
+        seal(&mut record);
+        assert!(check(&record));
+
+        // A marginal bit that read back flipped after the write
+        for idx in 0..record.len() {
+            let mut corrupted = record;
+            corrupted[idx] ^= 0x10;
+            assert!(!check(&corrupted));
+        }
+        // Erased flash
+        assert!(!check(&[0xFF; 8]));
+        assert!(!check(&[0x58]));
+    }
+
+    #[test]
+    fn interrupted_write() {
+        let mut record = [0x5Au8; 32];
+        seal(&mut record);
+        // Power lost halfway through, the rest of the half-words are still erased
+        for written in (2..record.len()).step_by(2) {
+            let mut partial = [0xFF; 32];
+            partial[..written].copy_from_slice(&record[..written]);
+            assert!(!check(&partial));
+        }
+    }
+}
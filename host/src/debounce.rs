@@ -0,0 +1,212 @@
+//! Alternative debounce strategies built on top of the debounced button states.
+use crate::{packets::InfoField, NUM_BTS};
+use num_enum::TryFromPrimitive;
+
+/// How button presses are debounced.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, TryFromPrimitive)]
+#[repr(u8)]
+pub enum LatencyMode {
+    /// Presses and releases are reported once the debouncer confirms them.
+    Normal = 0,
+    /// Presses are reported on the first edge, releases are still confirmed by the debouncer.
+    Eager,
+}
+
+/// Debounce window of the default settings, in samples.
+pub const DEFAULT_WINDOW: u8 = 16;
+/// Longest debounce window that can be set, in samples. The eager filter has to wait longer than
+/// this for the debouncer to confirm a press, see `EagerFilter`.
+pub const MAX_WINDOW: u8 = 30;
+
+/// Debouncer parameters of the firmware, reported to the host through `GetInfo` queries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DebounceInfo {
+    /// Number of samples the debouncer looks at, the same for presses and releases. Set by the
+    /// host, see `Settings::debounce_window`.
+    pub window: u16,
+    /// Threshold a button has to reach to be considered held.
+    pub hold_threshold: u16,
+    /// Samples per second, see `SCAN_HZ`.
+    pub scan_hz: u16,
+}
+
+impl DebounceInfo {
+    /// Value of `field`, `None` for fields that aren't about the debouncer.
+    pub fn field(&self, field: InfoField) -> Option<u16> {
+        match field {
+            InfoField::DebounceWindow => Some(self.window),
+            InfoField::HoldThreshold => Some(self.hold_threshold),
+            InfoField::ScanRate => Some(self.scan_hz),
+            InfoField::ConfigIndex
+            | InfoField::ConfigAttributes
+            | InfoField::MaxPower
+            | InfoField::Profile
+            | InfoField::EraseCount
+            | InfoField::Leds => None,
+        }
+    }
+
+    /// Converts a debounce time to a number of samples, `None` if it isn't a whole number of scans
+    /// or is longer than `MAX_WINDOW`.
+    pub fn samples_for(&self, ms: u16) -> Option<u16> {
+        let scaled = u32::from(ms) * u32::from(self.scan_hz);
+        if scaled % 1000 != 0 {
+            return None;
+        }
+        let samples = scaled / 1000;
+        if samples == 0 || samples > u32::from(MAX_WINDOW) {
+            return None;
+        }
+        Some(samples as u16)
+    }
+}
+
+/// Reports a press as soon as a button input goes active, without waiting for the debouncer.
+///
+/// An eager press is kept for `window` samples waiting for the debouncer to confirm it, if that
+/// doesn't happen the press is considered chatter and retracted, the button then needs to go
+/// inactive before another eager press can be reported. Releases are only reported after the
+/// debouncer confirms them, so chatter on release is still filtered.
+#[derive(Debug, Clone)]
+pub struct EagerFilter {
+    window: u8,
+    /// Samples left for the debouncer to confirm an eager press, zero if there's none pending.
+    pending: [u8; NUM_BTS],
+    /// An eager press was retracted and the input didn't go inactive since.
+    retracted: [bool; NUM_BTS],
+    state: [bool; NUM_BTS],
+}
+
+impl EagerFilter {
+    pub const fn new(window: u8) -> Self {
+        Self {
+            window,
+            pending: [0; NUM_BTS],
+            retracted: [false; NUM_BTS],
+            state: [false; NUM_BTS],
+        }
+    }
+
+    /// Feeds one sample, `raw` is the instantaneous state of the inputs and `debounced` the state
+    /// confirmed by the debouncer, `true` means pressed. Returns the state to be reported.
+    pub fn update(
+        &mut self,
+        raw: &[bool; NUM_BTS],
+        debounced: &[bool; NUM_BTS],
+    ) -> [bool; NUM_BTS] {
+        for idx in 0..NUM_BTS {
+            if !raw[idx] {
+                self.retracted[idx] = false;
+            }
+
+            if debounced[idx] {
+                // Confirmed press
+                self.pending[idx] = 0;
+                self.state[idx] = true;
+            } else if self.pending[idx] > 0 {
+                self.pending[idx] -= 1;
+                if self.pending[idx] == 0 {
+                    self.state[idx] = false;
+                    self.retracted[idx] = true;
+                }
+            } else if raw[idx] && !self.state[idx] && !self.retracted[idx] {
+                // First edge, report it right away
+                self.state[idx] = true;
+                self.pending[idx] = self.window;
+            } else {
+                self.state[idx] = false;
+            }
+        }
+        self.state
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WINDOW: u8 = 4;
+
+    #[test]
+    fn debounce_time() {
+        let info = DebounceInfo {
+            window: 16,
+            hold_threshold: 96,
+            scan_hz: 200,
+        };
+        assert_eq!(info.samples_for(5), Some(1));
+        assert_eq!(info.samples_for(80), Some(16));
+        // Longer than the window in use, it can be changed
+        assert_eq!(info.samples_for(150), Some(u16::from(MAX_WINDOW)));
+        // Not a multiple of the 5 ms scan period
+        assert_eq!(info.samples_for(12), None);
+        // Shorter than a scan and longer than the longest window
+        assert_eq!(info.samples_for(0), None);
+        assert_eq!(info.samples_for(155), None);
+    }
+
+    /// Runs a trace for the first button, each element is `(raw, debounced)`.
+    fn run(filter: &mut EagerFilter, trace: &[(bool, bool)]) -> [bool; 16] {
+        let mut out = [false; 16];
+        for (value, &(raw, debounced)) in out.iter_mut().zip(trace.iter()) {
+            let mut raw_state = [false; NUM_BTS];
+            let mut debounced_state = [false; NUM_BTS];
+            raw_state[0] = raw;
+            debounced_state[0] = debounced;
+            *value = filter.update(&raw_state, &debounced_state)[0];
+        }
+        out
+    }
+
+    #[test]
+    fn chattering_press_is_reported_immediately() {
+        let mut filter = EagerFilter::new(WINDOW);
+        let trace = [
+            (true, false),
+            (false, false),
+            (true, false),
+            (true, true),
+            (true, true),
+        ];
+        let out = run(&mut filter, &trace);
+        assert_eq!(&out[..trace.len()], &[true; 5]);
+    }
+
+    #[test]
+    fn unconfirmed_press_is_retracted() {
+        let mut filter = EagerFilter::new(WINDOW);
+        let trace = [
+            (true, false),
+            (false, false),
+            (true, false),
+            (true, false),
+            // Window expired without confirmation
+            (true, false),
+            // Still active, not re-armed
+            (true, false),
+            (false, false),
+            // New edge
+            (true, false),
+        ];
+        let out = run(&mut filter, &trace);
+        assert_eq!(
+            &out[..trace.len()],
+            &[true, true, true, true, false, false, false, true]
+        );
+    }
+
+    #[test]
+    fn release_chatter_is_filtered() {
+        let mut filter = EagerFilter::new(WINDOW);
+        let trace = [
+            (true, true),
+            (false, true),
+            (true, true),
+            (false, true),
+            (false, false),
+            (false, false),
+        ];
+        let out = run(&mut filter, &trace);
+        assert_eq!(&out[..trace.len()], &[true, true, true, true, false, false]);
+    }
+}
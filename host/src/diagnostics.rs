@@ -0,0 +1,40 @@
+//! Support bundle with every diagnostic report the device can answer.
+use crate::app::App;
+use anyhow::{Context, Result};
+use keylib::{PID, VID};
+use std::{
+    fs::File,
+    io::Write,
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+pub const DEFAULT_BUNDLE_PATH: &str = "keykey-diagnostics.txt";
+
+/// Writes all diagnostics from the device to a text file at `path`, reports unsupported by the
+/// firmware are marked as such instead of failing.
+pub fn write_bundle(app: &App, path: &Path) -> Result<()> {
+    let mut file =
+        File::create(path).with_context(|| format!("Failed to create {}", path.display()))?;
+
+    writeln!(file, "Keykey diagnostics")?;
+    writeln!(file, "Created at: {}", timestamp())?;
+    writeln!(file, "Host tool version: {}", env!("CARGO_PKG_VERSION"))?;
+    writeln!(file, "VID: {:#06x} PID: {:#06x}", VID, PID)?;
+    writeln!(file)?;
+
+    for (name, value) in app.diagnostics() {
+        let value = value.unwrap_or_else(|| String::from("unsupported"));
+        writeln!(file, "[{}] {}: {}", timestamp(), name, value)?;
+    }
+    file.flush()
+        .with_context(|| format!("Failed to write {}", path.display()))
+}
+
+/// Seconds since the Unix epoch, with millisecond resolution.
+fn timestamp() -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    format!("{}.{:03}", now.as_secs(), now.subsec_millis())
+}
@@ -0,0 +1,178 @@
+//! Rotary encoder support, two buttons can be wired to the quadrature channels of an encoder.
+use crate::matrix::TAP_SCANS;
+
+/// Quadrature transitions per detent.
+const STEPS_PER_DETENT: i8 = 4;
+/// How long each detent is pressed and then released, like a tap.
+const DETENT_SCANS: u8 = TAP_SCANS;
+/// Most detents waiting to be reported, a fast spin doesn't keep sending keys for long after it
+/// stops.
+const MAX_PENDING: i8 = 16;
+
+/// Movement for each transition, indexed by `previous state << 2 | current state`, where a state
+/// is `A << 1 | B`. Invalid transitions (both channels changing) count as no movement.
+#[rustfmt::skip]
+const TRANSITIONS: [i8; 16] = [
+    0, -1,  1,  0,
+    1,  0,  0, -1,
+   -1,  0,  0,  1,
+    0,  1, -1,  0,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// Channel A leads channel B.
+    Clockwise,
+    CounterClockwise,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QuadratureDecoder {
+    state: u8,
+    steps: i8,
+}
+
+impl QuadratureDecoder {
+    pub const fn new() -> Self {
+        Self { state: 0, steps: 0 }
+    }
+
+    /// Feeds the current state of the channels, `true` means active. Returns the direction of the
+    /// rotation once a full detent is completed.
+    pub fn update(&mut self, a: bool, b: bool) -> Option<Direction> {
+        let state = (a as u8) << 1 | b as u8;
+        self.steps += TRANSITIONS[(self.state << 2 | state) as usize];
+        self.state = state;
+
+        if self.steps >= STEPS_PER_DETENT {
+            self.steps = 0;
+            Some(Direction::Clockwise)
+        } else if self.steps <= -STEPS_PER_DETENT {
+            self.steps = 0;
+            Some(Direction::CounterClockwise)
+        } else {
+            None
+        }
+    }
+}
+
+/// Detents decoded but not reported yet. Every detent is reported as a press followed by a
+/// release, so the host sees detents that came faster than that as separate key presses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DetentQueue {
+    /// Positive for clockwise detents.
+    pending: i8,
+    /// Scans left in the press, then in the release, of the current detent.
+    remaining: u8,
+    current: Option<Direction>,
+}
+
+impl DetentQueue {
+    pub const fn new() -> Self {
+        Self {
+            pending: 0,
+            remaining: 0,
+            current: None,
+        }
+    }
+
+    /// Queues a detent, turning back cancels the ones still waiting in the other direction.
+    pub fn push(&mut self, direction: Direction) {
+        self.pending = match direction {
+            Direction::Clockwise => (self.pending + 1).min(MAX_PENDING),
+            Direction::CounterClockwise => (self.pending - 1).max(-MAX_PENDING),
+        };
+    }
+
+    /// Should be called once per scan, returns the direction of the detent pressed on this scan.
+    pub fn poll(&mut self) -> Option<Direction> {
+        if self.remaining == 0 {
+            self.current = match self.pending {
+                0 => return None,
+                pending if pending > 0 => Some(Direction::Clockwise),
+                _ => Some(Direction::CounterClockwise),
+            };
+            self.pending -= self.pending.signum();
+            self.remaining = 2 * DETENT_SCANS;
+        }
+        self.remaining -= 1;
+        // Pressed for the first half
+        self.current.filter(|_| self.remaining >= DETENT_SCANS)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CW: [(bool, bool); 4] = [(true, false), (true, true), (false, true), (false, false)];
+    const CCW: [(bool, bool); 4] = [(false, true), (true, true), (true, false), (false, false)];
+
+    fn feed(decoder: &mut QuadratureDecoder, sequence: &[(bool, bool)]) -> Option<Direction> {
+        let mut result = None;
+        for &(a, b) in sequence {
+            if let Some(dir) = decoder.update(a, b) {
+                assert!(result.is_none(), "more than one detent reported");
+                result = Some(dir);
+            }
+        }
+        result
+    }
+
+    #[test]
+    fn detents() {
+        let mut decoder = QuadratureDecoder::new();
+        assert_eq!(feed(&mut decoder, &CW), Some(Direction::Clockwise));
+        assert_eq!(feed(&mut decoder, &CW), Some(Direction::Clockwise));
+        assert_eq!(feed(&mut decoder, &CCW), Some(Direction::CounterClockwise));
+    }
+
+    #[test]
+    fn partial_and_bouncing() {
+        let mut decoder = QuadratureDecoder::new();
+        // Half a detent and back doesn't count
+        assert_eq!(feed(&mut decoder, &CW[..2]), None);
+        assert_eq!(feed(&mut decoder, &[(true, false), (false, false)]), None);
+        // Bouncing on one channel cancels itself
+        let bouncy = [
+            (true, false),
+            (false, false),
+            (true, false),
+            (true, true),
+            (false, true),
+            (false, false),
+        ];
+        assert_eq!(feed(&mut decoder, &bouncy), Some(Direction::Clockwise));
+    }
+
+    #[test]
+    fn queued_detents() {
+        let mut queue = DetentQueue::new();
+        assert_eq!(queue.poll(), None);
+        queue.push(Direction::Clockwise);
+        queue.push(Direction::Clockwise);
+        let press = [Some(Direction::Clockwise); DETENT_SCANS as usize];
+        let release = [None; DETENT_SCANS as usize];
+        let expected = press.iter().chain(&release).chain(&press).chain(&release);
+        assert!((0..4 * DETENT_SCANS)
+            .map(|_| queue.poll())
+            .eq(expected.copied()));
+        assert_eq!(queue.poll(), None);
+
+        // Turning back cancels what's still waiting
+        queue.push(Direction::Clockwise);
+        queue.push(Direction::Clockwise);
+        assert_eq!(queue.poll(), Some(Direction::Clockwise));
+        queue.push(Direction::CounterClockwise);
+        let reported = (0..4 * DETENT_SCANS).filter_map(|_| queue.poll()).count();
+        assert_eq!(reported, DETENT_SCANS as usize - 1);
+    }
+
+    #[test]
+    fn invalid_transition() {
+        let mut decoder = QuadratureDecoder::new();
+        // Both channels changing at once is ignored
+        assert_eq!(feed(&mut decoder, &[(true, true), (false, false)]), None);
+        assert_eq!(feed(&mut decoder, &CCW), Some(Direction::CounterClockwise));
+    }
+}
@@ -0,0 +1,98 @@
+//! Backup of the layout in use as a JSON file, to keep or share it, and restoring it.
+use crate::{app::App, parse_keys};
+use anyhow::{anyhow, Context, Result};
+use keylib::{action::Action, key_code::KeyCode, NUM_BTS};
+use std::{fs, path::Path};
+
+pub const DEFAULT_EXPORT_PATH: &str = "keykey-config.json";
+/// Bumped on incompatible changes to the file, so an import can reject files it doesn't know.
+pub const FORMAT_VERSION: u32 = 1;
+
+/// Reads the layout in use from the device and writes it to `path`. Buttons that send a plain key
+/// are listed by the key name, the others as `null`, and the whole layout is also stored as the
+/// hex of its bytes, so nothing is lost.
+pub fn export_config(app: &App, path: &Path) -> Result<()> {
+    let matrix = app.read_config()?;
+    let keys: Vec<String> = matrix
+        .layout()
+        .iter()
+        .map(|action| match action {
+            // Key names are lowercase ASCII, they don't need escaping
+            Action::Key(key) => format!("\"{}\"", key.as_ref()),
+            _ => String::from("null"),
+        })
+        .collect();
+    let config: String = matrix
+        .to_bytes()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    let json = format!(
+        "{{\n  \"version\": {},\n  \"keys\": [{}],\n  \"config\": \"{}\"\n}}\n",
+        FORMAT_VERSION,
+        keys.join(", "),
+        config
+    );
+    fs::write(path, json).with_context(|| format!("Failed to write {}", path.display()))
+}
+
+/// Reads a file written by `export_config` and applies its keys to the device, then saves them.
+/// The whole file is checked before sending anything, so a bad file can't be half applied.
+pub fn import_config(app: &mut App, path: &Path) -> Result<()> {
+    let json =
+        fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let layout = parse_config(&json).with_context(|| format!("Invalid file {}", path.display()))?;
+    app.apply_layout(&layout)
+}
+
+/// Parses the fields written by `export_config`, it isn't a general JSON parser.
+fn parse_config(json: &str) -> Result<[KeyCode; NUM_BTS]> {
+    let version = field(json, "version")?;
+    let digits = version
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(version.len());
+    match version[..digits].parse::<u32>() {
+        Ok(FORMAT_VERSION) => {}
+        Ok(version) => return Err(anyhow!("Unsupported version {}", version)),
+        Err(_) => return Err(anyhow!("Invalid version")),
+    }
+
+    let list = field(json, "keys")?
+        .strip_prefix('[')
+        .and_then(|list| list.split(']').next())
+        .ok_or_else(|| anyhow!("Invalid key list"))?;
+    let mut names = Vec::new();
+    for (button, entry) in list.split(',').map(str::trim).enumerate() {
+        if entry == "null" {
+            return Err(anyhow!(
+                "Button {} isn't a plain key, it can't be imported",
+                button + 1
+            ));
+        }
+        let name = entry
+            .strip_prefix('"')
+            .and_then(|entry| entry.strip_suffix('"'))
+            .filter(|name| !name.is_empty())
+            .ok_or_else(|| anyhow!("Invalid key name: {}", entry))?;
+        names.push(name);
+    }
+    let keys = parse_keys(&names.join(","))?;
+    if keys.len() != NUM_BTS {
+        return Err(anyhow!("Expected {} keys, found {}", NUM_BTS, keys.len()));
+    }
+    let mut layout = [KeyCode::No; NUM_BTS];
+    layout.copy_from_slice(&keys);
+    Ok(layout)
+}
+
+/// Text following the colon of the top level field `name`.
+fn field<'a>(json: &'a str, name: &str) -> Result<&'a str> {
+    let start = json
+        .find(&format!("\"{}\"", name))
+        .ok_or_else(|| anyhow!("Missing field {}", name))?;
+    json[start + name.len() + 2..]
+        .trim_start()
+        .strip_prefix(':')
+        .map(str::trim_start)
+        .ok_or_else(|| anyhow!("Invalid field {}", name))
+}
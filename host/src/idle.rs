@@ -0,0 +1,103 @@
+//! HID idle rate: how often an input report that didn't change is sent again, set by the host with
+//! SetIdle for every interface.
+use crate::SCAN_HZ;
+
+/// Time between scans, the idle rate is rounded up to it.
+const SCAN_MS: u16 = (1000 / SCAN_HZ) as u16;
+
+/// Idle rate of an interface and the time since its report was last sent.
+#[derive(Debug, Clone)]
+pub struct IdleRate {
+    /// In 4 ms units as in SetIdle, 0 only sends reports when they change.
+    rate: u8,
+    elapsed_ms: u16,
+}
+
+impl IdleRate {
+    /// Reports are only sent when they change, until the host asks for something else.
+    pub const fn new() -> Self {
+        Self {
+            rate: 0,
+            elapsed_ms: 0,
+        }
+    }
+
+    /// Rate as answered to GetIdle.
+    pub fn rate(&self) -> u8 {
+        self.rate
+    }
+
+    /// Sets the rate requested with SetIdle, the period starts over.
+    pub fn set(&mut self, rate: u8) {
+        self.rate = rate;
+        self.elapsed_ms = 0;
+    }
+
+    /// Should be called once per scan, returns `true` if the report is due to be sent again even
+    /// if it didn't change.
+    pub fn tick(&mut self) -> bool {
+        if self.rate == 0 {
+            return false;
+        }
+        self.elapsed_ms = self.elapsed_ms.saturating_add(SCAN_MS);
+        self.elapsed_ms >= u16::from(self.rate) * 4
+    }
+
+    /// Should be called every time the report is sent, changed or not.
+    pub fn sent(&mut self) {
+        self.elapsed_ms = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_on_change() {
+        let mut idle = IdleRate::new();
+        for _ in 0..1000 {
+            assert!(!idle.tick());
+        }
+    }
+
+    #[test]
+    fn resends_unchanged_report() {
+        let mut idle = IdleRate::new();
+        // 500 ms, the usual keyboard rate
+        idle.set(125);
+        assert_eq!(idle.rate(), 125);
+        let scans = 500 / SCAN_MS;
+        for _ in 1..scans {
+            assert!(!idle.tick());
+        }
+        assert!(idle.tick());
+        // Stays due until it's actually sent, e.g. while the endpoint is busy
+        assert!(idle.tick());
+        idle.sent();
+        assert!(!idle.tick());
+
+        // A report that changed restarts the period
+        for _ in 0..scans / 2 {
+            idle.tick();
+        }
+        idle.sent();
+        for _ in 1..scans {
+            assert!(!idle.tick());
+        }
+        assert!(idle.tick());
+
+        idle.set(0);
+        assert!(!idle.tick());
+    }
+
+    #[test]
+    fn rounds_up_to_scans() {
+        let mut idle = IdleRate::new();
+        // 4 ms is shorter than a scan, so it's every scan
+        idle.set(1);
+        assert!(idle.tick());
+        idle.sent();
+        assert!(idle.tick());
+    }
+}
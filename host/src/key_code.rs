@@ -271,6 +271,12 @@ impl KeyCode {
     pub fn is_modifier(self) -> bool {
         KeyCode::LCtrl <= self && self <= KeyCode::RGui
     }
+    pub fn is_letter(self) -> bool {
+        KeyCode::A <= self && self <= KeyCode::Z
+    }
+    pub fn is_digit(self) -> bool {
+        KeyCode::Kb1 <= self && self <= KeyCode::Kb0
+    }
     pub fn as_modifier_bit(self) -> u8 {
         if self.is_modifier() {
             1 << (self as u8 - KeyCode::LCtrl as u8)
@@ -278,19 +284,105 @@ impl KeyCode {
             0
         }
     }
+    /// Group the key is shown under when picking keys.
+    pub fn category(self) -> KeyCategory {
+        use KeyCode::*;
+        match self {
+            _ if self.is_letter() => KeyCategory::Letter,
+            _ if self.is_digit() => KeyCategory::Digit,
+            _ if self.is_modifier() => KeyCategory::Modifier,
+            Enter | Escape | BSpace | Tab | Space | CapsLock => KeyCategory::Basic,
+            Minus | Equal | LBracket | RBracket | Bslash | NonUsHash | SColon | Quote | Grave
+            | Comma | Dot | Slash | NonUsBslash => KeyCategory::Symbol,
+            F1 | F2 | F3 | F4 | F5 | F6 | F7 | F8 | F9 | F10 | F11 | F12 | F13 | F14 | F15
+            | F16 | F17 | F18 | F19 | F20 | F21 | F22 | F23 | F24 => KeyCategory::Function,
+            PScreen | ScrollLock | Pause | Insert | Home | PgUp | Delete | End | PgDown | Right
+            | Left | Down | Up => KeyCategory::Navigation,
+            _ if NumLock <= self && self <= KpDot => KeyCategory::Keypad,
+            KpEqual | KpComma | KpEqualSign => KeyCategory::Keypad,
+            _ if MediaPlayPause <= self => KeyCategory::Media,
+            Mute | VolUp | VolDown => KeyCategory::Media,
+            _ => KeyCategory::Other,
+        }
+    }
 }
 
+/// Groups of keys, see `KeyCode::category`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum KeyCategory {
+    Letter,
+    Digit,
+    /// Enter, escape, backspace, tab, space and caps lock.
+    Basic,
+    Symbol,
+    Function,
+    /// Arrows, page keys and the keys above them.
+    Navigation,
+    Keypad,
+    Modifier,
+    Media,
+    /// Error codes, international and rarely used keys.
+    Other,
+}
+
+/// Size of a keyboard report: the modifiers, a reserved byte and up to six keys, the layout of the
+/// boot report.
+#[cfg(not(feature = "nkro"))]
+pub const REPORT_SIZE: usize = 8;
+/// Size of a keyboard report: a bit for every usage of the keyboard page, modifiers included.
+#[cfg(feature = "nkro")]
+pub const REPORT_SIZE: usize = 256 / 8;
+
+/// Byte of the report holding the modifier bits.
+#[cfg(not(feature = "nkro"))]
+const MODIFIERS: usize = 0;
+#[cfg(feature = "nkro")]
+const MODIFIERS: usize = KeyCode::LCtrl as usize / 8;
+
 #[derive(Debug, Clone, Eq, PartialEq)]
-pub struct KbHidReport([u8; 8]);
+pub struct KbHidReport([u8; REPORT_SIZE]);
 
 impl KbHidReport {
     pub const fn new() -> Self {
-        KbHidReport([0; 8])
+        KbHidReport([0; REPORT_SIZE])
+    }
+
+    /// Builds a report holding `modifier` and `keys`, keys that are modifiers go to the modifier
+    /// byte as with `pressed`. More than six other keys give a rollover error report, unless built
+    /// with the `nkro` feature.
+    pub fn from_keys(modifier: u8, keys: &[KeyCode]) -> Self {
+        let mut report = Self::new();
+        report.add_modifiers(modifier);
+        for &key in keys {
+            report.pressed(key);
+        }
+        report
     }
 
     pub fn as_bytes(&self) -> &[u8] {
         &self.0
     }
+    /// Modifier bits in the report, as given by `KeyCode::as_modifier_bit`.
+    pub fn modifiers(&self) -> u8 {
+        self.0[MODIFIERS]
+    }
+    /// Usages of the keys in the report that aren't modifiers, in no particular order.
+    #[cfg(not(feature = "nkro"))]
+    pub fn keys(&self) -> impl Iterator<Item = u8> + '_ {
+        self.0[2..].iter().copied().filter(|&key| key != 0)
+    }
+    /// Usages of the keys in the report that aren't modifiers, in no particular order.
+    #[cfg(feature = "nkro")]
+    pub fn keys(&self) -> impl Iterator<Item = u8> + '_ {
+        (0..=u8::MAX)
+            .filter(|&usage| !(KeyCode::LCtrl as u8..=KeyCode::RGui as u8).contains(&usage))
+            .filter(move |&usage| self.0[usize::from(usage / 8)] & 1 << (usage % 8) != 0)
+    }
+    /// Returns `true` if the report has any key that isn't a modifier.
+    pub fn has_keys(&self) -> bool {
+        self.keys().next().is_some()
+    }
+    #[cfg(not(feature = "nkro"))]
     pub fn pressed(&mut self, kc: KeyCode) {
         use KeyCode::*;
         match kc {
@@ -304,9 +396,120 @@ impl KbHidReport {
                 .unwrap_or_else(|| self.set_all(ErrorRollOver)),
         }
     }
+    /// Sets the bit of `kc`, any number of keys can be pressed at once.
+    #[cfg(feature = "nkro")]
+    pub fn pressed(&mut self, kc: KeyCode) {
+        use KeyCode::*;
+        match kc {
+            // There's no rollover with a bitmap
+            No | ErrorRollOver | PostFail | ErrorUndefined => (),
+            kc => self.0[kc as usize / 8] |= 1 << (kc as u8 % 8),
+        }
+    }
+    /// Adds modifier bits, as given by `KeyCode::as_modifier_bit`, to the report.
+    pub fn add_modifiers(&mut self, modifiers: u8) {
+        self.0[MODIFIERS] |= modifiers;
+    }
+    #[cfg(not(feature = "nkro"))]
     fn set_all(&mut self, kc: KeyCode) {
         for c in &mut self.0[2..] {
             *c = kc as u8;
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(not(feature = "nkro"))]
+    fn report_from_keys() {
+        let shift = KeyCode::LShift.as_modifier_bit();
+        let report = KbHidReport::from_keys(shift, &[KeyCode::A, KeyCode::RCtrl]);
+        assert_eq!(
+            report.as_bytes(),
+            &[
+                shift | KeyCode::RCtrl.as_modifier_bit(),
+                0,
+                0x04,
+                0,
+                0,
+                0,
+                0,
+                0
+            ]
+        );
+        assert_eq!(KbHidReport::from_keys(0, &[]), KbHidReport::new());
+    }
+
+    #[test]
+    #[cfg(not(feature = "nkro"))]
+    fn report_key_limit() {
+        use KeyCode::*;
+        let report = KbHidReport::from_keys(0, &[A, B, C, D, E, F]);
+        assert_eq!(report.as_bytes(), &[0, 0, 4, 5, 6, 7, 8, 9]);
+
+        // Modifiers don't take a slot
+        let report = KbHidReport::from_keys(0, &[A, B, C, LAlt, D, E, F]);
+        assert_eq!(report.as_bytes()[2..], [4, 5, 6, 7, 8, 9]);
+
+        let report = KbHidReport::from_keys(0, &[A, B, C, D, E, F, G]);
+        assert_eq!(report.as_bytes()[2..], [ErrorRollOver as u8; 6]);
+    }
+
+    #[test]
+    fn report_accessors() {
+        use KeyCode::*;
+        let report = KbHidReport::from_keys(LShift.as_modifier_bit(), &[A, RCtrl, Kb1]);
+        assert_eq!(
+            report.modifiers(),
+            LShift.as_modifier_bit() | RCtrl.as_modifier_bit()
+        );
+        let mut keys = [0; 2];
+        for (slot, key) in keys.iter_mut().zip(report.keys()) {
+            *slot = key;
+        }
+        keys.sort_unstable();
+        assert_eq!(keys, [A as u8, Kb1 as u8]);
+        assert_eq!(report.keys().count(), 2);
+        assert!(report.has_keys());
+        assert!(!KbHidReport::from_keys(LAlt.as_modifier_bit(), &[LGui]).has_keys());
+    }
+
+    #[test]
+    #[cfg(feature = "nkro")]
+    fn nkro_report() {
+        use KeyCode::*;
+        let keys = [A, B, C, D, E, F, G, H, MediaCalc];
+        let report = KbHidReport::from_keys(0, &keys);
+        assert_eq!(report.as_bytes().len(), 32);
+        assert_eq!(report.as_bytes()[0], 0b1111_0000);
+        assert_eq!(report.as_bytes()[1], 0b0000_1111);
+        assert_eq!(report.as_bytes()[0xFB / 8], 1 << (0xFB % 8));
+        assert_eq!(report.keys().count(), keys.len());
+
+        let report = KbHidReport::from_keys(0, &[LCtrl, ErrorRollOver]);
+        assert_eq!(report.as_bytes()[0xE0 / 8], 1);
+        assert!(!report.has_keys());
+    }
+
+    #[test]
+    fn categories() {
+        use KeyCode::*;
+        assert_eq!(Q.category(), KeyCategory::Letter);
+        assert_eq!(Kb0.category(), KeyCategory::Digit);
+        assert_eq!(Escape.category(), KeyCategory::Basic);
+        assert_eq!(Grave.category(), KeyCategory::Symbol);
+        assert_eq!(F12.category(), KeyCategory::Function);
+        assert_eq!(F13.category(), KeyCategory::Function);
+        assert_eq!(PgUp.category(), KeyCategory::Navigation);
+        assert_eq!(Kp5.category(), KeyCategory::Keypad);
+        assert_eq!(KpEqual.category(), KeyCategory::Keypad);
+        assert_eq!(RGui.category(), KeyCategory::Modifier);
+        assert_eq!(VolUp.category(), KeyCategory::Media);
+        assert_eq!(MediaCalc.category(), KeyCategory::Media);
+        assert_eq!(Lang1.category(), KeyCategory::Other);
+        assert_eq!(No.category(), KeyCategory::Other);
+    }
+}
@@ -1,8 +1,34 @@
 #![cfg_attr(not(feature = "host"), no_std)]
+// Most types have a `const fn new` to be usable in statics and RTIC resources, `Default` can't be
+// called in a const context so it would only duplicate it
+#![allow(clippy::new_without_default)]
 
+pub mod action;
+pub mod boot;
+pub mod combo;
+pub mod compose;
+pub mod consumer;
+pub mod crc;
+pub mod debounce;
+pub mod encoder;
+pub mod idle;
 pub mod key_code;
+pub mod matrix;
+pub mod mouse;
+pub mod name;
 pub mod packets;
+pub mod quiet;
+pub mod repeat;
+pub mod selector;
+pub mod sequence;
+pub mod settings;
+pub mod storage;
+pub mod text;
 
 pub const VID: u16 = 0x1209;
 pub const PID: u16 = 0x000D;
 pub const CTRL_INTERFACE: u8 = 1;
+/// Number of buttons on the board.
+pub const NUM_BTS: usize = 3;
+/// Rate at which the firmware scans the buttons, `Matrix::update` is called once per scan.
+pub const SCAN_HZ: u32 = 200;
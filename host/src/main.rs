@@ -1,18 +1,134 @@
-use anyhow::Result;
-use crossterm::event::{read, Event, KeyCode as TermKey, KeyEvent, KeyModifiers};
+use anyhow::{anyhow, Result};
+use crossterm::event::{poll, read, Event, KeyCode as TermKey, KeyEvent, KeyModifiers};
+use keylib::{
+    action::Action,
+    boot::UsbAttributes,
+    compose::{self, ComposeLayout},
+    consumer::ConsumerCode,
+    debounce::MAX_WINDOW,
+    key_code::KeyCode,
+    mouse::{MouseButton, MouseDirection},
+    repeat::RepeatConfig,
+    selector::ButtonSelector,
+    sequence::MAX_KEYS,
+    storage::{self, NUM_PROFILES},
+    text::{self, TextError},
+    NUM_BTS,
+};
+use std::{
+    convert::TryFrom,
+    env,
+    io::{self, BufRead, Write},
+    path::Path,
+    thread,
+    time::Duration,
+};
+use strum::IntoEnumIterator;
 
 mod app;
+mod diagnostics;
+mod export;
+mod pager;
+mod preview;
+mod profiles;
+mod transport;
 use app::{App, State, Term};
+use preview::Preview;
+use profiles::{Picker, Profiles};
+
+/// Time between reads of the device state in `watch` and while waiting for a button press.
+const WATCH_INTERVAL: Duration = Duration::from_millis(50);
+/// Time between attempts to open the device again after it was unplugged.
+const RECONNECT_INTERVAL: Duration = Duration::from_millis(500);
+const REBOOT_MESSAGE: &str = "Device rebooting, the USB connection will drop and come back shortly";
 
 fn main() -> Result<()> {
+    let mut args = env::args().skip(1);
+    let mut app = match args.next() {
+        None => App::new()?,
+        // To try the menu and the key search without a device
+        Some(command) if command == "offline" => App::new_offline(),
+        Some(command) => return run_command(&command, &args.collect::<Vec<_>>()),
+    };
+
     let mut term = Term::new()?;
-    let mut app = App::new()?;
+    // Systems that don't let us read keyboards only show the buttons held
+    let mut preview = Preview::open(app.serial_number().as_deref());
+    // Older firmware can't tell, assume it isn't in safe mode and loaded its config
+    let mut status = app.status().unwrap_or_default();
+    // Same for the name
+    let mut name = app.name().unwrap_or_default();
+    // Older firmware doesn't answer its version either
+    let mut version = firmware_version(&app);
     let mut config_saved = false;
+    let mut exit_message = None;
+    // Read again every time the menu is shown after another screen, which may have changed it
+    let mut layout = None;
+    let mut read_layout = true;
+    // Outcome of the last menu option, shown until the next key
+    let mut notice = None;
+    let mut reconnected = false;
+
+    // Runs a device operation, if it fails because the device was unplugged, waits for it to be
+    // plugged back in and evaluates to `None`, without trying again. The screen is shown as it
+    // was, leaving the session if the user quits while waiting.
+    macro_rules! or_wait {
+        ($label:lifetime, $op:expr) => {
+            match $op {
+                Ok(value) => Some(value),
+                Err(e) => {
+                    if app.is_connected() {
+                        return Err(e);
+                    }
+                    if !wait_for_device(&mut term, &mut app)? {
+                        break $label;
+                    }
+                    reconnected = true;
+                    None
+                }
+            }
+        };
+    }
 
     'outer: loop {
         if term.state == State::SelectScreen {
-            term.render_menu_screen(config_saved)?;
-            match read()? {
+            if reconnected {
+                // It booted again, changes that weren't saved are gone
+                status = app.status().unwrap_or_default();
+                name = app.name().unwrap_or_default();
+                version = firmware_version(&app);
+                preview = Preview::open(app.serial_number().as_deref());
+                config_saved = false;
+                read_layout = true;
+                reconnected = false;
+            }
+            if read_layout {
+                // Older firmware can't answer, the menu is shown without it
+                layout = app.read_config().ok().map(|matrix| matrix.layout());
+            }
+            term.render_menu_screen(
+                config_saved,
+                status,
+                &name,
+                &version,
+                layout,
+                notice.as_deref(),
+            )?;
+            term.render_device_line(&app.device_line())?;
+            notice = None;
+            // Shows what the buttons do until a key is pressed
+            let mut shown = None;
+            let event = loop {
+                if poll(WATCH_INTERVAL)? {
+                    break read()?;
+                }
+                let line = preview_line(&app, &mut preview);
+                if shown.as_ref() != Some(&line) {
+                    term.render_preview(&line)?;
+                    shown = Some(line);
+                }
+            };
+            match event {
                 Event::Key(KeyEvent {
                     code: TermKey::Char('q'),
                     modifiers: KeyModifiers::CONTROL,
@@ -24,16 +140,190 @@ fn main() -> Result<()> {
                     '1' => term.state = State::Set1,
                     '2' => term.state = State::Set2,
                     '3' => term.state = State::Set3,
+                    'p' => term.state = State::Profiles,
+                    'k' => term.state = State::PressToSelect,
+                    'r' => term.state = State::ConfirmReset,
                     's' => {
-                        if !config_saved {
-                            app.save_config()?;
-                            config_saved = true;
+                        // Older firmware can't read its flash back, it's saved without asking
+                        let confirmed = !config_saved
+                            && match app.pending_changes() {
+                                Ok(changes) => {
+                                    term.render_save_confirm(&changes)?;
+                                    matches!(
+                                        read()?,
+                                        Event::Key(KeyEvent {
+                                            code: TermKey::Char('y'),
+                                            ..
+                                        }) | Event::Key(KeyEvent {
+                                            code: TermKey::Enter,
+                                            ..
+                                        })
+                                    )
+                                }
+                                Err(_) => true,
+                            };
+                        if confirmed {
+                            // A failed save leaves the session open to try again
+                            match app.save_config() {
+                                Ok(()) => config_saved = true,
+                                Err(e) if app.is_connected() => notice = Some(format!("{:#}", e)),
+                                Err(_) => {
+                                    if !wait_for_device(&mut term, &mut app)? {
+                                        break 'outer;
+                                    }
+                                    reconnected = true;
+                                }
+                            }
+                        }
+                    }
+                    'c' => {
+                        or_wait!('outer, app.compact());
+                    }
+                    'u' => {
+                        if let Some(undone) = or_wait!('outer, app.undo()) {
+                            notice = Some(String::from(if undone {
+                                "Last change undone, it isn't saved yet"
+                            } else {
+                                "Nothing to undo"
+                            }));
                         }
                     }
+                    'e' => {
+                        let path = Path::new(export::DEFAULT_EXPORT_PATH);
+                        // Older firmware can't answer, that shouldn't end the session
+                        notice = Some(match export::export_config(&app, path) {
+                            Ok(()) => format!("Configuration exported to {}", path.display()),
+                            Err(e) => format!("{:#}", e),
+                        });
+                    }
+                    'b' => {
+                        app.reboot()?;
+                        exit_message = Some(REBOOT_MESSAGE);
+                        break 'outer;
+                    }
                     _ => {}
                 },
                 _ => {}
             }
+            read_layout = term.state != State::SelectScreen;
+        } else if term.state == State::PressToSelect {
+            term.render_press_screen()?;
+            let mut selector = ButtonSelector::new();
+            term.state = loop {
+                let mask = match or_wait!('outer, app.pressed()) {
+                    Some(mask) => mask,
+                    None => {
+                        term.render_press_screen()?;
+                        continue;
+                    }
+                };
+                let mut cancel = false;
+                // The button being pressed may type into the terminal, those keys are dropped
+                while poll(Duration::from_millis(0))? {
+                    match read()? {
+                        Event::Key(KeyEvent {
+                            code: TermKey::Char('q'),
+                            modifiers: KeyModifiers::CONTROL,
+                        }) => break 'outer,
+                        // Unless it's a button mapped to esc
+                        Event::Key(KeyEvent {
+                            code: TermKey::Esc, ..
+                        }) => cancel |= mask == 0,
+                        _ => {}
+                    }
+                }
+                if cancel {
+                    break State::SelectScreen;
+                }
+                if let Some(button) = selector.update(mask) {
+                    while poll(Duration::from_millis(0))? {
+                        read()?;
+                    }
+                    break State::for_button(button)?;
+                }
+                thread::sleep(WATCH_INTERVAL);
+            };
+        } else if term.state == State::ConfirmReset {
+            term.render_reset_confirm()?;
+            // Anything but 'y' cancels, so a stray key doesn't wipe the config
+            if let Event::Key(KeyEvent {
+                code: TermKey::Char('y'),
+                ..
+            }) = read()?
+            {
+                match app.factory_reset() {
+                    Ok(()) => {
+                        // The menu reads the layout again, the name and status changed too
+                        status = app.status().unwrap_or_default();
+                        name = app.name().unwrap_or_default();
+                        notice = Some(String::from("Device reset to the defaults"));
+                    }
+                    Err(e) if app.is_connected() => notice = Some(format!("{:#}", e)),
+                    Err(_) => {
+                        if !wait_for_device(&mut term, &mut app)? {
+                            break 'outer;
+                        }
+                        reconnected = true;
+                    }
+                }
+            }
+            term.state = State::SelectScreen;
+        } else if term.state == State::Profiles {
+            let profiles = Profiles::open()?;
+            let mut picker = Picker::new(profiles.list()?);
+            loop {
+                picker.render(&mut term)?;
+                match read()? {
+                    Event::Key(KeyEvent {
+                        code: TermKey::Char('q'),
+                        modifiers: KeyModifiers::CONTROL,
+                    }) => break 'outer,
+                    Event::Key(KeyEvent {
+                        code: TermKey::Esc, ..
+                    }) => {
+                        term.state = State::SelectScreen;
+                        break;
+                    }
+                    Event::Key(KeyEvent {
+                        code: TermKey::Enter,
+                        ..
+                    }) => {
+                        if let Some(name) = picker.selected() {
+                            // A broken file shouldn't end the session
+                            match profiles.load(name) {
+                                Ok(layout) => {
+                                    if or_wait!('outer, app.apply_layout(&layout)).is_some() {
+                                        config_saved = true;
+                                        term.state = State::SelectScreen;
+                                        break;
+                                    }
+                                }
+                                Err(e) => picker.message = Some(format!("{:#}", e)),
+                            }
+                        }
+                    }
+                    Event::Key(KeyEvent {
+                        code: TermKey::Char('d'),
+                        ..
+                    }) => {
+                        if let Some(name) = picker.selected() {
+                            picker.message = match profiles.delete(name) {
+                                Ok(()) => Some(format!("Deleted {}", name)),
+                                Err(e) => Some(format!("{:#}", e)),
+                            };
+                            picker.set_names(profiles.list()?);
+                        }
+                    }
+                    Event::Key(KeyEvent {
+                        code: TermKey::Up, ..
+                    }) => picker.up(),
+                    Event::Key(KeyEvent {
+                        code: TermKey::Down,
+                        ..
+                    }) => picker.down(),
+                    _ => {}
+                }
+            }
         } else {
             'inner: loop {
                 config_saved = false;
@@ -54,10 +344,13 @@ fn main() -> Result<()> {
                         code: TermKey::Enter,
                         ..
                     }) => {
-                        app.send_selected(term.state.to_vendor_command()?)?;
-                        term.state = State::SelectScreen;
-                        app.clear();
-                        break 'inner;
+                        let button = term.state.button()?;
+                        // The search and selection are kept to send them again once it's back
+                        if or_wait!('outer, app.send_selected(button)).is_some() {
+                            term.state = State::SelectScreen;
+                            app.clear();
+                            break 'inner;
+                        }
                     }
                     Event::Key(KeyEvent {
                         code: TermKey::Char(c),
@@ -79,5 +372,792 @@ fn main() -> Result<()> {
             }
         }
     }
+
+    // Leave the alternate screen before printing
+    drop(term);
+    if let Some(message) = exit_message {
+        println!("{}", message);
+    }
     Ok(())
 }
+
+/// What the buttons are doing, shown in the menu: the ones held and the keys the device sends,
+/// where the system lets us read its keyboard interface.
+fn preview_line(app: &App, preview: &mut Option<Preview>) -> String {
+    let held = match app.pressed() {
+        Ok(0) => String::from("none"),
+        Ok(mask) => (0..NUM_BTS)
+            .filter(|button| mask & 1 << button != 0)
+            .map(|button| (button + 1).to_string())
+            .collect::<Vec<_>>()
+            .join(", "),
+        // Older firmware can't tell
+        Err(_) => String::from("unknown"),
+    };
+    if let Some(keyboard) = preview {
+        if !keyboard.update() {
+            *preview = None;
+        }
+    }
+    let sent = preview
+        .as_ref()
+        .map_or_else(|| String::from("unavailable"), Preview::keys);
+    format!("Buttons held: {} | Sending: {}", held, sent)
+}
+
+/// Firmware version shown in the menu, "unknown" if the firmware can't tell.
+fn firmware_version(app: &App) -> String {
+    app.firmware_version()
+        .map_or_else(|_| String::from("unknown"), |version| version.to_string())
+}
+
+/// Shows that the device was unplugged until it's opened again, returns `false` if the user quit
+/// instead.
+fn wait_for_device(term: &mut Term, app: &mut App) -> Result<bool> {
+    term.render_disconnected()?;
+    loop {
+        if poll(RECONNECT_INTERVAL)? {
+            if let Event::Key(KeyEvent {
+                code: TermKey::Char('q'),
+                modifiers: KeyModifiers::CONTROL,
+            }) = read()?
+            {
+                return Ok(false);
+            }
+        }
+        if app.reconnect()? {
+            return Ok(true);
+        }
+    }
+}
+
+/// Runs a non-interactive command, without touching the terminal.
+fn run_command(command: &str, args: &[String]) -> Result<()> {
+    let arg = args.first().cloned();
+    match command {
+        "diagnostics" => {
+            // Also for firmware that keyconfig can't configure
+            let app = App::new_unchecked()?;
+            let path = arg.unwrap_or_else(|| String::from(diagnostics::DEFAULT_BUNDLE_PATH));
+            diagnostics::write_bundle(&app, Path::new(&path))?;
+            println!("Diagnostics written to {}", path);
+            Ok(())
+        }
+        "reboot" => {
+            App::new()?.reboot()?;
+            println!("{}", REBOOT_MESSAGE);
+            Ok(())
+        }
+        "usart-bootloader" => {
+            App::new()?.enter_usart_bootloader()?;
+            println!(
+                "Device rebooting into the system (USART) bootloader, flash it over USART1 and reset it to get back to the firmware"
+            );
+            Ok(())
+        }
+        "export" => {
+            let app = App::new()?;
+            let path = arg.unwrap_or_else(|| String::from(export::DEFAULT_EXPORT_PATH));
+            export::export_config(&app, Path::new(&path))?;
+            println!("Configuration exported to {}", path);
+            Ok(())
+        }
+        "import" => {
+            let path = arg.ok_or_else(|| anyhow!("Usage: keyconfig import FILE"))?;
+            export::import_config(&mut App::new()?, Path::new(&path))?;
+            println!("Configuration imported from {}", path);
+            Ok(())
+        }
+        "compact" => {
+            App::new()?.compact()?;
+            println!("Config storage compacted");
+            Ok(())
+        }
+        "startup" => {
+            let keys = arg.as_deref().map_or(Ok(Vec::new()), parse_keys)?;
+            App::new()?.set_startup_sequence(&keys)?;
+            if keys.is_empty() {
+                println!("Startup sequence disabled");
+            } else {
+                println!("Startup sequence saved");
+            }
+            Ok(())
+        }
+        "compose" => {
+            let (button, ch) = match args {
+                [button, ch] | [button, ch, _] => (button, ch),
+                _ => {
+                    return Err(anyhow!(
+                        "Usage: keyconfig compose BUTTON CHAR [compose|deadkeys]"
+                    ))
+                }
+            };
+            let button = button
+                .parse::<usize>()
+                .ok()
+                .and_then(|button| button.checked_sub(1))
+                .ok_or_else(|| anyhow!("Invalid button: {}", button))?;
+            let layout = match args.get(2).map(String::as_str) {
+                None | Some("compose") => ComposeLayout::Compose,
+                Some("deadkeys") => ComposeLayout::DeadKeys,
+                Some(other) => return Err(anyhow!("Unknown layout: {}", other)),
+            };
+            let mut chars = ch.chars();
+            let sequence = match (chars.next(), chars.next()) {
+                (Some(ch), None) => compose::sequence(ch, layout),
+                _ => None,
+            }
+            .ok_or_else(|| anyhow!("Unsupported character: {}", ch))?;
+            App::new()?.set_sequence_button(button, &sequence)?;
+            println!("Button {} now types {}", button + 1, ch);
+            Ok(())
+        }
+        "text" => {
+            let (button, text) = match args {
+                [button, text] => (button, text),
+                _ => return Err(anyhow!("Usage: keyconfig text BUTTON TEXT")),
+            };
+            let button = button
+                .parse::<usize>()
+                .ok()
+                .and_then(|button| button.checked_sub(1))
+                .ok_or_else(|| anyhow!("Invalid button: {}", button))?;
+            let sequence = text::sequence(text).map_err(|err| match err {
+                TextError::Unsupported(ch) => anyhow!("Unsupported character: {:?}", ch),
+                TextError::TooLong => anyhow!(
+                    "Text too long, it has to fit in {} keys and uppercase letters and symbols \
+                     take two",
+                    MAX_KEYS
+                ),
+            })?;
+            App::new()?.set_sequence_button(button, &sequence)?;
+            println!("Button {} now types {:?}", button + 1, text);
+            Ok(())
+        }
+        "modifiers" => {
+            let (button, keys) = match args {
+                [button, keys] => (button, keys),
+                _ => return Err(anyhow!("Usage: keyconfig modifiers BUTTON KEYS")),
+            };
+            let button = button
+                .parse::<usize>()
+                .ok()
+                .and_then(|button| button.checked_sub(1))
+                .ok_or_else(|| anyhow!("Invalid button: {}", button))?;
+            App::new()?.set_modifier_button(button, &parse_keys(keys)?)?;
+            println!("Button {} now holds {}", button + 1, keys);
+            Ok(())
+        }
+        "shortcut" => {
+            let (button, keys) = match args {
+                [button, keys] => (button, keys),
+                _ => return Err(anyhow!("Usage: keyconfig shortcut BUTTON KEYS")),
+            };
+            let button = button
+                .parse::<usize>()
+                .ok()
+                .and_then(|button| button.checked_sub(1))
+                .ok_or_else(|| anyhow!("Invalid button: {}", button))?;
+            let (modifiers, key): (Vec<KeyCode>, Vec<KeyCode>) = parse_keys(keys)?
+                .into_iter()
+                .partition(|key| key.is_modifier());
+            let key = match key.as_slice() {
+                [key] => *key,
+                _ => return Err(anyhow!("A shortcut needs exactly one key that isn't a modifier")),
+            };
+            App::new()?.set_shortcut(button, key, &modifiers)?;
+            println!("Button {} now sends {}", button + 1, keys);
+            Ok(())
+        }
+        "combo" => {
+            const USAGE: &str = "Usage: keyconfig combo SLOT BUTTONS KEY | combo SLOT off";
+            let (slot, buttons, key) = match args {
+                [slot, off] if off == "off" => (slot, None, KeyCode::No),
+                [slot, buttons, key] => {
+                    let key = KeyCode::iter()
+                        .find(|code| code.as_ref() == key.to_lowercase())
+                        .ok_or_else(|| anyhow!("Unknown key: {}", key))?;
+                    (slot, Some(buttons), key)
+                }
+                _ => return Err(anyhow!(USAGE)),
+            };
+            let slot = slot
+                .parse::<usize>()
+                .ok()
+                .and_then(|slot| slot.checked_sub(1))
+                .ok_or_else(|| anyhow!("Invalid combo: {}", slot))?;
+            let buttons = match buttons {
+                Some(buttons) => buttons
+                    .split(',')
+                    .map(|button| {
+                        button
+                            .parse::<usize>()
+                            .ok()
+                            .and_then(|button| button.checked_sub(1))
+                            .ok_or_else(|| anyhow!("Invalid button: {}", button))
+                    })
+                    .collect::<Result<Vec<_>>>()?,
+                None => Vec::new(),
+            };
+            App::new()?.set_combo(slot, &buttons, key)?;
+            if buttons.is_empty() {
+                println!("Combo {} disabled", slot + 1);
+            } else {
+                println!("Combo {} now sends {}", slot + 1, key.as_ref());
+            }
+            Ok(())
+        }
+        "media" => {
+            let (button, name) = match args {
+                [button, name] => (button, name),
+                _ => return Err(anyhow!("Usage: keyconfig media BUTTON KEY")),
+            };
+            let button = button
+                .parse::<usize>()
+                .ok()
+                .and_then(|button| button.checked_sub(1))
+                .ok_or_else(|| anyhow!("Invalid button: {}", button))?;
+            let code = ConsumerCode::iter()
+                .find(|code| code.as_ref() == name.to_lowercase())
+                .ok_or_else(|| {
+                    let names: Vec<String> = ConsumerCode::iter()
+                        .map(|code| String::from(code.as_ref()))
+                        .collect();
+                    anyhow!("Unknown media key: {}, use one of: {}", name, names.join(", "))
+                })?;
+            App::new()?.set_consumer_button(button, code)?;
+            println!("Button {} now sends {}", button + 1, code.as_ref());
+            Ok(())
+        }
+        "mouse" => {
+            const USAGE: &str = "Usage: keyconfig mouse BUTTON left|right|middle | \
+                mouse BUTTON move left|right|up|down | mouse speed SPEED";
+            let (button, name) = match args {
+                [speed, value] if speed == "speed" => {
+                    let speed = value
+                        .parse::<u8>()
+                        .map_err(|_| anyhow!("Invalid mouse speed: {}", value))?;
+                    App::new()?.set_mouse_speed(speed)?;
+                    println!("Move buttons now move {} per scan", speed);
+                    return Ok(());
+                }
+                [button, action, name] if action == "move" => {
+                    let button = button
+                        .parse::<usize>()
+                        .ok()
+                        .and_then(|button| button.checked_sub(1))
+                        .ok_or_else(|| anyhow!("Invalid button: {}", button))?;
+                    let direction = MouseDirection::iter()
+                        .find(|direction| direction.as_ref() == name.to_lowercase())
+                        .ok_or_else(|| anyhow!("Unknown direction: {}", name))?;
+                    App::new()?.set_mouse_move(button, direction)?;
+                    println!(
+                        "Button {} now moves the cursor {}",
+                        button + 1,
+                        direction.as_ref()
+                    );
+                    return Ok(());
+                }
+                [button, name] => (button, name),
+                _ => return Err(anyhow!(USAGE)),
+            };
+            let button = button
+                .parse::<usize>()
+                .ok()
+                .and_then(|button| button.checked_sub(1))
+                .ok_or_else(|| anyhow!("Invalid button: {}", button))?;
+            let mouse = MouseButton::iter()
+                .find(|mouse| mouse.as_ref() == name.to_lowercase())
+                .ok_or_else(|| anyhow!("Unknown mouse button: {}", name))?;
+            App::new()?.set_mouse_button(button, mouse)?;
+            println!("Button {} now clicks {}", button + 1, mouse.as_ref());
+            Ok(())
+        }
+        "quiet" => {
+            let enabled = match arg.as_deref() {
+                Some("on") => true,
+                Some("off") => false,
+                _ => return Err(anyhow!("Usage: keyconfig quiet on|off")),
+            };
+            App::new()?.set_quiet_config(enabled)?;
+            println!(
+                "Key output {} paused while configuring",
+                if enabled { "is" } else { "isn't" }
+            );
+            Ok(())
+        }
+        "capsword" => {
+            let button = match args {
+                [button] => button,
+                _ => return Err(anyhow!("Usage: keyconfig capsword BUTTON")),
+            };
+            let button = button
+                .parse::<usize>()
+                .ok()
+                .and_then(|button| button.checked_sub(1))
+                .ok_or_else(|| anyhow!("Invalid button: {}", button))?;
+            App::new()?.set_caps_word_button(button)?;
+            println!("Button {} now toggles caps word", button + 1);
+            Ok(())
+        }
+        "encoder" => {
+            const USAGE: &str = "Usage: keyconfig encoder BUTTON CW CCW | encoder off";
+            let (first, actions) = match args {
+                [off] if off == "off" => (None, [Action::NONE; 2]),
+                [first, cw, ccw] => {
+                    let first = first
+                        .parse::<usize>()
+                        .ok()
+                        .and_then(|first| first.checked_sub(1))
+                        .ok_or_else(|| anyhow!("Invalid button: {}", first))?;
+                    (Some(first), [parse_action(cw)?, parse_action(ccw)?])
+                }
+                _ => return Err(anyhow!(USAGE)),
+            };
+            App::new()?.set_encoder(first, actions)?;
+            match first {
+                Some(first) => println!(
+                    "Buttons {} and {} are now an encoder sending {} and {}",
+                    first + 1,
+                    first + 2,
+                    args[1],
+                    args[2]
+                ),
+                None => println!("Encoder disabled"),
+            }
+            Ok(())
+        }
+        "layer" => {
+            let (button, keys) = match args {
+                [off] if off == "off" => {
+                    App::new()?.set_layer(None, &[])?;
+                    println!("Fn layer disabled");
+                    return Ok(());
+                }
+                [button, keys] => (button, keys),
+                _ => return Err(anyhow!("Usage: keyconfig layer BUTTON KEYS | layer off")),
+            };
+            let button = button
+                .parse::<usize>()
+                .ok()
+                .and_then(|button| button.checked_sub(1))
+                .ok_or_else(|| anyhow!("Invalid button: {}", button))?;
+            App::new()?.set_layer(Some(button), &parse_keys(keys)?)?;
+            println!(
+                "Holding button {} now sends {} from the other buttons",
+                button + 1,
+                keys
+            );
+            Ok(())
+        }
+        "taphold" => {
+            const USAGE: &str =
+                "Usage: keyconfig taphold BUTTON KEY MODIFIERS | taphold threshold MS";
+            match args {
+                [threshold, ms] if threshold == "threshold" => {
+                    let threshold = parse_tens_of_ms(ms, 1)?;
+                    App::new()?.set_tap_hold_threshold(threshold)?;
+                    println!(
+                        "Tap-hold buttons now hold after {} ms",
+                        threshold as u32 * 10
+                    );
+                }
+                [button, key, modifiers] => {
+                    let button = button
+                        .parse::<usize>()
+                        .ok()
+                        .and_then(|button| button.checked_sub(1))
+                        .ok_or_else(|| anyhow!("Invalid button: {}", button))?;
+                    let tap = match parse_keys(key)?.as_slice() {
+                        [tap] => *tap,
+                        _ => return Err(anyhow!(USAGE)),
+                    };
+                    App::new()?.set_tap_hold(button, tap, &parse_keys(modifiers)?)?;
+                    println!(
+                        "Button {} now sends {} when tapped and holds {}",
+                        button + 1,
+                        key,
+                        modifiers
+                    );
+                }
+                _ => return Err(anyhow!(USAGE)),
+            }
+            Ok(())
+        }
+        "doubletap" => {
+            let (button, keys) = match args {
+                [button, keys] => (button, keys),
+                _ => return Err(anyhow!("Usage: keyconfig doubletap BUTTON SINGLE,DOUBLE")),
+            };
+            let button = button
+                .parse::<usize>()
+                .ok()
+                .and_then(|button| button.checked_sub(1))
+                .ok_or_else(|| anyhow!("Invalid button: {}", button))?;
+            let (single, double) = match parse_keys(keys)?.as_slice() {
+                [single, double] => (*single, *double),
+                _ => return Err(anyhow!("Usage: keyconfig doubletap BUTTON SINGLE,DOUBLE")),
+            };
+            App::new()?.set_double_tap(button, single, double)?;
+            println!(
+                "Button {} now sends {} when tapped once and {} when tapped twice",
+                button + 1,
+                single.as_ref(),
+                double.as_ref()
+            );
+            Ok(())
+        }
+        "sticky" => {
+            let enabled = match arg.as_deref() {
+                Some("on") => true,
+                Some("off") => false,
+                _ => return Err(anyhow!("Usage: keyconfig sticky on|off [TENTHS]")),
+            };
+            // In 100 ms units, as stored in the device
+            let timeout = match args.get(1) {
+                Some(tenths) => Some(
+                    tenths
+                        .parse::<u8>()
+                        .ok()
+                        .filter(|&tenths| tenths > 0)
+                        .ok_or_else(|| anyhow!("Invalid timeout: {}, use 1 to 255", tenths))?,
+                ),
+                None => None,
+            };
+            App::new()?.set_sticky_shift(enabled, timeout)?;
+            println!(
+                "Sticky shift {}",
+                if enabled { "enabled" } else { "disabled" }
+            );
+            Ok(())
+        }
+        "debounce" => {
+            let ms = match args {
+                [ms] => ms,
+                _ => return Err(anyhow!("Usage: keyconfig debounce MS")),
+            };
+            let mut app = App::new()?;
+            let info = app.debounce_info()?;
+            let period = 1000 / info.scan_hz;
+            let window = ms
+                .parse::<u16>()
+                .ok()
+                .and_then(|ms| info.samples_for(ms))
+                .ok_or_else(|| {
+                    anyhow!(
+                        "Invalid time: {} ms, use multiples of {} from {} to {}",
+                        ms,
+                        period,
+                        period,
+                        period * u16::from(MAX_WINDOW)
+                    )
+                })?;
+            app.set_debounce(window as u8)?;
+            println!("Presses and releases are now confirmed after {} ms", ms);
+            Ok(())
+        }
+        "repeat" => {
+            let usage = || anyhow!("Usage: keyconfig repeat on [INITIAL FLOOR STEP] | off");
+            let mut config = RepeatConfig::new();
+            match args {
+                [off] if off == "off" => {}
+                [on, times @ ..] if on == "on" => {
+                    config.enabled = true;
+                    match times {
+                        [] => {}
+                        [initial, floor, step] => {
+                            config.initial = parse_tens_of_ms(initial, 1)?;
+                            config.floor = parse_tens_of_ms(floor, 1)?;
+                            config.step = parse_tens_of_ms(step, 0)?;
+                        }
+                        _ => return Err(usage()),
+                    }
+                }
+                _ => return Err(usage()),
+            }
+            App::new()?.set_repeat(&config)?;
+            if config.enabled {
+                println!(
+                    "Auto-repeat after {} ms, speeding up by {} ms down to {} ms",
+                    config.initial as u32 * 10,
+                    config.step as u32 * 10,
+                    config.floor as u32 * 10
+                );
+            } else {
+                println!("Auto-repeat disabled");
+            }
+            Ok(())
+        }
+        "usb" => {
+            let usage = || anyhow!("Usage: keyconfig usb MA [self-powered] [remote-wakeup]");
+            let (ma, flags) = args.split_first().ok_or_else(usage)?;
+            let mut attributes = UsbAttributes::new();
+            attributes.max_power = ma
+                .parse::<u16>()
+                .ok()
+                .filter(|ma| ma % 2 == 0)
+                .and_then(|ma| u8::try_from(ma / 2).ok())
+                .filter(|&units| units > 0 && units <= UsbAttributes::MAX_POWER_LIMIT)
+                .ok_or_else(|| anyhow!("Invalid current: {} mA, use even values up to 500", ma))?;
+            for flag in flags {
+                match flag.as_str() {
+                    "self-powered" => attributes.self_powered = true,
+                    "remote-wakeup" => attributes.remote_wakeup = true,
+                    _ => return Err(usage()),
+                }
+            }
+            App::new()?.set_usb_attributes(&attributes)?;
+            println!(
+                "USB attributes saved, reboot the device to apply them: keyconfig reboot"
+            );
+            Ok(())
+        }
+        "name" => {
+            let mut app = App::new()?;
+            match arg {
+                Some(name) => {
+                    app.set_name(&name)?;
+                    println!("Name saved");
+                }
+                None => println!("{}", app.name()?),
+            }
+            Ok(())
+        }
+        "devices" => {
+            for app in App::all()? {
+                let serial = app.serial_number().unwrap_or_else(|| String::from("?"));
+                // Older firmware has no name
+                let name = app.name().unwrap_or_default();
+                println!("{}\t{}", serial, name);
+            }
+            Ok(())
+        }
+        "set" => {
+            const USAGE: &str = "Usage: keyconfig set BUTTON KEY";
+            let (button, key) = match args {
+                [button, key] => (button, key),
+                _ => return Err(anyhow!(USAGE)),
+            };
+            let button = button
+                .parse::<usize>()
+                .ok()
+                .and_then(|button| button.checked_sub(1))
+                .ok_or_else(|| anyhow!("Invalid button: {}", button))?;
+            let key = match parse_keys(key)?[..] {
+                [key] => key,
+                _ => return Err(anyhow!(USAGE)),
+            };
+            App::new()?.set_key(button, key)?;
+            println!("Button {} now sends {:?}, not saved yet", button + 1, key);
+            Ok(())
+        }
+        "save" => {
+            App::new()?.save_config()?;
+            println!("Configuration saved");
+            Ok(())
+        }
+        "hold" => {
+            let keys = parse_keys(arg.as_deref().unwrap_or(""))?;
+            if keys.len() != NUM_BTS {
+                return Err(anyhow!("Usage: keyconfig hold KEYS, with {} keys", NUM_BTS));
+            }
+            let mut layout = [KeyCode::No; NUM_BTS];
+            layout.copy_from_slice(&keys);
+            hold_layout(&layout)
+        }
+        "profile" => run_profile_command(args),
+        "slot" => {
+            const USAGE: &str = "Usage: keyconfig slot [SLOT | next | button BUTTON]";
+            match args {
+                [] => println!("Slot {} in use", App::new()?.profile()? + 1),
+                [slot] if slot.parse::<usize>().is_ok() => {
+                    let slot = parse_slot(slot)?;
+                    App::new()?.select_profile(slot)?;
+                    println!("Switched to slot {}", slot + 1);
+                }
+                [next] if next == "next" => {
+                    App::new()?.next_profile()?;
+                    println!("Switched to the next slot");
+                }
+                [button, index] if button == "button" => {
+                    let button = index
+                        .parse::<usize>()
+                        .ok()
+                        .and_then(|button| button.checked_sub(1))
+                        .ok_or_else(|| anyhow!("Invalid button: {}", index))?;
+                    App::new()?.set_profile_button(button)?;
+                    println!("Button {} now switches to the next slot", button + 1);
+                }
+                _ => return Err(anyhow!(USAGE)),
+            }
+            Ok(())
+        }
+        "page" => {
+            let app = App::new()?;
+            let page = app.dump_page()?;
+            let reported = app.config_index().ok().map(usize::from);
+            pager::show(&storage::annotate(&page, reported))
+        }
+        "watch" => watch_layers(),
+        "show" => {
+            let matrix = App::new()?.read_config()?;
+            for (button, action) in matrix.layout().iter().enumerate() {
+                println!("Button {}: {:?}", button + 1, action);
+            }
+            Ok(())
+        }
+        _ => Err(anyhow!(
+            "Unknown command: {}\nUsage: keyconfig [diagnostics [FILE] | export [FILE] | import FILE | reboot | usart-bootloader | compact | startup [KEYS] | \
+             compose BUTTON CHAR [compose|deadkeys] | text BUTTON TEXT | modifiers BUTTON KEYS | shortcut BUTTON KEYS | combo SLOT BUTTONS KEY|off | media BUTTON KEY | mouse BUTTON CLICK|move DIR | mouse speed SPEED | quiet on|off | \
+             encoder BUTTON CW CCW|off | layer BUTTON KEYS|off | taphold ... | doubletap BUTTON KEYS | capsword BUTTON | sticky on|off [TENTHS] | debounce MS | repeat on|off ... | usb MA ... | name [NAME] | devices | set BUTTON KEY | save | hold KEYS | profile ... | slot [SLOT | next | button BUTTON] | watch | show | page]",
+            command
+        )),
+    }
+}
+
+/// Applies `layout` without saving and waits for the user to test it, then either saves it or
+/// reboots the device to go back to the stored layout.
+fn hold_layout(layout: &[KeyCode; NUM_BTS]) -> Result<()> {
+    let mut app = App::new()?;
+    app.set_layout(layout)?;
+    println!("Layout applied but not saved, try the buttons.");
+
+    let stdin = io::stdin();
+    loop {
+        print!("Keep it? [s]ave / [r]evert: ");
+        io::stdout().flush()?;
+        let mut answer = String::new();
+        // End of input counts as revert, so nothing is persisted by accident
+        if stdin.lock().read_line(&mut answer)? == 0 {
+            answer = String::from("r");
+        }
+        match answer.trim() {
+            "s" | "save" => {
+                app.save_config()?;
+                println!("Layout saved");
+                return Ok(());
+            }
+            "r" | "revert" => {
+                app.reboot()?;
+                println!("{}", REBOOT_MESSAGE);
+                return Ok(());
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Prints the layer state every time it changes, until the tool is interrupted.
+fn watch_layers() -> Result<()> {
+    let app = App::new()?;
+    println!("Watching the layer state, press Ctrl-C to stop");
+    let mut last = None;
+    loop {
+        let state = app.layer_state()?;
+        if last != Some(state) {
+            let layer = if state.shift_active { "shift" } else { "base" };
+            println!(
+                "Layer: {}, latched modifiers: {:#04x}",
+                layer, state.latched_modifiers
+            );
+            last = Some(state);
+        }
+        thread::sleep(WATCH_INTERVAL);
+    }
+}
+
+/// Manages the profiles on disk, see `profiles`.
+fn run_profile_command(args: &[String]) -> Result<()> {
+    let profiles = Profiles::open()?;
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    match args.as_slice() {
+        ["list"] => {
+            for name in profiles.list()? {
+                println!("{}", name);
+            }
+        }
+        ["create", name, keys] => {
+            profiles.create(name, &parse_keys(keys)?)?;
+            println!("Profile {} created", name);
+        }
+        ["rename", old, new] => {
+            profiles.rename(old, new)?;
+            println!("Profile {} renamed to {}", old, new);
+        }
+        ["delete", name] => {
+            profiles.delete(name)?;
+            println!("Profile {} deleted", name);
+        }
+        ["apply", name] => {
+            let layout = profiles.load(name)?;
+            App::new()?.apply_layout(&layout)?;
+            println!("Profile {} applied and saved to the device", name);
+        }
+        ["apply", name, slot] => {
+            let layout = profiles.load(name)?;
+            let slot = parse_slot(slot)?;
+            let mut app = App::new()?;
+            // Switching first, so the save goes to that slot
+            app.select_profile(slot)?;
+            app.apply_layout(&layout)?;
+            println!("Profile {} applied and saved to slot {}", name, slot + 1);
+        }
+        _ => {
+            return Err(anyhow!(
+            "Usage: keyconfig profile [list | create NAME KEYS | rename OLD NEW | delete NAME | \
+                 apply NAME [SLOT]]"
+        ))
+        }
+    }
+    Ok(())
+}
+
+/// Parses a slot of the device, as shown to the user, to a profile index starting at 0.
+fn parse_slot(slot: &str) -> Result<usize> {
+    slot.parse::<usize>()
+        .ok()
+        .and_then(|slot| slot.checked_sub(1))
+        .filter(|&slot| slot < NUM_PROFILES)
+        .ok_or_else(|| anyhow!("Invalid slot: {}, use 1 to {}", slot, NUM_PROFILES))
+}
+
+/// Parses a time in milliseconds to the 10 ms units stored in the device, `min` is in those units.
+fn parse_tens_of_ms(ms: &str, min: u8) -> Result<u8> {
+    ms.parse::<u16>()
+        .ok()
+        .filter(|ms| ms % 10 == 0)
+        .and_then(|ms| u8::try_from(ms / 10).ok())
+        .filter(|&units| units >= min)
+        .ok_or_else(|| {
+            anyhow!(
+                "Invalid time: {} ms, use multiples of 10 from {} to 2550",
+                ms,
+                min as u32 * 10
+            )
+        })
+}
+
+/// Parses a single key name or, if there's no key with that name, a media key.
+fn parse_action(name: &str) -> Result<Action> {
+    match parse_keys(name) {
+        Ok(keys) if keys.len() == 1 => Ok(Action::Key(keys[0])),
+        _ => ConsumerCode::iter()
+            .find(|code| code.as_ref() == name.to_lowercase())
+            .map(Action::from)
+            .ok_or_else(|| anyhow!("Unknown key or media key: {}", name)),
+    }
+}
+
+/// Parses a comma separated list of key names, as shown in the key selection screen.
+fn parse_keys(list: &str) -> Result<Vec<KeyCode>> {
+    list.split(',')
+        .filter(|name| !name.is_empty())
+        .map(|name| {
+            // "none" disables a button, as listed in the key search
+            KeyCode::iter()
+                .find(|key| key.as_ref() == name.to_lowercase())
+                .or_else(|| Some(KeyCode::No).filter(|_| name.eq_ignore_ascii_case("none")))
+                .ok_or_else(|| anyhow!("Unknown key: {}", name))
+        })
+        .collect()
+}
@@ -0,0 +1,1662 @@
+use crate::{
+    action::Action,
+    combo::{Combo, ComboFilter, MAX_COMBOS},
+    consumer::ConsumerReport,
+    encoder::{DetentQueue, Direction, QuadratureDecoder},
+    key_code::{KbHidReport, KeyCode},
+    mouse::{MouseReport, DEFAULT_MOUSE_SPEED, MAX_MOUSE_SPEED},
+    packets::AppCommand,
+    sequence::Sequence,
+    NUM_BTS, SCAN_HZ,
+};
+use core::convert::TryFrom;
+use static_assertions::const_assert;
+
+/// Number of sequences stored in a matrix, one for each button.
+pub const NUM_SEQUENCES: usize = NUM_BTS;
+
+/// Advisory problem found by `Matrix::validate`, buttons are indexes starting at 0.
+#[cfg(feature = "host")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Issue {
+    /// The button sends an error code instead of a key.
+    ReservedCode(usize),
+    /// Both buttons send the same action.
+    Duplicate(usize, usize),
+    /// Pressing the button doesn't send anything.
+    NoOp(usize),
+    /// The shift button is also an encoder channel, so it never shifts anything.
+    ShiftOnEncoder(usize),
+    /// The shift button doesn't add any modifier.
+    EmptyShift,
+    /// The layer button is also the shift button or an encoder channel, so the layer is never
+    /// selected on its own.
+    LayerConflict(usize),
+}
+
+/// Keys of a new matrix, the letters in order from the first button, so any number of buttons
+/// gets a distinct key.
+const DEFAULT_KEYS: [KeyCode; 26] = [
+    KeyCode::A,
+    KeyCode::B,
+    KeyCode::C,
+    KeyCode::D,
+    KeyCode::E,
+    KeyCode::F,
+    KeyCode::G,
+    KeyCode::H,
+    KeyCode::I,
+    KeyCode::J,
+    KeyCode::K,
+    KeyCode::L,
+    KeyCode::M,
+    KeyCode::N,
+    KeyCode::O,
+    KeyCode::P,
+    KeyCode::Q,
+    KeyCode::R,
+    KeyCode::S,
+    KeyCode::T,
+    KeyCode::U,
+    KeyCode::V,
+    KeyCode::W,
+    KeyCode::X,
+    KeyCode::Y,
+    KeyCode::Z,
+];
+const_assert!(NUM_BTS <= DEFAULT_KEYS.len());
+
+/// Default time a latched shift waits for a key, in 100 ms units.
+pub const DEFAULT_STICKY_TIMEOUT: u8 = 20;
+const SCANS_PER_STICKY_UNIT: u16 = (SCAN_HZ / 10) as u16;
+/// Default time a dual-role button has to be held to act as its modifiers, in 10 ms units.
+pub const DEFAULT_TAP_HOLD_THRESHOLD: u8 = 20;
+const SCANS_PER_TAP_HOLD_UNIT: u32 = SCAN_HZ / 100;
+/// Caps word ends after this long without any key, ~5 s.
+const CAPS_WORD_SCANS: u32 = 5 * SCAN_HZ;
+/// How long a tap is reported, ~20 ms. Longer than the 10 ms polling interval, so the tap isn't
+/// dropped while the previous report is still in flight. Anything else reported for a moment, like
+/// an encoder detent, lasts as long.
+pub(crate) const TAP_SCANS: u8 = (SCAN_HZ / 50) as u8;
+
+/// Sticky shift state, see `Matrix::sticky_shift`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Latch {
+    Idle,
+    /// Waiting for a key, with the scans left before giving up.
+    Latched(u16),
+    /// Applied to the keys pressed within the window, until they are all released.
+    Consumed,
+}
+
+/// One-shot modifier state, see `Action::OneShot`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum OneShot {
+    Idle,
+    /// Waiting for a key, for as long as it takes.
+    Pending(u8),
+    /// Applied to the keys of the last report, until they are all released.
+    Applied(u8),
+}
+
+/// Progress of an `Action::DoubleTap` button.
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum TapDance {
+    Idle,
+    /// First press, since the given scan.
+    First(u32),
+    /// Released on the given scan, waiting for a second press.
+    Released(u32),
+    /// Held past the window, sends the single tap key until released.
+    Single,
+    /// Second press, sends the double-tap key until released.
+    Double,
+}
+
+/// Snapshot of the shift layer, answered to a `GetLayerState` query on the control interface.
+///
+/// Report format: byte 0 is the active layer, 0 for the base layer and 1 while the shift button is
+/// held, byte 1 has the modifier bits latched by sticky shift, 0 if nothing is latched.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub struct LayerState {
+    pub shift_active: bool,
+    pub latched_modifiers: u8,
+}
+
+impl LayerState {
+    pub const SIZE: usize = 2;
+
+    pub fn to_bytes(self) -> [u8; Self::SIZE] {
+        [self.shift_active as u8, self.latched_modifiers]
+    }
+
+    pub fn from_bytes(bytes: [u8; Self::SIZE]) -> Self {
+        Self {
+            shift_active: bytes[0] != 0,
+            latched_modifiers: bytes[1],
+        }
+    }
+}
+
+/// Stored in place of a button index that isn't set.
+const NO_BUTTON: u8 = 0xFF;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Matrix {
+    layout: [Action; NUM_BTS],
+    /// Actions sent instead of `layout` while `layer_button` is held.
+    fn_layout: [Action; NUM_BTS],
+    /// Button that selects `fn_layout` while held, it doesn't send anything itself.
+    layer_button: Option<usize>,
+    /// Button wired to channel A of an encoder, channel B is wired to the next button. An encoder
+    /// push switch is just another button and keeps its regular mapping.
+    encoder: Option<usize>,
+    /// Actions for a clockwise and a counter-clockwise detent.
+    encoder_actions: [Action; 2],
+    decoder: QuadratureDecoder,
+    detents: DetentQueue,
+    /// Button that, while held, adds `shift_modifiers` to the keys sent by the other buttons
+    /// instead of sending its own action.
+    shift_button: Option<usize>,
+    shift_modifiers: u8,
+    /// Tapping the shift button alone latches the modifiers for the next key, like sticky keys.
+    sticky_shift: bool,
+    /// Time a latched shift waits for a key before clearing, in 100 ms units.
+    sticky_timeout: u8,
+    /// Another key was pressed since the shift button went down, so it wasn't a tap.
+    shift_used: bool,
+    latch: Latch,
+    /// Modifiers sent by an `Action::TapHold` button while held.
+    hold_modifiers: [u8; NUM_BTS],
+    /// Time a dual-role button has to be held to act as its modifiers, in 10 ms units. Also the
+    /// time a double-tap button waits for the second tap.
+    tap_hold_threshold: u8,
+    /// Key sent by an `Action::DoubleTap` button when tapped twice.
+    double_tap: [KeyCode; NUM_BTS],
+    tap_dance: [TapDance; NUM_BTS],
+    /// Distance moved by an `Action::MouseMove` button on every scan, up to `MAX_MOUSE_SPEED`.
+    mouse_speed: u8,
+    /// Modifiers sent together with the key of each `Action::Key` button of `layout`, e.g. for
+    /// Ctrl+C. A plain `AppCommand::SetKey` clears them.
+    key_modifiers: [u8; NUM_BTS],
+    /// Buttons that send another action when pressed together.
+    combos: [Combo; MAX_COMBOS],
+    combo_filter: ComboFilter,
+    /// Scans since power up, wrapping.
+    ticks: u32,
+    /// Scan on which each dual-role button went down, `None` while released.
+    press_start: [Option<u32>; NUM_BTS],
+    /// Scans left to report the tap key of each dual-role button.
+    tapping: [u8; NUM_BTS],
+    /// Key latched by each toggle button, reported until the button is pressed again.
+    latched: [Option<KeyCode>; NUM_BTS],
+    one_shot: OneShot,
+    /// Letters are shifted until a key that isn't part of a word, see `update_caps_word`.
+    caps_word: bool,
+    /// Scan on which caps word was turned on or last saw a key.
+    caps_word_since: u32,
+    /// Consumer usages of the last `update`.
+    consumer: ConsumerReport,
+    /// Mouse buttons of the last `update`.
+    mouse: MouseReport,
+    /// Played by `Action::Sequence` buttons.
+    sequences: [Sequence; NUM_SEQUENCES],
+    /// Sequence changed by `AppCommand::PushSequenceKey`, set by `AppCommand::ClearSequence`.
+    editing: usize,
+    /// Button state on the previous scan, sequences are only triggered by a new press.
+    previous: [bool; NUM_BTS],
+    triggered: Option<usize>,
+    /// An `Action::NextProfile` button was pressed since the last `take_profile_switch`.
+    switch_profile: bool,
+}
+
+impl Matrix {
+    /// Number of bytes used to store a matrix.
+    pub const SIZE: usize = 2 * NUM_BTS * Action::SIZE
+        + 1
+        + 2 * Action::SIZE
+        + 4
+        + 1
+        + NUM_BTS
+        + 1
+        + 1
+        + NUM_BTS
+        + NUM_BTS
+        + MAX_COMBOS * Combo::SIZE
+        + NUM_SEQUENCES * Sequence::SIZE;
+
+    pub const fn new() -> Self {
+        let mut layout = [Action::Key(KeyCode::No); NUM_BTS];
+        let mut button = 0;
+        while button < NUM_BTS {
+            layout[button] = Action::Key(DEFAULT_KEYS[button]);
+            button += 1;
+        }
+        Self {
+            layout,
+            // Same as the base layer, so setting a layer button alone doesn't change any key
+            fn_layout: layout,
+            layer_button: None,
+            encoder: None,
+            encoder_actions: [Action::Key(KeyCode::VolUp), Action::Key(KeyCode::VolDown)],
+            decoder: QuadratureDecoder::new(),
+            detents: DetentQueue::new(),
+            shift_button: None,
+            shift_modifiers: 0,
+            sticky_shift: false,
+            sticky_timeout: DEFAULT_STICKY_TIMEOUT,
+            shift_used: false,
+            latch: Latch::Idle,
+            hold_modifiers: [0; NUM_BTS],
+            double_tap: [KeyCode::No; NUM_BTS],
+            tap_dance: [TapDance::Idle; NUM_BTS],
+            tap_hold_threshold: DEFAULT_TAP_HOLD_THRESHOLD,
+            mouse_speed: DEFAULT_MOUSE_SPEED,
+            key_modifiers: [0; NUM_BTS],
+            combos: [Combo::new(); MAX_COMBOS],
+            combo_filter: ComboFilter::new(),
+            ticks: 0,
+            press_start: [None; NUM_BTS],
+            tapping: [0; NUM_BTS],
+            latched: [None; NUM_BTS],
+            one_shot: OneShot::Idle,
+            caps_word: false,
+            caps_word_since: 0,
+            consumer: ConsumerReport::new(),
+            mouse: MouseReport::new(),
+            sequences: [Sequence::new(); NUM_SEQUENCES],
+            editing: 0,
+            previous: [false; NUM_BTS],
+            triggered: None,
+            switch_profile: false,
+        }
+    }
+
+    /// Applies `command` to the layout, commands that don't change the layout are ignored.
+    pub fn update_layout(&mut self, command: AppCommand) {
+        match command {
+            AppCommand::SetKey(idx, value, modifiers) => {
+                self.layout[idx] = value.into();
+                self.key_modifiers[idx] = modifiers;
+            }
+            AppCommand::SetAll(keys) => {
+                for (idx, &key) in keys.iter().enumerate() {
+                    self.layout[idx] = key.into();
+                }
+                self.key_modifiers = [0; NUM_BTS];
+            }
+            AppCommand::SetModifiers(idx, bits) => self.layout[idx] = Action::Modifiers(bits),
+            AppCommand::SetEncoder(first) => {
+                self.encoder = first;
+                self.decoder = QuadratureDecoder::new();
+                self.detents = DetentQueue::new();
+            }
+            AppCommand::SetClockwise(value) => self.encoder_actions[0] = value.into(),
+            AppCommand::SetCounterClockwise(value) => self.encoder_actions[1] = value.into(),
+            AppCommand::SetClockwiseConsumer(usage) => {
+                self.encoder_actions[0] = Action::Consumer(usage)
+            }
+            AppCommand::SetCounterClockwiseConsumer(usage) => {
+                self.encoder_actions[1] = Action::Consumer(usage)
+            }
+            AppCommand::SetShiftButton(button) => self.shift_button = button,
+            AppCommand::SetShiftModifiers(modifiers) => self.shift_modifiers = modifiers,
+            AppCommand::SetStickyShift(enabled) => {
+                self.sticky_shift = enabled;
+                self.latch = Latch::Idle;
+            }
+            AppCommand::SetStickyTimeout(timeout) => self.sticky_timeout = timeout,
+            AppCommand::SetLayerButton(button) => self.layer_button = button,
+            AppCommand::SetLayer(idx, value) => self.fn_layout[idx] = value.into(),
+            AppCommand::SetTapHold(idx, key) => self.layout[idx] = Action::TapHold(key),
+            AppCommand::SetHoldModifiers(idx, bits) => self.hold_modifiers[idx] = bits,
+            AppCommand::SetTapHoldThreshold(threshold) => self.tap_hold_threshold = threshold,
+            AppCommand::SetToggle(idx, key) => self.layout[idx] = Action::Toggle(key),
+            AppCommand::SetOneShot(idx, bits) => self.layout[idx] = Action::OneShot(bits),
+            AppCommand::SetConsumer(idx, usage) => self.layout[idx] = Action::Consumer(usage),
+            AppCommand::SetMouse(idx, button) => self.layout[idx] = Action::Mouse(button),
+            AppCommand::SetMouseMove(idx, direction) => {
+                self.layout[idx] = Action::MouseMove(direction)
+            }
+            AppCommand::SetMouseSpeed(speed) => self.mouse_speed = speed,
+            AppCommand::SetKeyModifiers(idx, bits) => self.key_modifiers[idx] = bits,
+            AppCommand::SetDoubleTap(idx, key) => self.layout[idx] = Action::DoubleTap(key),
+            AppCommand::SetDoubleTapKey(idx, key) => self.double_tap[idx] = key,
+            AppCommand::SetComboButtons(slot, buttons) => self.combos[slot].buttons = buttons,
+            AppCommand::SetCombo(slot, value) => self.combos[slot].action = value.into(),
+            AppCommand::SetSequenceButton(button) => {
+                self.layout[button] = Action::Sequence(button as u8)
+            }
+            AppCommand::SetProfileButton(button) => self.layout[button] = Action::NextProfile,
+            AppCommand::SetCapsWordButton(button) => self.layout[button] = Action::CapsWord,
+            AppCommand::ClearSequence(slot) => {
+                self.sequences[slot].clear();
+                self.editing = slot;
+            }
+            AppCommand::PushSequenceKey(key) => {
+                // Keys past the limit are dropped, the host tool checks the length beforehand
+                self.sequences[self.editing].push(key);
+            }
+            _ => {}
+        };
+    }
+
+    /// Replaces the whole layout with `new`, returning the previous one. Runtime state, like a
+    /// half decoded encoder detent, isn't carried over since it belongs to the old layout, the
+    /// button state is kept so held buttons don't trigger a sequence again.
+    ///
+    /// It's a plain move, not a double buffer, so it doesn't protect against a concurrent `update`.
+    /// The caller has to replace it from the same context that calls `update`, the firmware does
+    /// it by only touching the matrix from tasks of the same priority as the scan task, those never
+    /// preempt each other.
+    pub fn replace(&mut self, new: Self) -> Self {
+        let mut new = new;
+        new.decoder = QuadratureDecoder::new();
+        new.detents = DetentQueue::new();
+        new.previous = self.previous;
+        new.triggered = None;
+        new.switch_profile = false;
+        new.shift_used = false;
+        new.latch = Latch::Idle;
+        new.ticks = self.ticks;
+        new.press_start = [None; NUM_BTS];
+        new.tapping = [0; NUM_BTS];
+        new.latched = [None; NUM_BTS];
+        new.one_shot = OneShot::Idle;
+        new.caps_word = false;
+        new.tap_dance = [TapDance::Idle; NUM_BTS];
+        new.combo_filter = ComboFilter::new();
+        core::mem::replace(self, new)
+    }
+
+    /// Decodes the encoder channels from the instantaneous state of the buttons, without
+    /// debouncing, since the decoder already ignores bouncing. Can be called more often than
+    /// `update`, e.g. on every edge of the channels, so detents closer than a scan aren't lost.
+    pub fn sample_encoder(&mut self, raw: &[bool; NUM_BTS]) {
+        if let Some(first) = self.encoder {
+            if let Some(direction) = self.decoder.update(raw[first], raw[first + 1]) {
+                self.detents.push(direction);
+            }
+        }
+    }
+
+    /// Builds a report from the state of the buttons, `true` means pressed. This should be called
+    /// on every scan, encoder detents sampled by `sample_encoder` are reported one at a time as a
+    /// short press.
+    pub fn update(&mut self, pressed: &[bool; NUM_BTS]) -> KbHidReport {
+        // Presses that may still become a combo are held back
+        let (pressed, combo) = self.combo_filter.update(&self.combos, pressed);
+        let pressed = &pressed;
+        let mut report = KbHidReport::new();
+        let mut consumer = ConsumerReport::new();
+        let mut mouse = MouseReport::new();
+        let mut any_pressed = false;
+        // The Fn layer has no modifiers of its own
+        let (layout, key_modifiers) = match self.layer_button {
+            Some(layer) if pressed[layer] => (self.fn_layout, [0; NUM_BTS]),
+            _ => (self.layout, self.key_modifiers),
+        };
+        self.ticks = self.ticks.wrapping_add(1);
+
+        for (idx, (&action, &pressed)) in layout.iter().zip(pressed.iter()).enumerate() {
+            let encoder_channel = self
+                .encoder
+                .is_some_and(|first| idx == first || idx == first + 1);
+            let regular = !encoder_channel
+                && self.shift_button != Some(idx)
+                && self.layer_button != Some(idx);
+            match action {
+                Action::TapHold(code) if regular => {
+                    self.tap_dance[idx] = TapDance::Idle;
+                    any_pressed |= self.update_tap_hold(&mut report, idx, code, pressed);
+                    continue;
+                }
+                Action::DoubleTap(code) if regular => {
+                    self.press_start[idx] = None;
+                    any_pressed |= self.update_double_tap(&mut report, idx, code, pressed);
+                    continue;
+                }
+                _ => {
+                    self.press_start[idx] = None;
+                    self.tapping[idx] = 0;
+                    self.tap_dance[idx] = TapDance::Idle;
+                }
+            }
+            if pressed && regular {
+                if !self.previous[idx] {
+                    // A second press only unlatches, even if the button changed since
+                    if self.latched[idx].take().is_none() {
+                        if let Action::Toggle(code) = action {
+                            self.latched[idx] = Some(code);
+                        }
+                    }
+                    match action {
+                        Action::Sequence(slot) => self.triggered = Some(slot as usize),
+                        Action::NextProfile => self.switch_profile = true,
+                        Action::CapsWord => {
+                            // A second press turns it off early
+                            self.caps_word = !self.caps_word;
+                            self.caps_word_since = self.ticks;
+                        }
+                        Action::OneShot(bits) => {
+                            // Tapping several one-shot buttons combines them
+                            self.one_shot = match self.one_shot {
+                                OneShot::Pending(pending) => OneShot::Pending(pending | bits),
+                                _ => OneShot::Pending(bits),
+                            };
+                        }
+                        _ => {}
+                    }
+                }
+                // A disabled button isn't a key for the shift button either
+                if action.is_none() {
+                    continue;
+                }
+                if let Action::Key(_) = action {
+                    report.add_modifiers(key_modifiers[idx]);
+                }
+                self.press(&mut report, &mut consumer, &mut mouse, action);
+                any_pressed = true;
+            }
+        }
+        if let Some(action) = combo {
+            self.press(&mut report, &mut consumer, &mut mouse, action);
+            any_pressed = true;
+        }
+
+        // Only keys from the buttons consume a one-shot, not latched keys or encoder detents
+        let keys = report.has_keys();
+        self.one_shot = match self.one_shot {
+            OneShot::Pending(bits) | OneShot::Applied(bits) if keys => {
+                report.add_modifiers(bits);
+                OneShot::Applied(bits)
+            }
+            OneShot::Applied(_) => OneShot::Idle,
+            one_shot => one_shot,
+        };
+
+        // Reported whether or not any button is down
+        for &code in self.latched.iter().flatten() {
+            report.pressed(code);
+        }
+
+        if let Some(shift) = self.shift_button {
+            self.update_shift(
+                &mut report,
+                pressed[shift],
+                self.previous[shift],
+                any_pressed,
+            );
+        }
+        self.previous = *pressed;
+
+        if self.encoder.is_some() {
+            match self.detents.poll() {
+                Some(Direction::Clockwise) => self.press(
+                    &mut report,
+                    &mut consumer,
+                    &mut mouse,
+                    self.encoder_actions[0],
+                ),
+                Some(Direction::CounterClockwise) => self.press(
+                    &mut report,
+                    &mut consumer,
+                    &mut mouse,
+                    self.encoder_actions[1],
+                ),
+                None => {}
+            }
+        }
+        self.update_caps_word(&mut report);
+        self.consumer = consumer;
+        // Built from scratch every scan, so released buttons are sent as a zeroed report
+        self.mouse = mouse;
+        report
+    }
+
+    /// Consumer usages of the buttons as of the last `update`, sent on their own interface.
+    pub fn consumer_report(&self) -> ConsumerReport {
+        self.consumer
+    }
+
+    /// Mouse buttons as of the last `update`, sent on their own interface.
+    pub fn mouse_report(&self) -> MouseReport {
+        self.mouse
+    }
+
+    /// Action of every button, without the second layer.
+    pub fn layout(&self) -> [Action; NUM_BTS] {
+        self.layout
+    }
+
+    /// Modifiers sent together with the key of every `Action::Key` button.
+    pub fn key_modifiers(&self) -> [u8; NUM_BTS] {
+        self.key_modifiers
+    }
+
+    /// First of the two buttons used as the channels of the encoder, if there's one.
+    pub fn encoder(&self) -> Option<usize> {
+        self.encoder
+    }
+
+    /// State of the shift layer as of the last `update`.
+    pub fn layer_state(&self) -> LayerState {
+        let latched_modifiers = match self.latch {
+            Latch::Idle => 0,
+            Latch::Latched(_) | Latch::Consumed => self.shift_modifiers,
+        };
+        LayerState {
+            shift_active: self.shift_button.is_some_and(|shift| self.previous[shift]),
+            latched_modifiers,
+        }
+    }
+
+    /// Adds the tap key or the hold modifiers of a dual-role button to `report`, returns `true` if
+    /// it added anything. Nothing is sent until the button is either released or held past the
+    /// threshold.
+    fn update_tap_hold(
+        &mut self,
+        report: &mut KbHidReport,
+        idx: usize,
+        code: KeyCode,
+        pressed: bool,
+    ) -> bool {
+        let threshold = u32::from(self.tap_hold_threshold) * SCANS_PER_TAP_HOLD_UNIT;
+        if pressed {
+            self.tapping[idx] = 0;
+            let start = *self.press_start[idx].get_or_insert(self.ticks);
+            if self.ticks.wrapping_sub(start) < threshold {
+                return false;
+            }
+            report.add_modifiers(self.hold_modifiers[idx]);
+            return true;
+        }
+
+        if let Some(start) = self.press_start[idx].take() {
+            if self.ticks.wrapping_sub(start) < threshold {
+                self.tapping[idx] = TAP_SCANS;
+            }
+        }
+        if self.tapping[idx] == 0 {
+            return false;
+        }
+        self.tapping[idx] -= 1;
+        report.pressed(code);
+        true
+    }
+
+    /// Adds the single or the double-tap key of a double-tap button to `report`, returns `true` if
+    /// it added anything. A single tap is only sent once the window for the second one is over.
+    fn update_double_tap(
+        &mut self,
+        report: &mut KbHidReport,
+        idx: usize,
+        code: KeyCode,
+        pressed: bool,
+    ) -> bool {
+        let window = u32::from(self.tap_hold_threshold) * SCANS_PER_TAP_HOLD_UNIT;
+        let elapsed = |since: u32| self.ticks.wrapping_sub(since);
+        self.tap_dance[idx] = match (self.tap_dance[idx], pressed) {
+            (TapDance::Idle, true) => TapDance::First(self.ticks),
+            (TapDance::First(start), true) if elapsed(start) >= window => TapDance::Single,
+            (TapDance::First(_), false) => TapDance::Released(self.ticks),
+            (TapDance::Released(_), true) => {
+                self.tapping[idx] = 0;
+                TapDance::Double
+            }
+            (TapDance::Released(release), false) if elapsed(release) >= window => {
+                self.tapping[idx] = TAP_SCANS;
+                TapDance::Idle
+            }
+            (TapDance::Single, false) | (TapDance::Double, false) => TapDance::Idle,
+            (state, _) => state,
+        };
+
+        let key = match self.tap_dance[idx] {
+            TapDance::Single => code,
+            TapDance::Double => self.double_tap[idx],
+            _ if self.tapping[idx] > 0 => {
+                self.tapping[idx] -= 1;
+                code
+            }
+            _ => return false,
+        };
+        report.pressed(key);
+        true
+    }
+
+    /// Shifts letters and minus, for snake case, while caps word is on. Digits and backspace keep
+    /// it on unshifted, any other key or modifier ends it, and so does going `CAPS_WORD_SCANS`
+    /// without a key.
+    fn update_caps_word(&mut self, report: &mut KbHidReport) {
+        if !self.caps_word {
+            return;
+        }
+        let shift = KeyCode::LShift.as_modifier_bit() | KeyCode::RShift.as_modifier_bit();
+        let other_modifiers = report.modifiers() & !shift != 0;
+        let mut any = false;
+        let mut shifted = false;
+        let mut unshifted = false;
+        for key in report.keys() {
+            any = true;
+            match KeyCode::try_from(key) {
+                Ok(key) if key.is_letter() || key == KeyCode::Minus => shifted = true,
+                Ok(key) if key.is_digit() || key == KeyCode::BSpace => unshifted = true,
+                _ => {
+                    self.caps_word = false;
+                    return;
+                }
+            }
+        }
+
+        if !any {
+            if self.ticks.wrapping_sub(self.caps_word_since) >= CAPS_WORD_SCANS {
+                self.caps_word = false;
+            }
+            return;
+        }
+        if other_modifiers {
+            self.caps_word = false;
+            return;
+        }
+        self.caps_word_since = self.ticks;
+        // Shifting a digit typed along with a letter would turn it into a symbol
+        if shifted && !unshifted {
+            report.add_modifiers(KeyCode::LShift.as_modifier_bit());
+        }
+    }
+
+    fn update_shift(&mut self, report: &mut KbHidReport, held: bool, was_held: bool, keys: bool) {
+        if held && !was_held {
+            // Pressing the shift button again also cancels a latch
+            self.shift_used = false;
+            self.latch = Latch::Idle;
+        }
+        if held {
+            self.shift_used |= keys;
+            // Only add the modifiers along with another key, otherwise holding the shift button
+            // alone would act as a plain modifier key
+            if keys {
+                report.add_modifiers(self.shift_modifiers);
+            }
+            return;
+        }
+        if was_held && !self.shift_used && self.sticky_shift {
+            self.latch = Latch::Latched(self.sticky_timeout as u16 * SCANS_PER_STICKY_UNIT);
+        }
+
+        self.latch = match self.latch {
+            Latch::Idle => Latch::Idle,
+            Latch::Latched(_) | Latch::Consumed if keys => {
+                report.add_modifiers(self.shift_modifiers);
+                Latch::Consumed
+            }
+            Latch::Latched(0) | Latch::Consumed => Latch::Idle,
+            Latch::Latched(remaining) => Latch::Latched(remaining - 1),
+        };
+    }
+
+    fn press(
+        &self,
+        report: &mut KbHidReport,
+        consumer: &mut ConsumerReport,
+        mouse: &mut MouseReport,
+        action: Action,
+    ) {
+        match action {
+            Action::Key(code) => report.pressed(code),
+            Action::Consumer(usage) => consumer.pressed(usage),
+            Action::Mouse(button) => mouse.pressed(button),
+            Action::MouseMove(direction) => {
+                let (x, y) = direction.delta(self.mouse_speed as i8);
+                mouse.move_by(x, y);
+            }
+            // Played separately, see `take_sequence`
+            Action::Sequence(_) => {}
+            // Done by the firmware, see `take_profile_switch`
+            Action::NextProfile => {}
+            // Applied to the whole report, see `update_caps_word`
+            Action::CapsWord => {}
+            Action::Modifiers(bits) | Action::OneShot(bits) => report.add_modifiers(bits),
+            // Needs the button state, see `update_tap_hold` and `update_double_tap`
+            Action::TapHold(_) | Action::DoubleTap(_) => {}
+            // Reported while latched, see `update`
+            Action::Toggle(_) => {}
+        }
+    }
+
+    /// Lists everything that looks wrong with the layout, an empty list means it's sensible.
+    #[cfg(feature = "host")]
+    pub fn validate(&self) -> Vec<Issue> {
+        let mut issues = Vec::new();
+        let encoder_channel = |idx: usize| {
+            self.encoder
+                .is_some_and(|first| idx == first || idx == first + 1)
+        };
+        // Buttons whose own action is sent when pressed
+        let regular: Vec<usize> = (0..NUM_BTS)
+            .filter(|&idx| {
+                !encoder_channel(idx)
+                    && self.shift_button != Some(idx)
+                    && self.layer_button != Some(idx)
+            })
+            .collect();
+
+        for &idx in &regular {
+            match self.layout[idx] {
+                Action::Consumer(0) | Action::Modifiers(0) | Action::OneShot(0) => {
+                    issues.push(Issue::NoOp(idx))
+                }
+                Action::Key(KeyCode::ErrorRollOver)
+                | Action::Key(KeyCode::PostFail)
+                | Action::Key(KeyCode::ErrorUndefined) => issues.push(Issue::ReservedCode(idx)),
+                Action::Sequence(slot) if self.sequences[slot as usize].keys().is_empty() => {
+                    issues.push(Issue::NoOp(idx))
+                }
+                _ => {}
+            }
+        }
+        for (pos, &first) in regular.iter().enumerate() {
+            for &second in &regular[pos + 1..] {
+                // Disabled on purpose, not by mistake
+                if self.layout[first] == self.layout[second] && !self.layout[first].is_none() {
+                    issues.push(Issue::Duplicate(first, second));
+                }
+            }
+        }
+
+        if let Some(shift) = self.shift_button {
+            if encoder_channel(shift) {
+                issues.push(Issue::ShiftOnEncoder(shift));
+            }
+            if self.shift_modifiers == 0 {
+                issues.push(Issue::EmptyShift);
+            }
+        }
+        if let Some(layer) = self.layer_button {
+            if encoder_channel(layer) || self.shift_button == Some(layer) {
+                issues.push(Issue::LayerConflict(layer));
+            }
+        }
+        issues
+    }
+
+    /// Returns the sequence of the last button configured as `Action::Sequence` that was pressed
+    /// since the previous call. The caller is responsible for playing it.
+    pub fn take_sequence(&mut self) -> Option<Sequence> {
+        let slot = self.triggered.take()?;
+        Some(self.sequences[slot])
+    }
+
+    /// Returns `true` once for every press of an `Action::NextProfile` button. The caller is
+    /// responsible for loading the next profile, which replaces this matrix.
+    pub fn take_profile_switch(&mut self) -> bool {
+        core::mem::replace(&mut self.switch_profile, false)
+    }
+
+    pub fn to_bytes(self) -> [u8; Self::SIZE] {
+        let mut bytes = [0; Self::SIZE];
+        let mut writer = Writer::new(&mut bytes);
+        for &action in self.layout.iter() {
+            writer.action(action);
+        }
+
+        writer.button(self.encoder);
+        for &action in self.encoder_actions.iter() {
+            writer.action(action);
+        }
+
+        writer.button(self.shift_button);
+        writer.byte(self.shift_modifiers);
+        writer.byte(self.sticky_shift as u8);
+        writer.byte(self.sticky_timeout);
+        writer.button(self.layer_button);
+        for &action in self.fn_layout.iter() {
+            writer.action(action);
+        }
+        for &modifiers in self.hold_modifiers.iter() {
+            writer.byte(modifiers);
+        }
+        writer.byte(self.tap_hold_threshold);
+        for &code in self.double_tap.iter() {
+            writer.byte(code as u8);
+        }
+        writer.byte(self.mouse_speed);
+        for &modifiers in self.key_modifiers.iter() {
+            writer.byte(modifiers);
+        }
+        for combo in self.combos.iter() {
+            writer.byte(combo.buttons);
+            writer.action(combo.action);
+        }
+        for sequence in self.sequences.iter() {
+            writer.sequence(sequence);
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: [u8; Self::SIZE]) -> Option<Self> {
+        let mut matrix = Self::new();
+        let mut reader = Reader::new(&bytes);
+        for action in matrix.layout.iter_mut() {
+            *action = reader.action()?;
+            if let Action::Sequence(slot) = *action {
+                if slot as usize >= NUM_SEQUENCES {
+                    return None;
+                }
+            }
+        }
+
+        matrix.encoder = reader.button()?;
+        if matrix.encoder.is_some_and(|first| first + 1 >= NUM_BTS) {
+            return None;
+        }
+        for action in matrix.encoder_actions.iter_mut() {
+            *action = reader.action()?;
+        }
+
+        matrix.shift_button = reader.button()?;
+        matrix.shift_modifiers = reader.byte();
+        matrix.sticky_shift = match reader.byte() {
+            0 => false,
+            1 => true,
+            _ => return None,
+        };
+        matrix.sticky_timeout = reader.byte();
+        matrix.layer_button = reader.button()?;
+        for action in matrix.fn_layout.iter_mut() {
+            *action = reader.action()?;
+            if let Action::Sequence(slot) = *action {
+                if slot as usize >= NUM_SEQUENCES {
+                    return None;
+                }
+            }
+        }
+        for modifiers in matrix.hold_modifiers.iter_mut() {
+            *modifiers = reader.byte();
+        }
+        matrix.tap_hold_threshold = reader.byte();
+        for code in matrix.double_tap.iter_mut() {
+            *code = KeyCode::try_from(reader.byte()).ok()?;
+        }
+        matrix.mouse_speed = match reader.byte() {
+            speed @ 1..=MAX_MOUSE_SPEED => speed,
+            _ => return None,
+        };
+        for modifiers in matrix.key_modifiers.iter_mut() {
+            *modifiers = reader.byte();
+        }
+        for combo in matrix.combos.iter_mut() {
+            combo.buttons = reader.byte();
+            if !Combo::valid_buttons(combo.buttons) {
+                return None;
+            }
+            combo.action = reader.action()?;
+        }
+        for sequence in matrix.sequences.iter_mut() {
+            *sequence = reader.sequence()?;
+        }
+        Some(matrix)
+    }
+}
+
+/// Serializes fields one after the other.
+struct Writer<'a> {
+    buf: &'a mut [u8],
+    pos: usize,
+}
+
+impl<'a> Writer<'a> {
+    fn new(buf: &'a mut [u8]) -> Self {
+        Self { buf, pos: 0 }
+    }
+
+    fn byte(&mut self, value: u8) {
+        self.buf[self.pos] = value;
+        self.pos += 1;
+    }
+
+    fn action(&mut self, action: Action) {
+        for &byte in action.to_bytes().iter() {
+            self.byte(byte);
+        }
+    }
+
+    fn button(&mut self, button: Option<usize>) {
+        self.byte(button.map_or(NO_BUTTON, |idx| idx as u8));
+    }
+
+    fn sequence(&mut self, sequence: &Sequence) {
+        for &byte in sequence.to_bytes().iter() {
+            self.byte(byte);
+        }
+    }
+}
+
+/// Deserializes fields in the same order they were written by `Writer`.
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        Self { buf, pos: 0 }
+    }
+
+    fn byte(&mut self) -> u8 {
+        let value = self.buf[self.pos];
+        self.pos += 1;
+        value
+    }
+
+    fn action(&mut self) -> Option<Action> {
+        let bytes = [self.byte(), self.byte()];
+        Action::from_bytes(bytes)
+    }
+
+    /// Returns `None` for an invalid button index.
+    fn button(&mut self) -> Option<Option<usize>> {
+        match self.byte() {
+            NO_BUTTON => Some(None),
+            idx if (idx as usize) < NUM_BTS => Some(Some(idx as usize)),
+            _ => None,
+        }
+    }
+
+    fn sequence(&mut self) -> Option<Sequence> {
+        let mut bytes = [0; Sequence::SIZE];
+        for byte in bytes.iter_mut() {
+            *byte = self.byte();
+        }
+        Sequence::from_bytes(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        consumer::ConsumerCode,
+        mouse::{MouseButton, MouseDirection},
+    };
+
+    fn report(keys: &[KeyCode]) -> KbHidReport {
+        KbHidReport::from_keys(0, keys)
+    }
+
+    #[test]
+    fn size() {
+        assert_eq!(
+            Matrix::SIZE,
+            7 * NUM_BTS + 12 + 3 * MAX_COMBOS + NUM_SEQUENCES * Sequence::SIZE
+        );
+        assert_eq!(Matrix::new().to_bytes().len(), Matrix::SIZE);
+    }
+
+    #[test]
+    fn bytes_round_trip() {
+        let mut matrix = Matrix::new();
+        matrix.layout[1] = Action::Consumer(0xE9);
+        matrix.layout[2] = Action::Key(KeyCode::LShift);
+        matrix.encoder = Some(1);
+        matrix.encoder_actions[1] = Action::Consumer(0xEA);
+        matrix.shift_button = Some(0);
+        matrix.shift_modifiers = KeyCode::LCtrl.as_modifier_bit();
+        matrix.sticky_shift = true;
+        matrix.sticky_timeout = 5;
+        matrix.layout[0] = Action::Sequence(2);
+        matrix.sequences[2].push(KeyCode::Application);
+        matrix.sequences[2].push(KeyCode::E);
+        matrix.layer_button = Some(2);
+        matrix.fn_layout[1] = Action::Key(KeyCode::PgDown);
+        matrix.layout[1] = Action::TapHold(KeyCode::Escape);
+        matrix.hold_modifiers[1] = KeyCode::RCtrl.as_modifier_bit();
+        matrix.tap_hold_threshold = 30;
+        matrix.mouse_speed = 10;
+        matrix.key_modifiers = [0, 0x01, 0x0A];
+        matrix.double_tap[2] = KeyCode::Enter;
+        matrix.combos[1] = Combo {
+            buttons: 0b110,
+            action: Action::Key(KeyCode::Tab),
+        };
+        assert_eq!(Matrix::from_bytes(matrix.to_bytes()), Some(matrix));
+    }
+
+    #[test]
+    fn invalid_bytes() {
+        let mut bytes = Matrix::new().to_bytes();
+        bytes[Action::SIZE] = 0xFF;
+        assert_eq!(Matrix::from_bytes(bytes), None);
+
+        // Encoder channel B would be out of range
+        let mut bytes = Matrix::new().to_bytes();
+        bytes[NUM_BTS * Action::SIZE] = NUM_BTS as u8 - 1;
+        assert_eq!(Matrix::from_bytes(bytes), None);
+
+        // Sequence slot out of range
+        let mut matrix = Matrix::new();
+        matrix.layout[0] = Action::Sequence(NUM_SEQUENCES as u8);
+        assert_eq!(Matrix::from_bytes(matrix.to_bytes()), None);
+    }
+
+    #[test]
+    fn sequence_button() {
+        let mut matrix = Matrix::new();
+        matrix.update_layout(AppCommand::ClearSequence(1));
+        matrix.update_layout(AppCommand::PushSequenceKey(KeyCode::Quote));
+        matrix.update_layout(AppCommand::PushSequenceKey(KeyCode::E));
+        matrix.update_layout(AppCommand::SetSequenceButton(1));
+
+        // Only a new press triggers the sequence, the button doesn't send keys itself
+        assert_eq!(matrix.update(&[false, true, false]), report(&[]));
+        let sequence = matrix.take_sequence().unwrap();
+        assert_eq!(sequence.keys(), &[KeyCode::Quote, KeyCode::E]);
+        assert_eq!(matrix.update(&[false, true, false]), report(&[]));
+        assert_eq!(matrix.take_sequence(), None);
+
+        matrix.update(&[false, false, false]);
+        matrix.update(&[false, true, false]);
+        assert_eq!(matrix.take_sequence(), Some(sequence));
+    }
+
+    #[test]
+    fn caps_word() {
+        let mut matrix = Matrix::new();
+        matrix.update_layout(AppCommand::SetCapsWordButton(0));
+        matrix.update_layout(AppCommand::SetKey(2, KeyCode::Kb1, 0));
+        let shift = KeyCode::LShift.as_modifier_bit();
+
+        assert_eq!(matrix.update(&[true, false, false]), report(&[]));
+        matrix.update(&[false, false, false]);
+        assert_eq!(
+            matrix.update(&[false, true, false]),
+            KbHidReport::from_keys(shift, &[KeyCode::B])
+        );
+        // Digits don't end it, and aren't shifted
+        assert_eq!(
+            matrix.update(&[false, false, true]),
+            report(&[KeyCode::Kb1])
+        );
+        assert_eq!(matrix.update(&[false, true, false]).modifiers(), shift);
+
+        // Any other key ends it
+        matrix.update_layout(AppCommand::SetKey(2, KeyCode::Space, 0));
+        assert_eq!(
+            matrix.update(&[false, false, true]),
+            report(&[KeyCode::Space])
+        );
+        assert_eq!(matrix.update(&[false, true, false]), report(&[KeyCode::B]));
+
+        // And so does being idle
+        matrix.update(&[true, false, false]);
+        for _ in 0..CAPS_WORD_SCANS {
+            matrix.update(&[false, false, false]);
+        }
+        assert_eq!(matrix.update(&[false, true, false]), report(&[KeyCode::B]));
+
+        // Pressing the button again turns it off early
+        matrix.update(&[true, false, false]);
+        matrix.update(&[false, false, false]);
+        matrix.update(&[true, false, false]);
+        matrix.update(&[false, false, false]);
+        assert_eq!(matrix.update(&[false, true, false]), report(&[KeyCode::B]));
+    }
+
+    #[test]
+    fn profile_button() {
+        let mut matrix = Matrix::new();
+        matrix.update_layout(AppCommand::SetProfileButton(2));
+
+        // Only a new press switches, the button doesn't send keys itself
+        assert_eq!(matrix.update(&[false, false, true]), report(&[]));
+        assert!(matrix.take_profile_switch());
+        assert_eq!(matrix.update(&[true, false, true]), report(&[KeyCode::A]));
+        assert!(!matrix.take_profile_switch());
+
+        // The switch isn't carried over to the profile that replaces the matrix
+        matrix.update(&[false, false, false]);
+        matrix.update(&[false, false, true]);
+        matrix.replace(Matrix::new());
+        assert!(!matrix.take_profile_switch());
+    }
+
+    #[cfg(feature = "host")]
+    #[test]
+    fn validate() {
+        assert_eq!(Matrix::new().validate(), &[]);
+
+        let mut matrix = Matrix::new();
+        matrix.update_layout(AppCommand::SetKey(0, KeyCode::PostFail, 0));
+        matrix.update_layout(AppCommand::SetKey(1, KeyCode::No, 0));
+        matrix.update_layout(AppCommand::SetKey(2, KeyCode::No, 0));
+        assert_eq!(matrix.validate(), &[Issue::ReservedCode(0)]);
+
+        let mut matrix = Matrix::new();
+        matrix.update_layout(AppCommand::SetKey(2, KeyCode::A, 0));
+        matrix.update_layout(AppCommand::SetSequenceButton(1));
+        assert_eq!(matrix.validate(), &[Issue::NoOp(1), Issue::Duplicate(0, 2)]);
+
+        // Encoder channels and the shift button don't send their own action
+        let mut matrix = Matrix::new();
+        matrix.update_layout(AppCommand::SetKey(0, KeyCode::No, 0));
+        matrix.update_layout(AppCommand::SetKey(1, KeyCode::No, 0));
+        matrix.update_layout(AppCommand::SetEncoder(Some(0)));
+        matrix.update_layout(AppCommand::SetShiftButton(Some(1)));
+        assert_eq!(
+            matrix.validate(),
+            &[Issue::ShiftOnEncoder(1), Issue::EmptyShift]
+        );
+
+        let mut matrix = Matrix::new();
+        matrix.update_layout(AppCommand::SetShiftButton(Some(2)));
+        matrix.update_layout(AppCommand::SetShiftModifiers(
+            KeyCode::LAlt.as_modifier_bit(),
+        ));
+        matrix.update_layout(AppCommand::SetLayerButton(Some(2)));
+        assert_eq!(matrix.validate(), &[Issue::LayerConflict(2)]);
+    }
+
+    #[test]
+    fn fn_layer() {
+        let mut matrix = Matrix::new();
+        matrix.update_layout(AppCommand::SetLayerButton(Some(0)));
+        matrix.update_layout(AppCommand::SetLayer(1, KeyCode::PgUp));
+        matrix.update_layout(AppCommand::SetLayer(2, KeyCode::PgDown));
+
+        // Held alone it doesn't send anything
+        assert_eq!(matrix.update(&[true, false, false]), report(&[]));
+        assert_eq!(
+            matrix.update(&[true, true, true]),
+            report(&[KeyCode::PgUp, KeyCode::PgDown])
+        );
+        // Back to the base layer as soon as it's released
+        assert_eq!(matrix.update(&[false, true, false]), report(&[KeyCode::B]));
+        assert_eq!(matrix.update(&[false, false, false]), report(&[]));
+    }
+
+    #[test]
+    fn shift_layer() {
+        let mut matrix = Matrix::new();
+        matrix.update_layout(AppCommand::SetShiftButton(Some(0)));
+        matrix.update_layout(AppCommand::SetShiftModifiers(
+            KeyCode::LCtrl.as_modifier_bit() | KeyCode::LShift.as_modifier_bit(),
+        ));
+
+        // Held alone it doesn't send anything
+        assert_eq!(matrix.update(&[true, false, false]), report(&[]));
+        assert_eq!(
+            matrix.update(&[true, true, false]),
+            report(&[KeyCode::LCtrl, KeyCode::LShift, KeyCode::B])
+        );
+        assert_eq!(
+            matrix.update(&[true, true, true]),
+            report(&[KeyCode::LCtrl, KeyCode::LShift, KeyCode::B, KeyCode::C])
+        );
+        // Releasing the shift button clears the modifiers and keeps the base keys
+        assert_eq!(
+            matrix.update(&[false, true, true]),
+            report(&[KeyCode::B, KeyCode::C])
+        );
+        assert_eq!(matrix.update(&[false, false, false]), report(&[]));
+    }
+
+    #[test]
+    fn disabled_button() {
+        let mut matrix = Matrix::new();
+        matrix.update_layout(AppCommand::SetKey(1, KeyCode::No, 0));
+        matrix.update_layout(AppCommand::SetShiftButton(Some(0)));
+        matrix.update_layout(AppCommand::SetShiftModifiers(
+            KeyCode::LCtrl.as_modifier_bit(),
+        ));
+
+        // Nothing to shift, so the modifiers aren't sent
+        assert_eq!(matrix.update(&[true, true, false]), report(&[]));
+        assert_eq!(
+            matrix.update(&[true, true, true]),
+            report(&[KeyCode::LCtrl, KeyCode::C])
+        );
+    }
+
+    fn tap_hold_matrix() -> Matrix {
+        let mut matrix = Matrix::new();
+        matrix.update_layout(AppCommand::SetTapHold(0, KeyCode::Escape));
+        matrix.update_layout(AppCommand::SetHoldModifiers(
+            0,
+            KeyCode::LCtrl.as_modifier_bit(),
+        ));
+        matrix.update_layout(AppCommand::SetTapHoldThreshold(2));
+        matrix
+    }
+
+    #[test]
+    fn tap_hold_tap() {
+        let mut matrix = tap_hold_matrix();
+        let threshold = 2 * SCANS_PER_TAP_HOLD_UNIT;
+        for _ in 0..threshold - 1 {
+            assert_eq!(matrix.update(&[true, false, false]), report(&[]));
+        }
+        // Released before the threshold, the tap key is sent for a while
+        for _ in 0..TAP_SCANS {
+            assert_eq!(
+                matrix.update(&[false, false, false]),
+                report(&[KeyCode::Escape])
+            );
+        }
+        assert_eq!(matrix.update(&[false, false, false]), report(&[]));
+    }
+
+    #[test]
+    fn tap_hold_hold() {
+        let mut matrix = tap_hold_matrix();
+        let threshold = 2 * SCANS_PER_TAP_HOLD_UNIT;
+        for _ in 0..threshold {
+            assert_eq!(matrix.update(&[true, false, false]), report(&[]));
+        }
+        // Still held, only the modifiers
+        assert_eq!(
+            matrix.update(&[true, false, false]),
+            report(&[KeyCode::LCtrl])
+        );
+        assert_eq!(
+            matrix.update(&[true, true, false]),
+            report(&[KeyCode::LCtrl, KeyCode::B])
+        );
+        // Released after the threshold, no tap
+        assert_eq!(matrix.update(&[false, true, false]), report(&[KeyCode::B]));
+        assert_eq!(matrix.update(&[false, false, false]), report(&[]));
+    }
+
+    #[test]
+    fn toggle_key() {
+        let mut matrix = Matrix::new();
+        matrix.update_layout(AppCommand::SetToggle(2, KeyCode::CapsLock));
+
+        assert_eq!(
+            matrix.update(&[false, false, true]),
+            report(&[KeyCode::CapsLock])
+        );
+        // Still latched with nothing pressed
+        assert_eq!(
+            matrix.update(&[false, false, false]),
+            report(&[KeyCode::CapsLock])
+        );
+        assert_eq!(
+            matrix.update(&[true, false, false]),
+            report(&[KeyCode::A, KeyCode::CapsLock])
+        );
+        // Cleared by the second press, not by its release
+        assert_eq!(matrix.update(&[false, false, true]), report(&[]));
+        assert_eq!(matrix.update(&[false, false, false]), report(&[]));
+        assert_eq!(
+            matrix.update(&[false, false, true]),
+            report(&[KeyCode::CapsLock])
+        );
+    }
+
+    #[test]
+    fn one_shot_modifier() {
+        let mut matrix = Matrix::new();
+        let ctrl = KeyCode::LCtrl.as_modifier_bit();
+        matrix.update_layout(AppCommand::SetOneShot(0, ctrl));
+
+        // Acts as the modifier while held
+        assert_eq!(
+            matrix.update(&[true, false, false]),
+            report(&[KeyCode::LCtrl])
+        );
+        // Survives any number of scans without keys
+        for _ in 0..10 {
+            assert_eq!(matrix.update(&[false, false, false]), report(&[]));
+        }
+        assert_eq!(
+            matrix.update(&[false, true, false]),
+            report(&[KeyCode::LCtrl, KeyCode::B])
+        );
+        assert_eq!(
+            matrix.update(&[false, true, false]),
+            report(&[KeyCode::LCtrl, KeyCode::B])
+        );
+        // Cleared once the key is released
+        assert_eq!(matrix.update(&[false, false, false]), report(&[]));
+        assert_eq!(matrix.update(&[false, false, true]), report(&[KeyCode::C]));
+    }
+
+    #[test]
+    fn consumer_button() {
+        let mut matrix = Matrix::new();
+        matrix.update_layout(AppCommand::SetConsumer(1, ConsumerCode::PlayPause as u8));
+
+        // Not part of the keyboard report
+        assert_eq!(matrix.update(&[true, true, false]), report(&[KeyCode::A]));
+        assert_eq!(
+            matrix.consumer_report().as_bytes(),
+            &[ConsumerCode::PlayPause as u8, 0, 0]
+        );
+        matrix.update(&[true, false, false]);
+        assert_eq!(matrix.consumer_report(), ConsumerReport::new());
+    }
+
+    #[test]
+    fn mouse_button() {
+        let mut matrix = Matrix::new();
+        matrix.update_layout(AppCommand::SetMouse(0, MouseButton::Left));
+        matrix.update_layout(AppCommand::SetMouse(2, MouseButton::Right));
+
+        assert_eq!(matrix.update(&[true, true, true]), report(&[KeyCode::B]));
+        assert_eq!(matrix.mouse_report().as_bytes(), &[0b11, 0, 0]);
+        // Released, so the click doesn't stick
+        matrix.update(&[false, true, false]);
+        assert_eq!(matrix.mouse_report(), MouseReport::new());
+    }
+
+    #[test]
+    fn key_modifiers() {
+        let mut matrix = Matrix::new();
+        matrix.update_layout(AppCommand::SetKey(0, KeyCode::C, 0));
+        matrix.update_layout(AppCommand::SetKeyModifiers(
+            0,
+            KeyCode::LCtrl.as_modifier_bit(),
+        ));
+        matrix.update_layout(AppCommand::SetLayerButton(Some(2)));
+
+        assert_eq!(
+            matrix.update(&[true, false, false]),
+            KbHidReport::from_keys(0, &[KeyCode::LCtrl, KeyCode::C])
+        );
+        // Only the keys of the regular layer have them
+        assert_eq!(
+            matrix.update(&[true, false, true]),
+            KbHidReport::from_keys(0, &[KeyCode::A])
+        );
+
+        // Setting the key again clears them
+        matrix.update_layout(AppCommand::SetKey(0, KeyCode::C, 0));
+        matrix.update(&[false, false, false]);
+        assert_eq!(
+            matrix.update(&[true, false, false]),
+            KbHidReport::from_keys(0, &[KeyCode::C])
+        );
+
+        // Or sets them in the same command
+        let alt = KeyCode::LAlt.as_modifier_bit();
+        matrix.update_layout(AppCommand::SetKey(0, KeyCode::C, alt));
+        matrix.update(&[false, false, false]);
+        assert_eq!(
+            matrix.update(&[true, false, false]),
+            KbHidReport::from_keys(0, &[KeyCode::LAlt, KeyCode::C])
+        );
+    }
+
+    #[test]
+    fn combo() {
+        let mut matrix = Matrix::new();
+        matrix.update_layout(AppCommand::SetComboButtons(0, 0b011));
+        matrix.update_layout(AppCommand::SetCombo(0, KeyCode::Escape));
+
+        // Rolling into the second button sends only the combo
+        assert_eq!(matrix.update(&[true, false, false]), KbHidReport::new());
+        assert_eq!(
+            matrix.update(&[true, true, false]),
+            KbHidReport::from_keys(0, &[KeyCode::Escape])
+        );
+        assert_eq!(matrix.update(&[false, true, false]), KbHidReport::new());
+        matrix.update(&[false, false, false]);
+
+        // Buttons outside of the combo aren't delayed
+        assert_eq!(
+            matrix.update(&[false, false, true]),
+            KbHidReport::from_keys(0, &[KeyCode::C])
+        );
+    }
+
+    #[test]
+    fn double_tap() {
+        let mut matrix = Matrix::new();
+        matrix.update_layout(AppCommand::SetDoubleTap(0, KeyCode::Escape));
+        matrix.update_layout(AppCommand::SetDoubleTapKey(0, KeyCode::Enter));
+        matrix.update_layout(AppCommand::SetTapHoldThreshold(2));
+        let window = 2 * SCANS_PER_TAP_HOLD_UNIT as usize;
+
+        // A single tap is sent once the window for the second one is over, counted from the release
+        assert_eq!(matrix.update(&[true, false, false]), report(&[]));
+        for _ in 0..window {
+            assert_eq!(matrix.update(&[false, false, false]), report(&[]));
+        }
+        for _ in 0..TAP_SCANS {
+            assert_eq!(
+                matrix.update(&[false, false, false]),
+                report(&[KeyCode::Escape])
+            );
+        }
+        assert_eq!(matrix.update(&[false, false, false]), report(&[]));
+
+        // Tapped twice, only the double-tap key is sent, for as long as it's held
+        matrix.update(&[true, false, false]);
+        matrix.update(&[false, false, false]);
+        for _ in 0..window * 2 {
+            assert_eq!(
+                matrix.update(&[true, false, false]),
+                report(&[KeyCode::Enter])
+            );
+        }
+        assert_eq!(matrix.update(&[false, false, false]), report(&[]));
+
+        // Held past the window it's a single key
+        for _ in 0..window {
+            assert_eq!(matrix.update(&[true, false, false]), report(&[]));
+        }
+        assert_eq!(
+            matrix.update(&[true, false, false]),
+            report(&[KeyCode::Escape])
+        );
+    }
+
+    #[test]
+    fn mouse_move() {
+        let mut matrix = Matrix::new();
+        matrix.update_layout(AppCommand::SetMouseMove(0, MouseDirection::Left));
+        matrix.update_layout(AppCommand::SetMouseMove(1, MouseDirection::Up));
+        matrix.update_layout(AppCommand::SetMouseSpeed(5));
+
+        // Every scan while held moves again
+        for _ in 0..3 {
+            matrix.update(&[true, true, false]);
+            assert_eq!(
+                matrix.mouse_report().as_bytes(),
+                &[0, (-5i8) as u8, (-5i8) as u8]
+            );
+        }
+        matrix.update(&[false, false, false]);
+        assert!(!matrix.mouse_report().has_motion());
+    }
+
+    #[test]
+    fn modifier_button() {
+        let mut matrix = Matrix::new();
+        let bits = KeyCode::LCtrl.as_modifier_bit() | KeyCode::LShift.as_modifier_bit();
+        matrix.update_layout(AppCommand::SetModifiers(0, bits));
+
+        // Held alone, only the modifier byte is set
+        assert_eq!(
+            matrix.update(&[true, false, false]),
+            report(&[KeyCode::LCtrl, KeyCode::LShift])
+        );
+        assert_eq!(
+            matrix.update(&[true, true, false]),
+            report(&[KeyCode::LCtrl, KeyCode::LShift, KeyCode::B])
+        );
+        assert_eq!(matrix.update(&[false, true, false]), report(&[KeyCode::B]));
+    }
+
+    fn sticky_matrix() -> Matrix {
+        let mut matrix = Matrix::new();
+        matrix.update_layout(AppCommand::SetShiftButton(Some(0)));
+        matrix.update_layout(AppCommand::SetShiftModifiers(
+            KeyCode::LCtrl.as_modifier_bit(),
+        ));
+        matrix.update_layout(AppCommand::SetStickyShift(true));
+        matrix.update_layout(AppCommand::SetStickyTimeout(1));
+        matrix
+    }
+
+    #[test]
+    fn sticky_shift_applies_once() {
+        let mut matrix = sticky_matrix();
+        // Tap
+        matrix.update(&[true, false, false]);
+        assert_eq!(matrix.update(&[false, false, false]), report(&[]));
+
+        let shifted = report(&[KeyCode::LCtrl, KeyCode::B]);
+        assert_eq!(matrix.update(&[false, true, false]), shifted);
+        assert_eq!(matrix.update(&[false, true, false]), shifted);
+        assert_eq!(matrix.update(&[false, false, false]), report(&[]));
+        // Cleared after the key was released
+        assert_eq!(matrix.update(&[false, true, false]), report(&[KeyCode::B]));
+    }
+
+    #[test]
+    fn sticky_shift_times_out() {
+        let mut matrix = sticky_matrix();
+        matrix.update(&[true, false, false]);
+        for _ in 0..=SCANS_PER_STICKY_UNIT {
+            assert_eq!(matrix.update(&[false, false, false]), report(&[]));
+        }
+        assert_eq!(matrix.update(&[false, true, false]), report(&[KeyCode::B]));
+    }
+
+    #[test]
+    fn sticky_shift_not_latched_after_use() {
+        let mut matrix = sticky_matrix();
+        // Used as a regular held shift
+        matrix.update(&[true, true, false]);
+        matrix.update(&[false, false, false]);
+        assert_eq!(matrix.update(&[false, true, false]), report(&[KeyCode::B]));
+
+        // Disabled by default
+        let mut matrix = sticky_matrix();
+        matrix.update_layout(AppCommand::SetStickyShift(false));
+        matrix.update(&[true, false, false]);
+        matrix.update(&[false, false, false]);
+        assert_eq!(matrix.update(&[false, true, false]), report(&[KeyCode::B]));
+    }
+
+    #[test]
+    fn layer_state() {
+        let mut matrix = sticky_matrix();
+        let ctrl = KeyCode::LCtrl.as_modifier_bit();
+        assert_eq!(matrix.layer_state(), LayerState::default());
+
+        // Every snapshot matches the report of the same scan
+        matrix.update(&[true, false, false]);
+        let state = matrix.layer_state();
+        assert!(state.shift_active);
+        assert_eq!(state.latched_modifiers, 0);
+
+        assert_eq!(matrix.update(&[false, false, false]), report(&[]));
+        let state = matrix.layer_state();
+        assert!(!state.shift_active);
+        assert_eq!(state.latched_modifiers, ctrl);
+
+        let report = matrix.update(&[false, true, false]);
+        assert_eq!(report.modifiers(), matrix.layer_state().latched_modifiers);
+        matrix.update(&[false, false, false]);
+        assert_eq!(matrix.layer_state(), LayerState::default());
+
+        let state = LayerState {
+            shift_active: true,
+            latched_modifiers: ctrl,
+        };
+        assert_eq!(LayerState::from_bytes(state.to_bytes()), state);
+    }
+
+    #[test]
+    fn replace() {
+        let mut matrix = Matrix::new();
+        matrix.update_layout(AppCommand::SetEncoder(Some(0)));
+        // A detent waiting to be reported and half of the next one
+        let cw = [(true, false), (true, true), (false, true), (false, false)];
+        for &(a, b) in cw.iter().chain(&cw[..2]) {
+            matrix.sample_encoder(&[a, b, false]);
+        }
+        assert_ne!(matrix.detents, DetentQueue::new());
+
+        // Edited from a copy, so it has the same detents
+        let mut new = matrix;
+        new.update_layout(AppCommand::SetKey(2, KeyCode::Escape, 0));
+        new.update_layout(AppCommand::SetClockwise(KeyCode::Up));
+        let old = matrix.replace(new);
+        assert_eq!(old.layout[2], Action::Key(KeyCode::C));
+
+        // Every field comes from the new layout and the old detents are gone
+        assert_eq!(matrix.to_bytes(), new.to_bytes());
+        assert_eq!(matrix.detents, DetentQueue::new());
+        for &(a, b) in &cw[2..] {
+            matrix.sample_encoder(&[a, b, false]);
+        }
+        assert_eq!(matrix.detents, DetentQueue::new());
+        assert_eq!(
+            matrix.update(&[false, true, true]),
+            report(&[KeyCode::Escape])
+        );
+        assert_eq!(
+            matrix.update(&[false, false, true]),
+            report(&[KeyCode::Escape])
+        );
+    }
+
+    #[test]
+    fn encoder_detent() {
+        let mut matrix = Matrix::new();
+        matrix.update_layout(AppCommand::SetEncoder(Some(0)));
+        matrix.update_layout(AppCommand::SetClockwise(KeyCode::Up));
+
+        let mut pressed = [false; NUM_BTS];
+        // Push switch
+        pressed[2] = true;
+        let sequence = [(true, false), (true, true), (false, true)];
+        for &(a, b) in &sequence {
+            pressed[0] = a;
+            pressed[1] = b;
+            matrix.sample_encoder(&pressed);
+            // Channels never produce their own keys
+            assert_eq!(matrix.update(&pressed), report(&[KeyCode::C]));
+        }
+        pressed[0] = false;
+        pressed[1] = false;
+        matrix.sample_encoder(&pressed);
+        assert_eq!(matrix.update(&pressed), report(&[KeyCode::C, KeyCode::Up]));
+        // Momentary
+        let held = (0..10)
+            .take_while(|_| matrix.update(&pressed) == report(&[KeyCode::C, KeyCode::Up]))
+            .count();
+        assert!(held < TAP_SCANS as usize);
+        assert_eq!(matrix.update(&pressed), report(&[KeyCode::C]));
+    }
+
+    #[test]
+    fn encoder_fast_detents() {
+        let mut matrix = Matrix::new();
+        matrix.update_layout(AppCommand::SetEncoder(Some(0)));
+        matrix.update_layout(AppCommand::SetCounterClockwiseConsumer(0xEA));
+
+        // Two detents between two scans
+        let ccw = [(false, true), (true, true), (true, false), (false, false)];
+        for _ in 0..2 {
+            for &(a, b) in &ccw {
+                matrix.sample_encoder(&[a, b, false]);
+            }
+        }
+        // Reported as two separate presses
+        let presses = (0..100)
+            .map(|_| {
+                matrix.update(&[false; NUM_BTS]);
+                matrix.consumer_report() != ConsumerReport::new()
+            })
+            .fold((0, false), |(count, was), now| {
+                (count + (now && !was) as usize, now)
+            })
+            .0;
+        assert_eq!(presses, 2);
+    }
+}
@@ -0,0 +1,122 @@
+//! Mouse buttons and cursor movement, sent on their own interface.
+use num_enum::TryFromPrimitive;
+#[cfg(feature = "host")]
+use strum_macros::{AsRefStr, EnumIter};
+
+/// Usages of the button page, as stored in an `Action::Mouse`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, TryFromPrimitive)]
+#[cfg_attr(feature = "host", derive(AsRefStr, EnumIter))]
+#[cfg_attr(feature = "host", strum(serialize_all = "lowercase"))]
+#[repr(u8)]
+pub enum MouseButton {
+    Left = 1,
+    Right,
+    Middle,
+}
+
+impl MouseButton {
+    fn bit(self) -> u8 {
+        1 << (self as u8 - 1)
+    }
+}
+
+/// Direction the cursor moves while an `Action::MouseMove` button is held.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, TryFromPrimitive)]
+#[cfg_attr(feature = "host", derive(AsRefStr, EnumIter))]
+#[cfg_attr(feature = "host", strum(serialize_all = "lowercase"))]
+#[repr(u8)]
+pub enum MouseDirection {
+    Left = 0,
+    Right,
+    Up,
+    Down,
+}
+
+impl MouseDirection {
+    /// X and Y of a move by `distance`, Y grows downwards.
+    pub fn delta(self, distance: i8) -> (i8, i8) {
+        match self {
+            MouseDirection::Left => (-distance, 0),
+            MouseDirection::Right => (distance, 0),
+            MouseDirection::Up => (0, -distance),
+            MouseDirection::Down => (0, distance),
+        }
+    }
+}
+
+/// Default distance moved on every scan, in mouse units.
+pub const DEFAULT_MOUSE_SPEED: u8 = 2;
+/// Largest distance moved on every scan, so a move always fits the report.
+pub const MAX_MOUSE_SPEED: u8 = 127;
+
+/// Report of the mouse interface: the button bits followed by the relative X and Y.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct MouseReport([u8; 3]);
+
+impl MouseReport {
+    pub const fn new() -> Self {
+        MouseReport([0; 3])
+    }
+
+    pub fn pressed(&mut self, button: MouseButton) {
+        self.0[0] |= button.bit();
+    }
+
+    /// Adds to the movement of the report, saturating at the limits of the report.
+    pub fn move_by(&mut self, x: i8, y: i8) {
+        self.0[1] = (self.0[1] as i8).saturating_add(x) as u8;
+        self.0[2] = (self.0[2] as i8).saturating_add(y) as u8;
+    }
+
+    pub fn has_motion(&self) -> bool {
+        self.0[1] != 0 || self.0[2] != 0
+    }
+
+    /// Takes the buttons of `next` and adds its movement to the one not sent yet, movement is
+    /// relative so dropping a report would lose it.
+    pub fn accumulate(&mut self, next: &MouseReport) {
+        self.0[0] = next.0[0];
+        self.move_by(next.0[1] as i8, next.0[2] as i8);
+    }
+
+    /// Same buttons, without movement.
+    pub fn buttons(&self) -> MouseReport {
+        MouseReport([self.0[0], 0, 0])
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn report() {
+        let mut report = MouseReport::new();
+        report.pressed(MouseButton::Left);
+        report.pressed(MouseButton::Middle);
+        assert_eq!(report.as_bytes(), &[0b101, 0, 0]);
+    }
+
+    #[test]
+    fn motion() {
+        let mut report = MouseReport::new();
+        let (x, y) = MouseDirection::Left.delta(100);
+        report.move_by(x, y);
+        report.move_by(x, y);
+        assert_eq!(report.as_bytes(), &[0, (-128i8) as u8, 0]);
+
+        let mut pending = MouseReport::new();
+        let mut next = MouseReport::new();
+        next.pressed(MouseButton::Right);
+        next.move_by(0, 3);
+        pending.accumulate(&next);
+        pending.accumulate(&next);
+        assert!(pending.has_motion());
+        assert_eq!(pending.as_bytes(), &[0b10, 0, 6]);
+        assert_eq!(pending.buttons().as_bytes(), &[0b10, 0, 0]);
+    }
+}
@@ -0,0 +1,129 @@
+//! Friendly name of the device, chosen by the user to tell several boards apart.
+
+/// Longest name that can be stored.
+pub const MAX_LEN: usize = 16;
+
+/// Printable ASCII only, so it can be shown anywhere.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct DeviceName {
+    bytes: [u8; MAX_LEN],
+    len: usize,
+}
+
+impl DeviceName {
+    /// Number of bytes used to store a name, unused bytes are stored as zero.
+    pub const SIZE: usize = MAX_LEN;
+
+    pub const fn new() -> Self {
+        Self {
+            bytes: [0; MAX_LEN],
+            len: 0,
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        // Only printable ASCII is pushed
+        core::str::from_utf8(&self.bytes[..self.len]).unwrap_or("")
+    }
+
+    pub fn clear(&mut self) {
+        *self = Self::new();
+    }
+
+    /// Appends `ch`, returns `false` if the name is full or `ch` isn't printable ASCII.
+    pub fn push(&mut self, ch: u8) -> bool {
+        if self.len == MAX_LEN || !(ch == b' ' || ch.is_ascii_graphic()) {
+            return false;
+        }
+        self.bytes[self.len] = ch;
+        self.len += 1;
+        true
+    }
+
+    pub fn to_bytes(self) -> [u8; Self::SIZE] {
+        self.bytes
+    }
+
+    pub fn from_bytes(bytes: [u8; Self::SIZE]) -> Option<Self> {
+        let mut name = Self::new();
+        let len = bytes.iter().position(|&byte| byte == 0).unwrap_or(MAX_LEN);
+        for &byte in &bytes[..len] {
+            if !name.push(byte) {
+                return None;
+            }
+        }
+        if bytes[len..].iter().any(|&byte| byte != 0) {
+            return None;
+        }
+        Some(name)
+    }
+}
+
+/// Size of the unique device ID of the STM32, programmed in the factory.
+pub const UID_SIZE: usize = 12;
+/// Length of the USB serial number, the unique ID in hex.
+pub const SERIAL_LEN: usize = 2 * UID_SIZE;
+
+/// Formats the unique device ID as the USB serial number, uppercase hex in the order the bytes are
+/// stored, so it's unique for every board.
+pub fn serial_number<'a>(uid: &[u8; UID_SIZE], buf: &'a mut [u8; SERIAL_LEN]) -> &'a str {
+    const DIGITS: &[u8; 16] = b"0123456789ABCDEF";
+    for (&byte, hex) in uid.iter().zip(buf.chunks_exact_mut(2)) {
+        hex[0] = DIGITS[usize::from(byte >> 4)];
+        hex[1] = DIGITS[usize::from(byte & 0xF)];
+    }
+    // Only hex digits were written
+    core::str::from_utf8(buf).unwrap_or("")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::Settings;
+
+    fn name(text: &str) -> DeviceName {
+        let mut name = DeviceName::new();
+        for &ch in text.as_bytes() {
+            assert!(name.push(ch));
+        }
+        name
+    }
+
+    #[test]
+    fn stored_with_settings() {
+        let mut settings = Settings::new();
+        settings.name = name("Left pad");
+        let stored = Settings::from_bytes(settings.to_bytes()).unwrap();
+        assert_eq!(stored.name.as_str(), "Left pad");
+
+        settings.name = name("0123456789abcdef");
+        let stored = Settings::from_bytes(settings.to_bytes()).unwrap();
+        assert_eq!(stored.name.as_str(), "0123456789abcdef");
+    }
+
+    #[test]
+    fn limits() {
+        let mut full = name("0123456789abcdef");
+        assert!(!full.push(b'g'));
+        assert!(!DeviceName::new().push(b'\n'));
+        assert!(!DeviceName::new().push(0xC3));
+    }
+
+    #[test]
+    fn invalid_bytes() {
+        let mut bytes = name("pad").to_bytes();
+        bytes[5] = b'x';
+        assert_eq!(DeviceName::from_bytes(bytes), None);
+        // Erased flash
+        assert_eq!(DeviceName::from_bytes([0xFF; DeviceName::SIZE]), None);
+    }
+
+    #[test]
+    fn serial_from_uid() {
+        let uid = [
+            0x36, 0xFF, 0xD8, 0x05, 0x4E, 0x59, 0x31, 0x38, 0x20, 0x43, 0x10, 0x57,
+        ];
+        let mut buf = [0; SERIAL_LEN];
+        assert_eq!(serial_number(&uid, &mut buf), "36FFD8054E59313820431057");
+    }
+}
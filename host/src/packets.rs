@@ -1,4 +1,16 @@
-use crate::key_code::KeyCode;
+use crate::{
+    action::Action,
+    boot::UsbAttributes,
+    combo::{Combo, MAX_COMBOS},
+    crc,
+    debounce::{self, LatencyMode},
+    key_code::KeyCode,
+    matrix::NUM_SEQUENCES,
+    mouse::{MouseButton, MouseDirection, MAX_MOUSE_SPEED},
+    storage::{self, NUM_PROFILES},
+    NUM_BTS,
+};
+use core::{convert::TryFrom, fmt};
 use num_enum::TryFromPrimitive;
 
 #[derive(Debug, Clone, Copy)]
@@ -34,6 +46,24 @@ impl Request {
     }
 }
 
+/// Protocol of a boot interface, chosen by the host with SetProtocol.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(u8)]
+pub enum Protocol {
+    Boot = 0,
+    Report = 1,
+}
+
+impl Protocol {
+    pub fn new(value: u16) -> Option<Protocol> {
+        match value {
+            0 => Some(Protocol::Boot),
+            1 => Some(Protocol::Report),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum ReportType {
     Input,
@@ -53,30 +83,1185 @@ impl From<u8> for ReportType {
     }
 }
 
-#[derive(Debug, TryFromPrimitive)]
+/// Data answered to a GetReport request of `length` bytes. Hosts may ask for more or less than the
+/// report size, a shorter request gets the start of the report instead of a stall.
+pub fn report_response(report: &[u8], length: u16) -> &[u8] {
+    &report[..report.len().min(length as usize)]
+}
+
+/// Set in the vendor command byte of a SetReport request to the control interface that ends with a
+/// checksum, a `crc::crc8` of the bytes before it, flag included. Reports without it aren't
+/// checked. Vendor commands are below 0x80, so a report can't be mistaken for one with the flag.
+pub const CHECKSUM_FLAG: u8 = 0x80;
+/// Longest data of a SetReport request to the control interface without the checksum, the one of
+/// `VendorCommand::SetAll` or of a `SetKey` with its modifiers, whichever is longer.
+pub const MAX_REPORT_LEN: usize = if NUM_BTS > 3 { NUM_BTS + 1 } else { 4 };
+/// Size of the feature report of the control interface in the descriptor, the longest data and its
+/// checksum. Shorter reports are padded with zeros, hosts that check the size, e.g. Windows, only
+/// send and read whole reports. GetReport answers are padded the same way.
+pub const CTRL_REPORT_SIZE: usize = MAX_REPORT_LEN + 1;
+
+/// Data of a SetReport request to the control interface, with its checksum checked and removed,
+/// see `CHECKSUM_FLAG`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CheckedReport {
+    data: [u8; CTRL_REPORT_SIZE],
+    len: usize,
+}
+
+impl CheckedReport {
+    /// `None` if the checksum doesn't match, or if the data is empty or longer than the report. The
+    /// checksum is always the last byte, after the padding.
+    pub fn new(data: &[u8]) -> Option<Self> {
+        if data.len() > CTRL_REPORT_SIZE {
+            return None;
+        }
+        let data = match data.first() {
+            Some(&req) if req & CHECKSUM_FLAG != 0 => {
+                if !crc::check_crc8(data) {
+                    return None;
+                }
+                &data[..data.len() - 1]
+            }
+            _ => data,
+        };
+        if data.is_empty() {
+            return None;
+        }
+        let mut report = Self {
+            data: [0; CTRL_REPORT_SIZE],
+            len: data.len(),
+        };
+        report.data[..data.len()].copy_from_slice(data);
+        report.data[0] &= !CHECKSUM_FLAG;
+        Some(report)
+    }
+
+    /// The vendor command followed by its value and maybe some padding, for
+    /// `AppCommand::from_report` and `Query::from_report`.
+    pub fn data(&self) -> &[u8] {
+        &self.data[..self.len]
+    }
+}
+
+/// Adds `CHECKSUM_FLAG` and the checksum to the data of a SetReport request, padded to a whole
+/// report, the opposite of `CheckedReport::new`. `None` if the data is empty or longer than
+/// `MAX_REPORT_LEN`.
+pub fn with_checksum(report: &[u8]) -> Option<[u8; CTRL_REPORT_SIZE]> {
+    if report.is_empty() || report.len() > MAX_REPORT_LEN {
+        return None;
+    }
+    let mut checked = [0; CTRL_REPORT_SIZE];
+    checked[..report.len()].copy_from_slice(report);
+    checked[0] |= CHECKSUM_FLAG;
+    checked[CTRL_REPORT_SIZE - 1] = crc::crc8(&checked[..CTRL_REPORT_SIZE - 1]);
+    Some(checked)
+}
+
+/// The first `len` bytes of `data`, `None` if it's shorter or if anything after them isn't zero
+/// padding, see `CTRL_REPORT_SIZE`.
+fn unpadded(data: &[u8], len: usize) -> Option<&[u8]> {
+    if data.len() >= len && data[len..].iter().all(|&byte| byte == 0) {
+        Some(&data[..len])
+    } else {
+        None
+    }
+}
+
+/// Data of the SetReport request that sets `button` to `action`, the opposite of
+/// `AppCommand::from_report`. The other settings of the button, like its key modifiers, have
+/// commands of their own. Commands that only take the button are padded. `None` if `button` is
+/// out of range.
+pub fn set_action_report(button: usize, action: Action) -> Option<[u8; 3]> {
+    if button >= NUM_BTS {
+        return None;
+    }
+    let index = button as u8;
+    let report = match action {
+        Action::Key(key) => [VendorCommand::SetKey as u8, index, key as u8],
+        Action::Consumer(usage) => [VendorCommand::SetConsumer as u8, index, usage],
+        Action::Mouse(mouse) => [VendorCommand::SetMouse as u8, index, mouse as u8],
+        // The slot of the sequence is the button itself
+        Action::Sequence(_) => [VendorCommand::SetSequenceButton as u8, index, 0],
+        Action::Modifiers(bits) => [VendorCommand::SetModifiers as u8, index, bits],
+        Action::TapHold(key) => [VendorCommand::SetTapHold as u8, index, key as u8],
+        Action::Toggle(key) => [VendorCommand::SetToggle as u8, index, key as u8],
+        Action::OneShot(bits) => [VendorCommand::SetOneShot as u8, index, bits],
+        Action::MouseMove(direction) => [VendorCommand::SetMouseMove as u8, index, direction as u8],
+        Action::DoubleTap(key) => [VendorCommand::SetDoubleTap as u8, index, key as u8],
+        Action::NextProfile => [VendorCommand::SetProfileButton as u8, index, 0],
+        Action::CapsWord => [VendorCommand::SetCapsWordButton as u8, index, 0],
+    };
+    Some(report)
+}
+
+/// Commands of a SetReport request to the control interface. Values of commands that were
+/// replaced stay unused, so older hosts sending them get rejected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, TryFromPrimitive)]
 #[repr(u8)]
 pub enum VendorCommand {
-    Set1 = 1,
-    Set2,
-    Set3,
-    Save,
+    Save = 4,
+    SetLatencyMode,
+    /// Value is the button wired to channel A of an encoder, 0xFF disables the encoder.
+    SetEncoder,
+    SetClockwise,
+    SetCounterClockwise,
+    Reboot,
+    /// Value is the button that adds the shift modifiers to the others, 0xFF disables it.
+    SetShiftButton,
+    /// Value is the modifier byte added by the shift button.
+    SetShiftModifiers,
+    ClearStartupSequence,
+    /// Appends the key in value to the startup sequence.
+    PushStartupKey,
+    /// Value is a button, it's set to play the sequence with the same index.
+    SetSequenceButton,
+    /// Value is the sequence to clear, which is also the one changed by `PushSequenceKey`.
+    ClearSequence,
+    /// Appends the key in value to the sequence last cleared.
+    PushSequenceKey,
+    /// Value is 1 to pause key output while configuring, 0 to keep it.
+    SetQuietConfig,
+    /// Value is 1 to latch the shift modifiers when the shift button is tapped alone, 0 disables.
+    SetStickyShift,
+    /// Value is the time a latched shift waits for a key, in 100 ms units, 0 isn't valid.
+    SetStickyTimeout,
+    /// Value is 0, the next GetReport of the control interface answers a `LayerState` instead of
+    /// the status.
+    GetLayerState,
+    /// Value is an `InfoField`, the next GetReport of the control interface answers it as a
+    /// little endian u16 instead of the status.
+    GetInfo = 24,
+    /// Value is the record to start from, 0 for the whole area, the next GetReports of the control
+    /// interface answer the config pages two bytes at a time from there, until their end. Records
+    /// are counted as in `storage::record_offset`.
+    DumpPage,
+    /// Value is 1 to enable auto-repeat of held buttons, 0 disables it.
+    SetRepeat,
+    /// Value is the time until the first repeat, in 10 ms units, 0 isn't valid.
+    SetRepeatInitial,
+    /// Value is the shortest time between repeats, in 10 ms units, 0 isn't valid.
+    SetRepeatFloor,
+    /// Value is the time taken off the repeat interval after every repeat, in 10 ms units.
+    SetRepeatStep,
+    /// Value is 1 to declare the device self-powered, 0 for bus-powered. Applied on reboot.
+    SetSelfPowered,
+    /// Value is 1 to declare remote wakeup support, 0 disables it. Applied on reboot.
+    SetRemoteWakeup,
+    /// Value is bMaxPower, in 2 mA units from 1 to 250. Applied on reboot.
+    SetMaxPower,
+    /// Value is 0, the next GetReport of the control interface answers the debounced state of
+    /// the buttons as a mask, see `selector::pressed_mask`, instead of the status.
+    GetPressed,
+    ClearName,
+    /// Appends the ASCII character in value to the device name.
+    PushNameChar,
+    /// Value is 0, the next GetReports of the control interface answer the device name two bytes
+    /// at a time, zero padded, until the whole `name::MAX_LEN` is read.
+    GetName,
+    /// Value is the button that selects the Fn layer while held, 0xFF disables it.
+    SetLayerButton,
+    /// Value is the time a tap-hold button has to be held to send its modifiers, in 10 ms units,
+    /// 0 isn't valid.
+    SetTapHoldThreshold = 47,
+    /// Value is the distance moved on every scan while a move button is held, from 1 to
+    /// `mouse::MAX_MOUSE_SPEED`.
+    SetMouseSpeed = 63,
+    /// Value is the media key sent by a clockwise encoder detent, see `consumer::ConsumerCode`.
+    /// 0 isn't valid.
+    SetClockwiseConsumer = 77,
+    /// Same as `SetClockwiseConsumer` for a counter-clockwise detent.
+    SetCounterClockwiseConsumer,
+    /// Value is the button that switches to the next profile stored in flash.
+    SetProfileButton,
+    /// Value is 0, switches to the next profile stored in flash, dropping unsaved changes.
+    NextProfile,
+    /// Value is the button that toggles caps word.
+    SetCapsWordButton,
+    /// Value is the profile stored in flash to switch to, from 0 to `storage::NUM_PROFILES` - 1.
+    /// Unsaved changes are dropped, even if it's the active profile.
+    SelectProfile,
+    /// Value is 0, the next GetReports of the control interface answer the layout in use, as
+    /// `Matrix::to_bytes`, two bytes at a time until the whole layout is read. It includes the
+    /// changes that weren't saved yet.
+    ReadConfig,
+    /// Value is 0, moves the config in use to the start of the other config page, so the erase a
+    /// later save would need happens now. Unsaved changes aren't stored.
+    Compact,
+    /// Value is 0, the next GetReports of the control interface answer the firmware version as
+    /// major, minor and patch, two bytes at a time and padded to a whole report.
+    GetVersion,
+    /// Value is 0, replaces the stored config with the defaults, every profile and the settings,
+    /// and loads them. A failure is reported like the one of `Save`.
+    FactoryReset,
+    /// Value is the number of samples the debouncer needs to confirm a press or a release, from 1
+    /// to `debounce::MAX_WINDOW`. The debouncer starts over with every button released.
+    SetDebounce,
+    /// Value is 0, the next GetReport of the control interface answers `PROTOCOL_VERSION`
+    /// followed by a 0.
+    GetProtocolVersion,
+    /// Followed by the key of every button instead of a single value, `NUM_BTS` bytes in order,
+    /// replaces the whole layout and clears the modifiers of the keys.
+    SetAll,
+    /// Value is 0, reboots into the system bootloader of the chip to flash a new firmware, like
+    /// `Reboot` does. On the STM32F103 it's the serial bootloader on USART1, it has no USB DFU, so
+    /// the device doesn't come back on USB until it's reset.
+    EnterBootloader,
+    // The commands below are followed by the button they change, starting at 0, and then its
+    // value, see `AppCommand::from_report`.
+    /// Value is the key of the button, it clears the modifiers sent with it. A byte after the
+    /// value sets them instead, see `SetKeyModifiers`.
+    SetKey,
+    /// Value is the modifier byte the button sets while held, without any key.
+    SetModifiers,
+    /// Value is the key sent by the button while the Fn layer is selected.
+    SetLayer,
+    /// Value is the key the button sends when tapped, it sends its hold modifiers when held.
+    SetTapHold,
+    /// Value is the modifier byte the button sends when held, if it's a tap-hold button.
+    SetHoldModifiers,
+    /// Value is the key the button latches on a press, until it's pressed again.
+    SetToggle,
+    /// Value is the modifier byte the button applies to the next key, see `Action::OneShot`.
+    SetOneShot,
+    /// Value is the consumer usage the button sends, see `consumer::ConsumerCode`, 0 isn't valid.
+    SetConsumer,
+    /// Value is the mouse button the button clicks, see `mouse::MouseButton`.
+    SetMouse,
+    /// Value is the direction the button moves the cursor, see `mouse::MouseDirection`.
+    SetMouseMove,
+    /// Value is the modifier byte sent together with the key of the button, set it after the
+    /// key since `SetKey` clears it.
+    SetKeyModifiers,
+    /// Value is the key the button sends when tapped once, it sends its double-tap key when
+    /// tapped twice.
+    SetDoubleTap,
+    /// Value is the key the button sends when tapped twice, if it's a double-tap button.
+    SetDoubleTapKey,
+    /// Followed by the combo instead of a button, from 0 to `combo::MAX_COMBOS` - 1. Value has
+    /// one bit per button of the combo, starting with the first button in the LSB. It needs at
+    /// least two buttons, 0 disables the combo.
+    SetComboButtons,
+    /// Followed by the combo instead of a button, value is the key sent by the combo.
+    SetCombo,
 }
 
-#[derive(Debug, Copy, Clone)]
+impl VendorCommand {
+    /// Whether it's followed by a button, or a combo, before its value.
+    pub fn takes_button(self) -> bool {
+        matches!(
+            self,
+            VendorCommand::SetKey
+                | VendorCommand::SetModifiers
+                | VendorCommand::SetLayer
+                | VendorCommand::SetTapHold
+                | VendorCommand::SetHoldModifiers
+                | VendorCommand::SetToggle
+                | VendorCommand::SetOneShot
+                | VendorCommand::SetConsumer
+                | VendorCommand::SetMouse
+                | VendorCommand::SetMouseMove
+                | VendorCommand::SetKeyModifiers
+                | VendorCommand::SetDoubleTap
+                | VendorCommand::SetDoubleTapKey
+                | VendorCommand::SetComboButtons
+                | VendorCommand::SetCombo
+        )
+    }
+}
+
+/// Firmware parameters that can be read with `GetInfo`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, TryFromPrimitive)]
+#[repr(u8)]
+pub enum InfoField {
+    DebounceWindow = 0,
+    HoldThreshold,
+    ScanRate,
+    /// Index of the record in use, counting the records of every config page, see `storage`.
+    ConfigIndex,
+    /// bmAttributes of the active configuration descriptor.
+    ConfigAttributes,
+    /// bMaxPower of the active configuration descriptor, in 2 mA units.
+    MaxPower,
+    /// Active profile, see `storage::NUM_PROFILES`.
+    Profile,
+    /// Total number of erases of the config pages, see `storage::erase_count`. It saturates at
+    /// `u16::MAX`.
+    EraseCount,
+    /// Lock LEDs set by the host in the keyboard output report, bit 0 is Num Lock, bit 1 Caps Lock
+    /// and bit 2 Scroll Lock.
+    Leds,
+}
+
+/// Command decoded from a `VendorCommand`. Per-button commands carry the button index, starting
+/// at 0, the same as on the wire.
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub enum AppCommand {
-    Set1(KeyCode),
-    Set2(KeyCode),
-    Set3(KeyCode),
+    /// Button, key and the modifiers sent with it.
+    SetKey(usize, KeyCode, u8),
+    /// Key of every button.
+    SetAll([KeyCode; NUM_BTS]),
     Save,
+    SetLatencyMode(LatencyMode),
+    SetEncoder(Option<usize>),
+    SetClockwise(KeyCode),
+    SetCounterClockwise(KeyCode),
+    Reboot,
+    SetShiftButton(Option<usize>),
+    SetShiftModifiers(u8),
+    ClearStartupSequence,
+    PushStartupKey(KeyCode),
+    SetSequenceButton(usize),
+    ClearSequence(usize),
+    PushSequenceKey(KeyCode),
+    SetQuietConfig(bool),
+    SetStickyShift(bool),
+    SetStickyTimeout(u8),
+    SetModifiers(usize, u8),
+    SetRepeat(bool),
+    SetRepeatInitial(u8),
+    SetRepeatFloor(u8),
+    SetRepeatStep(u8),
+    SetSelfPowered(bool),
+    SetRemoteWakeup(bool),
+    SetMaxPower(u8),
+    ClearName,
+    PushNameChar(u8),
+    SetLayerButton(Option<usize>),
+    SetLayer(usize, KeyCode),
+    SetTapHold(usize, KeyCode),
+    SetHoldModifiers(usize, u8),
+    SetTapHoldThreshold(u8),
+    SetToggle(usize, KeyCode),
+    SetOneShot(usize, u8),
+    SetConsumer(usize, u8),
+    SetMouse(usize, MouseButton),
+    SetMouseMove(usize, MouseDirection),
+    SetMouseSpeed(u8),
+    SetKeyModifiers(usize, u8),
+    SetComboButtons(usize, u8),
+    SetCombo(usize, KeyCode),
+    SetDoubleTap(usize, KeyCode),
+    SetDoubleTapKey(usize, KeyCode),
+    SetClockwiseConsumer(u8),
+    SetCounterClockwiseConsumer(u8),
+    SetProfileButton(usize),
+    NextProfile,
+    SetCapsWordButton(usize),
+    SelectProfile(usize),
+    Compact,
+    FactoryReset,
+    SetDebounce(u8),
+    EnterBootloader,
 }
 
 impl AppCommand {
-    pub fn from_req_value(req: VendorCommand, value: KeyCode) -> Self {
+    /// Builds a command from the data of a SetReport request to the control interface, which is
+    /// the vendor command followed by its value. Per-button commands have the button, or the
+    /// combo, before the value, `SetKey` can also carry the modifiers of the key after it, and
+    /// `SetAll` carries a key per button. The rest of the report has to be zero padding.
+    pub fn from_report(data: &[u8]) -> Option<Self> {
+        let (&req, args) = data.split_first()?;
+        let req = VendorCommand::try_from(req).ok()?;
         match req {
-            VendorCommand::Set1 => AppCommand::Set1(value),
-            VendorCommand::Set2 => AppCommand::Set2(value),
-            VendorCommand::Set3 => AppCommand::Set3(value),
+            VendorCommand::SetAll => {
+                let mut layout = [KeyCode::No; NUM_BTS];
+                for (key, &code) in layout.iter_mut().zip(unpadded(args, NUM_BTS)?) {
+                    *key = KeyCode::try_from(code).ok()?;
+                }
+                Some(AppCommand::SetAll(layout))
+            }
+            // The modifiers can be left out, which clears them
+            VendorCommand::SetKey => {
+                let (button, key, modifiers) = match *args {
+                    [button, key] => (button, key, 0),
+                    _ => match *unpadded(args, 3)? {
+                        [button, key, modifiers] => (button, key, modifiers),
+                        _ => return None,
+                    },
+                };
+                match Self::from_button_value(req, button, key)? {
+                    AppCommand::SetKey(button, key, _) => {
+                        Some(AppCommand::SetKey(button, key, modifiers))
+                    }
+                    _ => None,
+                }
+            }
+            _ if req.takes_button() => match *unpadded(args, 2)? {
+                [button, value] => Self::from_button_value(req, button, value),
+                _ => None,
+            },
+            _ => match *unpadded(args, 1)? {
+                [value] => Self::from_req_value(req, value),
+                _ => None,
+            },
+        }
+    }
+
+    /// Builds a command from a vendor request and its value, returns `None` if the value isn't
+    /// valid for the request.
+    pub fn from_req_value(req: VendorCommand, value: u8) -> Option<Self> {
+        let cmd = match req {
             VendorCommand::Save => AppCommand::Save,
+            VendorCommand::SetLatencyMode => {
+                AppCommand::SetLatencyMode(LatencyMode::try_from(value).ok()?)
+            }
+            VendorCommand::SetEncoder => match value {
+                0xFF => AppCommand::SetEncoder(None),
+                first if (first as usize) + 1 < NUM_BTS => {
+                    AppCommand::SetEncoder(Some(first as usize))
+                }
+                _ => return None,
+            },
+            VendorCommand::SetClockwise => AppCommand::SetClockwise(KeyCode::try_from(value).ok()?),
+            VendorCommand::SetCounterClockwise => {
+                AppCommand::SetCounterClockwise(KeyCode::try_from(value).ok()?)
+            }
+            VendorCommand::Reboot => AppCommand::Reboot,
+            VendorCommand::SetShiftButton => match value {
+                0xFF => AppCommand::SetShiftButton(None),
+                idx if (idx as usize) < NUM_BTS => AppCommand::SetShiftButton(Some(idx as usize)),
+                _ => return None,
+            },
+            VendorCommand::SetShiftModifiers => AppCommand::SetShiftModifiers(value),
+            VendorCommand::ClearStartupSequence => AppCommand::ClearStartupSequence,
+            VendorCommand::PushStartupKey => {
+                AppCommand::PushStartupKey(KeyCode::try_from(value).ok()?)
+            }
+            VendorCommand::SetSequenceButton => match value as usize {
+                button if button < NUM_BTS => AppCommand::SetSequenceButton(button),
+                _ => return None,
+            },
+            VendorCommand::SetProfileButton => match value as usize {
+                button if button < NUM_BTS => AppCommand::SetProfileButton(button),
+                _ => return None,
+            },
+            VendorCommand::NextProfile => AppCommand::NextProfile,
+            VendorCommand::Compact => AppCommand::Compact,
+            VendorCommand::FactoryReset => AppCommand::FactoryReset,
+            VendorCommand::EnterBootloader => AppCommand::EnterBootloader,
+            VendorCommand::SetDebounce => match value {
+                1..=debounce::MAX_WINDOW => AppCommand::SetDebounce(value),
+                _ => return None,
+            },
+            VendorCommand::SelectProfile => match value as usize {
+                profile if profile < NUM_PROFILES => AppCommand::SelectProfile(profile),
+                _ => return None,
+            },
+            VendorCommand::SetCapsWordButton => match value as usize {
+                button if button < NUM_BTS => AppCommand::SetCapsWordButton(button),
+                _ => return None,
+            },
+            VendorCommand::ClearSequence => match value as usize {
+                slot if slot < NUM_SEQUENCES => AppCommand::ClearSequence(slot),
+                _ => return None,
+            },
+            VendorCommand::PushSequenceKey => {
+                AppCommand::PushSequenceKey(KeyCode::try_from(value).ok()?)
+            }
+            VendorCommand::SetQuietConfig => match value {
+                0 => AppCommand::SetQuietConfig(false),
+                1 => AppCommand::SetQuietConfig(true),
+                _ => return None,
+            },
+            VendorCommand::SetStickyShift => match value {
+                0 => AppCommand::SetStickyShift(false),
+                1 => AppCommand::SetStickyShift(true),
+                _ => return None,
+            },
+            VendorCommand::SetStickyTimeout => match value {
+                0 => return None,
+                timeout => AppCommand::SetStickyTimeout(timeout),
+            },
+            VendorCommand::SetRepeat => match value {
+                0 => AppCommand::SetRepeat(false),
+                1 => AppCommand::SetRepeat(true),
+                _ => return None,
+            },
+            VendorCommand::SetRepeatInitial if value != 0 => AppCommand::SetRepeatInitial(value),
+            VendorCommand::SetRepeatFloor if value != 0 => AppCommand::SetRepeatFloor(value),
+            VendorCommand::SetRepeatInitial | VendorCommand::SetRepeatFloor => return None,
+            VendorCommand::SetRepeatStep => AppCommand::SetRepeatStep(value),
+            VendorCommand::SetSelfPowered => match value {
+                0 => AppCommand::SetSelfPowered(false),
+                1 => AppCommand::SetSelfPowered(true),
+                _ => return None,
+            },
+            VendorCommand::SetRemoteWakeup => match value {
+                0 => AppCommand::SetRemoteWakeup(false),
+                1 => AppCommand::SetRemoteWakeup(true),
+                _ => return None,
+            },
+            VendorCommand::SetMaxPower => match value {
+                1..=UsbAttributes::MAX_POWER_LIMIT => AppCommand::SetMaxPower(value),
+                _ => return None,
+            },
+            // Answered by the USB class itself, see `Query`
+            VendorCommand::GetLayerState
+            | VendorCommand::GetInfo
+            | VendorCommand::DumpPage
+            | VendorCommand::GetPressed
+            | VendorCommand::GetName
+            | VendorCommand::ReadConfig
+            | VendorCommand::GetVersion
+            | VendorCommand::GetProtocolVersion => return None,
+            // Carries more than a single value, see `from_report`
+            VendorCommand::SetAll => return None,
+            VendorCommand::ClearName => AppCommand::ClearName,
+            VendorCommand::PushNameChar => AppCommand::PushNameChar(value),
+            VendorCommand::SetLayerButton => match value {
+                0xFF => AppCommand::SetLayerButton(None),
+                idx if (idx as usize) < NUM_BTS => AppCommand::SetLayerButton(Some(idx as usize)),
+                _ => return None,
+            },
+            VendorCommand::SetTapHoldThreshold => match value {
+                0 => return None,
+                threshold => AppCommand::SetTapHoldThreshold(threshold),
+            },
+            VendorCommand::SetClockwiseConsumer if value != 0 => {
+                AppCommand::SetClockwiseConsumer(value)
+            }
+            VendorCommand::SetCounterClockwiseConsumer if value != 0 => {
+                AppCommand::SetCounterClockwiseConsumer(value)
+            }
+            VendorCommand::SetClockwiseConsumer | VendorCommand::SetCounterClockwiseConsumer => {
+                return None
+            }
+            VendorCommand::SetMouseSpeed => match value {
+                1..=MAX_MOUSE_SPEED => AppCommand::SetMouseSpeed(value),
+                _ => return None,
+            },
+            // Followed by a button, see `from_button_value`
+            VendorCommand::SetKey
+            | VendorCommand::SetModifiers
+            | VendorCommand::SetLayer
+            | VendorCommand::SetTapHold
+            | VendorCommand::SetHoldModifiers
+            | VendorCommand::SetToggle
+            | VendorCommand::SetOneShot
+            | VendorCommand::SetConsumer
+            | VendorCommand::SetMouse
+            | VendorCommand::SetMouseMove
+            | VendorCommand::SetKeyModifiers
+            | VendorCommand::SetDoubleTap
+            | VendorCommand::SetDoubleTapKey
+            | VendorCommand::SetComboButtons
+            | VendorCommand::SetCombo => return None,
+        };
+        Some(cmd)
+    }
+
+    /// Builds a command from a per-button vendor request, see `VendorCommand::takes_button`,
+    /// returns `None` if the button or the value isn't valid for the request.
+    pub fn from_button_value(req: VendorCommand, button: u8, value: u8) -> Option<Self> {
+        let button = usize::from(button);
+        // Combos are counted on their own
+        let limit = match req {
+            VendorCommand::SetComboButtons | VendorCommand::SetCombo => MAX_COMBOS,
+            _ => NUM_BTS,
+        };
+        if button >= limit {
+            return None;
+        }
+        let cmd = match req {
+            VendorCommand::SetKey => AppCommand::SetKey(button, KeyCode::try_from(value).ok()?, 0),
+            VendorCommand::SetModifiers if value != 0 => AppCommand::SetModifiers(button, value),
+            VendorCommand::SetLayer => AppCommand::SetLayer(button, KeyCode::try_from(value).ok()?),
+            VendorCommand::SetTapHold => {
+                AppCommand::SetTapHold(button, KeyCode::try_from(value).ok()?)
+            }
+            VendorCommand::SetHoldModifiers => AppCommand::SetHoldModifiers(button, value),
+            VendorCommand::SetToggle => {
+                AppCommand::SetToggle(button, KeyCode::try_from(value).ok()?)
+            }
+            VendorCommand::SetOneShot if value != 0 => AppCommand::SetOneShot(button, value),
+            VendorCommand::SetConsumer if value != 0 => AppCommand::SetConsumer(button, value),
+            VendorCommand::SetMouse => {
+                AppCommand::SetMouse(button, MouseButton::try_from(value).ok()?)
+            }
+            VendorCommand::SetMouseMove => {
+                AppCommand::SetMouseMove(button, MouseDirection::try_from(value).ok()?)
+            }
+            VendorCommand::SetKeyModifiers => AppCommand::SetKeyModifiers(button, value),
+            VendorCommand::SetDoubleTap => {
+                AppCommand::SetDoubleTap(button, KeyCode::try_from(value).ok()?)
+            }
+            VendorCommand::SetDoubleTapKey => {
+                AppCommand::SetDoubleTapKey(button, KeyCode::try_from(value).ok()?)
+            }
+            VendorCommand::SetComboButtons if Combo::valid_buttons(value) => {
+                AppCommand::SetComboButtons(button, value)
+            }
+            VendorCommand::SetCombo => AppCommand::SetCombo(button, KeyCode::try_from(value).ok()?),
+            // Zero values and commands that only take a value, see `from_req_value`
+            _ => return None,
+        };
+        Some(cmd)
+    }
+}
+
+/// What the next GetReport of the control interface answers.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Query {
+    Status,
+    LayerState,
+    Info(InfoField),
+    /// Offset of the next bytes of the config pages.
+    Page(u16),
+    Pressed,
+    /// Offset of the next bytes of the device name.
+    Name(u8),
+    /// Offset of the next bytes of the layout in use.
+    Config(u8),
+    /// Offset of the next bytes of the firmware version.
+    Version(u8),
+    ProtocolVersion,
+}
+
+/// Version of the control interface protocol, the host tool only talks to firmware with the same
+/// one, see `VendorCommand::GetProtocolVersion`. Bump it when a command or a query changes meaning,
+/// new ones don't need it since the firmware rejects what it doesn't know.
+/// 1: first version with `GetProtocolVersion`
+/// 2: control report of `CTRL_REPORT_SIZE` bytes, checksum in its last byte, per-button commands
+///    followed by the button
+pub const PROTOCOL_VERSION: u8 = 2;
+
+/// Firmware version answered to `GetVersion`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Version {
+    pub major: u8,
+    pub minor: u8,
+    pub patch: u8,
+}
+
+impl Version {
+    pub const SIZE: usize = 3;
+
+    /// Builds the version from its parts in decimal, e.g. as given by `CARGO_PKG_VERSION_MAJOR`,
+    /// at compile time. Parts that aren't plain numbers up to 255 are 0.
+    pub const fn parse(major: &str, minor: &str, patch: &str) -> Self {
+        Self {
+            major: parse_part(major),
+            minor: parse_part(minor),
+            patch: parse_part(patch),
+        }
+    }
+
+    pub fn to_bytes(self) -> [u8; Self::SIZE] {
+        [self.major, self.minor, self.patch]
+    }
+
+    pub fn from_bytes(bytes: [u8; Self::SIZE]) -> Self {
+        Self {
+            major: bytes[0],
+            minor: bytes[1],
+            patch: bytes[2],
+        }
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+/// Decimal number of a part of `Version::parse`.
+const fn parse_part(digits: &str) -> u8 {
+    let digits = digits.as_bytes();
+    if digits.is_empty() {
+        return 0;
+    }
+    let mut value = 0u16;
+    let mut index = 0;
+    while index < digits.len() {
+        let digit = digits[index];
+        if digit < b'0' || digit > b'9' {
+            return 0;
+        }
+        value = value * 10 + (digit - b'0') as u16;
+        if value > u8::MAX as u16 {
+            return 0;
+        }
+        index += 1;
+    }
+    value as u8
+}
+
+impl Query {
+    /// Builds a query from the data of a SetReport request to the control interface, `None` if
+    /// it's an `AppCommand` instead.
+    pub fn from_report(data: &[u8]) -> Option<Self> {
+        match *unpadded(data, 2)? {
+            [req, 0] if req == VendorCommand::GetLayerState as u8 => Some(Query::LayerState),
+            [req, record] if req == VendorCommand::DumpPage as u8 => {
+                if usize::from(record) < storage::NUM_RECORDS {
+                    Some(Query::Page(
+                        storage::record_offset(usize::from(record)) as u16
+                    ))
+                } else {
+                    None
+                }
+            }
+            [req, 0] if req == VendorCommand::GetPressed as u8 => Some(Query::Pressed),
+            [req, 0] if req == VendorCommand::GetName as u8 => Some(Query::Name(0)),
+            [req, 0] if req == VendorCommand::ReadConfig as u8 => Some(Query::Config(0)),
+            [req, 0] if req == VendorCommand::GetVersion as u8 => Some(Query::Version(0)),
+            [req, 0] if req == VendorCommand::GetProtocolVersion as u8 => {
+                Some(Query::ProtocolVersion)
+            }
+            [req, field] if req == VendorCommand::GetInfo as u8 => {
+                InfoField::try_from(field).ok().map(Query::Info)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// What to do with an OUT control request to the control interface.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum CtrlOut {
+    /// Accept it, the next GetReport answers the query.
+    Query(Query),
+    /// Accept it if the command can be queued for the app, reject it otherwise.
+    Command(AppCommand),
+    Reject,
+}
+
+impl CtrlOut {
+    /// Decides from the `bRequest` and the data stage of the request, only a SetReport with a
+    /// valid checksum and a known query or command is accepted.
+    pub fn new(request: u8, data: &[u8]) -> Self {
+        if Request::new(request) != Some(Request::SetReport) {
+            return CtrlOut::Reject;
+        }
+        let report = match CheckedReport::new(data) {
+            Some(report) => report,
+            None => return CtrlOut::Reject,
+        };
+        if let Some(query) = Query::from_report(report.data()) {
+            return CtrlOut::Query(query);
         }
+        match AppCommand::from_report(report.data()) {
+            Some(cmd) => CtrlOut::Command(cmd),
+            None => CtrlOut::Reject,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Values of every vendor command as released, the host and older firmware rely on them.
+    /// New commands have to be added here, existing ones must keep their value.
+    const VENDOR_COMMANDS: &[(VendorCommand, u8)] = &[
+        (VendorCommand::Save, 4),
+        (VendorCommand::SetLatencyMode, 5),
+        (VendorCommand::SetEncoder, 6),
+        (VendorCommand::SetClockwise, 7),
+        (VendorCommand::SetCounterClockwise, 8),
+        (VendorCommand::Reboot, 9),
+        (VendorCommand::SetShiftButton, 10),
+        (VendorCommand::SetShiftModifiers, 11),
+        (VendorCommand::ClearStartupSequence, 12),
+        (VendorCommand::PushStartupKey, 13),
+        (VendorCommand::SetSequenceButton, 14),
+        (VendorCommand::ClearSequence, 15),
+        (VendorCommand::PushSequenceKey, 16),
+        (VendorCommand::SetQuietConfig, 17),
+        (VendorCommand::SetStickyShift, 18),
+        (VendorCommand::SetStickyTimeout, 19),
+        (VendorCommand::GetLayerState, 20),
+        (VendorCommand::GetInfo, 24),
+        (VendorCommand::DumpPage, 25),
+        (VendorCommand::SetRepeat, 26),
+        (VendorCommand::SetRepeatInitial, 27),
+        (VendorCommand::SetRepeatFloor, 28),
+        (VendorCommand::SetRepeatStep, 29),
+        (VendorCommand::SetSelfPowered, 30),
+        (VendorCommand::SetRemoteWakeup, 31),
+        (VendorCommand::SetMaxPower, 32),
+        (VendorCommand::GetPressed, 33),
+        (VendorCommand::ClearName, 34),
+        (VendorCommand::PushNameChar, 35),
+        (VendorCommand::GetName, 36),
+        (VendorCommand::SetLayerButton, 37),
+        (VendorCommand::SetTapHoldThreshold, 47),
+        (VendorCommand::SetMouseSpeed, 63),
+        (VendorCommand::SetClockwiseConsumer, 77),
+        (VendorCommand::SetCounterClockwiseConsumer, 78),
+        (VendorCommand::SetProfileButton, 79),
+        (VendorCommand::NextProfile, 80),
+        (VendorCommand::SetCapsWordButton, 81),
+        (VendorCommand::SelectProfile, 82),
+        (VendorCommand::ReadConfig, 83),
+        (VendorCommand::Compact, 84),
+        (VendorCommand::GetVersion, 85),
+        (VendorCommand::FactoryReset, 86),
+        (VendorCommand::SetDebounce, 87),
+        (VendorCommand::GetProtocolVersion, 88),
+        (VendorCommand::SetAll, 89),
+        (VendorCommand::EnterBootloader, 90),
+        (VendorCommand::SetKey, 91),
+        (VendorCommand::SetModifiers, 92),
+        (VendorCommand::SetLayer, 93),
+        (VendorCommand::SetTapHold, 94),
+        (VendorCommand::SetHoldModifiers, 95),
+        (VendorCommand::SetToggle, 96),
+        (VendorCommand::SetOneShot, 97),
+        (VendorCommand::SetConsumer, 98),
+        (VendorCommand::SetMouse, 99),
+        (VendorCommand::SetMouseMove, 100),
+        (VendorCommand::SetKeyModifiers, 101),
+        (VendorCommand::SetDoubleTap, 102),
+        (VendorCommand::SetDoubleTapKey, 103),
+        (VendorCommand::SetComboButtons, 104),
+        (VendorCommand::SetCombo, 105),
+    ];
+
+    /// Values of the commands with one variant per button, replaced by the ones taking the button.
+    /// They must not be reused, older hosts still send them.
+    const RETIRED_COMMANDS: &[u8] = &[
+        1, 2, 3, 21, 22, 23, 38, 39, 40, 41, 42, 43, 44, 45, 46, 48, 49, 50, 51, 52, 53, 54, 55,
+        56, 57, 58, 59, 60, 61, 62, 64, 65, 66, 67, 68, 69, 70, 71, 72, 73, 74, 75, 76,
+    ];
+
+    #[test]
+    fn vendor_command_values() {
+        for &(command, value) in VENDOR_COMMANDS {
+            assert_eq!(command as u8, value, "{:?} changed value", command);
+            assert_eq!(VendorCommand::try_from(value), Ok(command));
+        }
+        // Every value the firmware accepts is listed, so none can go unchecked
+        for value in 0..=u8::MAX {
+            if let Ok(command) = VendorCommand::try_from(value) {
+                assert!(
+                    VENDOR_COMMANDS.contains(&(command, value)),
+                    "{:?} isn't listed",
+                    command
+                );
+            }
+        }
+        for &value in RETIRED_COMMANDS {
+            assert!(VendorCommand::try_from(value).is_err(), "{} reused", value);
+        }
+    }
+
+    #[test]
+    fn report_response_length() {
+        let report = [1, 2, 3, 4, 5, 6, 7, 8];
+        assert_eq!(report_response(&report, 8), &report);
+        // Longer than the report, the host gets a short packet
+        assert_eq!(report_response(&report, 64), &report);
+        assert_eq!(report_response(&report, 2), &[1, 2]);
+        assert_eq!(report_response(&report, 0), &[]);
+    }
+
+    #[test]
+    fn protocol_values() {
+        assert_eq!(Protocol::new(0), Some(Protocol::Boot));
+        assert_eq!(Protocol::new(1), Some(Protocol::Report));
+        assert_eq!(Protocol::new(2), None);
+        assert_eq!(Protocol::Report as u8, 1);
+    }
+
+    #[test]
+    fn valid_report() {
+        let data = [VendorCommand::SetKey as u8, 1, KeyCode::Escape as u8];
+        assert_eq!(
+            AppCommand::from_report(&data),
+            Some(AppCommand::SetKey(1, KeyCode::Escape, 0))
+        );
+        let data = [VendorCommand::SetEncoder as u8, 0xFF];
+        assert_eq!(
+            AppCommand::from_report(&data),
+            Some(AppCommand::SetEncoder(None))
+        );
+        // The modifiers can come with the key
+        let shift = KeyCode::LShift.as_modifier_bit();
+        let data = [VendorCommand::SetKey as u8, 2, KeyCode::A as u8, shift];
+        assert_eq!(
+            AppCommand::from_report(&data),
+            Some(AppCommand::SetKey(2, KeyCode::A, shift))
+        );
+        let data = [VendorCommand::SetEncoder as u8, 0xFF, shift];
+        assert_eq!(AppCommand::from_report(&data), None);
+        // Combos are counted on their own
+        let data = [VendorCommand::SetCombo as u8, 1, KeyCode::A as u8];
+        assert_eq!(
+            AppCommand::from_report(&data),
+            Some(AppCommand::SetCombo(1, KeyCode::A))
+        );
+    }
+
+    #[test]
+    fn checksum() {
+        let report = [VendorCommand::SetKey as u8, 1, KeyCode::Escape as u8];
+        let checked = with_checksum(&report).unwrap();
+        assert_eq!(checked.len(), CTRL_REPORT_SIZE);
+        let data = CheckedReport::new(&checked).unwrap();
+        assert_eq!(data.data()[..3], report);
+        assert_eq!(
+            AppCommand::from_report(data.data()),
+            Some(AppCommand::SetKey(1, KeyCode::Escape, 0))
+        );
+        // Without the checksum it's taken as it is
+        assert_eq!(CheckedReport::new(&report).unwrap().data(), &report);
+
+        // A flipped bit anywhere is caught
+        for bit in 0..checked.len() * 8 {
+            let mut corrupted = checked;
+            corrupted[bit / 8] ^= 1 << (bit % 8);
+            // Except the flag itself, the report is then read as one without a checksum
+            if bit == 7 {
+                continue;
+            }
+            assert_eq!(CheckedReport::new(&corrupted), None, "bit {}", bit);
+        }
+
+        assert_eq!(CheckedReport::new(&[]), None);
+        assert_eq!(CheckedReport::new(&[CHECKSUM_FLAG]), None);
+        assert_eq!(CheckedReport::new(&[0; CTRL_REPORT_SIZE + 1]), None);
+        assert_eq!(with_checksum(&[]), None);
+        assert_eq!(with_checksum(&[0; MAX_REPORT_LEN + 1]), None);
+    }
+
+    #[test]
+    fn ctrl_out() {
+        let set_report = Request::SetReport as u8;
+        let checked =
+            with_checksum(&[VendorCommand::SetKey as u8, 1, KeyCode::Escape as u8]).unwrap();
+        assert_eq!(
+            CtrlOut::new(set_report, &checked),
+            CtrlOut::Command(AppCommand::SetKey(1, KeyCode::Escape, 0))
+        );
+        let checked = with_checksum(&[VendorCommand::GetPressed as u8, 0]).unwrap();
+        assert_eq!(
+            CtrlOut::new(set_report, &checked),
+            CtrlOut::Query(Query::Pressed)
+        );
+
+        // Only SetReport carries commands
+        assert_eq!(
+            CtrlOut::new(Request::SetIdle as u8, &checked),
+            CtrlOut::Reject
+        );
+        let mut corrupted = checked;
+        corrupted[1] ^= 1;
+        assert_eq!(CtrlOut::new(set_report, &corrupted), CtrlOut::Reject);
+        assert_eq!(CtrlOut::new(set_report, &[]), CtrlOut::Reject);
+        let unknown = with_checksum(&[0x7F, 0]).unwrap();
+        assert_eq!(CtrlOut::new(set_report, &unknown), CtrlOut::Reject);
+    }
+
+    #[test]
+    fn set_all() {
+        use crate::{key_code::KbHidReport, matrix::Matrix};
+        let layout = [KeyCode::A, KeyCode::Escape, KeyCode::LCtrl];
+        let mut data = [VendorCommand::SetAll as u8, 0, 0, 0, KeyCode::B as u8];
+        for (byte, &key) in data[1..].iter_mut().zip(layout.iter()) {
+            *byte = key as u8;
+        }
+        let command = AppCommand::from_report(&data[..=NUM_BTS]).unwrap();
+        assert_eq!(command, AppCommand::SetAll(layout));
+
+        let mut matrix = Matrix::new();
+        let shift = KeyCode::LShift.as_modifier_bit();
+        matrix.update_layout(AppCommand::SetKeyModifiers(1, shift));
+        matrix.update_layout(command);
+        assert_eq!(
+            matrix.layout(),
+            [layout[0].into(), layout[1].into(), layout[2].into()]
+        );
+        // The modifiers were cleared
+        assert_eq!(
+            matrix.update(&[false, true, false]),
+            KbHidReport::from_keys(0, &[KeyCode::Escape])
+        );
+
+        // One key per button, all of them valid
+        assert_eq!(AppCommand::from_report(&data[..NUM_BTS]), None);
+        assert_eq!(AppCommand::from_report(&data), None);
+        data[1] = 0xA5;
+        assert_eq!(AppCommand::from_report(&data[..=NUM_BTS]), None);
+    }
+
+    #[test]
+    fn wrong_length() {
+        assert_eq!(AppCommand::from_report(&[]), None);
+        assert_eq!(AppCommand::from_report(&[VendorCommand::Save as u8]), None);
+        // Report ID left in by the host
+        let data = [0, VendorCommand::Save as u8, 0];
+        assert_eq!(AppCommand::from_report(&data), None);
+        // Without the value after the button
+        let data = [VendorCommand::SetMouse as u8, 0];
+        assert_eq!(AppCommand::from_report(&data), None);
+    }
+
+    #[test]
+    fn padded_report() {
+        let mut data = [0; CTRL_REPORT_SIZE];
+        data[..3].copy_from_slice(&[VendorCommand::SetKey as u8, 1, KeyCode::Escape as u8]);
+        assert_eq!(
+            AppCommand::from_report(&data),
+            Some(AppCommand::SetKey(1, KeyCode::Escape, 0))
+        );
+        data[..3].copy_from_slice(&[VendorCommand::SetMouse as u8, 0, MouseButton::Left as u8]);
+        assert_eq!(
+            AppCommand::from_report(&data),
+            Some(AppCommand::SetMouse(0, MouseButton::Left))
+        );
+        data[..3].copy_from_slice(&[VendorCommand::SetEncoder as u8, 0xFF, 0]);
+        assert_eq!(
+            AppCommand::from_report(&data),
+            Some(AppCommand::SetEncoder(None))
+        );
+        data[..3].copy_from_slice(&[VendorCommand::GetName as u8, 0, 0]);
+        assert_eq!(Query::from_report(&data), Some(Query::Name(0)));
+        assert_eq!(AppCommand::from_report(&data), None);
+
+        // Every key of `SetAll` counts, even `No`
+        let mut data = [0; CTRL_REPORT_SIZE];
+        data[0] = VendorCommand::SetAll as u8;
+        assert_eq!(
+            AppCommand::from_report(&data),
+            Some(AppCommand::SetAll([KeyCode::No; NUM_BTS]))
+        );
+
+        // Only zeros are padding
+        data[CTRL_REPORT_SIZE - 1] = 1;
+        assert_eq!(AppCommand::from_report(&data), None);
+        let data = [VendorCommand::GetName as u8, 0, 0, 1];
+        assert_eq!(Query::from_report(&data), None);
+        let data = [VendorCommand::SetKey as u8, 1, KeyCode::Escape as u8, 0, 1];
+        assert_eq!(AppCommand::from_report(&data), None);
+    }
+
+    #[test]
+    fn unknown_command() {
+        assert_eq!(AppCommand::from_report(&[0, 0]), None);
+        assert_eq!(AppCommand::from_report(&[0xFF, 0]), None);
+    }
+
+    #[test]
+    fn layer_state_query() {
+        let data = [VendorCommand::GetLayerState as u8, 0];
+        assert_eq!(Query::from_report(&data), Some(Query::LayerState));
+        assert_eq!(AppCommand::from_report(&data), None);
+        assert_eq!(
+            Query::from_report(&[VendorCommand::GetLayerState as u8, 1]),
+            None
+        );
+        assert_eq!(Query::from_report(&[VendorCommand::Save as u8, 0]), None);
+
+        let data = [VendorCommand::GetInfo as u8, InfoField::ScanRate as u8];
+        assert_eq!(
+            Query::from_report(&data),
+            Some(Query::Info(InfoField::ScanRate))
+        );
+        assert_eq!(
+            Query::from_report(&[VendorCommand::GetInfo as u8, 0xFF]),
+            None
+        );
+        let data = [VendorCommand::DumpPage as u8, 0];
+        assert_eq!(Query::from_report(&data), Some(Query::Page(0)));
+        let data = [
+            VendorCommand::DumpPage as u8,
+            storage::CONFIGS_IN_PAGE as u8,
+        ];
+        assert_eq!(
+            Query::from_report(&data),
+            Some(Query::Page(storage::PAGE_SIZE as u16))
+        );
+        let data = [VendorCommand::DumpPage as u8, storage::NUM_RECORDS as u8];
+        assert_eq!(Query::from_report(&data), None);
+        let data = [VendorCommand::GetPressed as u8, 0];
+        assert_eq!(Query::from_report(&data), Some(Query::Pressed));
+        let data = [VendorCommand::ReadConfig as u8, 0];
+        assert_eq!(Query::from_report(&data), Some(Query::Config(0)));
+        assert_eq!(AppCommand::from_report(&data), None);
+        let data = [VendorCommand::GetVersion as u8, 0];
+        assert_eq!(Query::from_report(&data), Some(Query::Version(0)));
+        assert_eq!(AppCommand::from_report(&data), None);
+        let data = [VendorCommand::GetProtocolVersion as u8, 0];
+        assert_eq!(Query::from_report(&data), Some(Query::ProtocolVersion));
+        assert_eq!(AppCommand::from_report(&data), None);
+    }
+
+    #[test]
+    fn invalid_value() {
+        let data = [VendorCommand::SetKey as u8, 0, 0xA5];
+        assert_eq!(AppCommand::from_report(&data), None);
+        let data = [VendorCommand::SetKey as u8, NUM_BTS as u8, KeyCode::A as u8];
+        assert_eq!(AppCommand::from_report(&data), None);
+        let data = [
+            VendorCommand::SetCombo as u8,
+            MAX_COMBOS as u8,
+            KeyCode::A as u8,
+        ];
+        assert_eq!(AppCommand::from_report(&data), None);
+        let data = [VendorCommand::SetLatencyMode as u8, 0xFF];
+        assert_eq!(AppCommand::from_report(&data), None);
+        // Channel B would be out of range
+        let data = [VendorCommand::SetEncoder as u8, NUM_BTS as u8 - 1];
+        assert_eq!(AppCommand::from_report(&data), None);
+        let data = [VendorCommand::SetShiftButton as u8, NUM_BTS as u8];
+        assert_eq!(AppCommand::from_report(&data), None);
+        let data = [VendorCommand::SetLayerButton as u8, NUM_BTS as u8];
+        assert_eq!(AppCommand::from_report(&data), None);
+        let data = [VendorCommand::SelectProfile as u8, NUM_PROFILES as u8];
+        assert_eq!(AppCommand::from_report(&data), None);
+        let data = [VendorCommand::SetDebounce as u8, 0];
+        assert_eq!(AppCommand::from_report(&data), None);
+        let data = [VendorCommand::SetDebounce as u8, debounce::MAX_WINDOW + 1];
+        assert_eq!(AppCommand::from_report(&data), None);
+        // A modifier button without modifiers
+        let data = [VendorCommand::SetModifiers as u8, 1, 0];
+        assert_eq!(AppCommand::from_report(&data), None);
+    }
+
+    #[test]
+    fn action_reports() {
+        use crate::{consumer::ConsumerCode, matrix::Matrix};
+        let actions = [
+            Action::Key(KeyCode::Escape),
+            Action::Consumer(ConsumerCode::PlayPause as u8),
+            Action::Mouse(MouseButton::Right),
+            Action::Sequence(2),
+            Action::Modifiers(KeyCode::LCtrl.as_modifier_bit()),
+            Action::TapHold(KeyCode::Space),
+            Action::Toggle(KeyCode::LShift),
+            Action::OneShot(KeyCode::RAlt.as_modifier_bit()),
+            Action::MouseMove(MouseDirection::Up),
+            Action::DoubleTap(KeyCode::A),
+            Action::NextProfile,
+            Action::CapsWord,
+        ];
+        for &action in &actions {
+            let mut matrix = Matrix::new();
+            let report = set_action_report(2, action).unwrap();
+            matrix.update_layout(AppCommand::from_report(&report).unwrap());
+            assert_eq!(matrix.layout()[2], action);
+        }
+        assert_eq!(set_action_report(NUM_BTS, Action::NONE), None);
+    }
+
+    #[test]
+    fn version() {
+        let version = Version::parse("1", "12", "255");
+        assert_eq!(
+            version,
+            Version {
+                major: 1,
+                minor: 12,
+                patch: 255
+            }
+        );
+        assert_eq!(Version::from_bytes(version.to_bytes()), version);
+        #[cfg(feature = "host")]
+        assert_eq!(version.to_string(), "1.12.255");
+        // Not something a crate version has, but shouldn't give a wrong number
+        assert_eq!(Version::parse("256", "1a", ""), Version::from_bytes([0; 3]));
     }
 }
@@ -0,0 +1,52 @@
+//! Scrollable view of long text, like the config page dump.
+use crate::app::Term;
+use anyhow::Result;
+use crossterm::{
+    cursor,
+    event::{read, Event, KeyCode as TermKey, KeyEvent},
+    queue, style,
+    terminal::{self, ClearType},
+};
+use std::io::Write;
+
+const PAGER_HELP: &str = "'up'/'down', 'pgup'/'pgdn' - scroll, 'esc' or 'q' - quit";
+
+/// Shows `lines` until the user quits.
+pub fn show(lines: &[String]) -> Result<()> {
+    let mut term = Term::new()?;
+    let mut top = 0;
+    loop {
+        // One line is taken by the help
+        let (_, rows) = terminal::size()?;
+        let height = (rows as usize).saturating_sub(1).max(1);
+        let last_top = lines.len().saturating_sub(height);
+        top = top.min(last_top);
+
+        queue!(
+            term,
+            style::ResetColor,
+            terminal::Clear(ClearType::All),
+            cursor::Hide,
+            cursor::MoveTo(0, 0),
+            style::Print(PAGER_HELP),
+            cursor::MoveToNextLine(1)
+        )?;
+        for line in lines.iter().skip(top).take(height) {
+            queue!(term, style::Print(line), cursor::MoveToNextLine(1))?;
+        }
+        term.flush()?;
+
+        if let Event::Key(KeyEvent { code, .. }) = read()? {
+            match code {
+                TermKey::Esc | TermKey::Char('q') => return Ok(()),
+                TermKey::Up => top = top.saturating_sub(1),
+                TermKey::Down => top += 1,
+                TermKey::PageUp => top = top.saturating_sub(height),
+                TermKey::PageDown => top += height,
+                TermKey::Home => top = 0,
+                TermKey::End => top = last_top,
+                _ => {}
+            }
+        }
+    }
+}
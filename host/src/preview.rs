@@ -0,0 +1,87 @@
+//! Live view of the keys the device sends, read from its keyboard interface, to check the wiring
+//! and the layout before saving.
+use hidapi::{HidApi, HidDevice};
+use keylib::{key_code::KeyCode, PID, VID};
+use strum::IntoEnumIterator;
+
+/// The keyboard interface comes first, before `CTRL_INTERFACE`.
+const KEYBOARD_INTERFACE: i32 = 0;
+/// Size of the boot keyboard report, firmware built with `nkro` sends a bitmap instead.
+const BOOT_REPORT_SIZE: usize = 8;
+/// Byte of the modifiers in the NKRO bitmap, where `KeyCode::LCtrl` would be.
+const NKRO_MODIFIERS: usize = KeyCode::LCtrl as usize / 8;
+
+pub struct Preview {
+    keyboard: HidDevice,
+    modifiers: u8,
+    keys: Vec<KeyCode>,
+}
+
+impl Preview {
+    /// Opens the keyboard interface of the device with `serial`, or of any device if it has
+    /// none. `None` if it can't be opened, e.g. Windows doesn't let applications read keyboards.
+    pub fn open(serial: Option<&str>) -> Option<Self> {
+        let context = HidApi::new().ok()?;
+        let device = context.device_list().find(|device| {
+            device.vendor_id() == VID
+                && device.product_id() == PID
+                && device.interface_number() == KEYBOARD_INTERFACE
+                && (serial.is_none() || device.serial_number() == serial)
+        })?;
+        let keyboard = device.open_device(&context).ok()?;
+        keyboard.set_blocking_mode(false).ok()?;
+        Some(Self {
+            keyboard,
+            modifiers: 0,
+            keys: Vec::new(),
+        })
+    }
+
+    /// Reads the reports sent since the last call, without blocking. Returns `false` once the
+    /// interface can't be read anymore, e.g. after the device was unplugged.
+    pub fn update(&mut self) -> bool {
+        let mut buf = [0; 64];
+        loop {
+            match self.keyboard.read(&mut buf) {
+                Ok(0) => return true,
+                Ok(len) => self.decode(&buf[..len]),
+                Err(_) => return false,
+            }
+        }
+    }
+
+    /// Keys held in the last report, modifiers first, e.g. "LCtrl + A".
+    pub fn keys(&self) -> String {
+        let names: Vec<String> = KeyCode::iter()
+            .filter(|key| key.is_modifier() && self.modifiers & key.as_modifier_bit() != 0)
+            .chain(self.keys.iter().copied())
+            .map(|key| format!("{:?}", key))
+            .collect();
+        if names.is_empty() {
+            String::from("nothing")
+        } else {
+            names.join(" + ")
+        }
+    }
+
+    fn decode(&mut self, report: &[u8]) {
+        let codes: Vec<u8> = if report.len() == BOOT_REPORT_SIZE {
+            self.modifiers = report[0];
+            report[2..]
+                .iter()
+                .copied()
+                .filter(|&code| code != 0)
+                .collect()
+        } else {
+            self.modifiers = report.get(NKRO_MODIFIERS).copied().unwrap_or(0);
+            (0..report.len() * 8)
+                .filter(|&bit| bit / 8 != NKRO_MODIFIERS && report[bit / 8] & 1 << (bit % 8) != 0)
+                .map(|bit| bit as u8)
+                .collect()
+        };
+        self.keys = codes
+            .into_iter()
+            .filter_map(|code| KeyCode::iter().find(|&key| key as u8 == code))
+            .collect();
+    }
+}
@@ -0,0 +1,201 @@
+//! Library of named layouts kept on disk, independent of what's stored in the device.
+//!
+//! Each profile is a `<name>.profile` text file holding the key names of every button, comma
+//! separated and in order, e.g. `escape,enter,space`. The directory is `$KEYKEY_PROFILES` if set,
+//! otherwise `~/.config/keykey/profiles`.
+use crate::parse_keys;
+use anyhow::{anyhow, Context, Result};
+use crossterm::{
+    cursor, queue,
+    style::{self, Colorize},
+    terminal::{self, ClearType},
+};
+use keylib::{key_code::KeyCode, NUM_BTS};
+use std::{
+    env, fs,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+const EXTENSION: &str = "profile";
+const PICKER_HELP: &str = "'enter' - apply and save to the device, 'd' - delete, 'esc' - return";
+
+pub struct Profiles {
+    dir: PathBuf,
+}
+
+impl Profiles {
+    /// Opens the profiles directory, creating it if needed.
+    pub fn open() -> Result<Self> {
+        let dir = match env::var_os("KEYKEY_PROFILES") {
+            Some(dir) => PathBuf::from(dir),
+            None => env::var_os("HOME")
+                .map(|home| Path::new(&home).join(".config/keykey/profiles"))
+                .ok_or_else(|| anyhow!("Couldn't find the home directory, set KEYKEY_PROFILES"))?,
+        };
+        fs::create_dir_all(&dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+        Ok(Self { dir })
+    }
+
+    /// Names of every profile, sorted.
+    pub fn list(&self) -> Result<Vec<String>> {
+        let entries = fs::read_dir(&self.dir)
+            .with_context(|| format!("Failed to read {}", self.dir.display()))?;
+        let mut names = Vec::new();
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == EXTENSION) {
+                if let Some(name) = path.file_stem().and_then(|stem| stem.to_str()) {
+                    names.push(String::from(name));
+                }
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    pub fn load(&self, name: &str) -> Result<[KeyCode; NUM_BTS]> {
+        let path = self.path(name)?;
+        let text = fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read profile {}", name))?;
+        let keys = parse_keys(text.trim())
+            .with_context(|| format!("Invalid profile {}", path.display()))?;
+        if keys.len() != NUM_BTS {
+            return Err(anyhow!(
+                "Invalid profile {}: expected {} keys, found {}",
+                path.display(),
+                NUM_BTS,
+                keys.len()
+            ));
+        }
+        let mut layout = [KeyCode::No; NUM_BTS];
+        layout.copy_from_slice(&keys);
+        Ok(layout)
+    }
+
+    pub fn create(&self, name: &str, keys: &[KeyCode]) -> Result<()> {
+        if keys.len() != NUM_BTS {
+            return Err(anyhow!("A profile needs exactly {} keys", NUM_BTS));
+        }
+        let path = self.path(name)?;
+        if path.exists() {
+            return Err(anyhow!("Profile {} already exists", name));
+        }
+        let text = keys
+            .iter()
+            .map(|key| key.as_ref())
+            .collect::<Vec<_>>()
+            .join(",");
+        fs::write(&path, text + "\n").with_context(|| format!("Failed to write {}", path.display()))
+    }
+
+    pub fn rename(&self, old: &str, new: &str) -> Result<()> {
+        let from = self.existing(old)?;
+        let to = self.path(new)?;
+        if to.exists() {
+            return Err(anyhow!("Profile {} already exists", new));
+        }
+        fs::rename(&from, &to).with_context(|| format!("Failed to rename profile {}", old))
+    }
+
+    pub fn delete(&self, name: &str) -> Result<()> {
+        let path = self.existing(name)?;
+        fs::remove_file(&path).with_context(|| format!("Failed to delete profile {}", name))
+    }
+
+    fn existing(&self, name: &str) -> Result<PathBuf> {
+        let path = self.path(name)?;
+        if path.exists() {
+            Ok(path)
+        } else {
+            Err(anyhow!("Profile {} not found", name))
+        }
+    }
+
+    /// Only plain names are accepted, so a profile can't point outside of the directory.
+    fn path(&self, name: &str) -> Result<PathBuf> {
+        let valid = !name.is_empty()
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if valid {
+            Ok(self.dir.join(name).with_extension(EXTENSION))
+        } else {
+            Err(anyhow!(
+                "Invalid profile name: {}, use letters, digits, '-' and '_'",
+                name
+            ))
+        }
+    }
+}
+
+/// Profile selection screen of the interactive tool.
+pub struct Picker {
+    names: Vec<String>,
+    current_line: usize,
+    /// Outcome of the last action, like an error loading a profile.
+    pub message: Option<String>,
+}
+
+impl Picker {
+    pub fn new(names: Vec<String>) -> Self {
+        Self {
+            names,
+            current_line: 0,
+            message: None,
+        }
+    }
+
+    pub fn selected(&self) -> Option<&str> {
+        self.names.get(self.current_line).map(String::as_str)
+    }
+
+    pub fn up(&mut self) {
+        self.current_line = self.current_line.saturating_sub(1);
+    }
+
+    pub fn down(&mut self) {
+        if self.current_line + 1 < self.names.len() {
+            self.current_line += 1;
+        }
+    }
+
+    /// Replaces the list, e.g. after a profile was deleted, keeping the cursor in range.
+    pub fn set_names(&mut self, names: Vec<String>) {
+        self.names = names;
+        if self.current_line + 1 > self.names.len() {
+            self.current_line = self.names.len().saturating_sub(1);
+        }
+    }
+
+    pub fn render(&self, w: &mut impl Write) -> Result<()> {
+        queue!(
+            w,
+            style::ResetColor,
+            terminal::Clear(ClearType::All),
+            cursor::MoveTo(0, 0),
+            style::Print(PICKER_HELP),
+            cursor::MoveToNextLine(2),
+        )?;
+        if self.names.is_empty() {
+            queue!(
+                w,
+                style::Print("No profiles yet, create one with `keyconfig profile create`"),
+                cursor::MoveToNextLine(1)
+            )?;
+        }
+        for (index, name) in self.names.iter().enumerate() {
+            if index == self.current_line {
+                queue!(w, style::Print(name.as_str().black().on_yellow()))?;
+            } else {
+                queue!(w, style::Print(name))?;
+            }
+            queue!(w, cursor::MoveToNextLine(1))?;
+        }
+        if let Some(message) = &self.message {
+            queue!(w, cursor::MoveToNextLine(1), style::Print(message))?;
+        }
+        w.flush()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,57 @@
+//! Pauses key output while the host is configuring the device, so the configuration session
+//! doesn't type into the user's terminal.
+
+/// Counts scans since the last configuration command.
+#[derive(Debug, Clone)]
+pub struct QuietTimer {
+    timeout: u16,
+    remaining: u16,
+}
+
+impl QuietTimer {
+    /// Output resumes `timeout` scans after the last command.
+    pub const fn new(timeout: u16) -> Self {
+        Self {
+            timeout,
+            remaining: 0,
+        }
+    }
+
+    /// Should be called for every configuration command received.
+    pub fn command(&mut self) {
+        self.remaining = self.timeout;
+    }
+
+    /// Should be called once per scan, returns `true` while key output should be paused.
+    pub fn tick(&mut self) -> bool {
+        if self.remaining > 0 {
+            self.remaining -= 1;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pauses_while_configuring() {
+        let mut timer = QuietTimer::new(3);
+        assert!(!timer.tick());
+
+        timer.command();
+        assert!(timer.tick());
+        assert!(timer.tick());
+        // Another command restarts the timeout
+        timer.command();
+        assert!(timer.tick());
+        assert!(timer.tick());
+        assert!(timer.tick());
+        // Idle for long enough
+        assert!(!timer.tick());
+        assert!(!timer.tick());
+    }
+}
@@ -0,0 +1,190 @@
+//! Auto-repeat for held buttons, with an interval that speeds up the longer they are held.
+//!
+//! The keys are released for a moment every interval, so the host sees a new press. Modifiers
+//! stay pressed, only the keys are repeated. The interval shrinks linearly: it starts at
+//! `initial`, every repeat takes `step` off it, down to `floor`. The release isn't part of the
+//! interval.
+use crate::{key_code::KbHidReport, matrix::TAP_SCANS, SCAN_HZ};
+
+/// How long the keys are released on a repeat, like a tap.
+const RELEASE_SCANS: u16 = TAP_SCANS as u16;
+
+/// Auto-repeat parameters, the times are in 10 ms units.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RepeatConfig {
+    pub enabled: bool,
+    /// Time until the first repeat.
+    pub initial: u8,
+    /// Shortest time between repeats, clamped to `initial`.
+    pub floor: u8,
+    /// Time taken off the interval after every repeat, 0 repeats at a constant rate.
+    pub step: u8,
+}
+
+impl RepeatConfig {
+    /// Number of bytes used to store the config.
+    pub const SIZE: usize = 4;
+
+    pub const fn new() -> Self {
+        Self {
+            enabled: false,
+            initial: 50,
+            floor: 3,
+            step: 5,
+        }
+    }
+
+    pub fn to_bytes(self) -> [u8; Self::SIZE] {
+        [self.enabled as u8, self.initial, self.floor, self.step]
+    }
+
+    pub fn from_bytes(bytes: [u8; Self::SIZE]) -> Option<Self> {
+        let enabled = match bytes[0] {
+            0 => false,
+            1 => true,
+            _ => return None,
+        };
+        Some(Self {
+            enabled,
+            initial: bytes[1],
+            floor: bytes[2],
+            step: bytes[3],
+        })
+    }
+}
+
+fn scans(units: u8) -> u16 {
+    (u32::from(units) * SCAN_HZ / 100).max(1) as u16
+}
+
+/// Tracks how long the same report has been held.
+#[derive(Debug, Clone)]
+pub struct Repeater {
+    held: KbHidReport,
+    /// Scans until the next repeat.
+    remaining: u16,
+    /// Current interval, in 10 ms units.
+    interval: u8,
+    /// Scans left in the current release.
+    releasing: u16,
+}
+
+impl Repeater {
+    pub const fn new() -> Self {
+        Self {
+            held: KbHidReport::new(),
+            remaining: 0,
+            interval: 0,
+            releasing: 0,
+        }
+    }
+
+    /// Should be called once per scan with the report of the buttons, returns the report to send.
+    pub fn update(&mut self, config: &RepeatConfig, report: KbHidReport) -> KbHidReport {
+        if !config.enabled || !report.has_keys() || report != self.held {
+            // Released or changed, start over
+            self.interval = config.initial;
+            self.remaining = scans(self.interval);
+            self.releasing = 0;
+            self.held = report.clone();
+            return report;
+        }
+
+        if self.releasing > 0 {
+            self.releasing -= 1;
+        } else {
+            self.remaining -= 1;
+            if self.remaining > 0 {
+                return report;
+            }
+            let floor = config.floor.min(config.initial);
+            self.interval = self.interval.saturating_sub(config.step).max(floor);
+            // Held for the whole interval after the release, the scan reaching zero releases again
+            self.remaining = scans(self.interval) + 1;
+            // This scan is the first one of the release
+            self.releasing = RELEASE_SCANS - 1;
+        }
+
+        let mut released = KbHidReport::new();
+        released.add_modifiers(report.modifiers());
+        released
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::key_code::KeyCode;
+
+    fn held() -> KbHidReport {
+        KbHidReport::from_keys(0, &[KeyCode::LShift, KeyCode::VolUp])
+    }
+
+    /// Scans between the starts of the releases of a sustained hold.
+    fn intervals<const N: usize>(repeater: &mut Repeater, config: &RepeatConfig) -> [u16; N] {
+        let mut intervals = [0; N];
+        let mut count = 0;
+        let mut scans = 0;
+        let mut was_released = false;
+        while count < N {
+            scans += 1;
+            let report = repeater.update(config, held());
+            let released = report != held();
+            if released {
+                assert_eq!(report.modifiers(), KeyCode::LShift.as_modifier_bit());
+                assert!(!report.has_keys());
+                if !was_released {
+                    intervals[count] = scans;
+                    count += 1;
+                    scans = 0;
+                }
+            }
+            was_released = released;
+        }
+        intervals
+    }
+
+    #[test]
+    fn accelerates_to_floor() {
+        let config = RepeatConfig {
+            enabled: true,
+            initial: 10,
+            floor: 4,
+            step: 3,
+        };
+        let mut repeater = Repeater::new();
+        // The first call only starts the hold
+        assert_eq!(repeater.update(&config, held()), held());
+        let per_unit = SCAN_HZ as u16 / 100;
+        // Every repeat after the first also waits for the release of the previous one
+        let expected = [10, 2 + 7, 2 + 4, 2 + 4].map(|units| units * per_unit);
+        assert_eq!(intervals(&mut repeater, &config), expected);
+
+        // Released, the next hold starts slow again
+        assert_eq!(
+            repeater.update(&config, KbHidReport::new()),
+            KbHidReport::new()
+        );
+        assert_eq!(repeater.update(&config, held()), held());
+        assert_eq!(intervals(&mut repeater, &config), [10 * per_unit]);
+    }
+
+    #[test]
+    fn disabled() {
+        let config = RepeatConfig::new();
+        let mut repeater = Repeater::new();
+        for _ in 0..1000 {
+            assert_eq!(repeater.update(&config, held()), held());
+        }
+    }
+
+    #[test]
+    fn config_bytes() {
+        let config = RepeatConfig {
+            enabled: true,
+            ..RepeatConfig::new()
+        };
+        assert_eq!(RepeatConfig::from_bytes(config.to_bytes()), Some(config));
+        assert_eq!(RepeatConfig::from_bytes([2, 50, 3, 5]), None);
+    }
+}
@@ -0,0 +1,79 @@
+//! Finds the physical button the user means by pressing it, for the host's press to select flow.
+use crate::NUM_BTS;
+
+/// Packs the state of the buttons in a mask, bit `n` is set while button `n` is pressed.
+pub fn pressed_mask(pressed: &[bool; NUM_BTS]) -> u8 {
+    pressed
+        .iter()
+        .enumerate()
+        .filter(|(_, &pressed)| pressed)
+        .fold(0, |mask, (idx, _)| mask | 1 << idx)
+}
+
+/// Picks a button once it's pressed alone and released, presses of several buttons at once are
+/// ignored until they're all released.
+#[derive(Debug, Clone, Default)]
+pub struct ButtonSelector {
+    candidate: Option<usize>,
+    /// More than one button was held, nothing is picked until all of them are released.
+    ambiguous: bool,
+}
+
+impl ButtonSelector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Feeds the last mask read from the device, returns the button once it's picked.
+    pub fn update(&mut self, mask: u8) -> Option<usize> {
+        match mask.count_ones() {
+            0 => {
+                self.ambiguous = false;
+                return self.candidate.take();
+            }
+            1 if !self.ambiguous => self.candidate = Some(mask.trailing_zeros() as usize),
+            1 => {}
+            _ => {
+                self.ambiguous = true;
+                self.candidate = None;
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mask() {
+        assert_eq!(pressed_mask(&[false; NUM_BTS]), 0);
+        assert_eq!(pressed_mask(&[true, false, true]), 0b101);
+    }
+
+    #[test]
+    fn single_press() {
+        let mut selector = ButtonSelector::new();
+        assert_eq!(selector.update(0), None);
+        assert_eq!(selector.update(0b010), None);
+        assert_eq!(selector.update(0b010), None);
+        // Picked on release
+        assert_eq!(selector.update(0), Some(1));
+        assert_eq!(selector.update(0), None);
+    }
+
+    #[test]
+    fn simultaneous_presses() {
+        let mut selector = ButtonSelector::new();
+        selector.update(0b001);
+        selector.update(0b011);
+        // Still ambiguous when one of them is released first
+        assert_eq!(selector.update(0b010), None);
+        assert_eq!(selector.update(0), None);
+
+        // Isolated afterwards
+        selector.update(0b100);
+        assert_eq!(selector.update(0), Some(2));
+    }
+}
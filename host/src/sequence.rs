@@ -0,0 +1,266 @@
+//! Key sequences typed by the device on its own, like the optional one played when the host
+//! connects.
+use crate::key_code::{KbHidReport, KeyCode};
+use core::convert::TryFrom;
+
+/// Maximum number of keys in a sequence.
+pub const MAX_KEYS: usize = 8;
+
+/// A fixed capacity list of keys, typed one at a time. Empty by default.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Sequence {
+    keys: [KeyCode; MAX_KEYS],
+    len: usize,
+}
+
+impl Sequence {
+    /// Number of bytes used to store a sequence, unused slots are stored as `KeyCode::No`.
+    pub const SIZE: usize = MAX_KEYS;
+
+    pub const fn new() -> Self {
+        Self {
+            keys: [KeyCode::No; MAX_KEYS],
+            len: 0,
+        }
+    }
+
+    pub fn keys(&self) -> &[KeyCode] {
+        &self.keys[..self.len]
+    }
+
+    pub fn clear(&mut self) {
+        *self = Self::new();
+    }
+
+    /// Appends `key`, returns `false` if the sequence is full. `KeyCode::No` is ignored since it
+    /// marks the end of a stored sequence.
+    pub fn push(&mut self, key: KeyCode) -> bool {
+        if self.len == MAX_KEYS {
+            return false;
+        }
+        if key != KeyCode::No {
+            self.keys[self.len] = key;
+            self.len += 1;
+        }
+        true
+    }
+
+    pub fn to_bytes(self) -> [u8; Self::SIZE] {
+        let mut bytes = [KeyCode::No as u8; Self::SIZE];
+        for (byte, &key) in bytes.iter_mut().zip(self.keys().iter()) {
+            *byte = key as u8;
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: [u8; Self::SIZE]) -> Option<Self> {
+        let mut sequence = Self::new();
+        for &byte in bytes.iter().take_while(|&&byte| byte != KeyCode::No as u8) {
+            sequence.push(KeyCode::try_from(byte).ok()?);
+        }
+        Some(sequence)
+    }
+}
+
+/// Turns a `Sequence` into reports, every key is pressed and then released, so repeated keys are
+/// seen as separate presses. Modifier keys are held along with the key that follows them, e.g.
+/// `LShift, A` types an uppercase A.
+#[derive(Debug, Clone)]
+pub struct Player {
+    sequence: Sequence,
+    /// First key of the chord being played.
+    pos: usize,
+    released: bool,
+}
+
+impl Player {
+    /// A player with nothing left to play.
+    pub const fn idle() -> Self {
+        Self {
+            sequence: Sequence::new(),
+            pos: 0,
+            released: false,
+        }
+    }
+
+    pub fn new(sequence: Sequence) -> Self {
+        Self {
+            sequence,
+            pos: 0,
+            released: false,
+        }
+    }
+
+    /// The report to be sent, `None` once the whole sequence was played.
+    pub fn report(&self) -> Option<KbHidReport> {
+        let end = self.chord_end()?;
+        let mut report = KbHidReport::new();
+        if !self.released {
+            for &key in &self.sequence.keys()[self.pos..=end] {
+                report.pressed(key);
+            }
+        }
+        Some(report)
+    }
+
+    /// Moves to the next report, this should only be called after the current one was sent.
+    pub fn advance(&mut self) {
+        if let Some(end) = self.chord_end() {
+            if self.released {
+                self.pos = end + 1;
+            }
+            self.released = !self.released;
+        }
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.chord_end().is_none()
+    }
+
+    /// Index of the last key of the current chord, the first key that isn't a modifier.
+    fn chord_end(&self) -> Option<usize> {
+        let keys = self.sequence.keys();
+        if self.pos >= keys.len() {
+            return None;
+        }
+        let end = (self.pos..keys.len())
+            .find(|&idx| !keys[idx].is_modifier())
+            .unwrap_or(keys.len() - 1);
+        Some(end)
+    }
+}
+
+/// Tells a fresh enumeration apart from a resume, so the startup sequence is played once per
+/// connection.
+#[derive(Debug, Clone)]
+pub struct ConnectDetector {
+    armed: bool,
+}
+
+impl ConnectDetector {
+    pub const fn new() -> Self {
+        Self { armed: true }
+    }
+
+    /// Should be called on every bus reset, which the host issues before enumerating the device.
+    /// A suspend/resume cycle doesn't involve a reset.
+    pub fn reset(&mut self) {
+        self.armed = true;
+    }
+
+    /// Should be called while the device is configured, returns `true` only for the first call
+    /// after a reset.
+    pub fn configured(&mut self) -> bool {
+        core::mem::replace(&mut self.armed, false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn play(player: &mut Player) -> usize {
+        let mut count = 0;
+        while player.report().is_some() {
+            player.advance();
+            count += 1;
+        }
+        count
+    }
+
+    #[test]
+    fn bytes_round_trip() {
+        let mut sequence = Sequence::new();
+        assert_eq!(sequence.to_bytes(), [0; Sequence::SIZE]);
+        assert_eq!(Sequence::from_bytes(sequence.to_bytes()), Some(sequence));
+
+        for _ in 0..MAX_KEYS {
+            assert!(sequence.push(KeyCode::Escape));
+        }
+        assert!(!sequence.push(KeyCode::Enter));
+        assert_eq!(Sequence::from_bytes(sequence.to_bytes()), Some(sequence));
+
+        // Erased flash
+        assert_eq!(Sequence::from_bytes([0xFF; Sequence::SIZE]), None);
+    }
+
+    #[test]
+    fn press_and_release() {
+        let mut sequence = Sequence::new();
+        sequence.push(KeyCode::A);
+        sequence.push(KeyCode::A);
+        let mut player = Player::new(sequence);
+
+        let mut pressed = KbHidReport::new();
+        pressed.pressed(KeyCode::A);
+        let expected = [&pressed, &KbHidReport::new(), &pressed, &KbHidReport::new()];
+        for &report in expected.iter() {
+            assert_eq!(player.report().as_ref(), Some(report));
+            // Not sent yet
+            assert_eq!(player.report().as_ref(), Some(report));
+            player.advance();
+        }
+        assert_eq!(player.report(), None);
+    }
+
+    #[test]
+    fn modifiers_are_held() {
+        let mut sequence = Sequence::new();
+        sequence.push(KeyCode::LShift);
+        sequence.push(KeyCode::LAlt);
+        sequence.push(KeyCode::A);
+        sequence.push(KeyCode::B);
+        let mut player = Player::new(sequence);
+
+        let mut chord = KbHidReport::new();
+        chord.pressed(KeyCode::LShift);
+        chord.pressed(KeyCode::LAlt);
+        chord.pressed(KeyCode::A);
+        let mut plain = KbHidReport::new();
+        plain.pressed(KeyCode::B);
+        let expected = [&chord, &KbHidReport::new(), &plain, &KbHidReport::new()];
+        for &report in expected.iter() {
+            assert_eq!(player.report().as_ref(), Some(report));
+            player.advance();
+        }
+        assert!(player.is_done());
+    }
+
+    #[test]
+    fn plays_once_per_connect() {
+        let mut sequence = Sequence::new();
+        sequence.push(KeyCode::Enter);
+        let mut detector = ConnectDetector::new();
+        let mut player = Player::idle();
+
+        let mut reports = 0;
+        let poll = |detector: &mut ConnectDetector, player: &mut Player| {
+            if detector.configured() {
+                *player = Player::new(sequence);
+            }
+            play(player)
+        };
+
+        // Power up, then stay configured, a key is a press and a release report
+        reports += poll(&mut detector, &mut player);
+        reports += poll(&mut detector, &mut player);
+        assert_eq!(reports, 2);
+
+        // Suspend and resume, no reset involved
+        reports += poll(&mut detector, &mut player);
+        assert_eq!(reports, 2);
+
+        // Unplugged and plugged back
+        detector.reset();
+        reports += poll(&mut detector, &mut player);
+        reports += poll(&mut detector, &mut player);
+        assert_eq!(reports, 4);
+    }
+
+    #[test]
+    fn empty_sequence_sends_nothing() {
+        let mut player = Player::new(Sequence::new());
+        assert_eq!(player.report(), None);
+        assert_eq!(play(&mut player), 0);
+    }
+}
@@ -0,0 +1,117 @@
+//! Device wide settings, stored in flash alongside the `Matrix`.
+use crate::{
+    boot::UsbAttributes,
+    debounce::{self, LatencyMode},
+    name::DeviceName,
+    packets::AppCommand,
+    repeat::RepeatConfig,
+    sequence::Sequence,
+};
+use core::convert::TryFrom;
+
+const REPEAT_OFFSET: usize = Sequence::SIZE + 2;
+const USB_OFFSET: usize = REPEAT_OFFSET + RepeatConfig::SIZE;
+const NAME_OFFSET: usize = USB_OFFSET + UsbAttributes::SIZE;
+const DEBOUNCE_OFFSET: usize = NAME_OFFSET + DeviceName::SIZE;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Settings {
+    pub latency_mode: LatencyMode,
+    /// Typed once every time the host enumerates the device, empty by default.
+    pub startup_sequence: Sequence,
+    /// Key output is paused while the host sends configuration commands.
+    pub quiet_config: bool,
+    pub repeat: RepeatConfig,
+    /// Applied on the next boot.
+    pub usb: UsbAttributes,
+    /// Empty by default.
+    pub name: DeviceName,
+    /// Samples the debouncer needs to confirm a press or a release, from 1 to
+    /// `debounce::MAX_WINDOW`.
+    pub debounce_window: u8,
+}
+
+impl Settings {
+    /// Number of bytes used to store the settings.
+    pub const SIZE: usize = DEBOUNCE_OFFSET + 1;
+
+    pub const fn new() -> Self {
+        Self {
+            latency_mode: LatencyMode::Normal,
+            startup_sequence: Sequence::new(),
+            quiet_config: false,
+            repeat: RepeatConfig::new(),
+            usb: UsbAttributes::new(),
+            name: DeviceName::new(),
+            debounce_window: debounce::DEFAULT_WINDOW,
+        }
+    }
+
+    /// Applies `command` to the settings, commands that don't change a setting are ignored.
+    pub fn update(&mut self, command: AppCommand) {
+        match command {
+            AppCommand::SetLatencyMode(mode) => self.latency_mode = mode,
+            AppCommand::ClearStartupSequence => self.startup_sequence.clear(),
+            AppCommand::PushStartupKey(key) => {
+                // Keys past the limit are dropped, the host tool checks the length beforehand
+                self.startup_sequence.push(key);
+            }
+            AppCommand::SetQuietConfig(enabled) => self.quiet_config = enabled,
+            AppCommand::SetRepeat(enabled) => self.repeat.enabled = enabled,
+            AppCommand::SetRepeatInitial(initial) => self.repeat.initial = initial,
+            AppCommand::SetRepeatFloor(floor) => self.repeat.floor = floor,
+            AppCommand::SetRepeatStep(step) => self.repeat.step = step,
+            AppCommand::SetSelfPowered(enabled) => self.usb.self_powered = enabled,
+            AppCommand::SetRemoteWakeup(enabled) => self.usb.remote_wakeup = enabled,
+            AppCommand::SetMaxPower(max_power) => self.usb.max_power = max_power,
+            AppCommand::ClearName => self.name.clear(),
+            AppCommand::PushNameChar(ch) => {
+                // Characters past the limit are dropped, the host tool checks the length beforehand
+                self.name.push(ch);
+            }
+            AppCommand::SetDebounce(window) => self.debounce_window = window,
+            _ => {}
+        }
+    }
+
+    pub fn to_bytes(self) -> [u8; Self::SIZE] {
+        let mut bytes = [0; Self::SIZE];
+        bytes[0] = self.latency_mode as u8;
+        bytes[1..=Sequence::SIZE].copy_from_slice(&self.startup_sequence.to_bytes());
+        bytes[Sequence::SIZE + 1] = self.quiet_config as u8;
+        bytes[REPEAT_OFFSET..USB_OFFSET].copy_from_slice(&self.repeat.to_bytes());
+        bytes[USB_OFFSET..NAME_OFFSET].copy_from_slice(&self.usb.to_bytes());
+        bytes[NAME_OFFSET..DEBOUNCE_OFFSET].copy_from_slice(&self.name.to_bytes());
+        bytes[DEBOUNCE_OFFSET] = self.debounce_window;
+        bytes
+    }
+
+    pub fn from_bytes(bytes: [u8; Self::SIZE]) -> Option<Self> {
+        let mut sequence = [0; Sequence::SIZE];
+        sequence.copy_from_slice(&bytes[1..=Sequence::SIZE]);
+        let quiet_config = match bytes[Sequence::SIZE + 1] {
+            0 => false,
+            1 => true,
+            _ => return None,
+        };
+        let mut repeat = [0; RepeatConfig::SIZE];
+        repeat.copy_from_slice(&bytes[REPEAT_OFFSET..USB_OFFSET]);
+        let mut usb = [0; UsbAttributes::SIZE];
+        usb.copy_from_slice(&bytes[USB_OFFSET..NAME_OFFSET]);
+        let mut name = [0; DeviceName::SIZE];
+        name.copy_from_slice(&bytes[NAME_OFFSET..DEBOUNCE_OFFSET]);
+        let debounce_window = match bytes[DEBOUNCE_OFFSET] {
+            window @ 1..=debounce::MAX_WINDOW => window,
+            _ => return None,
+        };
+        Some(Self {
+            latency_mode: LatencyMode::try_from(bytes[0]).ok()?,
+            startup_sequence: Sequence::from_bytes(sequence)?,
+            quiet_config,
+            repeat: RepeatConfig::from_bytes(repeat)?,
+            usb: UsbAttributes::from_bytes(usb)?,
+            name: DeviceName::from_bytes(name)?,
+            debounce_window,
+        })
+    }
+}
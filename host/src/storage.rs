@@ -0,0 +1,553 @@
+//! Layout of the configuration pages in flash, written by the firmware and read back by the host
+//! tool for the page dump.
+//!
+//! Records of `CONFIG_SIZE` bytes are written one after the other from the start of a page, each
+//! one starts with `MAGIC` and ends with a CRC-16 of everything before it, see `crc`. In between
+//! there's a sequence number, the active profile, the matrix of every profile and the settings,
+//! which are shared by all profiles.
+//!
+//! There are `NUM_PAGES` pages, once the page in use is full the next record goes to the start of
+//! the other one, which is erased first. The page in use is never erased, so a power loss during
+//! the erase leaves its last record valid. The sequence number tells which page is newer, see
+//! `newest`.
+//!
+//! The tail of every page, after the last record, holds an erase counter written right after the
+//! page is erased, see `ERASE_COUNT_OFFSET`. Saving never touches it, so it doesn't add any wear.
+//!
+//! Records of the first firmware, a single layout on the last page, are upgraded to the current
+//! format by `migrate`.
+use crate::{
+    crc, key_code::KeyCode, matrix::Matrix, packets::AppCommand, settings::Settings, NUM_BTS,
+};
+use core::convert::TryFrom;
+use static_assertions::const_assert;
+
+pub const PAGE_SIZE: usize = 1024;
+// Magic byte to mark a valid config, it also works as a format version: changing it makes configs
+// stored in an older format be discarded instead of misread, unless `migrate` knows the format.
+// A format newer than the firmware is always discarded.
+// 0x55: one byte per button on the last page, see `migrate`
+// 0x56: this layout, a sequence number, the matrix of every profile, the settings and a CRC-16,
+//       spread over two pages
+pub const MAGIC: u8 = 0x56;
+/// Number of pages the records are spread over, the last ones of the flash.
+pub const NUM_PAGES: usize = 2;
+/// Size of all the config pages together, as answered by the page dump.
+pub const CONFIG_AREA_SIZE: usize = NUM_PAGES * PAGE_SIZE;
+/// Number of layouts stored in a record, only the active one is loaded.
+pub const NUM_PROFILES: usize = 3;
+/// Offset of the sequence number in a record, a little endian u32 incremented on every write.
+pub const SEQUENCE_OFFSET: usize = 1;
+/// Offset of the active profile in a record.
+pub const PROFILE_OFFSET: usize = SEQUENCE_OFFSET + 4;
+/// Offset of the settings in a record, after the matrices.
+pub const SETTINGS_OFFSET: usize = PROFILE_OFFSET + 1 + NUM_PROFILES * Matrix::SIZE;
+/// Magic, sequence number, active profile, matrices, settings and CRC, padded to a multiple of 2
+/// bytes since the flash is written a half-word at a time.
+pub const CONFIG_SIZE: usize = ((SETTINGS_OFFSET + Settings::SIZE + crc::SIZE) + 1) & !1;
+const_assert!(SETTINGS_OFFSET + Settings::SIZE < CONFIG_SIZE);
+/// How many configs fit on one page.
+pub const CONFIGS_IN_PAGE: usize = PAGE_SIZE / CONFIG_SIZE;
+// A single record per page would erase the page on every save
+const_assert!(CONFIGS_IN_PAGE >= 2);
+/// Records in the whole config area.
+pub const NUM_RECORDS: usize = NUM_PAGES * CONFIGS_IN_PAGE;
+/// Offset of the erase counter in every page: the total number of erases of all the config pages
+/// as of the last erase of that page, a little endian u32 followed by its complement, so one left
+/// half written by a power loss isn't read back.
+pub const ERASE_COUNT_OFFSET: usize = PAGE_SIZE - ERASE_COUNT_SIZE;
+/// Size of the erase counter of a page.
+pub const ERASE_COUNT_SIZE: usize = 8;
+// The erase counter lives in the tail of the page, after the records
+const_assert!(CONFIGS_IN_PAGE * CONFIG_SIZE <= ERASE_COUNT_OFFSET);
+
+/// Offset of the matrix of `profile` in a record.
+pub const fn matrix_offset(profile: usize) -> usize {
+    PROFILE_OFFSET + 1 + profile * Matrix::SIZE
+}
+
+/// Offset in the config area of the record `index`, counting the records of every page in order,
+/// like the firmware reports the one in use.
+pub const fn record_offset(index: usize) -> usize {
+    index / CONFIGS_IN_PAGE * PAGE_SIZE + index % CONFIGS_IN_PAGE * CONFIG_SIZE
+}
+
+/// Layout of the active profile as stored in `record`, `None` if it isn't a valid record.
+pub fn active_matrix(record: &[u8]) -> Option<[u8; Matrix::SIZE]> {
+    if record.len() != CONFIG_SIZE || record[0] != MAGIC || !crc::check(record) {
+        return None;
+    }
+    let offset = matrix_offset(active_profile(record));
+    let mut matrix = [0; Matrix::SIZE];
+    matrix.copy_from_slice(&record[offset..offset + Matrix::SIZE]);
+    Some(matrix)
+}
+
+/// Sequence number of `record`, see `SEQUENCE_OFFSET`.
+pub fn sequence(record: &[u8]) -> u32 {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(&record[SEQUENCE_OFFSET..SEQUENCE_OFFSET + 4]);
+    u32::from_le_bytes(bytes)
+}
+
+/// Active profile of `record`. One out of range, e.g. stored by a firmware with more profiles,
+/// falls back to the first profile instead of discarding the whole record.
+pub fn active_profile(record: &[u8]) -> usize {
+    match usize::from(record[PROFILE_OFFSET]) {
+        profile if profile < NUM_PROFILES => profile,
+        _ => 0,
+    }
+}
+
+/// Stores `sequence` in `record`, which has to be sealed again afterwards.
+pub fn set_sequence(record: &mut [u8], sequence: u32) {
+    record[SEQUENCE_OFFSET..SEQUENCE_OFFSET + 4].copy_from_slice(&sequence.to_le_bytes());
+}
+
+/// Finds the last valid record of `page`: records are valid from the start of the page as long as
+/// they begin with `MAGIC` and pass the CRC check, a record left half written by a power loss ends
+/// the scan. Returns `None` if the first one isn't valid, e.g. on an erased page.
+pub fn last_valid_index(page: &[u8]) -> Option<usize> {
+    page.chunks_exact(CONFIG_SIZE)
+        .take_while(|record| record[0] == MAGIC && crc::check(record))
+        .count()
+        .checked_sub(1)
+}
+
+/// Finds the record in use, the firmware does the same at boot: the last valid record of every
+/// page in `area`, which holds them one after the other, with the highest sequence number wins.
+/// Returns its page and its index in that page, or `None` if no page has a valid record.
+pub fn newest(area: &[u8]) -> Option<(usize, usize)> {
+    area.chunks_exact(PAGE_SIZE)
+        .enumerate()
+        .filter_map(|(page, data)| {
+            let index = last_valid_index(data)?;
+            Some((page, index, sequence(&data[index * CONFIG_SIZE..])))
+        })
+        .max_by_key(|&(_, _, sequence)| sequence)
+        .map(|(page, index, _)| (page, index))
+}
+
+/// Whether every half-word of `page` reads as erased, the flash is erased and written a half-word
+/// at a time.
+pub fn is_erased(page: &[u8]) -> bool {
+    page.chunks_exact(2).all(|half| half == [0xFF, 0xFF])
+}
+
+/// Bytes stored at `ERASE_COUNT_OFFSET` for a total of `count` erases.
+pub fn erase_count_bytes(count: u32) -> [u8; ERASE_COUNT_SIZE] {
+    let mut bytes = [0; ERASE_COUNT_SIZE];
+    bytes[..4].copy_from_slice(&count.to_le_bytes());
+    bytes[4..].copy_from_slice(&(!count).to_le_bytes());
+    bytes
+}
+
+/// Erase counter of `page`, `None` if it was never written, e.g. by an older firmware.
+pub fn page_erase_count(page: &[u8]) -> Option<u32> {
+    let mut count = [0; 4];
+    count.copy_from_slice(&page[ERASE_COUNT_OFFSET..ERASE_COUNT_OFFSET + 4]);
+    let count = u32::from_le_bytes(count);
+    if erase_count_bytes(count)[..]
+        == page[ERASE_COUNT_OFFSET..ERASE_COUNT_OFFSET + ERASE_COUNT_SIZE]
+    {
+        Some(count)
+    } else {
+        None
+    }
+}
+
+/// Total number of erases of the config pages in `area`, the highest of their counters. Erases
+/// done before the counter existed aren't included.
+pub fn erase_count(area: &[u8]) -> u32 {
+    area.chunks_exact(PAGE_SIZE)
+        .filter_map(page_erase_count)
+        .max()
+        .unwrap_or(0)
+}
+
+/// Magic byte of the records of the first firmware: the key of every button, one byte each, on
+/// the last page and without a CRC.
+const LEGACY_MAGIC: u8 = 0x55;
+/// Size of a record of the first firmware, magic byte and keys padded to a half-word.
+const LEGACY_SIZE: usize = ((NUM_BTS + 1) + 1) & !1;
+
+/// Builds a record in the current format out of the last record of the first firmware in `area`,
+/// the config pages one after the other. Returns it with the page the old record is in, which has
+/// to be kept until the new one is written to the other page, or `None` if there's no such record
+/// or one of its keys isn't valid.
+///
+/// The old layout becomes the first profile with every button sending its key, the other
+/// profiles and the settings get their defaults and the sequence number starts from 0.
+pub fn migrate(area: &[u8]) -> Option<(usize, [u8; CONFIG_SIZE])> {
+    let last = area.chunks_exact(PAGE_SIZE).last()?;
+    // Records were only checked by their magic byte
+    let old = last
+        .chunks_exact(LEGACY_SIZE)
+        .take_while(|record| record[0] == LEGACY_MAGIC)
+        .last()?;
+    let mut layout = [KeyCode::No; NUM_BTS];
+    for (key, &code) in layout.iter_mut().zip(&old[1..]) {
+        *key = KeyCode::try_from(code).ok()?;
+    }
+    let mut matrix = Matrix::new();
+    matrix.update_layout(AppCommand::SetAll(layout));
+
+    let mut record = [0; CONFIG_SIZE];
+    record[matrix_offset(0)..matrix_offset(1)].copy_from_slice(&matrix.to_bytes());
+    let default = Matrix::new().to_bytes();
+    for profile in 1..NUM_PROFILES {
+        record[matrix_offset(profile)..matrix_offset(profile + 1)].copy_from_slice(&default);
+    }
+    record[SETTINGS_OFFSET..SETTINGS_OFFSET + Settings::SIZE]
+        .copy_from_slice(&Settings::new().to_bytes());
+    record[0] = MAGIC;
+    crc::seal(&mut record);
+    Some((NUM_PAGES - 1, record))
+}
+
+/// Renders `area`, every config page one after the other, as an annotated hex dump. Records are
+/// numbered across pages, `reported` is the one the firmware says it uses.
+#[cfg(feature = "host")]
+pub fn annotate(area: &[u8], reported: Option<usize>) -> Vec<String> {
+    const ROW: usize = 16;
+    let detected = newest(area).map(|(page, index)| page * CONFIGS_IN_PAGE + index);
+    let mut lines = vec![
+        format!(
+            "Record size: {} bytes, {} per page, {} pages, magic: {:#04x}",
+            CONFIG_SIZE, CONFIGS_IN_PAGE, NUM_PAGES, MAGIC
+        ),
+        format!(
+            "Record in use: {}, reported by the device: {}",
+            detected.map_or(String::from("none"), |idx| idx.to_string()),
+            reported.map_or(String::from("unknown"), |idx| idx.to_string())
+        ),
+    ];
+    if area.iter().all(|&byte| byte == 0xFF) {
+        lines.push(String::from(
+            "Pages erased (all 0xFF), the firmware writes the defaults on the next boot",
+        ));
+    }
+
+    fn hex_rows(lines: &mut Vec<String>, start: usize, bytes: &[u8]) {
+        for (row, chunk) in bytes.chunks(ROW).enumerate() {
+            let hex: Vec<String> = chunk.iter().map(|byte| format!("{:02x}", byte)).collect();
+            lines.push(format!("  {:#06x}  {}", start + row * ROW, hex.join(" ")));
+        }
+    }
+
+    for (page, data) in area.chunks_exact(PAGE_SIZE).enumerate() {
+        let start = page * PAGE_SIZE;
+        lines.push(format!("Page {} at {:#06x}", page, start));
+        for (idx, record) in data.chunks_exact(CONFIG_SIZE).enumerate() {
+            let offset = start + idx * CONFIG_SIZE;
+            let idx = page * CONFIGS_IN_PAGE + idx;
+            let state = if record.iter().all(|&byte| byte == 0xFF) {
+                String::from("erased")
+            } else if record[0] != MAGIC {
+                String::from("bad magic")
+            } else if !crc::check(record) {
+                String::from("CRC mismatch")
+            } else {
+                format!("valid, sequence {}", sequence(record))
+            };
+            let marker = if Some(idx) == detected {
+                " <- in use"
+            } else {
+                ""
+            };
+            lines.push(format!(
+                "Record {} at {:#06x}: {}{}",
+                idx, offset, state, marker
+            ));
+            hex_rows(&mut lines, offset, record);
+        }
+
+        let tail = CONFIGS_IN_PAGE * CONFIG_SIZE;
+        lines.push(format!(
+            "Tail at {:#06x}, erase count: {}",
+            start + tail,
+            page_erase_count(data).map_or(String::from("none"), |count| count.to_string())
+        ));
+        hex_rows(&mut lines, start + tail, &data[tail..]);
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::action::Action;
+
+    /// A page with `records` valid records, numbered from `first`.
+    fn page_with(records: usize, first: u32) -> [u8; PAGE_SIZE] {
+        let mut page = [0xFF; PAGE_SIZE];
+        for (idx, record) in page.chunks_exact_mut(CONFIG_SIZE).take(records).enumerate() {
+            record.iter_mut().for_each(|byte| *byte = 0);
+            record[0] = MAGIC;
+            set_sequence(record, first + idx as u32);
+            crate::crc::seal(record);
+        }
+        page
+    }
+
+    fn area(first: [u8; PAGE_SIZE], second: [u8; PAGE_SIZE]) -> [u8; CONFIG_AREA_SIZE] {
+        let mut area = [0xFF; CONFIG_AREA_SIZE];
+        area[..PAGE_SIZE].copy_from_slice(&first);
+        area[PAGE_SIZE..2 * PAGE_SIZE].copy_from_slice(&second);
+        area
+    }
+
+    #[test]
+    fn last_index() {
+        assert_eq!(last_valid_index(&page_with(1, 0)), Some(0));
+        assert_eq!(last_valid_index(&page_with(3, 0)), Some(2));
+        assert_eq!(
+            last_valid_index(&page_with(CONFIGS_IN_PAGE, 0)),
+            Some(CONFIGS_IN_PAGE - 1)
+        );
+        // Erased or mid-erase page
+        assert_eq!(last_valid_index(&[0xFF; PAGE_SIZE]), None);
+
+        // A write interrupted after the magic byte, the previous record is still in use
+        let mut page = page_with(3, 0);
+        page[2 * CONFIG_SIZE + CONFIG_SIZE / 2..3 * CONFIG_SIZE]
+            .iter_mut()
+            .for_each(|byte| *byte = 0xFF);
+        assert_eq!(last_valid_index(&page), Some(1));
+        let mut page = page_with(1, 0);
+        page[1] ^= 1;
+        assert_eq!(last_valid_index(&page), None);
+    }
+
+    #[test]
+    fn stored_matrix() {
+        let mut matrix = Matrix::new();
+        matrix.update_layout(AppCommand::SetKey(1, KeyCode::Escape, 0));
+        let mut page = page_with(2, 0);
+        let record = &mut page[CONFIG_SIZE..2 * CONFIG_SIZE];
+        record[PROFILE_OFFSET] = 2;
+        record[matrix_offset(2)..matrix_offset(2) + Matrix::SIZE]
+            .copy_from_slice(&matrix.to_bytes());
+        crate::crc::seal(record);
+
+        let area = area([0xFF; PAGE_SIZE], page);
+        let offset = record_offset(CONFIGS_IN_PAGE + 1);
+        assert_eq!(offset, PAGE_SIZE + CONFIG_SIZE);
+        let record = &area[offset..offset + CONFIG_SIZE];
+        assert_eq!(active_matrix(record), Some(matrix.to_bytes()));
+
+        let mut corrupted = [0; CONFIG_SIZE];
+        corrupted.copy_from_slice(record);
+        corrupted[matrix_offset(2)] ^= 1;
+        assert_eq!(active_matrix(&corrupted), None);
+        assert_eq!(active_matrix(&[0xFF; CONFIG_SIZE]), None);
+    }
+
+    /// Power lost after `written` bytes of the record at `index`, the rest is still erased.
+    fn truncate(page: &mut [u8; PAGE_SIZE], index: usize, written: usize) {
+        page[index * CONFIG_SIZE + written..(index + 1) * CONFIG_SIZE]
+            .iter_mut()
+            .for_each(|byte| *byte = 0xFF);
+    }
+
+    #[test]
+    fn truncated_write() {
+        // Every half-word boundary, including right after the magic byte
+        for written in (0..CONFIG_SIZE).step_by(2) {
+            let mut page = page_with(3, 0);
+            truncate(&mut page, 2, written);
+            assert_eq!(
+                last_valid_index(&page),
+                Some(1),
+                "{} bytes written",
+                written
+            );
+
+            // The first record of the other page, the full one stays in use
+            let mut other = page_with(1, CONFIGS_IN_PAGE as u32);
+            truncate(&mut other, 0, written);
+            let full = page_with(CONFIGS_IN_PAGE, 0);
+            assert_eq!(
+                newest(&area(full, other)),
+                Some((0, CONFIGS_IN_PAGE - 1)),
+                "{} bytes written",
+                written
+            );
+        }
+    }
+
+    #[test]
+    fn newest_record() {
+        let erased = [0xFF; PAGE_SIZE];
+        assert_eq!(newest(&area(erased, erased)), None);
+        assert_eq!(newest(&area(page_with(3, 0), erased)), Some((0, 2)));
+
+        // The first page filled up and the next record went to the second one
+        let full = page_with(CONFIGS_IN_PAGE, 0);
+        let moved = page_with(2, CONFIGS_IN_PAGE as u32);
+        assert_eq!(newest(&area(full, moved)), Some((1, 1)));
+        // Then the second one filled up, and the first one was erased and written again
+        let wrapped = page_with(1, 2 * CONFIGS_IN_PAGE as u32);
+        let full = page_with(CONFIGS_IN_PAGE, CONFIGS_IN_PAGE as u32);
+        assert_eq!(newest(&area(wrapped, full)), Some((0, 0)));
+
+        // Power lost while writing the first record of the other page
+        let mut partial = page_with(1, CONFIGS_IN_PAGE as u32);
+        partial[CONFIG_SIZE / 2..CONFIG_SIZE]
+            .iter_mut()
+            .for_each(|byte| *byte = 0xFF);
+        let full = page_with(CONFIGS_IN_PAGE, 0);
+        assert_eq!(newest(&area(full, partial)), Some((0, CONFIGS_IN_PAGE - 1)));
+    }
+
+    #[test]
+    fn erased_page() {
+        // The second page filled up and the first one was just erased to wrap around, the records
+        // right after it don't count
+        let area = area([0xFF; PAGE_SIZE], page_with(CONFIGS_IN_PAGE, 0));
+        assert!(is_erased(&area[..PAGE_SIZE]));
+        assert!(!is_erased(&area[PAGE_SIZE..]));
+
+        let mut page = [0xFF; PAGE_SIZE];
+        page[PAGE_SIZE - 1] = 0xFE;
+        assert!(!is_erased(&page));
+    }
+
+    #[test]
+    fn record_layout() {
+        assert_eq!(matrix_offset(0), PROFILE_OFFSET + 1);
+        assert_eq!(matrix_offset(NUM_PROFILES), SETTINGS_OFFSET);
+
+        let mut record = [0; CONFIG_SIZE];
+        set_sequence(&mut record, 0x0102_0304);
+        assert_eq!(record[SEQUENCE_OFFSET..PROFILE_OFFSET], [4, 3, 2, 1]);
+        assert_eq!(sequence(&record), 0x0102_0304);
+    }
+
+    #[test]
+    fn erase_counter() {
+        let erased = [0xFF; PAGE_SIZE];
+        assert_eq!(page_erase_count(&erased), None);
+        assert_eq!(erase_count(&area(erased, erased)), 0);
+
+        let mut first = erased;
+        first[ERASE_COUNT_OFFSET..].copy_from_slice(&erase_count_bytes(41));
+        assert_eq!(page_erase_count(&first), Some(41));
+        let mut second = erased;
+        second[ERASE_COUNT_OFFSET..].copy_from_slice(&erase_count_bytes(42));
+        assert_eq!(erase_count(&area(first, second)), 42);
+
+        // Power lost after the first half-words of the counter of the second page
+        second[ERASE_COUNT_OFFSET + 4..]
+            .iter_mut()
+            .for_each(|byte| *byte = 0xFF);
+        assert_eq!(page_erase_count(&second), None);
+        assert_eq!(erase_count(&area(first, second)), 41);
+    }
+
+    #[test]
+    fn profile_clamped() {
+        let mut record = [0; CONFIG_SIZE];
+        record[PROFILE_OFFSET] = NUM_PROFILES as u8 - 1;
+        assert_eq!(active_profile(&record), NUM_PROFILES - 1);
+        record[PROFILE_OFFSET] = NUM_PROFILES as u8;
+        assert_eq!(active_profile(&record), 0);
+        record[PROFILE_OFFSET] = 0xFF;
+        assert_eq!(active_profile(&record), 0);
+    }
+
+    #[test]
+    fn migrate_first_format() {
+        let mut page = [0xFF; PAGE_SIZE];
+        let layouts = [
+            [KeyCode::A, KeyCode::B, KeyCode::C],
+            [KeyCode::Escape, KeyCode::No, KeyCode::VolUp],
+        ];
+        for (old, layout) in page.chunks_exact_mut(LEGACY_SIZE).zip(&layouts) {
+            old[0] = LEGACY_MAGIC;
+            for (byte, &key) in old[1..].iter_mut().zip(layout) {
+                *byte = key as u8;
+            }
+        }
+
+        // The last record is used
+        let (page_idx, record) = migrate(&area([0xFF; PAGE_SIZE], page)).unwrap();
+        assert_eq!(page_idx, NUM_PAGES - 1);
+        assert_eq!(last_valid_index(&record), Some(0));
+        assert_eq!(sequence(&record), 0);
+        assert_eq!(active_profile(&record), 0);
+        let mut matrix = Matrix::new();
+        matrix.update_layout(AppCommand::SetAll(layouts[1]));
+        assert_eq!(active_matrix(&record), Some(matrix.to_bytes()));
+        assert_eq!(
+            matrix.layout(),
+            [
+                Action::Key(KeyCode::Escape),
+                Action::Key(KeyCode::No),
+                Action::Key(KeyCode::VolUp)
+            ]
+        );
+        assert_eq!(
+            &record[matrix_offset(2)..matrix_offset(3)],
+            &Matrix::new().to_bytes()[..]
+        );
+        assert_eq!(
+            &record[SETTINGS_OFFSET..SETTINGS_OFFSET + Settings::SIZE],
+            &Settings::new().to_bytes()[..]
+        );
+
+        // Only the last page was used
+        assert_eq!(migrate(&area(page, [0xFF; PAGE_SIZE])), None);
+        // An invalid key can't be migrated
+        page[LEGACY_SIZE + 1] = 0xFF;
+        assert_eq!(migrate(&area([0xFF; PAGE_SIZE], page)), None);
+    }
+
+    #[test]
+    fn migrate_unknown() {
+        let erased = [0xFF; PAGE_SIZE];
+        assert_eq!(migrate(&area(erased, erased)), None);
+        // Already the current format, or newer than this firmware
+        assert_eq!(migrate(&area(erased, page_with(1, 0))), None);
+        let mut page = page_with(1, 0);
+        page[0] = MAGIC + 1;
+        assert_eq!(migrate(&area(erased, page)), None);
+    }
+
+    #[cfg(feature = "host")]
+    #[test]
+    fn annotated_dump() {
+        let mut first = page_with(2, 0);
+        first[CONFIG_SIZE + 1] ^= 1;
+        let mut second = [0xFF; PAGE_SIZE];
+        second[..CONFIG_SIZE].copy_from_slice(&page_with(1, 7)[..CONFIG_SIZE]);
+        second[CONFIG_SIZE] = 0x42;
+        let lines = annotate(&area(first, second), Some(CONFIGS_IN_PAGE));
+        let in_use = CONFIGS_IN_PAGE.to_string();
+        assert!(lines[1].contains(&format!(
+            "Record in use: {}, reported by the device: {}",
+            in_use, in_use
+        )));
+        assert!(lines
+            .iter()
+            .any(|line| line == "Record 0 at 0x0000: valid, sequence 0"));
+        assert!(lines
+            .iter()
+            .any(|line| line.contains("Record 1") && line.ends_with("CRC mismatch")));
+        assert!(lines
+            .iter()
+            .any(|line| line.contains("Record 2") && line.ends_with("erased")));
+        assert!(lines.iter().any(|line| line == "Page 1 at 0x0400"));
+        assert!(lines.iter().any(|line| line
+            == &format!(
+                "Record {} at 0x0400: valid, sequence 7 <- in use",
+                CONFIGS_IN_PAGE
+            )));
+        assert!(lines.iter().any(|line| line.ends_with("bad magic")));
+        assert!(lines.iter().any(|line| line.ends_with("erase count: none")));
+
+        let lines = annotate(&[0xFF; CONFIG_AREA_SIZE], None);
+        assert!(lines[1].contains("none"));
+        assert!(lines[2].starts_with("Pages erased"));
+    }
+}
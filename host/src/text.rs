@@ -0,0 +1,127 @@
+//! Builds the key sequence that types a short ASCII text, to be stored in a sequence button.
+//!
+//! The keys are the ones of a US layout, so the host needs to be set to it for the text to come
+//! out as expected.
+use crate::{key_code::KeyCode, sequence::Sequence};
+use core::convert::TryFrom;
+
+/// Key typing `ch` on a US layout, `true` if it needs shift.
+fn key(ch: char) -> Option<(KeyCode, bool)> {
+    let key = match ch {
+        'a'..='z' => (
+            KeyCode::try_from(KeyCode::A as u8 + (ch as u8 - b'a')).ok()?,
+            false,
+        ),
+        'A'..='Z' => (
+            KeyCode::try_from(KeyCode::A as u8 + (ch as u8 - b'A')).ok()?,
+            true,
+        ),
+        '0' => (KeyCode::Kb0, false),
+        '1'..='9' => (
+            KeyCode::try_from(KeyCode::Kb1 as u8 + (ch as u8 - b'1')).ok()?,
+            false,
+        ),
+        ' ' => (KeyCode::Space, false),
+        '\n' => (KeyCode::Enter, false),
+        '\t' => (KeyCode::Tab, false),
+        '-' => (KeyCode::Minus, false),
+        '=' => (KeyCode::Equal, false),
+        '[' => (KeyCode::LBracket, false),
+        ']' => (KeyCode::RBracket, false),
+        '\\' => (KeyCode::Bslash, false),
+        ';' => (KeyCode::SColon, false),
+        '\'' => (KeyCode::Quote, false),
+        '`' => (KeyCode::Grave, false),
+        ',' => (KeyCode::Comma, false),
+        '.' => (KeyCode::Dot, false),
+        '/' => (KeyCode::Slash, false),
+        '!' => (KeyCode::Kb1, true),
+        '@' => (KeyCode::Kb2, true),
+        '#' => (KeyCode::Kb3, true),
+        '$' => (KeyCode::Kb4, true),
+        '%' => (KeyCode::Kb5, true),
+        '^' => (KeyCode::Kb6, true),
+        '&' => (KeyCode::Kb7, true),
+        '*' => (KeyCode::Kb8, true),
+        '(' => (KeyCode::Kb9, true),
+        ')' => (KeyCode::Kb0, true),
+        '_' => (KeyCode::Minus, true),
+        '+' => (KeyCode::Equal, true),
+        '{' => (KeyCode::LBracket, true),
+        '}' => (KeyCode::RBracket, true),
+        '|' => (KeyCode::Bslash, true),
+        ':' => (KeyCode::SColon, true),
+        '"' => (KeyCode::Quote, true),
+        '~' => (KeyCode::Grave, true),
+        '<' => (KeyCode::Comma, true),
+        '>' => (KeyCode::Dot, true),
+        '?' => (KeyCode::Slash, true),
+        _ => return None,
+    };
+    Some(key)
+}
+
+/// Why a text can't be typed by a sequence button.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TextError {
+    Unsupported(char),
+    /// Every shifted character takes two keys of the sequence, the shift and the key itself.
+    TooLong,
+}
+
+/// Returns the sequence typing `text`.
+pub fn sequence(text: &str) -> Result<Sequence, TextError> {
+    let mut sequence = Sequence::new();
+    for ch in text.chars() {
+        let (key, shift) = key(ch).ok_or(TextError::Unsupported(ch))?;
+        if (shift && !sequence.push(KeyCode::LShift)) || !sequence.push(key) {
+            return Err(TextError::TooLong);
+        }
+    }
+    Ok(sequence)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lowercase_and_digits() {
+        let seq = sequence("az 09").unwrap();
+        assert_eq!(
+            seq.keys(),
+            &[
+                KeyCode::A,
+                KeyCode::Z,
+                KeyCode::Space,
+                KeyCode::Kb0,
+                KeyCode::Kb9
+            ]
+        );
+        // Eight keys fill the sequence
+        assert_eq!(sequence("hunter22").unwrap().keys().len(), 8);
+    }
+
+    #[test]
+    fn shifted() {
+        let seq = sequence("Hi!").unwrap();
+        assert_eq!(
+            seq.keys(),
+            &[
+                KeyCode::LShift,
+                KeyCode::H,
+                KeyCode::I,
+                KeyCode::LShift,
+                KeyCode::Kb1
+            ]
+        );
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(sequence("ábc"), Err(TextError::Unsupported('á')));
+        assert_eq!(sequence("123456789"), Err(TextError::TooLong));
+        // Fits as characters, but not with the shifts
+        assert_eq!(sequence("ABCDE"), Err(TextError::TooLong));
+    }
+}
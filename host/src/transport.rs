@@ -0,0 +1,84 @@
+//! Feature reports to and from the device, behind a trait so `App` can be tested without one.
+use hidapi::{HidDevice, HidError, HidResult};
+use keylib::packets::CTRL_REPORT_SIZE;
+
+/// What `App` needs from the device, see `HidDevice` for the details of every method.
+pub trait Transport {
+    /// Sends a feature report, the first byte of `data` is the report ID.
+    fn send_feature_report(&self, data: &[u8]) -> HidResult<()>;
+    /// Reads a feature report, the first byte of `buf` is the report ID. Returns the number of
+    /// bytes read.
+    fn get_feature_report(&self, buf: &mut [u8]) -> HidResult<usize>;
+    fn get_manufacturer_string(&self) -> HidResult<Option<String>>;
+    fn get_product_string(&self) -> HidResult<Option<String>>;
+    fn get_serial_number_string(&self) -> HidResult<Option<String>>;
+}
+
+/// Stands in for the device when there's none, every report fails with a "no device" error.
+pub struct NoDevice;
+
+impl NoDevice {
+    fn error() -> HidError {
+        HidError::HidApiError {
+            message: String::from("No device, running offline"),
+        }
+    }
+}
+
+impl Transport for NoDevice {
+    fn send_feature_report(&self, _data: &[u8]) -> HidResult<()> {
+        Err(Self::error())
+    }
+
+    fn get_feature_report(&self, _buf: &mut [u8]) -> HidResult<usize> {
+        Err(Self::error())
+    }
+
+    fn get_manufacturer_string(&self) -> HidResult<Option<String>> {
+        Ok(None)
+    }
+
+    fn get_product_string(&self) -> HidResult<Option<String>> {
+        Ok(None)
+    }
+
+    fn get_serial_number_string(&self) -> HidResult<Option<String>> {
+        Ok(None)
+    }
+}
+
+// Hosts that check the size of the report, e.g. Windows, only send and read whole control reports,
+// so shorter ones are padded, see `CTRL_REPORT_SIZE`
+impl Transport for HidDevice {
+    fn send_feature_report(&self, data: &[u8]) -> HidResult<()> {
+        // Plus the report ID
+        let mut report = [0; CTRL_REPORT_SIZE + 1];
+        match report.get_mut(..data.len()) {
+            Some(start) => {
+                start.copy_from_slice(data);
+                HidDevice::send_feature_report(self, &report[..])
+            }
+            None => HidDevice::send_feature_report(self, data),
+        }
+    }
+
+    fn get_feature_report(&self, buf: &mut [u8]) -> HidResult<usize> {
+        let mut report = [0; CTRL_REPORT_SIZE + 1];
+        report[0] = buf[0];
+        let read = HidDevice::get_feature_report(self, &mut report[..])?.min(buf.len());
+        buf[..read].copy_from_slice(&report[..read]);
+        Ok(read)
+    }
+
+    fn get_manufacturer_string(&self) -> HidResult<Option<String>> {
+        HidDevice::get_manufacturer_string(self)
+    }
+
+    fn get_product_string(&self) -> HidResult<Option<String>> {
+        HidDevice::get_product_string(self)
+    }
+
+    fn get_serial_number_string(&self) -> HidResult<Option<String>> {
+        HidDevice::get_serial_number_string(self)
+    }
+}
@@ -1,7 +1,8 @@
 MEMORY
 {
   /* NOTE 1 K = 1 KiBi = 1024 bytes */
-  FLASH : ORIGIN = 0x08000000, LENGTH = 64K
+  /* The last two pages store the configuration, see src/flash.rs */
+  FLASH : ORIGIN = 0x08000000, LENGTH = 62K
   RAM : ORIGIN = 0x20000000, LENGTH = 20K
 }
 
@@ -0,0 +1,53 @@
+//! Reboots into the system bootloader of the chip, see `VendorCommand::EnterBootloader`.
+//!
+//! The request is left in RAM that the runtime doesn't initialize, so it survives the reset, and
+//! `start_if_requested` jumps to the bootloader before the runtime even initializes RAM, the
+//! bootloader expects the chip as it is after a reset. On the STM32F103 it's the serial bootloader
+//! on USART1, BOOT0 doesn't need to be held for it, the part has no USB DFU.
+
+use core::{mem::MaybeUninit, ptr};
+use cortex_m::peripheral::SCB;
+use cortex_m_rt::pre_init;
+
+/// Start of the system memory, the bootloader's vector table.
+const SYSTEM_MEMORY: usize = 0x1FFF_F000;
+/// Any other value, e.g. what's left in RAM after a power on, starts the firmware.
+const MAGIC: u32 = 0xB007_10AD;
+
+#[link_section = ".uninit.BOOTLOADER_REQUEST"]
+static mut REQUEST: MaybeUninit<u32> = MaybeUninit::uninit();
+
+/// Resets the chip into the bootloader, the caller has to let a pending transfer finish first.
+pub fn reboot() -> ! {
+    // Only written here and read once at boot, before anything else runs
+    unsafe { ptr::write_volatile(REQUEST.as_mut_ptr(), MAGIC) };
+    SCB::sys_reset()
+}
+
+/// Starts the bootloader if `reboot` asked for it, run by the runtime right after the reset,
+/// before RAM is initialized and with the interrupts and the peripherals still untouched.
+#[pre_init]
+unsafe fn start_if_requested() {
+    // Cleared before jumping, so the next reset starts the firmware again
+    let requested = ptr::read_volatile(REQUEST.as_ptr()) == MAGIC;
+    ptr::write_volatile(REQUEST.as_mut_ptr(), 0);
+    if requested {
+        // The system memory is read only and holds a valid vector table
+        let stack = ptr::read_volatile(SYSTEM_MEMORY as *const u32);
+        let reset = ptr::read_volatile((SYSTEM_MEMORY + 4) as *const u32);
+        bootstrap(stack, reset)
+    }
+}
+
+/// Loads `stack` into MSP and branches to `reset`, like `asm::bootstrap` of cortex-m 0.7. Both
+/// happen in the same asm block, so nothing is read from the old stack after it's switched.
+#[inline(always)]
+unsafe fn bootstrap(stack: u32, reset: u32) -> ! {
+    core::arch::asm!(
+        "msr msp, {stack}",
+        "bx {reset}",
+        stack = in(reg) stack,
+        reset = in(reg) reset,
+        options(noreturn, nomem, nostack),
+    )
+}
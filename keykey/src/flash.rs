@@ -1,23 +1,43 @@
 //! Flash writing abstraction for storing configurations.
 //!
-//! Each configuration will have a magic byte to mark it as valid and will occupy (in bytes):
+//! Each configuration will have a magic byte to mark it as valid followed by a sequence number,
+//! the active profile, the serialized `Matrix` of every profile and the `Settings`, the last two
+//! bytes are a CRC-16 of everything before them. It will occupy (in bytes):
 //! ```
-//! ((NUM_BTS + 1) + 1) & !1
+//! ((6 + NUM_PROFILES * Matrix::SIZE + Settings::SIZE + 2) + 1) & !1
 //! ```
 //!
 //! The `+ 1 & !1` is used to have a multiple of 2 bytes, this is done for convenience when dealing
 //! with the flash, because it can only be written 2 bytes at a time.
 //!
-//! The last page of the device flash is used to store the configuration, they are written one after
-//! the other in flash, the last valid configuration is the used one, this is used to avoid flash
-//! wear. When the page gets full, the whole page is erased and the desired configuration is saved
-//! at the start of the page.
+//! The last two pages of the device flash are used to store the configuration, they are written one
+//! after the other in a page, the last valid configuration is the used one, this is used to avoid
+//! flash wear. When the page gets full, the other page is erased and the desired configuration is
+//! saved at the start of it, so the page in use is only erased once the other one holds a newer
+//! configuration. Every configuration gets the sequence number after the one in use, at boot the
+//! page with the highest one wins, see `storage::newest`.
+//!
+//! A configuration is only valid if its CRC checks, so one left half written by a power loss is
+//! skipped at boot, see `storage::last_valid_index`, and the previous one is used instead. One
+//! stored by an older firmware is upgraded to the current format at boot if possible, see
+//! `storage::migrate`, otherwise the defaults are written.
+//!
+//! Only the matrix of the active profile is kept in RAM, saving it copies the other profiles from
+//! the last configuration.
 
 // Remove this later
 #![allow(dead_code)]
 
-use super::{Matrix, NUM_BTS};
 use core::{ptr, slice};
+use keylib::{
+    crc,
+    matrix::Matrix,
+    settings::Settings,
+    storage::{
+        self, CONFIGS_IN_PAGE, CONFIG_AREA_SIZE, CONFIG_SIZE, ERASE_COUNT_OFFSET, MAGIC, NUM_PAGES,
+        NUM_PROFILES, PAGE_SIZE, PROFILE_OFFSET, SETTINGS_OFFSET,
+    },
+};
 use static_assertions::const_assert;
 use stm32f1xx_hal::{
     flash::Parts,
@@ -25,19 +45,17 @@ use stm32f1xx_hal::{
 };
 
 const FLASH_START: usize = 0x0800_0000;
-const PAGE_SIZE: usize = 1024;
 const FLASH_SIZE_KB: usize = 64;
 const FLASH_END: usize = FLASH_START + FLASH_SIZE_KB * PAGE_SIZE;
 
-/// We will use the last flash page for storing the configuration.
-const CONFIG_ADD: usize = FLASH_START + (FLASH_SIZE_KB - 1) * PAGE_SIZE;
-// Magic byte to mark a valid config
-const MAGIC: u8 = 0x55;
-
-const CONFIG_SIZE: usize = ((NUM_BTS + 1) + 1) & !1;
-// How many configs we can fit on one page
-const CONFIGS_IN_PAGE: usize = PAGE_SIZE / CONFIG_SIZE;
+/// We will use the last flash pages for storing the configuration, they are left out of the program
+/// space in `memory.x`.
+const CONFIG_ADD: usize = FLASH_START + (FLASH_SIZE_KB - NUM_PAGES) * PAGE_SIZE;
+// The record layout and the magic byte are in `keylib::storage`, the host reads them for the page
+// dump
 const_assert!(CONFIGS_IN_PAGE > 0);
+// Writing to the start of the page in use would lose the configuration on a power loss
+const_assert!(NUM_PAGES >= 2);
 
 const KEY1: u32 = 0x45670123;
 const KEY2: u32 = 0xCDEF89AB;
@@ -57,100 +75,312 @@ pub enum FlashError {
 pub struct ConfigWriter {
     // Guarantee for the ownership of the registers, zero sized
     _parts: Parts,
+    /// Page of the record in use, counted from `CONFIG_ADD`.
+    page: usize,
+    /// Index of the record in use in its page.
     last_valid_index: usize,
+    /// Sequence number of the record in use, see `storage::SEQUENCE_OFFSET`.
+    sequence: u32,
+    /// Total number of erases of the config pages, see `storage::erase_count`.
+    erase_count: u32,
+    /// The stored config couldn't be read, at boot or by `get_config`, and the defaults took its
+    /// place. Cleared by the next successful `write_config`.
+    load_failed: bool,
+    /// The last `write_config` failed, see `Status::save_failed`.
+    save_failed: bool,
+    /// Profile stored as active and loaded at boot, or switched to by `select_profile`, the one
+    /// `write_config` saves to.
+    profile: usize,
 }
 
 impl ConfigWriter {
     pub fn new(_parts: Parts) -> Result<Self, FlashError> {
         let mut writer = Self {
             _parts,
+            page: 0,
             last_valid_index: 0,
+            sequence: 0,
+            erase_count: storage::erase_count(config_area()),
+            load_failed: false,
+            save_failed: false,
+            profile: 0,
         };
 
-        // Do we need to erase the whole thing ?
-        if unsafe { ptr::read_volatile(CONFIG_ADD as *const u8) } != MAGIC {
-            log!("No saved config found, creating default one");
-            writer.write_default()?;
-            Ok(writer)
-        } else {
-            // Look for the last valid index, zero index already checked
-            for current_idx in 1..CONFIGS_IN_PAGE {
-                let current_addr = CONFIG_ADD + current_idx * CONFIG_SIZE;
-                let value = unsafe { ptr::read_volatile(current_addr as *const u8) };
-                if value == MAGIC {
-                    writer.last_valid_index += 1;
-                } else {
-                    break;
-                }
+        match storage::newest(config_area()) {
+            Some((page, index)) => {
+                writer.page = page;
+                writer.last_valid_index = index;
+                writer.sequence =
+                    storage::sequence(writer.read(Self::record_addr(page, index), CONFIG_SIZE)?);
+                writer.profile = writer.last_record().map_or(0, storage::active_profile);
             }
-            Ok(writer)
+            // Do we need to erase the whole thing ?
+            None => match storage::migrate(config_area()) {
+                Some((page, config)) => {
+                    // The old page counts as the one in use, so it's kept until the migrated
+                    // config is written to the other one
+                    log!("Config stored in an older format, migrating it");
+                    writer.page = page;
+                    writer.rewrite(&config)?;
+                    writer.profile = storage::active_profile(&config);
+                }
+                None => {
+                    log!("No valid config found, creating default one");
+                    // Erased pages are a fresh device, anything else was a config that got lost
+                    writer.load_failed = config_area().iter().any(|&byte| byte != 0xFF);
+                    writer.write_default()?;
+                }
+            },
         }
+        Ok(writer)
     }
 
-    /// Writes a default configuration to the start of the config page.
+    /// Index of the record in use, counting the records of every config page in order, like the
+    /// host's page dump does.
+    pub fn last_valid_index(&self) -> usize {
+        self.page * CONFIGS_IN_PAGE + self.last_valid_index
+    }
+
+    /// Total number of erases of the config pages since the counter was introduced.
+    pub fn erase_count(&self) -> u32 {
+        self.erase_count
+    }
+
+    /// Active profile, see `storage::NUM_PROFILES`.
+    pub fn profile(&self) -> usize {
+        self.profile
+    }
+
+    /// Writes a default configuration to the start of the other config page.
     pub fn write_default(&mut self) -> Result<(), FlashError> {
-        self.erase_page()?;
+        self.profile = 0;
+        self.rewrite(&Self::default_config(&Settings::new()))
+    }
+
+    /// Replaces every profile and the settings with the defaults, see `write_default`. A failure
+    /// is reported like the one of `write_config`.
+    pub fn factory_reset(&mut self) -> Result<(), FlashError> {
+        let result = self.write_default();
+        self.save_failed = result.is_err();
+        if result.is_ok() {
+            self.load_failed = false;
+        }
+        result
+    }
+
+    /// Whether the stored config was lost and replaced by the defaults, see `Status::load_failed`.
+    pub fn load_failed(&self) -> bool {
+        self.load_failed
+    }
+
+    /// Whether the last `write_config` failed, see `Status::save_failed`.
+    pub fn save_failed(&self) -> bool {
+        self.save_failed
+    }
+
+    /// Reads the matrix of the active profile and the settings, if they can't be decoded the
+    /// caller falls back to the defaults and `load_failed` is set.
+    pub fn get_config(&mut self) -> Option<(Matrix, Settings)> {
+        let config = self.decode_config();
+        if config.is_none() {
+            log!("Stored config can't be decoded");
+            self.load_failed = true;
+        }
+        config
+    }
+
+    fn decode_config(&self) -> Option<(Matrix, Settings)> {
+        let record = self.last_record()?;
+        let matrix = Self::profile_matrix(record, self.profile)?;
+        // Remove possible padding and the CRC
+        let mut data = [0u8; Settings::SIZE];
+        data.copy_from_slice(&record[SETTINGS_OFFSET..SETTINGS_OFFSET + Settings::SIZE]);
+        let settings = Settings::from_bytes(data)?;
+        Some((matrix, settings))
+    }
+
+    /// Switches to `profile` and stores it as the active one, so it's also used after a reset.
+    /// Returns its matrix, or `None` if the last config can't be read, the write failed or
+    /// `profile` is out of range, in which case the active profile doesn't change.
+    ///
+    /// The matrix comes from flash, so changes to the current profile that weren't saved are
+    /// dropped, even when selecting the active profile again.
+    pub fn select_profile(&mut self, profile: usize) -> Option<Matrix> {
+        if profile >= NUM_PROFILES {
+            return None;
+        }
         let mut config = [0u8; CONFIG_SIZE];
-        Self::matrix_to_config(Matrix::new(), &mut config);
+        config.copy_from_slice(self.last_record()?);
+        // Nothing to store if it's already the active one
+        if profile != self.profile {
+            config[PROFILE_OFFSET] = profile as u8;
+            self.append(&config).ok()?;
+            self.profile = profile;
+        }
+        // A profile that was never saved, or that's no longer valid, starts from the defaults
+        Some(Self::profile_matrix(&config, profile).unwrap_or_else(Matrix::new))
+    }
 
-        self.write(CONFIG_ADD, &config[..])?;
-        self.last_valid_index = 0;
-        Ok(())
+    /// Switches to the profile after the active one, see `select_profile`.
+    pub fn next_profile(&mut self) -> Option<Matrix> {
+        self.select_profile((self.profile + 1) % NUM_PROFILES)
     }
 
-    pub fn get_config(&self) -> Option<Matrix> {
-        let last_addr = CONFIG_ADD + self.last_valid_index * CONFIG_SIZE;
-        let config = self.read(last_addr + 1, CONFIG_SIZE - 1).ok()?;
-        // Remove possible padding byte
-        let mut data = [0u8; NUM_BTS];
-        data.copy_from_slice(&config[..NUM_BTS]);
-        if let Some(matrix) = Matrix::from_bytes(data) {
-            Some(matrix)
-        } else {
-            None
+    /// Tries to write a config to the next flash index, if the current index is the last one of its
+    /// page, this method will erase the other page and write to the first place of it. The same
+    /// happens if the next place isn't erased, e.g. because a previous write was interrupted.
+    ///
+    /// The whole record is read back and its CRC checked before it's considered the last valid
+    /// one, on a mismatch the previous config stays in use.
+    pub fn write_config(&mut self, matrix: &Matrix, settings: &Settings) -> Result<(), FlashError> {
+        // The other profiles are kept, or reset if the last config can't be read
+        let mut config = match self.last_record() {
+            Some(record) => {
+                let mut config = [0u8; CONFIG_SIZE];
+                config.copy_from_slice(record);
+                config
+            }
+            None => Self::default_config(settings),
+        };
+        Self::matrix_to_config(self.profile, matrix, settings, &mut config);
+        let result = self.append(&config);
+        self.save_failed = result.is_err();
+        if result.is_ok() {
+            self.load_failed = false;
         }
+        result
     }
 
-    /// Tries to write a config to the next flash index, if the current index is the last one, this
-    /// method will erase the whole page and write to the first place. It will fail if the next
-    /// place to write is not already erased.
-    pub fn write_config(&mut self, matrix: Matrix) -> Result<(), FlashError> {
+    /// Moves the config in use to the start of the other page, erasing it first, so the next
+    /// `write_config`s don't need an erase. Nothing is done if the config in use is already the
+    /// first one of its page.
+    pub fn compact(&mut self) -> Result<(), FlashError> {
+        if self.last_valid_index == 0 {
+            return Ok(());
+        }
         let mut config = [0u8; CONFIG_SIZE];
-        Self::matrix_to_config(matrix, &mut config);
+        config.copy_from_slice(self.last_record().ok_or(FlashError::VerificationError)?);
+        self.rewrite(&config)
+    }
 
-        if self.last_valid_index + 1 < CONFIGS_IN_PAGE {
-            let next_addr = CONFIG_ADD + (self.last_valid_index + 1) * CONFIG_SIZE;
+    /// Writes `config` to the next flash index, see `write_config`.
+    fn append(&mut self, config: &[u8; CONFIG_SIZE]) -> Result<(), FlashError> {
+        let next = self.last_valid_index + 1;
+        if next < CONFIGS_IN_PAGE {
+            let next_addr = Self::record_addr(self.page, next);
             let value = unsafe { ptr::read_volatile(next_addr as *const u8) };
             if value != 0xFF {
-                log!("Found no erased flash while attempting write");
-                return Err(FlashError::FlashNotErased);
+                log!("Found no erased flash while attempting write, moving to the other page");
+                return self.rewrite(config);
             }
-            self.write(next_addr, &config[..])?;
-            self.last_valid_index += 1;
+            self.program(self.page, next, config)
         } else {
-            // No more space in the page, erase and go back to the start
-            log!("Got to the end of page, going back to start");
-            self.erase_page()?;
-            self.write(CONFIG_ADD, &config[..])?;
-            self.last_valid_index = 0;
+            // No more space in the page, erase the other one and start there
+            log!("Got to the end of page, moving to the other page");
+            self.rewrite(config)
         }
+    }
+
+    /// Erases the page after the one in use and writes `config` to the start of it. The page in
+    /// use is left alone, so its config stays valid if this is interrupted.
+    fn rewrite(&mut self, config: &[u8; CONFIG_SIZE]) -> Result<(), FlashError> {
+        let page = (self.page + 1) % NUM_PAGES;
+        self.erase_page(page)?;
+        self.program(page, 0, config)
+    }
+
+    /// Writes `config` with the next sequence number to `index` of `page`, it only becomes the
+    /// record in use once it's read back successfully.
+    fn program(
+        &mut self,
+        page: usize,
+        index: usize,
+        config: &[u8; CONFIG_SIZE],
+    ) -> Result<(), FlashError> {
+        let sequence = self.sequence.wrapping_add(1);
+        let mut config = *config;
+        storage::set_sequence(&mut config[..], sequence);
+        crc::seal(&mut config[..]);
+
+        let addr = Self::record_addr(page, index);
+        self.write(addr, &config[..])?;
+        self.verify(addr)?;
+        self.page = page;
+        self.last_valid_index = index;
+        self.sequence = sequence;
         Ok(())
     }
 
-    fn matrix_to_config(matrix: Matrix, config: &mut [u8; CONFIG_SIZE]) {
-        let bytes = matrix.to_bytes();
+    fn record_addr(page: usize, index: usize) -> usize {
+        CONFIG_ADD + page * PAGE_SIZE + index * CONFIG_SIZE
+    }
+
+    /// The record in use, if its CRC is valid. Its active profile is read with
+    /// `storage::active_profile`, which handles one out of range.
+    fn last_record(&self) -> Option<&[u8]> {
+        let last_addr = Self::record_addr(self.page, self.last_valid_index);
+        let record = self.read(last_addr, CONFIG_SIZE).ok()?;
+        if !crc::check(record) {
+            log!("Stored config failed the CRC check");
+            return None;
+        }
+        Some(record)
+    }
+
+    fn profile_matrix(record: &[u8], profile: usize) -> Option<Matrix> {
+        let offset = storage::matrix_offset(profile);
+        let mut data = [0u8; Matrix::SIZE];
+        data.copy_from_slice(&record[offset..offset + Matrix::SIZE]);
+        Matrix::from_bytes(data)
+    }
+
+    /// A config with every profile set to the default matrix and the first one active.
+    fn default_config(settings: &Settings) -> [u8; CONFIG_SIZE] {
+        let mut config = [0u8; CONFIG_SIZE];
+        for profile in 0..NUM_PROFILES {
+            Self::matrix_to_config(profile, &Matrix::new(), settings, &mut config);
+        }
+        config[PROFILE_OFFSET] = 0;
+        config
+    }
+
+    /// Reads back the record at `addr` and checks its CRC.
+    fn verify(&self, addr: usize) -> Result<(), FlashError> {
+        if crc::check(self.read(addr, CONFIG_SIZE)?) {
+            Ok(())
+        } else {
+            log!("CRC mismatch after programming");
+            Err(FlashError::VerificationError)
+        }
+    }
+
+    /// Stores `matrix` as `profile`, which becomes the active one, and `settings` in `config`. The
+    /// sequence number and the CRC are only filled in when it's written.
+    fn matrix_to_config(
+        profile: usize,
+        matrix: &Matrix,
+        settings: &Settings,
+        config: &mut [u8; CONFIG_SIZE],
+    ) {
         config[0] = MAGIC;
-        config[1..=NUM_BTS].copy_from_slice(&bytes[..]);
+        config[PROFILE_OFFSET] = profile as u8;
+        let offset = storage::matrix_offset(profile);
+        config[offset..offset + Matrix::SIZE].copy_from_slice(&matrix.to_bytes());
+        config[SETTINGS_OFFSET..SETTINGS_OFFSET + Settings::SIZE]
+            .copy_from_slice(&settings.to_bytes());
     }
 
-    fn erase_page(&mut self) -> Result<(), FlashError> {
+    /// Erases `page` of the config pages, counted from `CONFIG_ADD`, and stores the new erase count
+    /// in its tail.
+    fn erase_page(&mut self, page: usize) -> Result<(), FlashError> {
+        let page_addr = CONFIG_ADD + page * PAGE_SIZE;
         self.unlock()?;
         self.flash().cr.modify(|_, w| w.per().set_bit());
 
         // NOTE(unsafe) valid address to write to far
         self.flash()
             .ar
-            .write(|w| unsafe { w.far().bits(CONFIG_ADD as u32) });
+            .write(|w| unsafe { w.far().bits(page_addr as u32) });
 
         // Start Operation
         self.flash().cr.modify(|_, w| w.strt().set_bit());
@@ -169,14 +399,20 @@ impl ConfigWriter {
             self.flash().sr.modify(|_, w| w.wrprterr().clear_bit());
             Err(FlashError::EraseError)
         } else {
-            // Verifying
-            for address in CONFIG_ADD..CONFIG_ADD + PAGE_SIZE {
-                // NOTE(unsafe) This is a valid address to read from
-                let verify = unsafe { ptr::read_volatile(address as *const u16) };
-                if verify != 0xFFFF {
-                    log!("Verification error during erasing");
-                    return Err(FlashError::VerificationError);
-                }
+            if !storage::is_erased(self.read(page_addr, PAGE_SIZE)?) {
+                log!("Verification error during erasing");
+                return Err(FlashError::VerificationError);
+            }
+
+            // The counter is only written here, so saving doesn't wear the page any further. The
+            // page is usable even if it can't be written, the count is just lower
+            self.erase_count = self.erase_count.saturating_add(1);
+            let count = storage::erase_count_bytes(self.erase_count);
+            if self
+                .write(page_addr + ERASE_COUNT_OFFSET, &count[..])
+                .is_err()
+            {
+                log!("Failed to store the erase count");
             }
             Ok(())
         }
@@ -269,6 +505,13 @@ impl ConfigWriter {
     }
 
     fn valid_range(start: usize, length: usize) -> bool {
-        (start >= CONFIG_ADD) && (start + length < FLASH_END)
+        (start >= CONFIG_ADD) && (start + length <= FLASH_END)
     }
 }
+
+/// Every config page, one after the other, for the host's page dump. It can be read while the scan
+/// task writes to it, which is fine for a diagnostic.
+pub fn config_area() -> &'static [u8] {
+    // NOTE(unsafe) The config pages are always mapped and only changed through the flash registers
+    unsafe { slice::from_raw_parts(CONFIG_ADD as *const u8, CONFIG_AREA_SIZE) }
+}
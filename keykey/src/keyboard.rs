@@ -1,21 +1,26 @@
-use super::{
-    flash::{ConfigWriter, FlashError},
-    BtnsType, NUM_BTS,
-};
-use core::{
-    convert::TryFrom,
-    sync::atomic::{compiler_fence, Ordering},
-};
+use super::BtnsType;
+use crate::flash;
+use core::sync::atomic::{compiler_fence, Ordering};
 use debouncer::typenum::consts::*;
 use debouncer::{BtnState, PortDebouncer};
 use heapless::spsc::Producer;
 use keylib::{
-    key_code::{
-        valid_ranges::{ZONE1_FIRST, ZONE1_LAST, ZONE2_FIRST, ZONE2_LAST},
-        KbHidReport, KeyCode,
+    boot::{Status, UsbAttributes},
+    consumer::{ConsumerReport, CONSUMER_SLOTS},
+    debounce::DebounceInfo,
+    idle::IdleRate,
+    key_code::{KbHidReport, REPORT_SIZE},
+    matrix::{LayerState, Matrix},
+    mouse::MouseReport,
+    name::{self, DeviceName},
+    packets::{
+        self, AppCommand, CtrlOut, DescriptorType, InfoField, Protocol, Query, ReportType, Request,
+        Version, CTRL_REPORT_SIZE, PROTOCOL_VERSION,
     },
-    packets::{AppCommand, DescriptorType, ReportType, Request, VendorCommand},
-    CTRL_INTERFACE,
+    selector,
+    sequence::ConnectDetector,
+    storage::CONFIG_AREA_SIZE,
+    CTRL_INTERFACE, NUM_BTS,
 };
 use usb_device::{
     bus::{InterfaceNumber, StringIndex, UsbBus, UsbBusAllocator},
@@ -26,6 +31,7 @@ use usb_device::{
     UsbError,
 };
 
+#[cfg(not(feature = "nkro"))]
 #[rustfmt::skip]
 const KEY_REPORT_DESCRIPTOR: &[u8] = &[
     0x05, 0x01,             // Usage Page (Generic Desktop Ctrls)
@@ -42,6 +48,15 @@ const KEY_REPORT_DESCRIPTOR: &[u8] = &[
     0x95, 0x01,             //   Report Count (1)
     0x75, 0x08,             //   Report Size (8)
     0x81, 0x03,             //   Input (Const,Var,Abs,No Wrap,Linear,Preferred State,No Null Position)
+    0x05, 0x08,             //   Usage Page (LEDs)
+    0x19, 0x01,             //   Usage Minimum (Num Lock)
+    0x29, 0x03,             //   Usage Maximum (Scroll Lock)
+    0x95, 0x03,             //   Report Count (3)
+    0x75, 0x01,             //   Report Size (1)
+    0x91, 0x02,             //   Output (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
+    0x95, 0x01,             //   Report Count (1)
+    0x75, 0x05,             //   Report Size (5)
+    0x91, 0x03,             //   Output (Const,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
     0x95, 0x06,             //   Report Count (6)
     0x75, 0x08,             //   Report Size (8)
     0x15, 0x00,             //   Logical Minimum (0)
@@ -53,8 +68,83 @@ const KEY_REPORT_DESCRIPTOR: &[u8] = &[
     0xC0,                   // End Collection
 ];
 
+// A bit for every usage of the keyboard page, modifiers included, it can't be a boot keyboard
+#[cfg(feature = "nkro")]
+#[rustfmt::skip]
+const KEY_REPORT_DESCRIPTOR: &[u8] = &[
+    0x05, 0x01,             // Usage Page (Generic Desktop Ctrls)
+    0x09, 0x06,             // Usage (Keyboard)
+    0xA1, 0x01,             // Collection (Application)
+    0x05, 0x08,             //   Usage Page (LEDs)
+    0x19, 0x01,             //   Usage Minimum (Num Lock)
+    0x29, 0x03,             //   Usage Maximum (Scroll Lock)
+    0x15, 0x00,             //   Logical Minimum (0)
+    0x25, 0x01,             //   Logical Maximum (1)
+    0x95, 0x03,             //   Report Count (3)
+    0x75, 0x01,             //   Report Size (1)
+    0x91, 0x02,             //   Output (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
+    0x95, 0x01,             //   Report Count (1)
+    0x75, 0x05,             //   Report Size (5)
+    0x91, 0x03,             //   Output (Const,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
+    0x05, 0x07,             //   Usage Page (Kbrd/Keypad)
+    0x19, 0x00,             //   Usage Minimum (0x00)
+    0x29, 0xFF,             //   Usage Maximum (0xFF)
+    0x96, 0x00, 0x01,       //   Report Count (256)
+    0x75, 0x01,             //   Report Size (1)
+    0x81, 0x02,             //   Input (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position)
+    0xC0,                   // End Collection
+];
+
+// Media keys go on their own interface, so the keyboard report keeps its boot-like layout without a
+// report ID
+#[rustfmt::skip]
+const CONSUMER_REPORT_DESCRIPTOR: &[u8] = &[
+    0x05, 0x0C,             // Usage Page (Consumer)
+    0x09, 0x01,             // Usage (Consumer Control)
+    0xA1, 0x01,             // Collection (Application)
+    0x15, 0x00,             //   Logical Minimum (0)
+    0x26, 0xFF, 0x00,       //   Logical Maximum (255)
+    0x19, 0x00,             //   Usage Minimum (Unassigned)
+    0x2A, 0xFF, 0x00,       //   Usage Maximum (0xFF)
+    0x75, 0x08,             //   Report Size (8)
+    0x95, CONSUMER_SLOTS as u8, //   Report Count (CONSUMER_SLOTS)
+    0x81, 0x00,             //   Input (Data,Array,Abs,No Wrap,Linear,Preferred State,No Null Position)
+    0xC0,                   // End Collection
+];
+
+#[rustfmt::skip]
+const MOUSE_REPORT_DESCRIPTOR: &[u8] = &[
+    0x05, 0x01,             // Usage Page (Generic Desktop Ctrls)
+    0x09, 0x02,             // Usage (Mouse)
+    0xA1, 0x01,             // Collection (Application)
+    0x09, 0x01,             //   Usage (Pointer)
+    0xA1, 0x00,             //   Collection (Physical)
+    0x05, 0x09,             //     Usage Page (Button)
+    0x19, 0x01,             //     Usage Minimum (0x01)
+    0x29, 0x03,             //     Usage Maximum (0x03)
+    0x15, 0x00,             //     Logical Minimum (0)
+    0x25, 0x01,             //     Logical Maximum (1)
+    0x75, 0x01,             //     Report Size (1)
+    0x95, 0x03,             //     Report Count (3)
+    0x81, 0x02,             //     Input (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position)
+    0x75, 0x05,             //     Report Size (5)
+    0x95, 0x01,             //     Report Count (1)
+    0x81, 0x03,             //     Input (Const,Var,Abs,No Wrap,Linear,Preferred State,No Null Position)
+    0x05, 0x01,             //     Usage Page (Generic Desktop Ctrls)
+    0x09, 0x30,             //     Usage (X)
+    0x09, 0x31,             //     Usage (Y)
+    0x15, 0x81,             //     Logical Minimum (-127)
+    0x25, 0x7F,             //     Logical Maximum (127)
+    0x75, 0x08,             //     Report Size (8)
+    0x95, 0x02,             //     Report Count (2)
+    0x81, 0x06,             //     Input (Data,Var,Rel,No Wrap,Linear,Preferred State,No Null Position)
+    0xC0,                   //   End Collection
+    0xC0,                   // End Collection
+];
+
 // Windows doesn't let you access a keyboard interface, so create another interface for
 // configuration. A WinUSB interface would be better, but I hit libusb #619.
+// The report fits the longest command, shorter ones are padded, see `CTRL_REPORT_SIZE`.
 #[rustfmt::skip]
 const CTRL_REPORT_DESCRIPTOR: &[u8] = &[
     0x06, 0x00, 0xFF,       // Usage Page (Vendor Defined 0xFF00)
@@ -64,15 +154,38 @@ const CTRL_REPORT_DESCRIPTOR: &[u8] = &[
     0x15, 0x00,             //   Logical Minimum (0)
     0x26, 0xFF, 0x00,       //   Logical Maximum (255)
     0x75, 0x08,             //   Report Size (8)
-    0x95, 0x02,             //   Report Count (2)
+    0x95, CTRL_REPORT_SIZE as u8, //   Report Count (CTRL_REPORT_SIZE)
     0xB1, 0x02,             //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
     0xC0,                   // End Collection
 ];
 
 const SPECIFICATION_RELEASE: u16 = 0x111;
 const INTERFACE_CLASS_HID: u8 = 0x03;
+/// Version of this firmware, from its crate, see `VendorCommand::GetVersion`.
+const FIRMWARE_VERSION: Version = Version::parse(
+    env!("CARGO_PKG_VERSION_MAJOR"),
+    env!("CARGO_PKG_VERSION_MINOR"),
+    env!("CARGO_PKG_VERSION_PATCH"),
+);
 const SUBCLASS_NONE: u8 = 0x00;
-const KEYBOARD_PROTOCOL: u8 = 0x01;
+// BIOS and UEFI setups only look for boot keyboards, the key report already has the boot layout
+#[cfg(not(feature = "nkro"))]
+const KEY_SUBCLASS: u8 = 0x01;
+#[cfg(not(feature = "nkro"))]
+const KEY_PROTOCOL: u8 = 0x01;
+// The NKRO report doesn't have the boot layout, so it's a plain keyboard
+#[cfg(feature = "nkro")]
+const KEY_SUBCLASS: u8 = SUBCLASS_NONE;
+#[cfg(feature = "nkro")]
+const KEY_PROTOCOL: u8 = 0x00;
+
+/// Names of the interfaces in the order they are allocated, shown by hosts and USB inspectors.
+const INTERFACE_NAMES: [&str; 4] = [
+    "KeyKey Keyboard",
+    "KeyKey Configuration",
+    "KeyKey Media Keys",
+    "KeyKey Mouse",
+];
 
 pub struct Keykey<'a, 'b, B: UsbBus> {
     interface: InterfaceNumber,
@@ -81,11 +194,62 @@ pub struct Keykey<'a, 'b, B: UsbBus> {
     dummy_endpoint: EndpointIn<'a, B>,
     expect_interrupt_in_complete: bool,
     report: KbHidReport,
+    /// Lock LEDs from the output report of the keyboard interface, see `InfoField::Leds`.
+    leds: u8,
+    key_idle: IdleRate,
+    /// Chosen by the host, the key report is the same 8-byte boot report in both protocols, so it
+    /// doesn't change what's sent, only what GetProtocol answers. Not used by hosts with NKRO,
+    /// since the interface isn't a boot one.
+    protocol: Protocol,
+    consumer_interface: InterfaceNumber,
+    consumer_endpoint: EndpointIn<'a, B>,
+    expect_consumer_complete: bool,
+    consumer_report: ConsumerReport,
+    consumer_idle: IdleRate,
+    mouse_interface: InterfaceNumber,
+    mouse_endpoint: EndpointIn<'a, B>,
+    expect_mouse_complete: bool,
+    /// Buttons of the last report sent, the movement in it was already applied by the host.
+    mouse_report: MouseReport,
+    mouse_idle: IdleRate,
+    /// Buttons of the latest report and the movement not sent yet.
+    pending_mouse: MouseReport,
     cmd_prod: Producer<'b, AppCommand, U8>,
+    connect: ConnectDetector,
+    /// The device was enumerated and the startup sequence wasn't started yet.
+    connected: bool,
+    /// Suspended by a host that enabled remote wakeup, and no wakeup was signaled yet.
+    wakeup: bool,
+    status: Status,
+    debounce_info: DebounceInfo,
+    /// The ones the device was built with, not the stored ones.
+    usb_attributes: UsbAttributes,
+    /// Updated by the scan task, which owns the flash writer.
+    config_index: u16,
+    profile: u16,
+    erase_count: u16,
+    /// Debounced state of the buttons as of the last scan.
+    pressed: u8,
+    /// Copy of the name in the settings, which belong to the scan task.
+    name: DeviceName,
+    /// Answer of the next GetReport of the control interface.
+    query: Query,
+    /// Updated by the scan task, so a query always sees the state of a complete scan.
+    layer_state: LayerState,
+    /// Layout in use, also updated by the scan task.
+    config: [u8; Matrix::SIZE],
+    /// String descriptors of `INTERFACE_NAMES`, the device strings are answered by `UsbDevice`.
+    interface_strings: [StringIndex; INTERFACE_NAMES.len()],
 }
 
 impl<'a, 'b, B: UsbBus> Keykey<'a, 'b, B> {
-    pub fn new(alloc: &'a UsbBusAllocator<B>, prod: Producer<'b, AppCommand, U8>) -> Self {
+    pub fn new(
+        alloc: &'a UsbBusAllocator<B>,
+        prod: Producer<'b, AppCommand, U8>,
+        status: Status,
+        debounce_info: DebounceInfo,
+        usb_attributes: UsbAttributes,
+    ) -> Self {
         let key_interface = alloc.interface();
 
         // We want key interface to be 0 and ctrl interface to be 1, We use this because hidapi on
@@ -96,11 +260,46 @@ impl<'a, 'b, B: UsbBus> Keykey<'a, 'b, B> {
         let keykey = Self {
             interface: key_interface,
             ctrl_interface: alloc.interface(),
-            endpoint_interrupt_in: alloc.interrupt(8, 10),
+            endpoint_interrupt_in: alloc.interrupt(REPORT_SIZE as u16, 10),
             dummy_endpoint: alloc.interrupt(16, 10),
             expect_interrupt_in_complete: false,
             report: KbHidReport::new(),
+            leds: 0,
+            key_idle: IdleRate::new(),
+            protocol: Protocol::Report,
+            // After the control interface, which has to be 1
+            consumer_interface: alloc.interface(),
+            consumer_endpoint: alloc.interrupt(8, 10),
+            expect_consumer_complete: false,
+            consumer_report: ConsumerReport::new(),
+            consumer_idle: IdleRate::new(),
+            mouse_interface: alloc.interface(),
+            mouse_endpoint: alloc.interrupt(8, 10),
+            expect_mouse_complete: false,
+            mouse_report: MouseReport::new(),
+            mouse_idle: IdleRate::new(),
+            pending_mouse: MouseReport::new(),
             cmd_prod: prod,
+            connect: ConnectDetector::new(),
+            connected: false,
+            wakeup: false,
+            status,
+            debounce_info,
+            usb_attributes,
+            config_index: 0,
+            profile: 0,
+            erase_count: 0,
+            pressed: 0,
+            name: DeviceName::new(),
+            query: Query::Status,
+            layer_state: LayerState::default(),
+            config: [0; Matrix::SIZE],
+            interface_strings: [
+                alloc.string(),
+                alloc.string(),
+                alloc.string(),
+                alloc.string(),
+            ],
         };
 
         // This should always be true, given how `alloc.interface()` is implemented, this assert is
@@ -114,47 +313,233 @@ impl<'a, 'b, B: UsbBus> Keykey<'a, 'b, B> {
             return Ok(0);
         }
 
-        if data.len() >= 8 {
-            self.expect_interrupt_in_complete = true;
-        }
-
         match self.endpoint_interrupt_in.write(data) {
-            Ok(count) => Ok(count),
+            Ok(count) => {
+                if data.len() >= 8 {
+                    self.expect_interrupt_in_complete = true;
+                }
+                Ok(count)
+            }
             Err(UsbError::WouldBlock) => Ok(0),
             Err(_) => Err(()),
         }
     }
 
-    pub fn set_keyboard_report(&mut self, report: KbHidReport) -> bool {
-        if report == self.report {
-            false
+    /// Sends `report` if it's different from the last one sent, or if the idle rate set by the
+    /// host is due. Nothing is sent while the previous report is in flight, so the report should be
+    /// offered again on the next scan, which is also what the idle rate counts. Returns `true` if
+    /// `report` is the last one sent.
+    pub fn send_keyboard_report(&mut self, report: &KbHidReport) -> Result<bool, ()> {
+        // Without NKRO, KbHidReport is the 8-byte boot report, so it's the same in both protocols
+        let idle = self.key_idle.tick();
+        if (*report != self.report || idle) && self.write(report.as_bytes())? > 0 {
+            self.report = report.clone();
+            self.key_idle.sent();
+        }
+        Ok(*report == self.report)
+    }
+
+    /// Same as `send_keyboard_report`, for the consumer interface.
+    pub fn send_consumer_report(&mut self, report: &ConsumerReport) -> Result<bool, ()> {
+        let idle = self.consumer_idle.tick();
+        if (*report != self.consumer_report || idle)
+            && write_report(
+                &self.consumer_endpoint,
+                &mut self.expect_consumer_complete,
+                report.as_bytes(),
+            )?
+        {
+            self.consumer_report = *report;
+            self.consumer_idle.sent();
+        }
+        Ok(*report == self.consumer_report)
+    }
+
+    /// Same as `send_keyboard_report`, for the mouse interface. The movement is relative, so a
+    /// report that moves is sent even if it's the same as the last one, and the movement of the
+    /// reports that couldn't be sent is added up instead of dropped.
+    pub fn send_mouse_report(&mut self, report: &MouseReport) -> Result<bool, ()> {
+        self.pending_mouse.accumulate(report);
+        let idle = self.mouse_idle.tick();
+        if (self.pending_mouse != self.mouse_report || self.pending_mouse.has_motion() || idle)
+            && write_report(
+                &self.mouse_endpoint,
+                &mut self.expect_mouse_complete,
+                self.pending_mouse.as_bytes(),
+            )?
+        {
+            self.mouse_report = self.pending_mouse.buttons();
+            self.pending_mouse = self.mouse_report;
+            self.mouse_idle.sent();
+        }
+        Ok(self.pending_mouse == self.mouse_report && report.buttons() == self.mouse_report)
+    }
+
+    /// Should be called after polling while the device is in the configured state.
+    pub fn configured(&mut self) {
+        if self.connect.configured() {
+            self.connected = true;
+        }
+    }
+
+    /// Returns `true` once for every fresh enumeration, a resume from suspend doesn't count.
+    pub fn take_connected(&mut self) -> bool {
+        core::mem::replace(&mut self.connected, false)
+    }
+
+    /// Should be called after polling, `wakeup` is `true` while the device is suspended and the
+    /// host enabled remote wakeup.
+    pub fn set_suspended(&mut self, wakeup: bool) {
+        self.wakeup = wakeup;
+    }
+
+    /// Returns `true` once per suspend if the host can be woken up, the host resuming the bus
+    /// is what clears the suspended state.
+    pub fn take_wakeup(&mut self) -> bool {
+        core::mem::replace(&mut self.wakeup, false)
+    }
+
+    /// Lock LEDs the host wants on, bit 0 is Num Lock, bit 1 Caps Lock and bit 2 Scroll Lock.
+    pub fn leds(&self) -> u8 {
+        self.leds
+    }
+
+    /// Idle rate of `interface`, `None` if it has no input report.
+    fn idle_rate(&mut self, interface: u8) -> Option<&mut IdleRate> {
+        if interface == u8::from(self.interface) {
+            Some(&mut self.key_idle)
+        } else if interface == u8::from(self.consumer_interface) {
+            Some(&mut self.consumer_idle)
+        } else if interface == u8::from(self.mouse_interface) {
+            Some(&mut self.mouse_idle)
         } else {
-            self.report = report;
-            true
+            None
         }
     }
 
+    pub fn set_layer_state(&mut self, state: LayerState) {
+        self.layer_state = state;
+    }
+
+    pub fn set_config_index(&mut self, index: usize) {
+        self.config_index = index as u16;
+    }
+
+    pub fn set_profile(&mut self, profile: usize) {
+        self.profile = profile as u16;
+    }
+
+    pub fn set_erase_count(&mut self, count: u32) {
+        self.erase_count = count.min(u32::from(u16::MAX)) as u16;
+    }
+
+    pub fn set_load_failed(&mut self, failed: bool) {
+        self.status.load_failed = failed;
+    }
+
+    pub fn set_save_failed(&mut self, failed: bool) {
+        self.status.save_failed = failed;
+    }
+
+    pub fn set_config(&mut self, config: [u8; Matrix::SIZE]) {
+        self.config = config;
+    }
+
+    pub fn set_name(&mut self, name: DeviceName) {
+        self.name = name;
+    }
+
+    pub fn set_debounce_info(&mut self, info: DebounceInfo) {
+        self.debounce_info = info;
+    }
+
+    pub fn set_pressed(&mut self, pressed: &[bool; NUM_BTS]) {
+        self.pressed = selector::pressed_mask(pressed);
+    }
+
     fn get_report(&mut self, xfer: ControlIn<B>) {
         let req = xfer.request();
         let [report_type, _report_id] = req.value.to_be_bytes();
         let report_type = ReportType::from(report_type);
         let interface = req.index as u8;
 
+        // Answers are padded to a whole report
+        let mut ctrl_report = [0; CTRL_REPORT_SIZE];
         let response = if interface == u8::from(self.interface) {
             self.report.as_bytes()
+        } else if interface == u8::from(self.consumer_interface) {
+            self.consumer_report.as_bytes()
+        } else if interface == u8::from(self.mouse_interface) {
+            self.mouse_report.as_bytes()
         } else if interface == u8::from(self.ctrl_interface) {
-            &[0; 16]
+            // A query is only answered once, later reads go back to the status
+            let answer = match core::mem::replace(&mut self.query, Query::Status) {
+                Query::Status => self.status.to_bytes(),
+                Query::LayerState => self.layer_state.to_bytes(),
+                Query::Info(InfoField::ConfigIndex) => self.config_index.to_le_bytes(),
+                Query::Info(InfoField::Profile) => self.profile.to_le_bytes(),
+                Query::Info(InfoField::Leds) => u16::from(self.leds()).to_le_bytes(),
+                Query::Info(InfoField::EraseCount) => self.erase_count.to_le_bytes(),
+                Query::Info(InfoField::ConfigAttributes) => {
+                    u16::from(self.usb_attributes.bm_attributes()).to_le_bytes()
+                }
+                Query::Info(InfoField::MaxPower) => {
+                    u16::from(self.usb_attributes.max_power).to_le_bytes()
+                }
+                Query::Info(field) => self.debounce_info.field(field).unwrap_or(0).to_le_bytes(),
+                Query::Pressed => [self.pressed, 0],
+                Query::Name(offset) => {
+                    // Keep answering the name until its end
+                    let offset = offset as usize;
+                    if offset + 2 < name::MAX_LEN {
+                        self.query = Query::Name(offset as u8 + 2);
+                    }
+                    let name = self.name.to_bytes();
+                    [name[offset], name[offset + 1]]
+                }
+                Query::Page(offset) => {
+                    // Keep answering the pages until their end
+                    let offset = offset as usize;
+                    if offset + 2 < CONFIG_AREA_SIZE {
+                        self.query = Query::Page(offset as u16 + 2);
+                    }
+                    let page = flash::config_area();
+                    [page[offset], page[offset + 1]]
+                }
+                Query::Config(offset) => {
+                    // Keep answering the layout until its end, padded to a whole report
+                    let offset = offset as usize;
+                    if offset + 2 < Matrix::SIZE {
+                        self.query = Query::Config(offset as u8 + 2);
+                    }
+                    let config = &self.config;
+                    [config[offset], config.get(offset + 1).copied().unwrap_or(0)]
+                }
+                Query::Version(offset) => {
+                    // Keep answering the version until its end, padded to a whole report
+                    let offset = offset as usize;
+                    if offset + 2 < Version::SIZE {
+                        self.query = Query::Version(offset as u8 + 2);
+                    }
+                    let version = FIRMWARE_VERSION.to_bytes();
+                    [
+                        version[offset],
+                        version.get(offset + 1).copied().unwrap_or(0),
+                    ]
+                }
+                Query::ProtocolVersion => [PROTOCOL_VERSION, 0],
+            };
+            ctrl_report[..answer.len()].copy_from_slice(&answer);
+            &ctrl_report[..]
         } else {
             // This isn't for us
             return;
         };
 
-        if req.length < response.len() as u16 {
-            xfer.reject().ok();
-            return;
-        }
         match report_type {
-            ReportType::Input | ReportType::Feature => xfer.accept_with(response).ok(),
+            ReportType::Input | ReportType::Feature => xfer
+                .accept_with(packets::report_response(response, req.length))
+                .ok(),
             _ => xfer.reject().ok(),
         };
     }
@@ -165,73 +550,91 @@ impl<B: UsbBus> UsbClass<B> for Keykey<'_, '_, B> {
 
     fn reset(&mut self) {
         self.expect_interrupt_in_complete = false;
+        // The host sees every key released after a reset, so agree with it, a button still held is
+        // different from this and gets sent again on the next scan
+        self.report = KbHidReport::new();
+        // The host sends them again once it configures the device, the idle rates too
+        self.leds = 0;
+        self.key_idle = IdleRate::new();
+        // The report protocol is the default after a reset
+        self.protocol = Protocol::Report;
+        self.consumer_idle = IdleRate::new();
+        self.mouse_idle = IdleRate::new();
+        self.expect_consumer_complete = false;
+        self.consumer_report = ConsumerReport::new();
+        self.expect_mouse_complete = false;
+        self.mouse_report = MouseReport::new();
+        self.pending_mouse = MouseReport::new();
+        self.connect.reset();
+        self.connected = false;
     }
 
     fn get_configuration_descriptors(
         &self,
         writer: &mut DescriptorWriter,
     ) -> usb_device::Result<()> {
-        writer.interface(
+        writer.interface_alt(
             self.interface,
+            0,
             INTERFACE_CLASS_HID,
-            SUBCLASS_NONE,
-            KEYBOARD_PROTOCOL,
+            KEY_SUBCLASS,
+            KEY_PROTOCOL,
+            Some(self.interface_strings[0]),
         )?;
-
-        let descriptor_len = KEY_REPORT_DESCRIPTOR.len();
-        if descriptor_len > u16::max_value() as usize {
-            return Err(UsbError::InvalidState);
-        }
-        let descriptor_len = (descriptor_len as u16).to_le_bytes();
-        let specification_release = SPECIFICATION_RELEASE.to_le_bytes();
-        writer.write(
-            DescriptorType::Hid as u8,
-            &[
-                specification_release[0],     // bcdHID.lower
-                specification_release[1],     // bcdHID.upper
-                0,                            // bCountryCode: 0 = not supported
-                1,                            // bNumDescriptors
-                DescriptorType::Report as u8, // bDescriptorType
-                descriptor_len[0],            // bDescriptorLength.lower
-                descriptor_len[1],            // bDescriptorLength.upper
-            ],
-        )?;
-
+        write_hid_descriptor(writer, KEY_REPORT_DESCRIPTOR)?;
         writer.endpoint(&self.endpoint_interrupt_in)?;
 
         // CTRL interface
-        writer.interface(self.ctrl_interface, INTERFACE_CLASS_HID, SUBCLASS_NONE, 0)?;
+        writer.interface_alt(
+            self.ctrl_interface,
+            0,
+            INTERFACE_CLASS_HID,
+            SUBCLASS_NONE,
+            0,
+            Some(self.interface_strings[1]),
+        )?;
+        write_hid_descriptor(writer, CTRL_REPORT_DESCRIPTOR)?;
+        writer.endpoint(&self.dummy_endpoint)?;
 
-        let descriptor_len = CTRL_REPORT_DESCRIPTOR.len();
-        if descriptor_len > u16::max_value() as usize {
-            return Err(UsbError::InvalidState);
-        }
-        let descriptor_len = (descriptor_len as u16).to_le_bytes();
-        let specification_release = SPECIFICATION_RELEASE.to_le_bytes();
-        writer.write(
-            DescriptorType::Hid as u8,
-            &[
-                specification_release[0],     // bcdHID.lower
-                specification_release[1],     // bcdHID.upper
-                0,                            // bCountryCode: 0 = not supported
-                1,                            // bNumDescriptors
-                DescriptorType::Report as u8, // bDescriptorType
-                descriptor_len[0],            // bDescriptorLength.lower
-                descriptor_len[1],            // bDescriptorLength.upper
-            ],
+        writer.interface_alt(
+            self.consumer_interface,
+            0,
+            INTERFACE_CLASS_HID,
+            SUBCLASS_NONE,
+            0,
+            Some(self.interface_strings[2]),
         )?;
+        write_hid_descriptor(writer, CONSUMER_REPORT_DESCRIPTOR)?;
+        writer.endpoint(&self.consumer_endpoint)?;
 
-        writer.endpoint(&self.dummy_endpoint)?;
+        writer.interface_alt(
+            self.mouse_interface,
+            0,
+            INTERFACE_CLASS_HID,
+            SUBCLASS_NONE,
+            0,
+            Some(self.interface_strings[3]),
+        )?;
+        write_hid_descriptor(writer, MOUSE_REPORT_DESCRIPTOR)?;
+        writer.endpoint(&self.mouse_endpoint)?;
         Ok(())
     }
 
-    fn get_string(&self, _index: StringIndex, _lang_id: u16) -> Option<&str> {
-        None
+    fn get_string(&self, index: StringIndex, _lang_id: u16) -> Option<&str> {
+        // Only English is declared, so the language is ignored
+        self.interface_strings
+            .iter()
+            .position(|&string| string == index)
+            .map(|pos| INTERFACE_NAMES[pos])
     }
 
     fn endpoint_in_complete(&mut self, addr: EndpointAddress) {
         if addr == self.endpoint_interrupt_in.address() {
             self.expect_interrupt_in_complete = false;
+        } else if addr == self.consumer_endpoint.address() {
+            self.expect_consumer_complete = false;
+        } else if addr == self.mouse_endpoint.address() {
+            self.expect_mouse_complete = false;
         }
     }
 
@@ -250,6 +653,10 @@ impl<B: UsbBus> UsbClass<B> for Keykey<'_, '_, B> {
                             KEY_REPORT_DESCRIPTOR
                         } else if req.index == u8::from(self.ctrl_interface) as u16 {
                             CTRL_REPORT_DESCRIPTOR
+                        } else if req.index == u8::from(self.consumer_interface) as u16 {
+                            CONSUMER_REPORT_DESCRIPTOR
+                        } else if req.index == u8::from(self.mouse_interface) as u16 {
+                            MOUSE_REPORT_DESCRIPTOR
                         } else {
                             // This isn't for us
                             return;
@@ -260,110 +667,153 @@ impl<B: UsbBus> UsbClass<B> for Keykey<'_, '_, B> {
                     }
                 }
             }
-            (RequestType::Class, Recipient::Interface) => {
-                if let Some(Request::GetReport) = Request::new(req.request) {
-                    self.get_report(xfer);
+            (RequestType::Class, Recipient::Interface) => match Request::new(req.request) {
+                Some(Request::GetReport) => self.get_report(xfer),
+                Some(Request::GetIdle) => {
+                    if let Some(idle) = self.idle_rate(req.index as u8) {
+                        xfer.accept_with(&[idle.rate()]).ok();
+                    }
                 }
-            }
+                Some(Request::GetProtocol) if req.index == u8::from(self.interface) as u16 => {
+                    xfer.accept_with(&[self.protocol as u8]).ok();
+                }
+                _ => {}
+            },
             _ => {}
         }
     }
 
     fn control_out(&mut self, xfer: ControlOut<B>) {
         let req = xfer.request();
+        if req.request_type == RequestType::Class
+            && req.recipient == Recipient::Interface
+            && Request::new(req.request) == Some(Request::SetIdle)
+        {
+            // There's a single report per interface, so the report ID doesn't matter
+            let [rate, _report_id] = req.value.to_be_bytes();
+            if let Some(idle) = self.idle_rate(req.index as u8) {
+                idle.set(rate);
+                xfer.accept().ok();
+                return;
+            }
+        }
+
+        // There's no interrupt OUT endpoint, so the host sends the LED output report this way
+        if req.request_type == RequestType::Class
+            && req.recipient == Recipient::Interface
+            && req.index == u8::from(self.interface) as u16
+        {
+            let [report_type, _report_id] = req.value.to_be_bytes();
+            match (Request::new(req.request), ReportType::from(report_type)) {
+                (Some(Request::SetReport), ReportType::Output) => {
+                    if let Some(&leds) = xfer.data().first() {
+                        self.leds = leds;
+                        xfer.accept().ok();
+                    }
+                }
+                (Some(Request::SetProtocol), _) => {
+                    if let Some(protocol) = Protocol::new(req.value) {
+                        self.protocol = protocol;
+                        xfer.accept().ok();
+                    }
+                }
+                _ => {}
+            }
+            return;
+        }
+
         // Check if this is for us
         if req.request_type == RequestType::Class
             && req.recipient == Recipient::Interface
             && req.index == u8::from(self.ctrl_interface) as u16
         {
-            if let Some(Request::SetReport) = Request::new(req.request) {
-                let data = xfer.data();
-                if data.len() == 2 {
-                    if let (Ok(cmd), Ok(key)) =
-                        (VendorCommand::try_from(data[0]), KeyCode::try_from(data[1]))
-                    {
-                        if self
-                            .cmd_prod
-                            .enqueue(AppCommand::from_req_value(cmd, key))
-                            .is_ok()
-                        {
-                            xfer.accept().ok();
-                            return;
-                        }
+            // `SetAll` fills the whole report, the data stage is buffered by `UsbDevice`
+            match CtrlOut::new(req.request, xfer.data()) {
+                CtrlOut::Query(query) => {
+                    self.query = query;
+                    xfer.accept().ok();
+                    return;
+                }
+                CtrlOut::Command(cmd) => {
+                    if self.cmd_prod.enqueue(cmd).is_ok() {
+                        xfer.accept().ok();
+                        return;
                     }
                 }
+                CtrlOut::Reject => {}
             }
             log!(
                 "Couldn't process request, req: {:?}, data: {:?}",
                 req,
                 xfer.data()
             );
+            xfer.reject().ok();
         }
     }
 }
 
-#[derive(Debug, Copy, Clone)]
-pub struct Matrix {
-    layout: [KeyCode; NUM_BTS],
-}
-
-impl Matrix {
-    pub const fn new() -> Self {
-        Self {
-            layout: [KeyCode::A, KeyCode::B, KeyCode::C],
+/// Writes a report to `endpoint` unless the previous one is still in flight, returns `true` if it
+/// was written.
+fn write_report<B: UsbBus>(
+    endpoint: &EndpointIn<B>,
+    in_flight: &mut bool,
+    data: &[u8],
+) -> Result<bool, ()> {
+    if *in_flight {
+        return Ok(false);
+    }
+    match endpoint.write(data) {
+        Ok(_) => {
+            *in_flight = true;
+            Ok(true)
         }
+        Err(UsbError::WouldBlock) => Ok(false),
+        Err(_) => Err(()),
     }
+}
 
-    pub fn update_layout(
-        &mut self,
-        command: AppCommand,
-        writer: &mut ConfigWriter,
-    ) -> Result<(), FlashError> {
-        match command {
-            AppCommand::Set1(value) => self.layout[0] = value,
-            AppCommand::Set2(value) => self.layout[1] = value,
-            AppCommand::Set3(value) => self.layout[2] = value,
-            AppCommand::Save => writer.write_config(*self)?,
-        };
-        Ok(())
+/// Writes the HID class descriptor of an interface with a single report descriptor.
+fn write_hid_descriptor(
+    writer: &mut DescriptorWriter,
+    report_descriptor: &[u8],
+) -> usb_device::Result<()> {
+    let descriptor_len = report_descriptor.len();
+    if descriptor_len > u16::max_value() as usize {
+        return Err(UsbError::InvalidState);
     }
+    let descriptor_len = (descriptor_len as u16).to_le_bytes();
+    let specification_release = SPECIFICATION_RELEASE.to_le_bytes();
+    writer.write(
+        DescriptorType::Hid as u8,
+        &[
+            specification_release[0],     // bcdHID.lower
+            specification_release[1],     // bcdHID.upper
+            0,                            // bCountryCode: 0 = not supported
+            1,                            // bNumDescriptors
+            DescriptorType::Report as u8, // bDescriptorType
+            descriptor_len[0],            // bDescriptorLength.lower
+            descriptor_len[1],            // bDescriptorLength.upper
+        ],
+    )
+}
 
-    pub fn update(&self, debouncer: &mut PortDebouncer<U8, BtnsType>) -> KbHidReport {
-        let mut report = KbHidReport::new();
-
-        for (index, &btn) in self.layout.iter().enumerate() {
-            let state = debouncer.get_state(index);
-            if let Ok(value) = state {
-                if value != BtnState::UnPressed {
-                    report.pressed(btn);
-                }
-            }
+/// Reads the debounced state of every button, `true` means pressed.
+pub fn pressed_buttons(debouncer: &mut PortDebouncer<U8, BtnsType>) -> [bool; NUM_BTS] {
+    let mut pressed = [false; NUM_BTS];
+    for (index, value) in pressed.iter_mut().enumerate() {
+        if let Ok(state) = debouncer.get_state(index) {
+            *value = state != BtnState::UnPressed;
         }
-        report
-    }
-
-    pub fn to_bytes(self) -> [u8; NUM_BTS] {
-        // NOTE(unsafe) `self.layout` is `[KeyCode; NUM_BTS]` and `KeyCode` is `repr(u8)`
-        unsafe { core::mem::transmute(self.layout) }
     }
+    pressed
+}
 
-    pub fn from_bytes(bytes: [u8; NUM_BTS]) -> Option<Self> {
-        // Look for invalid codes
-        #[allow(clippy::absurd_extreme_comparisons)]
-        let invalid_code = bytes.iter().any(|&code| {
-            // The first test will probably get optimized out when `ZONE1_FIRST` == 0, but we do it
-            // anyway because that can change
-            (code < ZONE1_FIRST) || (code > ZONE1_LAST && code < ZONE2_FIRST) || (code > ZONE2_LAST)
-        });
-        if invalid_code {
-            None
-        } else {
-            // NOTE(unsafe) safe based on the check above
-            unsafe {
-                Some(Self {
-                    layout: core::mem::transmute(bytes),
-                })
-            }
-        }
+/// Reads the instantaneous state of every button from the inverted port value, `true` means
+/// pressed.
+pub fn raw_buttons(port: u32) -> [bool; NUM_BTS] {
+    let mut pressed = [false; NUM_BTS];
+    for (index, value) in pressed.iter_mut().enumerate() {
+        *value = port & (1 << index) != 0;
     }
+    pressed
 }
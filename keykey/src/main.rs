@@ -5,16 +5,32 @@ use core::{
     panic::PanicInfo,
     sync::atomic::{compiler_fence, Ordering},
 };
-use cortex_m::asm;
+use cortex_m::{asm, peripheral::SCB};
 use debouncer::{
     typenum::{consts::*, Unsigned},
     PortDebouncer,
 };
 use embedded_hal::digital::v2::OutputPin;
 use heapless::spsc::{Consumer, Queue};
-use keylib::{packets::AppCommand, PID, VID};
+use keylib::{
+    boot::{self, Status, SAFE_MODE_BUTTON},
+    consumer::ConsumerReport,
+    debounce::{self, DebounceInfo, EagerFilter, LatencyMode},
+    key_code::KbHidReport,
+    matrix::Matrix,
+    mouse::MouseReport,
+    name::{self, SERIAL_LEN, UID_SIZE},
+    packets::AppCommand,
+    quiet::QuietTimer,
+    repeat::Repeater,
+    sequence::Player,
+    settings::Settings,
+    NUM_BTS, PID, SCAN_HZ, VID,
+};
 use rtic::app;
+use static_assertions::{const_assert, const_assert_eq};
 use stm32f1xx_hal::{
+    gpio::{Edge, ExtiPin},
     pac,
     prelude::*,
     timer::{CountDownTimer, Event, Timer},
@@ -24,36 +40,70 @@ use usb_device::{bus, class::UsbClass, prelude::*};
 
 #[macro_use]
 mod loggy;
+mod bootloader;
 mod flash;
 mod keyboard;
-use flash::{ConfigWriter, FlashError};
-use keyboard::{Keykey, Matrix};
+use flash::ConfigWriter;
+use keyboard::Keykey;
 
 type UsbType = UsbDevice<'static, UsbBus<UsbPeripheral>>;
 type KeyboardType = Keykey<'static, 'static, UsbBus<UsbPeripheral>>;
 pub type BtnsType = U3;
-pub const NUM_BTS: usize = BtnsType::USIZE;
+const_assert_eq!(BtnsType::USIZE, NUM_BTS);
+/// How many samples an eager press waits for the debouncer to confirm it before being retracted,
+/// this should be longer than the debouncer takes to confirm a clean press.
+const EAGER_WINDOW: u8 = 32;
+const_assert!(EAGER_WINDOW > debounce::MAX_WINDOW);
+/// Scans given to the USB task to finish the status stage of a reboot request, ~10 ms.
+const REBOOT_SCANS: u8 = 2;
+const_assert!(REBOOT_SCANS > 0);
+// Resume signaling of a remote wakeup is started on a scan and stopped on the next one, the spec
+// asks for 1 to 15 ms.
+const_assert!(SCAN_HZ * 15 >= 1000 && SCAN_HZ <= 1000);
+/// Scans without configuration commands before key output resumes in quiet config mode, ~2 s.
+const QUIET_CONFIG_SCANS: u16 = 2 * SCAN_HZ as u16;
+/// Parameter of `PortDebouncer::new`, also reported to the host. The window comes from the
+/// settings.
+const DEBOUNCE_HOLD_THRESHOLD: u16 = 96;
+/// Address of the 96-bit unique device ID.
+const UID_ADDRESS: usize = 0x1FFF_F7E8;
 
 #[app(device = stm32f1xx_hal::pac, peripherals = true)]
 const APP: () = {
     struct Resources {
         debouncer_timer: CountDownTimer<pac::TIM2>,
         debouncer_handler: PortDebouncer<U8, BtnsType>,
+        // Parameters `debouncer_handler` was built with
+        debounce_info: DebounceInfo,
         usb_dev: UsbType,
         keyboard: KeyboardType,
         app_consumer: Consumer<'static, AppCommand, U8>,
+        // Only used by `debouncer_task` and the encoder tasks, all at the same priority, they can't
+        // preempt each other, so a layout replaced with `Matrix::replace` is never seen half done
         matrix: Matrix,
+        settings: Settings,
+        eager_filter: EagerFilter,
+        // Plays the startup sequence and the ones triggered by sequence buttons
+        player: Player,
+        quiet_timer: QuietTimer,
+        repeater: Repeater,
         writer: ConfigWriter,
+        // Resume signaling was started on the previous scan
+        resuming: bool,
+        // Pending reboot and the scans left before it
+        reboot: Option<(Reboot, u8)>,
     }
 
     #[init]
     fn init(cx: init::Context) -> init::LateResources {
         static mut USB_BUS: Option<bus::UsbBusAllocator<UsbBusType>> = None;
         static mut Q: Queue<AppCommand, U8> = Queue(heapless::i::Queue::new());
+        static mut SERIAL: [u8; SERIAL_LEN] = [0; SERIAL_LEN];
 
         let mut flash = cx.device.FLASH.constrain();
         let mut rcc = cx.device.RCC.constrain();
         let mut gpioa = cx.device.GPIOA.split(&mut rcc.apb2);
+        let mut afio = cx.device.AFIO.constrain(&mut rcc.apb2);
 
         let clocks = rcc
             .cfgr
@@ -66,13 +116,31 @@ const APP: () = {
         assert!(clocks.usbclk_valid());
 
         // buttons, in order: shoot, left, right
-        let _ = gpioa.pa0.into_pull_up_input(&mut gpioa.crl);
-        let _ = gpioa.pa1.into_pull_up_input(&mut gpioa.crl);
-        let _ = gpioa.pa2.into_pull_up_input(&mut gpioa.crl);
+        let mut pa0 = gpioa.pa0.into_pull_up_input(&mut gpioa.crl);
+        let mut pa1 = gpioa.pa1.into_pull_up_input(&mut gpioa.crl);
+        let mut pa2 = gpioa.pa2.into_pull_up_input(&mut gpioa.crl);
+        // Any pair of buttons can be an encoder, so every edge of its channels samples them,
+        // detents faster than a scan aren't lost this way. Only the lines of the configured
+        // encoder are unmasked, see `set_encoder_lines`
+        pa0.make_interrupt_source(&mut afio);
+        pa0.trigger_on_edge(&cx.device.EXTI, Edge::RISING_FALLING);
+        pa1.make_interrupt_source(&mut afio);
+        pa1.trigger_on_edge(&cx.device.EXTI, Edge::RISING_FALLING);
+        pa2.make_interrupt_source(&mut afio);
+        pa2.trigger_on_edge(&cx.device.EXTI, Edge::RISING_FALLING);
+
+        // Give the pull-ups time to settle before checking for safe mode, ~1 ms
+        asm::delay(clocks.sysclk().0 / 1000);
+        let port = !(unsafe { (*pac::GPIOA::ptr()).idr.read().bits() });
+        let safe_mode = keyboard::raw_buttons(port)[SAFE_MODE_BUTTON];
+        if safe_mode {
+            log!("Safe mode, ignoring the stored config");
+        }
 
         // Flash writer
-        let writer = ConfigWriter::new(flash).unwrap();
-        let matrix = writer.get_config().unwrap_or_else(Matrix::new);
+        let mut writer = ConfigWriter::new(flash).unwrap();
+        let (matrix, settings) = boot::load_config(safe_mode, || writer.get_config());
+        set_encoder_lines(matrix.encoder());
 
         // BluePill board has a pull-up resistor on the D+ line.
         // Pull the D+ pin down to send a RESET condition to the USB bus.
@@ -94,28 +162,57 @@ const APP: () = {
         *USB_BUS = Some(UsbBus::new(usb));
         let (prod, cons) = Q.split();
 
-        let keyboard = Keykey::new(USB_BUS.as_ref().unwrap(), prod);
+        let debounce_info = DebounceInfo {
+            window: settings.debounce_window.into(),
+            hold_threshold: DEBOUNCE_HOLD_THRESHOLD,
+            scan_hz: SCAN_HZ as u16,
+        };
+        let keyboard = Keykey::new(
+            USB_BUS.as_ref().unwrap(),
+            prod,
+            Status {
+                safe_mode,
+                load_failed: writer.load_failed(),
+                save_failed: false,
+            },
+            debounce_info,
+            settings.usb,
+        );
 
         let usb_dev = UsbDeviceBuilder::new(USB_BUS.as_ref().unwrap(), UsbVidPid(VID, PID))
             .manufacturer("Fake company")
             .product("KeyKey")
-            .serial_number("TEST")
+            .serial_number(name::serial_number(&read_uid(), SERIAL))
+            .self_powered(settings.usb.self_powered)
+            .supports_remote_wakeup(settings.usb.remote_wakeup)
+            .max_power(settings.usb.max_power_ma())
             .build();
 
         let mut timer2 =
-            Timer::tim2(cx.device.TIM2, &clocks, &mut rcc.apb1).start_count_down(200.hz());
+            Timer::tim2(cx.device.TIM2, &clocks, &mut rcc.apb1).start_count_down(SCAN_HZ.hz());
         timer2.listen(Event::Update);
 
         log!("Init finished");
 
         init::LateResources {
             debouncer_timer: timer2,
-            debouncer_handler: PortDebouncer::new(16, 96),
+            debouncer_handler: PortDebouncer::new(
+                settings.debounce_window as _,
+                DEBOUNCE_HOLD_THRESHOLD as _,
+            ),
+            debounce_info,
             usb_dev,
             keyboard,
             app_consumer: cons,
             writer,
             matrix,
+            settings,
+            eager_filter: EagerFilter::new(EAGER_WINDOW),
+            player: Player::idle(),
+            quiet_timer: QuietTimer::new(QUIET_CONFIG_SCANS),
+            repeater: Repeater::new(),
+            resuming: false,
+            reboot: None,
         }
     }
 
@@ -128,34 +225,199 @@ const APP: () = {
         }
     }
 
-    #[task(binds = TIM2, priority = 2, resources = [debouncer_timer, debouncer_handler, keyboard, matrix, app_consumer, writer])]
+    #[task(binds = TIM2, priority = 2, resources = [debouncer_timer, debouncer_handler, debounce_info, keyboard, matrix, settings, eager_filter, player, quiet_timer, repeater, app_consumer, writer, usb_dev, resuming, reboot])]
     fn debouncer_task(mut cx: debouncer_task::Context) {
         cx.resources.debouncer_timer.clear_update_interrupt_flag();
-        if cx
+        // The window changes with `SetDebounce` and a factory reset
+        let window = cx.resources.settings.debounce_window;
+        if u16::from(window) != cx.resources.debounce_info.window {
+            // Every button starts released, a held one is pressed again once the new window
+            // confirms it
+            *cx.resources.debouncer_handler =
+                PortDebouncer::new(window as _, DEBOUNCE_HOLD_THRESHOLD as _);
+            cx.resources.debounce_info.window = window.into();
+        }
+        let port = !(unsafe { (*pac::GPIOA::ptr()).idr.read().bits() });
+        cx.resources.debouncer_handler.update(port);
+        cx.resources
+            .matrix
+            .sample_encoder(&keyboard::raw_buttons(port));
+
+        // The matrix and the eager filter need to see every sample, not only the ones where the
+        // debounced state changed, unchanged reports aren't sent again
+        let mut pressed = keyboard::pressed_buttons(cx.resources.debouncer_handler);
+        if cx.resources.settings.latency_mode == LatencyMode::Eager {
+            pressed = cx
+                .resources
+                .eager_filter
+                .update(&keyboard::raw_buttons(port), &pressed);
+        }
+        let report = cx.resources.matrix.update(&pressed);
+        let report = cx
             .resources
-            .debouncer_handler
-            .update(!(unsafe { (*pac::GPIOA::ptr()).idr.read().bits() }))
-        {
-            let report = cx.resources.matrix.update(cx.resources.debouncer_handler);
-
-            cx.resources.keyboard.lock(|shared| {
-                if shared.set_keyboard_report(report.clone()) {
-                    if shared.write(report.as_bytes()).is_err() {
-                        log!("Error while sending report");
-                    }
+            .repeater
+            .update(&cx.resources.settings.repeat, report);
+        let consumer = cx.resources.matrix.consumer_report();
+        let mouse = cx.resources.matrix.mouse_report();
+        let triggered = cx.resources.matrix.take_sequence();
+        let switch_profile = cx.resources.matrix.take_profile_switch();
+
+        let player = cx.resources.player;
+        let startup_sequence = cx.resources.settings.startup_sequence;
+        // The timer keeps running when quiet config is disabled, so enabling it takes effect on
+        // the very next command
+        let quiet = cx.resources.quiet_timer.tick() && cx.resources.settings.quiet_config;
+        let layer_state = cx.resources.matrix.layer_state();
+        let config = cx.resources.matrix.to_bytes();
+        let config_index = cx.resources.writer.last_valid_index();
+        let profile = cx.resources.writer.profile();
+        let load_failed = cx.resources.writer.load_failed();
+        let save_failed = cx.resources.writer.save_failed();
+        let erase_count = cx.resources.writer.erase_count();
+        let name = cx.resources.settings.name;
+        let debounce_info = *cx.resources.debounce_info;
+        let any_pressed = pressed.iter().any(|&p| p);
+        let mut wakeup = false;
+        cx.resources.keyboard.lock(|shared| {
+            wakeup = any_pressed && shared.take_wakeup();
+            shared.set_layer_state(layer_state);
+            shared.set_config(config);
+            shared.set_config_index(config_index);
+            shared.set_profile(profile);
+            shared.set_load_failed(load_failed);
+            shared.set_save_failed(save_failed);
+            shared.set_erase_count(erase_count);
+            shared.set_pressed(&pressed);
+            shared.set_name(name);
+            shared.set_debounce_info(debounce_info);
+            if shared.take_connected() {
+                *player = Player::new(startup_sequence);
+            } else if let Some(sequence) = triggered {
+                // Presses during a sequence are dropped instead of queued
+                if player.is_done() {
+                    *player = Player::new(sequence);
+                }
+            }
+            if quiet {
+                // Release everything instead of leaving keys stuck, a sequence resumes afterwards
+                if shared.send_keyboard_report(&KbHidReport::new()).is_err()
+                    || shared.send_consumer_report(&ConsumerReport::new()).is_err()
+                    || shared.send_mouse_report(&MouseReport::new()).is_err()
+                {
+                    log!("Error while sending report");
+                }
+                return;
+            }
+            // The buttons are ignored until the sequence is done
+            let (consumer, mouse) = if player.is_done() {
+                (consumer, mouse)
+            } else {
+                (ConsumerReport::new(), MouseReport::new())
+            };
+            if shared.send_consumer_report(&consumer).is_err()
+                || shared.send_mouse_report(&mouse).is_err()
+            {
+                log!("Error while sending report");
+            }
+            let report = player.report().unwrap_or(report);
+            match shared.send_keyboard_report(&report) {
+                Ok(true) => player.advance(),
+                Ok(false) => {}
+                Err(_) => log!("Error while sending report"),
+            }
+        });
+        // Stopped a scan later instead of waiting here, the USB task has to keep running meanwhile
+        if *cx.resources.resuming {
+            stop_resume(&mut cx.resources.usb_dev);
+            *cx.resources.resuming = false;
+        } else if wakeup {
+            log!("Waking up the host");
+            start_resume(&mut cx.resources.usb_dev);
+            *cx.resources.resuming = true;
+        }
+        if let Some((target, scans)) = cx.resources.reboot {
+            *scans -= 1;
+            if *scans == 0 {
+                match target {
+                    Reboot::Firmware => SCB::sys_reset(),
+                    Reboot::Bootloader => bootloader::reboot(),
                 }
-            });
+            }
+        }
+        // Profiles, a factory reset and `SetEncoder` can all change the encoder
+        let encoder = cx.resources.matrix.encoder();
+        // The report of this scan was already built from the old profile, so the new one is used
+        // starting on the next scan
+        if switch_profile {
+            load_profile(cx.resources.matrix, cx.resources.writer.next_profile());
         }
         // Update the layout if needed
         if let Some(cmd) = cx.resources.app_consumer.dequeue() {
-            let writer = cx.resources.writer;
-            if let Err(FlashError::FlashNotErased) = cx.resources.matrix.update_layout(cmd, writer)
-            {
-                // Something went wrong, erase the flash and try one more time
-                writer.write_default().unwrap();
-                cx.resources.matrix.update_layout(cmd, writer).unwrap();
+            cx.resources.quiet_timer.command();
+            let matrix = &mut *cx.resources.matrix;
+            let settings = cx.resources.settings;
+            match cmd {
+                AppCommand::Save => {
+                    // A record that wasn't erased is dealt with by the writer, the previous config
+                    // stays in use on any other error
+                    if cx.resources.writer.write_config(matrix, settings).is_err() {
+                        log!("Error while saving the config");
+                    }
+                }
+                AppCommand::Compact => {
+                    // The previous config stays in use on errors, the same as a failed save
+                    if cx.resources.writer.compact().is_err() {
+                        log!("Error while compacting the config");
+                    }
+                }
+                AppCommand::FactoryReset => {
+                    // The previous config stays in use on errors, the same as a failed save
+                    if cx.resources.writer.factory_reset().is_ok() {
+                        matrix.replace(Matrix::new());
+                        *settings = Settings::new();
+                    } else {
+                        log!("Error while resetting the config");
+                    }
+                }
+                AppCommand::NextProfile => load_profile(matrix, cx.resources.writer.next_profile()),
+                AppCommand::SelectProfile(profile) => {
+                    load_profile(matrix, cx.resources.writer.select_profile(profile))
+                }
+                AppCommand::Reboot => {
+                    // Commands are handled in order and flash operations are blocking, so a save
+                    // requested before this is already finished
+                    log!("Rebooting");
+                    *cx.resources.reboot = Some((Reboot::Firmware, REBOOT_SCANS));
+                }
+                AppCommand::EnterBootloader => {
+                    // The same as a reboot, the device then disconnects until the next reset
+                    log!("Rebooting into the bootloader");
+                    *cx.resources.reboot = Some((Reboot::Bootloader, REBOOT_SCANS));
+                }
+                cmd => {
+                    matrix.update_layout(cmd);
+                    settings.update(cmd);
+                }
             }
         }
+        if cx.resources.matrix.encoder() != encoder {
+            set_encoder_lines(cx.resources.matrix.encoder());
+        }
+    }
+
+    #[task(binds = EXTI0, priority = 2, resources = [matrix])]
+    fn encoder_a(cx: encoder_a::Context) {
+        sample_encoder(cx.resources.matrix, 0);
+    }
+
+    #[task(binds = EXTI1, priority = 2, resources = [matrix])]
+    fn encoder_b(cx: encoder_b::Context) {
+        sample_encoder(cx.resources.matrix, 1);
+    }
+
+    #[task(binds = EXTI2, priority = 2, resources = [matrix])]
+    fn encoder_c(cx: encoder_c::Context) {
+        sample_encoder(cx.resources.matrix, 2);
     }
 
     #[task(binds = USB_LP_CAN_RX0, priority = 3, resources = [usb_dev, keyboard])]
@@ -163,9 +425,87 @@ const APP: () = {
         if cx.resources.usb_dev.poll(&mut [cx.resources.keyboard]) {
             cx.resources.keyboard.poll();
         }
+        let state = cx.resources.usb_dev.state();
+        if state == UsbDeviceState::Configured {
+            cx.resources.keyboard.configured();
+        }
+        // Only signaled if the host enabled it with SetFeature, declaring support isn't enough
+        cx.resources.keyboard.set_suspended(
+            state == UsbDeviceState::Suspend && cx.resources.usb_dev.remote_wakeup_enabled(),
+        );
     }
 };
 
+/// Puts a profile loaded by the flash writer in place of `matrix`, `None` if it couldn't be
+/// loaded.
+fn load_profile(matrix: &mut Matrix, profile: Option<Matrix>) {
+    match profile {
+        Some(new) => {
+            matrix.replace(new);
+        }
+        None => log!("Couldn't switch profiles"),
+    }
+}
+
+/// Reads the unique device ID from the system memory, it's the same on every boot.
+fn read_uid() -> [u8; UID_SIZE] {
+    let mut uid = [0; UID_SIZE];
+    for (index, byte) in uid.iter_mut().enumerate() {
+        // Safe since it's a read only region programmed in the factory
+        *byte = unsafe { core::ptr::read_volatile((UID_ADDRESS + index) as *const u8) };
+    }
+    uid
+}
+
+/// What a reboot requested by the host resets into.
+#[derive(Clone, Copy)]
+enum Reboot {
+    Firmware,
+    Bootloader,
+}
+
+/// Starts resume signaling on the bus to wake up a suspended host, `stop_resume` ends it. The
+/// host then resumes the bus and the USB task sees the device leave the suspended state.
+fn start_resume(usb_dev: &mut impl rtic::Mutex<T = UsbType>) {
+    // The USB driver also changes CNTR from the USB task on suspend and wakeup interrupts, and the
+    // host resuming the bus raises one while we're still signaling, so every change is done with
+    // the device locked
+    let usb = unsafe { &*pac::USB::ptr() };
+    usb_dev.lock(|_| {
+        // Leave low-power mode first, the driver sets both on suspend
+        usb.cntr
+            .modify(|_, w| w.lpmode().clear_bit().fsusp().clear_bit());
+        usb.cntr.modify(|_, w| w.resume().set_bit());
+    });
+}
+
+/// Ends the resume signaling started by `start_resume`.
+fn stop_resume(usb_dev: &mut impl rtic::Mutex<T = UsbType>) {
+    let usb = unsafe { &*pac::USB::ptr() };
+    usb_dev.lock(|_| usb.cntr.modify(|_, w| w.resume().clear_bit()));
+}
+
+/// Unmasks the EXTI lines of the encoder channels and masks the others, the buttons that aren't
+/// part of the encoder are only read by the scan.
+fn set_encoder_lines(encoder: Option<usize>) {
+    let lines = (1 << NUM_BTS) - 1;
+    let unmasked = encoder.map_or(0, |first| 0b11 << first);
+    // Only touching our own lines, the EXTI tasks run at the same priority as every caller
+    unsafe {
+        (*pac::EXTI::ptr())
+            .imr
+            .modify(|r, w| w.bits((r.bits() & !lines) | unmasked))
+    };
+}
+
+/// Clears the pending edge of EXTI `line` and feeds the channels to the encoder decoder.
+fn sample_encoder(matrix: &mut Matrix, line: u8) {
+    // Safe since it's a write to a clear-on-write register, only touching our own line
+    unsafe { (*pac::EXTI::ptr()).pr.write(|w| w.bits(1 << line)) };
+    let port = !(unsafe { (*pac::GPIOA::ptr()).idr.read().bits() });
+    matrix.sample_encoder(&keyboard::raw_buttons(port));
+}
+
 #[inline(never)]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {